@@ -0,0 +1,1131 @@
+/**
+ * Simulator of the 2A03 audio processing unit.
+ *
+ * The APU has five channels: two pulse waves, a triangle wave, noise and a delta modulation
+ * channel (DMC) that plays 1-bit delta encoded samples. Channels are driven by timers clocked by
+ * the CPU clock, and by a frame sequencer that clocks envelopes, sweeps and length counters
+ * roughly 240 times per second.
+ *
+ * The 2A07 of PAL consoles runs at a slower clock, and uses its own noise and DMC periods and
+ * frame sequencer steps so that pitches and tempo stay close to NTSC. The Dendy keeps the NTSC
+ * periods.
+ *
+ * See https://wiki.nesdev.com/w/index.php/APU
+ */
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use simple_error::SimpleError;
+
+// NTSC CPU clock rate in Hz.
+pub const CPU_CLOCK_RATE_NTSC: f64 = 1_789_773.0;
+
+// Default sample rate of the rendered audio.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// Memory-mapped registers.
+const REG_PULSE1_START: u16 = 0x4000;
+const REG_PULSE2_START: u16 = 0x4004;
+const REG_TRIANGLE_START: u16 = 0x4008;
+const REG_NOISE_START: u16 = 0x400c;
+const REG_DMC_START: u16 = 0x4010;
+const REG_STATUS: u16 = 0x4015;
+const REG_FRAME_COUNTER: u16 = 0x4017;
+
+// Length counter values indexed by the 5-bit value written to the length register.
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Pulse waveforms for the four duty cycles: 12.5%, 25%, 50% and 25% negated.
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+// Noise timer periods in CPU cycles.
+const NOISE_PERIOD_TABLE_NTSC: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const NOISE_PERIOD_TABLE_PAL: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+// DMC timer periods in CPU cycles.
+const DMC_RATE_TABLE_NTSC: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const DMC_RATE_TABLE_PAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+// Frame sequencer steps in CPU cycles. The last entry of each sequence is its period.
+const FRAME_SEQUENCE_4_STEP_NTSC: [u32; 4] = [7457, 14913, 22371, 29830];
+const FRAME_SEQUENCE_5_STEP_NTSC: [u32; 5] = [7457, 14913, 22371, 29829, 37282];
+const FRAME_SEQUENCE_4_STEP_PAL: [u32; 4] = [8313, 16627, 24939, 33254];
+const FRAME_SEQUENCE_5_STEP_PAL: [u32; 5] = [8313, 16627, 24939, 33253, 41566];
+
+// Volume envelope shared by the pulse and noise channels.
+#[derive(Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant_volume: bool,
+    // Either the constant volume or the divider period.
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, val: u8) {
+        self.looping = val & 0b0010_0000 != 0;
+        self.constant_volume = val & 0b0001_0000 != 0;
+        self.volume = val & 0b0000_1111;
+    }
+
+    // Clocked by quarter frames.
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+            return;
+        }
+
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+
+        self.divider = self.volume;
+        if self.decay > 0 {
+            self.decay -= 1;
+        } else if self.looping {
+            self.decay = 15;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pulse {
+    // Pulse 1 negates the sweep with one's complement, pulse 2 with two's complement.
+    ones_complement: bool,
+    enabled: bool,
+    duty: u8,
+    sequence_step: u8,
+    envelope: Envelope,
+    length_counter: u8,
+    timer_period: u16,
+    timer: u16,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            ..Default::default()
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.duty = val >> 6;
+                self.envelope.write(val);
+            }
+            1 => {
+                self.sweep_enabled = val & 0b1000_0000 != 0;
+                self.sweep_period = (val >> 4) & 0b0111;
+                self.sweep_negate = val & 0b0000_1000 != 0;
+                self.sweep_shift = val & 0b0000_0111;
+                self.sweep_reload = true;
+            }
+            2 => {
+                self.timer_period = (self.timer_period & 0xff00) | val as u16;
+            }
+            _ => {
+                self.timer_period = (self.timer_period & 0x00ff) | (((val & 0b0111) as u16) << 8);
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.sequence_step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    // Clocked every other CPU cycle.
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_step = (self.sequence_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.envelope.looping && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.timer_period.wrapping_add(change)
+        } else if self.ones_complement {
+            self.timer_period.saturating_sub(change + 1)
+        } else {
+            self.timer_period.saturating_sub(change)
+        }
+    }
+
+    fn is_muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7ff
+    }
+
+    // Clocked by half frames.
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.is_muted()
+        {
+            self.timer_period = self.sweep_target();
+        }
+
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length_counter == 0
+            || self.is_muted()
+            || DUTY_TABLE[self.duty as usize][self.sequence_step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[derive(Default)]
+struct Triangle {
+    enabled: bool,
+    // Doubles as the length counter halt flag.
+    control: bool,
+    linear_counter_reload_value: u8,
+    linear_counter_reload: bool,
+    linear_counter: u8,
+    length_counter: u8,
+    timer_period: u16,
+    timer: u16,
+    sequence_step: u8,
+}
+
+impl Triangle {
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.control = val & 0b1000_0000 != 0;
+                self.linear_counter_reload_value = val & 0b0111_1111;
+            }
+            1 => {}
+            2 => {
+                self.timer_period = (self.timer_period & 0xff00) | val as u16;
+            }
+            _ => {
+                self.timer_period = (self.timer_period & 0x00ff) | (((val & 0b0111) as u16) << 8);
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.linear_counter_reload = true;
+            }
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    // Clocked every CPU cycle.
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length_counter > 0 && self.linear_counter > 0 {
+                self.sequence_step = (self.sequence_step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear_counter(&mut self) {
+        if self.linear_counter_reload {
+            self.linear_counter = self.linear_counter_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control {
+            self.linear_counter_reload = false;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.control && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.sequence_step as usize]
+    }
+}
+
+struct Noise {
+    enabled: bool,
+    envelope: Envelope,
+    length_counter: u8,
+    // Short mode feeds back bit 6 instead of bit 1, giving a metallic tone.
+    short_mode: bool,
+    period_table: &'static [u16; 16],
+    timer_period: u16,
+    timer: u16,
+    shift_register: u16,
+}
+
+impl Noise {
+    fn new(period_table: &'static [u16; 16]) -> Self {
+        Noise {
+            enabled: false,
+            envelope: Envelope::default(),
+            length_counter: 0,
+            short_mode: false,
+            period_table,
+            timer_period: period_table[0],
+            timer: 0,
+            shift_register: 1,
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => self.envelope.write(val),
+            1 => {}
+            2 => {
+                self.short_mode = val & 0b1000_0000 != 0;
+                self.timer_period = self.period_table[(val & 0b0000_1111) as usize];
+            }
+            _ => {
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    // Clocked every CPU cycle.
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            let other_bit = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register & 1) ^ ((self.shift_register >> other_bit) & 1);
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.envelope.looping && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length_counter == 0 || self.shift_register & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+struct Dmc {
+    irq_enabled: bool,
+    irq: bool,
+    looping: bool,
+    rate_table: &'static [u16; 16],
+    timer_period: u16,
+    timer: u16,
+    output_level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    fn new(rate_table: &'static [u16; 16]) -> Self {
+        Dmc {
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            rate_table,
+            timer_period: rate_table[0],
+            timer: 0,
+            output_level: 0,
+            sample_address: 0xc000,
+            sample_length: 1,
+            current_address: 0xc000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = val & 0b1000_0000 != 0;
+                self.looping = val & 0b0100_0000 != 0;
+                self.timer_period = self.rate_table[(val & 0b0000_1111) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => self.output_level = val & 0b0111_1111,
+            2 => self.sample_address = 0xc000 | ((val as u16) << 6),
+            _ => self.sample_length = ((val as u16) << 4) | 1,
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // Address of the next sample byte if the sample buffer needs to be refilled.
+    fn pending_read(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    fn fill_sample_buffer(&mut self, val: u8) {
+        self.sample_buffer = Some(val);
+        // The address wraps around to 0x8000 rather than 0x0000.
+        self.current_address = if self.current_address == 0xffff {
+            0x8000
+        } else {
+            self.current_address + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle.
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(val) => {
+                    self.silence = false;
+                    self.shift_register = val;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+
+    // Frame sequencer.
+    frame_sequence_4_step: [u32; 4],
+    frame_sequence_5_step: [u32; 5],
+    five_step_mode: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+
+    // Number of CPU cycles elapsed.
+    cycles: u64,
+
+    // Resampling from the CPU clock rate to the output sample rate.
+    sample_rate: u32,
+    cpu_clock_rate: f64,
+    sample_phase: f64,
+    sample_sum: f32,
+    sample_count: u32,
+    // State of the high-pass filter removing the DC offset.
+    filter_prev_input: f32,
+    filter_prev_output: f32,
+    samples: Vec<f32>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu::with_region(Region::Ntsc, DEFAULT_SAMPLE_RATE)
+    }
+
+    // Creates the APU of a |region| console, producing |sample_rate| samples per second.
+    pub fn with_region(region: Region, sample_rate: u32) -> Self {
+        let (noise_period_table, dmc_rate_table, frame_sequence_4_step, frame_sequence_5_step) =
+            match region {
+                Region::Ntsc | Region::Dendy => (
+                    &NOISE_PERIOD_TABLE_NTSC,
+                    &DMC_RATE_TABLE_NTSC,
+                    FRAME_SEQUENCE_4_STEP_NTSC,
+                    FRAME_SEQUENCE_5_STEP_NTSC,
+                ),
+                Region::Pal => (
+                    &NOISE_PERIOD_TABLE_PAL,
+                    &DMC_RATE_TABLE_PAL,
+                    FRAME_SEQUENCE_4_STEP_PAL,
+                    FRAME_SEQUENCE_5_STEP_PAL,
+                ),
+            };
+        Apu {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(noise_period_table),
+            dmc: Dmc::new(dmc_rate_table),
+            frame_sequence_4_step,
+            frame_sequence_5_step,
+            five_step_mode: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            cycles: 0,
+            sample_rate,
+            cpu_clock_rate: region.cpu_clock_rate(),
+            sample_phase: 0.0,
+            sample_sum: 0.0,
+            sample_count: 0,
+            filter_prev_input: 0.0,
+            filter_prev_output: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Handles a CPU write to one of the registers in [0x4000, 0x4017].
+    pub fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            REG_PULSE1_START..=0x4003 => self.pulse1.write(addr - REG_PULSE1_START, val),
+            REG_PULSE2_START..=0x4007 => self.pulse2.write(addr - REG_PULSE2_START, val),
+            REG_TRIANGLE_START..=0x400b => self.triangle.write(addr - REG_TRIANGLE_START, val),
+            REG_NOISE_START..=0x400f => self.noise.write(addr - REG_NOISE_START, val),
+            REG_DMC_START..=0x4013 => self.dmc.write(addr - REG_DMC_START, val),
+            REG_STATUS => {
+                self.pulse1.set_enabled(val & 0b0000_0001 != 0);
+                self.pulse2.set_enabled(val & 0b0000_0010 != 0);
+                self.triangle.set_enabled(val & 0b0000_0100 != 0);
+                self.noise.set_enabled(val & 0b0000_1000 != 0);
+                self.dmc.set_enabled(val & 0b0001_0000 != 0);
+            }
+            REG_FRAME_COUNTER => {
+                self.five_step_mode = val & 0b1000_0000 != 0;
+                self.irq_inhibit = val & 0b0100_0000 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                if self.five_step_mode {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Handles a CPU read of 0x4015. Reading clears the frame interrupt flag.
+    pub fn read_status(&mut self) -> u8 {
+        let val = self.peek_status();
+        self.frame_irq = false;
+        val
+    }
+
+    // Same as read_status() but without side effects.
+    pub fn peek_status(&self) -> u8 {
+        let mut val = 0;
+        if self.pulse1.length_counter > 0 {
+            val |= 0b0000_0001;
+        }
+        if self.pulse2.length_counter > 0 {
+            val |= 0b0000_0010;
+        }
+        if self.triangle.length_counter > 0 {
+            val |= 0b0000_0100;
+        }
+        if self.noise.length_counter > 0 {
+            val |= 0b0000_1000;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            val |= 0b0001_0000;
+        }
+        if self.frame_irq {
+            val |= 0b0100_0000;
+        }
+        if self.dmc.irq {
+            val |= 0b1000_0000;
+        }
+        val
+    }
+
+    // Whether the APU asserts the IRQ line.
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // Address the DMC wants to read its next sample byte from, if any. The owner of the bus should
+    // read it and hand it over with dmc_fill_sample_buffer().
+    pub fn dmc_pending_read(&self) -> Option<u16> {
+        self.dmc.pending_read()
+    }
+
+    pub fn dmc_fill_sample_buffer(&mut self, val: u8) {
+        self.dmc.fill_sample_buffer(val);
+    }
+
+    // Advances the APU by one CPU cycle.
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycles.is_multiple_of(2) {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+
+        self.clock_frame_sequencer();
+        self.mix();
+    }
+
+    // Advances the APU by |cycles| CPU cycles.
+    pub fn tick_cycles(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.tick();
+        }
+    }
+
+    // Takes the samples rendered so far.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        self.frame_cycle += 1;
+
+        if self.five_step_mode {
+            let steps = self.frame_sequence_5_step;
+            if self.frame_cycle == steps[0] || self.frame_cycle == steps[2] {
+                self.clock_quarter_frame();
+            } else if self.frame_cycle == steps[1] || self.frame_cycle == steps[4] {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            if self.frame_cycle >= steps[4] {
+                self.frame_cycle = 0;
+            }
+        } else {
+            let steps = self.frame_sequence_4_step;
+            if self.frame_cycle == steps[0] || self.frame_cycle == steps[2] {
+                self.clock_quarter_frame();
+            } else if self.frame_cycle == steps[1] || self.frame_cycle == steps[3] {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            if self.frame_cycle >= steps[3] {
+                if !self.irq_inhibit {
+                    self.frame_irq = true;
+                }
+                self.frame_cycle = 0;
+            }
+        }
+    }
+
+    // Clocks envelopes and the triangle's linear counter.
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+
+    // Clocks length counters and sweep units.
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_length();
+        self.pulse2.clock_length();
+        self.triangle.clock_length();
+        self.noise.clock_length();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    // Mixes the channels with the non-linear formulas of the real hardware, see
+    // https://wiki.nesdev.com/w/index.php/APU_Mixer. The result is in [0.0, 1.0].
+    fn output(&self) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+
+        let tnd = self.triangle.output() as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc.output() as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+
+        pulse_out + tnd_out
+    }
+
+    // Averages the output over each sample period and removes the DC offset.
+    fn mix(&mut self) {
+        self.sample_sum += self.output();
+        self.sample_count += 1;
+
+        self.sample_phase += self.sample_rate as f64;
+        if self.sample_phase < self.cpu_clock_rate {
+            return;
+        }
+        self.sample_phase -= self.cpu_clock_rate;
+
+        let input = self.sample_sum / self.sample_count as f32;
+        let output = input - self.filter_prev_input + 0.996 * self.filter_prev_output;
+        self.filter_prev_input = input;
+        self.filter_prev_output = output;
+        self.samples.push(output);
+
+        self.sample_sum = 0.0;
+        self.sample_count = 0;
+    }
+}
+
+impl Savestate for Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.start);
+        w.write(&self.looping);
+        w.write(&self.constant_volume);
+        w.write(&self.volume);
+        w.write(&self.divider);
+        w.write(&self.decay);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.start = r.read()?;
+        self.looping = r.read()?;
+        self.constant_volume = r.read()?;
+        self.volume = r.read()?;
+        self.divider = r.read()?;
+        self.decay = r.read()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Pulse {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.enabled);
+        w.write(&self.duty);
+        w.write(&self.sequence_step);
+        self.envelope.save_state(w);
+        w.write(&self.length_counter);
+        w.write(&self.timer_period);
+        w.write(&self.timer);
+        w.write(&self.sweep_enabled);
+        w.write(&self.sweep_period);
+        w.write(&self.sweep_negate);
+        w.write(&self.sweep_shift);
+        w.write(&self.sweep_reload);
+        w.write(&self.sweep_divider);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.enabled = r.read()?;
+        self.duty = r.read()?;
+        self.sequence_step = r.read()?;
+        self.envelope.load_state(r)?;
+        self.length_counter = r.read()?;
+        self.timer_period = r.read()?;
+        self.timer = r.read()?;
+        self.sweep_enabled = r.read()?;
+        self.sweep_period = r.read()?;
+        self.sweep_negate = r.read()?;
+        self.sweep_shift = r.read()?;
+        self.sweep_reload = r.read()?;
+        self.sweep_divider = r.read()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Triangle {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.enabled);
+        w.write(&self.control);
+        w.write(&self.linear_counter_reload_value);
+        w.write(&self.linear_counter_reload);
+        w.write(&self.linear_counter);
+        w.write(&self.length_counter);
+        w.write(&self.timer_period);
+        w.write(&self.timer);
+        w.write(&self.sequence_step);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.enabled = r.read()?;
+        self.control = r.read()?;
+        self.linear_counter_reload_value = r.read()?;
+        self.linear_counter_reload = r.read()?;
+        self.linear_counter = r.read()?;
+        self.length_counter = r.read()?;
+        self.timer_period = r.read()?;
+        self.timer = r.read()?;
+        self.sequence_step = r.read()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Noise {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.enabled);
+        self.envelope.save_state(w);
+        w.write(&self.length_counter);
+        w.write(&self.short_mode);
+        w.write(&self.timer_period);
+        w.write(&self.timer);
+        w.write(&self.shift_register);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.enabled = r.read()?;
+        self.envelope.load_state(r)?;
+        self.length_counter = r.read()?;
+        self.short_mode = r.read()?;
+        self.timer_period = r.read()?;
+        self.timer = r.read()?;
+        self.shift_register = r.read()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Dmc {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.irq_enabled);
+        w.write(&self.irq);
+        w.write(&self.looping);
+        w.write(&self.timer_period);
+        w.write(&self.timer);
+        w.write(&self.output_level);
+        w.write(&self.sample_address);
+        w.write(&self.sample_length);
+        w.write(&self.current_address);
+        w.write(&self.bytes_remaining);
+        w.write(&self.sample_buffer);
+        w.write(&self.shift_register);
+        w.write(&self.bits_remaining);
+        w.write(&self.silence);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.irq_enabled = r.read()?;
+        self.irq = r.read()?;
+        self.looping = r.read()?;
+        self.timer_period = r.read()?;
+        self.timer = r.read()?;
+        self.output_level = r.read()?;
+        self.sample_address = r.read()?;
+        self.sample_length = r.read()?;
+        self.current_address = r.read()?;
+        self.bytes_remaining = r.read()?;
+        self.sample_buffer = r.read()?;
+        self.shift_register = r.read()?;
+        self.bits_remaining = r.read()?;
+        self.silence = r.read()?;
+        Ok(())
+    }
+}
+
+// The region tables and the sample rate are part of the configuration, not of the state. Samples
+// not yet taken are dropped on load.
+impl Savestate for Apu {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+        w.write(&self.five_step_mode);
+        w.write(&self.irq_inhibit);
+        w.write(&self.frame_irq);
+        w.write(&self.frame_cycle);
+        w.write(&self.cycles);
+        w.write(&self.sample_phase);
+        w.write(&self.sample_sum);
+        w.write(&self.sample_count);
+        w.write(&self.filter_prev_input);
+        w.write(&self.filter_prev_output);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.dmc.load_state(r)?;
+        self.five_step_mode = r.read()?;
+        self.irq_inhibit = r.read()?;
+        self.frame_irq = r.read()?;
+        self.frame_cycle = r.read()?;
+        self.cycles = r.read()?;
+        self.sample_phase = r.read()?;
+        self.sample_sum = r.read()?;
+        self.sample_count = r.read()?;
+        self.filter_prev_input = r.read()?;
+        self.filter_prev_output = r.read()?;
+        self.samples.clear();
+        Ok(())
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_status_reports_length_counters() {
+        let mut apu = Apu::new();
+
+        apu.write_register(0x4015, 0b0000_0011);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x4007, 0b0000_1000);
+
+        assert_eq!(apu.read_status(), 0b0000_0011);
+    }
+
+    #[test]
+    fn test_length_counter_ignored_when_disabled() {
+        let mut apu = Apu::new();
+
+        apu.write_register(0x400b, 0b0000_1000);
+
+        assert_eq!(apu.read_status(), 0);
+    }
+
+    #[test]
+    fn test_disabling_channel_clears_length_counter() {
+        let mut apu = Apu::new();
+
+        apu.write_register(0x4015, 0b0000_0001);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x4015, 0);
+
+        assert_eq!(apu.read_status(), 0);
+    }
+
+    #[test]
+    fn test_length_counter_expires() {
+        let mut apu = Apu::new();
+
+        apu.write_register(0x4015, 0b0000_0001);
+        // Length index 3 loads a length of 2, i.e. two half frames.
+        apu.write_register(0x4003, 0b0001_1000);
+        apu.tick_cycles(FRAME_SEQUENCE_4_STEP_NTSC[3] as u64);
+
+        assert_eq!(apu.read_status() & 0b0000_0001, 0);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new();
+
+        apu.tick_cycles(FRAME_SEQUENCE_4_STEP_NTSC[3] as u64);
+
+        assert_eq!(apu.irq(), true);
+        assert_eq!(apu.read_status(), 0b0100_0000);
+        assert_eq!(apu.irq(), false);
+    }
+
+    #[test]
+    fn test_frame_irq_inhibit() {
+        let mut apu = Apu::new();
+
+        apu.write_register(0x4017, 0b0100_0000);
+        apu.tick_cycles(FRAME_SEQUENCE_4_STEP_NTSC[3] as u64);
+
+        assert_eq!(apu.irq(), false);
+    }
+
+    #[test]
+    fn test_dmc_reads_sample() {
+        let mut apu = Apu::new();
+
+        apu.write_register(0x4012, 0x01);
+        apu.write_register(0x4013, 0x00);
+        apu.write_register(0x4015, 0b0001_0000);
+
+        assert_eq!(apu.dmc_pending_read(), Some(0xc040));
+        apu.dmc_fill_sample_buffer(0xff);
+        assert_eq!(apu.dmc_pending_read(), None);
+        assert_eq!(apu.read_status() & 0b0001_0000, 0);
+    }
+
+    #[test]
+    fn test_renders_samples_at_sample_rate() {
+        let mut apu = Apu::new();
+
+        apu.tick_cycles(CPU_CLOCK_RATE_NTSC as u64);
+
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), DEFAULT_SAMPLE_RATE as usize);
+    }
+
+    #[test]
+    fn test_pulse_produces_sound() {
+        let mut apu = Apu::new();
+
+        apu.write_register(0x4015, 0b0000_0001);
+        // 50% duty, constant volume 15.
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0xfd);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.tick_cycles(10_000);
+
+        let samples = apu.take_samples();
+        assert!(samples.iter().any(|s| s.abs() > 0.01));
+    }
+
+    #[test]
+    fn test_pal_frame_irq() {
+        let mut apu = Apu::with_region(Region::Pal, DEFAULT_SAMPLE_RATE);
+
+        apu.tick_cycles(FRAME_SEQUENCE_4_STEP_NTSC[3] as u64);
+        assert_eq!(apu.irq(), false);
+        apu.tick_cycles((FRAME_SEQUENCE_4_STEP_PAL[3] - FRAME_SEQUENCE_4_STEP_NTSC[3]) as u64);
+
+        assert_eq!(apu.irq(), true);
+    }
+
+    #[test]
+    fn test_pal_periods() {
+        let mut apu = Apu::with_region(Region::Pal, DEFAULT_SAMPLE_RATE);
+
+        apu.write_register(0x400e, 0x0f);
+        apu.write_register(0x4010, 0x0f);
+
+        assert_eq!(apu.noise.timer_period, 3778);
+        assert_eq!(apu.dmc.timer_period, 50);
+    }
+}
@@ -0,0 +1,149 @@
+/**
+ * Arkanoid "Vaus" paddle controller.
+ *
+ * The paddle has a potentiometer and a fire button. The strobe latches the position of the knob
+ * as an 8-bit value, which is then read serially from D4, most significant bit first and
+ * inverted. D3 reports the fire button.
+ *
+ * See https://wiki.nesdev.com/w/index.php/Arkanoid_controller
+ */
+use crate::input::{InputContext, InputDevice};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use simple_error::SimpleError;
+
+const FIRE_PRESSED: u8 = 0b0000_1000;
+const SERIAL_DATA_SHIFT: u8 = 4;
+
+// Range of positions reported by real controllers.
+pub const POSITION_MIN: u8 = 0x62;
+pub const POSITION_MAX: u8 = 0xf2;
+
+pub struct ArkanoidPaddle {
+    position: u8,
+    fire: bool,
+    strobe: bool,
+    shift_register: u8,
+}
+
+impl ArkanoidPaddle {
+    pub fn new() -> Self {
+        ArkanoidPaddle {
+            position: POSITION_MIN,
+            fire: false,
+            strobe: false,
+            shift_register: 0,
+        }
+    }
+
+    // Turns the knob. |position| is clamped to the range of real controllers.
+    pub fn set_position(&mut self, position: u8) {
+        self.position = position.clamp(POSITION_MIN, POSITION_MAX);
+    }
+
+    pub fn position(&self) -> u8 {
+        self.position
+    }
+
+    pub fn set_fire(&mut self, pressed: bool) {
+        self.fire = pressed;
+    }
+
+    fn current_bits(&self) -> u8 {
+        let register = if self.strobe {
+            !self.position
+        } else {
+            self.shift_register
+        };
+        let mut val = (register >> 7) << SERIAL_DATA_SHIFT;
+        if self.fire {
+            val |= FIRE_PRESSED;
+        }
+        val
+    }
+}
+
+impl InputDevice for ArkanoidPaddle {
+    fn write(&mut self, val: u8) {
+        self.strobe = val & 0b0000_0001 != 0;
+        if self.strobe {
+            self.shift_register = !self.position;
+        }
+    }
+
+    fn read(&mut self, _ctx: &InputContext) -> u8 {
+        let val = self.current_bits();
+        if !self.strobe {
+            self.shift_register <<= 1;
+        }
+        val
+    }
+
+    fn peek(&self, _ctx: &InputContext) -> u8 {
+        self.current_bits()
+    }
+}
+
+impl Savestate for ArkanoidPaddle {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.position);
+        w.write(&self.fire);
+        w.write(&self.strobe);
+        w.write(&self.shift_register);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.position = r.read()?;
+        self.fire = r.read()?;
+        self.strobe = r.read()?;
+        self.shift_register = r.read()?;
+        Ok(())
+    }
+}
+
+impl Default for ArkanoidPaddle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serial_position() {
+        let mut paddle = ArkanoidPaddle::new();
+        paddle.set_position(0b1010_1100);
+
+        paddle.write(1);
+        paddle.write(0);
+
+        let bits: Vec<u8> = (0..8)
+            .map(|_| paddle.read(&InputContext::none()) >> SERIAL_DATA_SHIFT)
+            .collect();
+        assert_eq!(bits, vec![0, 1, 0, 1, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_fire() {
+        let mut paddle = ArkanoidPaddle::new();
+
+        paddle.set_fire(true);
+
+        assert_eq!(
+            paddle.peek(&InputContext::none()) & FIRE_PRESSED,
+            FIRE_PRESSED
+        );
+    }
+
+    #[test]
+    fn test_position_clamped() {
+        let mut paddle = ArkanoidPaddle::new();
+
+        paddle.set_position(0x00);
+        assert_eq!(paddle.position(), POSITION_MIN);
+
+        paddle.set_position(0xff);
+        assert_eq!(paddle.position(), POSITION_MAX);
+    }
+}
@@ -0,0 +1,982 @@
+/**
+ * Assembler of 6502 code, with a syntax close to the one of ca65.
+ *
+ * A line holds an optional label ("loop:"), then an instruction or a directive, then an optional
+ * comment after ';'. Constants are defined with "NAME = expression". Labels starting with '@' are
+ * local to the last label without '@'.
+ *
+ * Supported directives:
+ *   .org address            Sets the address of what follows. Gaps are filled with zeros.
+ *   .byte value, "text"     Emits bytes. ".db" is an alias.
+ *   .word value             Emits little endian words. ".dw" is an alias.
+ *   .res count[, value]     Emits |count| times |value|, zero by default.
+ *
+ * Expressions hold numbers ($hex, %binary, decimal or 'c'), symbols, '*' for the address of the
+ * current line, unary - ~ < (low byte) > (high byte), the binary operators * / + - << >> & ^ |
+ * with the precedence of C, and parentheses.
+ *
+ * All addressing modes use the usual syntax: "#value", "A", "value", "value,X", "value,Y",
+ * "(value)", "(value,X)" and "(value),Y". An address known when the line is first seen and below
+ * $100 uses zero page addressing if the instruction has it. Prefix the address with "a:" to
+ * force absolute addressing.
+ *
+ * The code is assembled in two passes: the first one computes the address of every line and
+ * symbol, the second one emits the bytes.
+ */
+use crate::cpu::{self, AddressingMode, OpCode};
+use simple_error::SimpleError;
+use std::collections::BTreeMap;
+
+// Address of the code when there is no .org.
+const DEFAULT_ORIGIN: u16 = 0x8000;
+
+#[derive(Debug, PartialEq)]
+pub struct Assembly {
+    // Address of the first byte.
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    // Labels and constants.
+    pub symbols: BTreeMap<String, u16>,
+}
+
+impl Assembly {
+    // Returns the address of |symbol|, if it is defined.
+    pub fn symbol(&self, symbol: &str) -> Option<u16> {
+        self.symbols.get(symbol).copied()
+    }
+}
+
+// Assembles |source|.
+pub fn assemble(source: &str) -> Result<Assembly, SimpleError> {
+    let mut lines = Vec::new();
+    let mut scope = String::new();
+    for (i, text) in source.lines().enumerate() {
+        let line = parse_line(text, &mut scope)
+            .map_err(|e| SimpleError::new(format!("line {}: {}", i + 1, e.as_str())))?;
+        lines.push((i + 1, line));
+    }
+    let mut assembler = Assembler {
+        symbols: BTreeMap::new(),
+        origin: None,
+        pc: DEFAULT_ORIGIN,
+    };
+    let sizes = lines
+        .iter()
+        .map(|(number, line)| {
+            assembler
+                .first_pass(line)
+                .map_err(|e| SimpleError::new(format!("line {}: {}", number, e.as_str())))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let origin = assembler.origin.unwrap_or(DEFAULT_ORIGIN);
+    assembler.pc = origin;
+    let mut bytes = Vec::new();
+    for ((number, line), opcode) in lines.iter().zip(sizes) {
+        assembler
+            .second_pass(line, opcode, origin, &mut bytes)
+            .map_err(|e| SimpleError::new(format!("line {}: {}", number, e.as_str())))?;
+    }
+    Ok(Assembly {
+        origin,
+        bytes,
+        symbols: assembler.symbols,
+    })
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(i64),
+    Symbol(String),
+    // Address of the current line.
+    Pc,
+    Unary(char, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    // Zero page or absolute, with an optional index register.
+    Address {
+        expr: Expr,
+        index: Option<char>,
+        force_absolute: bool,
+    },
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+}
+
+enum ByteItem {
+    Expr(Expr),
+    Text(Vec<u8>),
+}
+
+enum Statement {
+    None,
+    Instruction(String, Operand),
+    Org(Expr),
+    Byte(Vec<ByteItem>),
+    Word(Vec<Expr>),
+    Res(Expr, Option<Expr>),
+    Constant(String, Expr),
+}
+
+struct Line {
+    label: Option<String>,
+    statement: Statement,
+}
+
+struct Assembler {
+    symbols: BTreeMap<String, u16>,
+    origin: Option<u16>,
+    pc: u16,
+}
+
+impl Assembler {
+    // Defines the labels and constants of |line| and advances the address. Returns the opcode
+    // chosen for an instruction, which the second pass must keep.
+    fn first_pass(&mut self, line: &Line) -> Result<Option<&'static OpCode>, SimpleError> {
+        if let Statement::Org(expr) = &line.statement {
+            self.set_pc(self.resolve(expr)?)?;
+        }
+        if let Some(label) = &line.label {
+            self.define(label, self.pc)?;
+        }
+        let mut opcode = None;
+        let size = match &line.statement {
+            Statement::None | Statement::Org(_) => 0,
+            Statement::Constant(name, expr) => {
+                let val = self.resolve(expr)?;
+                self.define(name, val)?;
+                0
+            }
+            Statement::Instruction(mnemonic, operand) => {
+                let chosen = self.choose_opcode(mnemonic, operand)?;
+                opcode = Some(chosen);
+                chosen.bytes as usize
+            }
+            Statement::Byte(items) => items
+                .iter()
+                .map(|item| match item {
+                    ByteItem::Expr(_) => 1,
+                    ByteItem::Text(text) => text.len(),
+                })
+                .sum(),
+            Statement::Word(exprs) => exprs.len() * 2,
+            Statement::Res(count, _) => self.resolve(count)? as usize,
+        };
+        self.advance(size)?;
+        Ok(opcode)
+    }
+
+    fn second_pass(
+        &mut self,
+        line: &Line,
+        opcode: Option<&'static OpCode>,
+        origin: u16,
+        bytes: &mut Vec<u8>,
+    ) -> Result<(), SimpleError> {
+        if let Statement::Org(expr) = &line.statement {
+            self.pc = self.resolve(expr)?;
+            let offset = (self.pc - origin) as usize;
+            if offset > bytes.len() {
+                bytes.resize(offset, 0);
+            }
+        }
+        let start = bytes.len();
+        match &line.statement {
+            Statement::None | Statement::Org(_) | Statement::Constant(_, _) => {}
+            Statement::Instruction(_, operand) => {
+                let opcode = opcode.unwrap();
+                bytes.push(opcode.code);
+                self.emit_operand(opcode, operand, bytes)?;
+            }
+            Statement::Byte(items) => {
+                for item in items {
+                    match item {
+                        ByteItem::Expr(expr) => bytes.push(self.byte(expr)?),
+                        ByteItem::Text(text) => bytes.extend_from_slice(text),
+                    }
+                }
+            }
+            Statement::Word(exprs) => {
+                for expr in exprs {
+                    bytes.extend_from_slice(&self.word(expr)?.to_le_bytes());
+                }
+            }
+            Statement::Res(count, fill) => {
+                let count = self.resolve(count)? as usize;
+                let fill = match fill {
+                    Some(fill) => self.byte(fill)?,
+                    None => 0,
+                };
+                bytes.resize(bytes.len() + count, fill);
+            }
+        }
+        let size = bytes.len() - start;
+        self.pc = self.pc.wrapping_add(size as u16);
+        Ok(())
+    }
+
+    fn emit_operand(
+        &self,
+        opcode: &OpCode,
+        operand: &Operand,
+        bytes: &mut Vec<u8>,
+    ) -> Result<(), SimpleError> {
+        let expr = match operand {
+            Operand::None | Operand::Accumulator => return Ok(()),
+            Operand::Immediate(expr)
+            | Operand::Indirect(expr)
+            | Operand::IndirectX(expr)
+            | Operand::IndirectY(expr)
+            | Operand::Address { expr, .. } => expr,
+        };
+        match opcode.addressing_mode {
+            AddressingMode::Relative => {
+                let target = self.word(expr)?;
+                let offset = target.wrapping_sub(self.pc.wrapping_add(2)) as i16;
+                if !(-128..=127).contains(&offset) {
+                    return Err(SimpleError::new(format!(
+                        "branch target ${:04X} is out of range",
+                        target
+                    )));
+                }
+                bytes.push(offset as u8);
+            }
+            AddressingMode::Immediate => bytes.push(self.byte(expr)?),
+            _ if opcode.bytes == 2 => {
+                let val = self.resolve(expr)?;
+                if val > 0xff {
+                    return Err(SimpleError::new(format!(
+                        "${:04X} is not a zero page address",
+                        val
+                    )));
+                }
+                bytes.push(val as u8);
+            }
+            _ => bytes.extend_from_slice(&self.word(expr)?.to_le_bytes()),
+        }
+        Ok(())
+    }
+
+    // Picks the opcode of |mnemonic| for |operand|.
+    fn choose_opcode(
+        &self,
+        mnemonic: &str,
+        operand: &Operand,
+    ) -> Result<&'static OpCode, SimpleError> {
+        let find = |mode: AddressingMode| {
+            cpu::opcodes()
+                .iter()
+                .find(|opcode| opcode.name == mnemonic && opcode.addressing_mode == mode)
+        };
+        if !cpu::opcodes().iter().any(|opcode| opcode.name == mnemonic) {
+            return Err(SimpleError::new(format!(
+                "unknown instruction {}",
+                mnemonic
+            )));
+        }
+
+        let found = match operand {
+            Operand::None => {
+                find(AddressingMode::NoneAddressing).or_else(|| find(AddressingMode::Accumulator))
+            }
+            Operand::Accumulator => find(AddressingMode::Accumulator),
+            Operand::Immediate(_) => find(AddressingMode::Immediate),
+            Operand::Indirect(_) => find(AddressingMode::Indirect),
+            Operand::IndirectX(_) => find(AddressingMode::IndirectX),
+            Operand::IndirectY(_) => find(AddressingMode::IndirectY),
+            Operand::Address {
+                expr,
+                index,
+                force_absolute,
+            } => {
+                let (zero_page, absolute) = match index {
+                    None => (AddressingMode::ZeroPage, AddressingMode::Absolute),
+                    Some('X') => (AddressingMode::ZeroPageX, AddressingMode::AbsoluteX),
+                    _ => (AddressingMode::ZeroPageY, AddressingMode::AbsoluteY),
+                };
+                let fits_zero_page =
+                    matches!(self.eval(expr)?, Some(val) if (0..=0xff).contains(&val));
+                let relative = if index.is_none() {
+                    find(AddressingMode::Relative)
+                } else {
+                    None
+                };
+                relative
+                    .or_else(|| {
+                        if fits_zero_page && !force_absolute {
+                            find(zero_page)
+                        } else {
+                            None
+                        }
+                    })
+                    .or_else(|| find(absolute))
+                    .or_else(|| find(zero_page))
+            }
+        };
+        found.ok_or_else(|| {
+            SimpleError::new(format!(
+                "{} does not support this addressing mode",
+                mnemonic
+            ))
+        })
+    }
+
+    fn define(&mut self, name: &str, val: u16) -> Result<(), SimpleError> {
+        if self.symbols.insert(name.to_string(), val).is_some() {
+            return Err(SimpleError::new(format!("{} is defined twice", name)));
+        }
+        Ok(())
+    }
+
+    fn set_pc(&mut self, pc: u16) -> Result<(), SimpleError> {
+        if self.origin.is_some() && pc < self.pc {
+            return Err(SimpleError::new(format!(
+                ".org ${:04X} goes backwards from ${:04X}",
+                pc, self.pc
+            )));
+        }
+        self.origin.get_or_insert(pc);
+        self.pc = pc;
+        Ok(())
+    }
+
+    fn advance(&mut self, size: usize) -> Result<(), SimpleError> {
+        if self.pc as usize + size > 0x10000 {
+            return Err(SimpleError::new("code goes past $FFFF"));
+        }
+        // Code before any .org starts at the default origin.
+        if size > 0 {
+            self.origin.get_or_insert(DEFAULT_ORIGIN);
+        }
+        self.pc = self.pc.wrapping_add(size as u16);
+        Ok(())
+    }
+
+    // Evaluates |expr|. None if a symbol is not defined yet.
+    fn eval(&self, expr: &Expr) -> Result<Option<i64>, SimpleError> {
+        Ok(match expr {
+            Expr::Number(val) => Some(*val),
+            Expr::Symbol(name) => self.symbols.get(name).map(|val| *val as i64),
+            Expr::Pc => Some(self.pc as i64),
+            Expr::Unary(op, expr) => self.eval(expr)?.map(|val| match op {
+                '-' => val.wrapping_neg(),
+                '~' => !val,
+                '<' => val & 0xff,
+                _ => (val >> 8) & 0xff,
+            }),
+            Expr::Binary(op, lhs, rhs) => match (self.eval(lhs)?, self.eval(rhs)?) {
+                (Some(lhs), Some(rhs)) => Some(match *op {
+                    "*" => lhs.wrapping_mul(rhs),
+                    "/" if rhs == 0 => return Err(SimpleError::new("division by zero")),
+                    "/" => lhs.wrapping_div(rhs),
+                    "+" => lhs.wrapping_add(rhs),
+                    "-" => lhs.wrapping_sub(rhs),
+                    "<<" => lhs.wrapping_shl(rhs as u32),
+                    ">>" => lhs.wrapping_shr(rhs as u32),
+                    "&" => lhs & rhs,
+                    "^" => lhs ^ rhs,
+                    _ => lhs | rhs,
+                }),
+                _ => None,
+            },
+        })
+    }
+
+    // Evaluates |expr| as an address or a count, which must be known.
+    fn resolve(&self, expr: &Expr) -> Result<u16, SimpleError> {
+        let val = self
+            .eval(expr)?
+            .ok_or_else(|| SimpleError::new(format!("undefined symbol in {}", describe(expr))))?;
+        if !(0..=0xffff).contains(&val) {
+            return Err(SimpleError::new(format!("{} does not fit in 16 bits", val)));
+        }
+        Ok(val as u16)
+    }
+
+    // Evaluates |expr| as a byte. Negative values down to -128 are allowed.
+    fn byte(&self, expr: &Expr) -> Result<u8, SimpleError> {
+        let val = self
+            .eval(expr)?
+            .ok_or_else(|| SimpleError::new(format!("undefined symbol in {}", describe(expr))))?;
+        if !(-128..=0xff).contains(&val) {
+            return Err(SimpleError::new(format!("{} does not fit in a byte", val)));
+        }
+        Ok(val as u8)
+    }
+
+    // Evaluates |expr| as a word. Negative values down to -32768 are allowed.
+    fn word(&self, expr: &Expr) -> Result<u16, SimpleError> {
+        let val = self
+            .eval(expr)?
+            .ok_or_else(|| SimpleError::new(format!("undefined symbol in {}", describe(expr))))?;
+        if !(-0x8000..=0xffff).contains(&val) {
+            return Err(SimpleError::new(format!("{} does not fit in a word", val)));
+        }
+        Ok(val as u16)
+    }
+}
+
+// Names the first symbol of |expr|, for errors.
+fn describe(expr: &Expr) -> String {
+    match expr {
+        Expr::Symbol(name) => name.clone(),
+        Expr::Unary(_, expr) => describe(expr),
+        Expr::Binary(_, lhs, rhs) => {
+            let lhs = describe(lhs);
+            if lhs.is_empty() {
+                describe(rhs)
+            } else {
+                lhs
+            }
+        }
+        _ => String::new(),
+    }
+}
+
+fn parse_line(text: &str, scope: &mut String) -> Result<Line, SimpleError> {
+    let text = strip_comment(text).trim();
+    let mut line = Line {
+        label: None,
+        statement: Statement::None,
+    };
+
+    let (word, rest) = split_word(text);
+    let rest = rest.trim_start();
+    let text = if let Some(after_colon) = rest.strip_prefix(':').filter(|_| is_symbol(word)) {
+        line.label = Some(scoped(word, scope)?);
+        if !word.starts_with('@') {
+            *scope = word.to_string();
+        }
+        after_colon.trim()
+    } else if let Some(value) = rest.strip_prefix('=').filter(|_| is_symbol(word)) {
+        let name = scoped(word, scope)?;
+        line.statement = Statement::Constant(name, parse_expr(value, scope)?);
+        return Ok(line);
+    } else {
+        text
+    };
+    if text.is_empty() {
+        return Ok(line);
+    }
+
+    let (word, operand) = split_word(text);
+    let operand = operand.trim();
+    line.statement = match word.to_ascii_lowercase().as_str() {
+        ".org" => Statement::Org(parse_expr(operand, scope)?),
+        ".byte" | ".db" => Statement::Byte(
+            split_list(operand)
+                .iter()
+                .map(|item| parse_byte_item(item, scope))
+                .collect::<Result<_, _>>()?,
+        ),
+        ".word" | ".dw" | ".addr" => Statement::Word(
+            split_list(operand)
+                .iter()
+                .map(|item| parse_expr(item, scope))
+                .collect::<Result<_, _>>()?,
+        ),
+        ".res" => {
+            let items = split_list(operand);
+            if items.is_empty() || items.len() > 2 {
+                return Err(SimpleError::new(".res takes a count and an optional value"));
+            }
+            Statement::Res(
+                parse_expr(&items[0], scope)?,
+                items
+                    .get(1)
+                    .map(|item| parse_expr(item, scope))
+                    .transpose()?,
+            )
+        }
+        directive if directive.starts_with('.') => {
+            return Err(SimpleError::new(format!("unknown directive {}", word)));
+        }
+        _ => Statement::Instruction(word.to_ascii_uppercase(), parse_operand(operand, scope)?),
+    };
+    Ok(line)
+}
+
+fn parse_operand(text: &str, scope: &str) -> Result<Operand, SimpleError> {
+    if text.is_empty() {
+        return Ok(Operand::None);
+    }
+    if text.eq_ignore_ascii_case("a") {
+        return Ok(Operand::Accumulator);
+    }
+    if let Some(value) = text.strip_prefix('#') {
+        return Ok(Operand::Immediate(parse_expr(value, scope)?));
+    }
+
+    let upper = text.to_ascii_uppercase().replace(' ', "");
+    if text.starts_with('(') {
+        if upper.ends_with(",X)") {
+            let comma = text.rfind(',').unwrap();
+            return Ok(Operand::IndirectX(parse_expr(&text[1..comma], scope)?));
+        }
+        if upper.ends_with("),Y") {
+            let end = text.rfind(')').unwrap();
+            return Ok(Operand::IndirectY(parse_expr(&text[1..end], scope)?));
+        }
+        if matching_paren(text) == Some(text.len() - 1) {
+            return Ok(Operand::Indirect(parse_expr(
+                &text[1..text.len() - 1],
+                scope,
+            )?));
+        }
+    }
+
+    let (text, force_absolute) = match text.strip_prefix("a:") {
+        Some(text) => (text, true),
+        None => (text, false),
+    };
+    let (text, index) = if upper.ends_with(",X") || upper.ends_with(",Y") {
+        let comma = text.rfind(',').unwrap();
+        (&text[..comma], upper.chars().last())
+    } else {
+        (text, None)
+    };
+    Ok(Operand::Address {
+        expr: parse_expr(text, scope)?,
+        index,
+        force_absolute,
+    })
+}
+
+fn parse_byte_item(text: &str, scope: &str) -> Result<ByteItem, SimpleError> {
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        return Ok(ByteItem::Text(text.as_bytes()[1..text.len() - 1].to_vec()));
+    }
+    Ok(ByteItem::Expr(parse_expr(text, scope)?))
+}
+
+fn parse_expr(text: &str, scope: &str) -> Result<Expr, SimpleError> {
+    let mut parser = ExprParser {
+        chars: text.chars().collect(),
+        pos: 0,
+        scope,
+    };
+    let expr = parser.binary(0)?;
+    parser.skip_spaces();
+    if parser.pos != parser.chars.len() {
+        return Err(SimpleError::new(format!(
+            "invalid expression {}",
+            text.trim()
+        )));
+    }
+    Ok(expr)
+}
+
+// Binary operators from the lowest precedence to the highest.
+const BINARY_OPERATORS: [&[&str]; 6] = [
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/"],
+];
+
+struct ExprParser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    scope: &'a str,
+}
+
+impl ExprParser<'_> {
+    fn binary(&mut self, level: usize) -> Result<Expr, SimpleError> {
+        if level == BINARY_OPERATORS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        'outer: loop {
+            self.skip_spaces();
+            for op in BINARY_OPERATORS[level] {
+                if self.starts_with(op) {
+                    self.pos += op.len();
+                    let rhs = self.binary(level + 1)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, SimpleError> {
+        self.skip_spaces();
+        match self.peek() {
+            Some(op @ ('-' | '~' | '<' | '>')) => {
+                self.pos += 1;
+                Ok(Expr::Unary(op, Box::new(self.unary()?)))
+            }
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.binary(0)?;
+                self.skip_spaces();
+                if self.peek() != Some(')') {
+                    return Err(SimpleError::new("missing )"));
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            Some('*') => {
+                self.pos += 1;
+                Ok(Expr::Pc)
+            }
+            Some('$') => {
+                self.pos += 1;
+                self.number(16)
+            }
+            Some('%') => {
+                self.pos += 1;
+                self.number(2)
+            }
+            Some('\'') => {
+                let c = self.chars.get(self.pos + 1).copied();
+                if c.is_none() || self.chars.get(self.pos + 2) != Some(&'\'') {
+                    return Err(SimpleError::new("invalid character literal"));
+                }
+                self.pos += 3;
+                Ok(Expr::Number(c.unwrap() as i64))
+            }
+            Some(c) if c.is_ascii_digit() => self.number(10),
+            Some(c) if is_symbol_char(c) && !c.is_ascii_digit() => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if is_symbol_char(c)) {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+                Ok(Expr::Symbol(scoped(&name, self.scope)?))
+            }
+            _ => Err(SimpleError::new("missing value in expression")),
+        }
+    }
+
+    fn number(&mut self, radix: u32) -> Result<Expr, SimpleError> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_digit(radix)) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        i64::from_str_radix(&digits, radix)
+            .map(Expr::Number)
+            .map_err(|_| SimpleError::new("invalid number"))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(c) if c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+}
+
+// Names a local label "@name" after its scope.
+fn scoped(name: &str, scope: &str) -> Result<String, SimpleError> {
+    if !name.starts_with('@') {
+        return Ok(name.to_string());
+    }
+    if scope.is_empty() {
+        return Err(SimpleError::new(format!(
+            "local label {} is not after a label",
+            name
+        )));
+    }
+    Ok(format!("{}{}", scope, name))
+}
+
+fn is_symbol(word: &str) -> bool {
+    let mut chars = word.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '@')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Whether |c| can be part of a symbol in an expression.
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '@'
+}
+
+// Splits the first word, made of anything but spaces, ':' and '='.
+fn split_word(text: &str) -> (&str, &str) {
+    let end = text
+        .find(|c: char| c.is_whitespace() || c == ':' || c == '=')
+        .unwrap_or(text.len());
+    text.split_at(end)
+}
+
+// Removes a comment, unless ';' is in a string or a character literal.
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, ';') => return &text[..i],
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    text
+}
+
+// Splits a comma-separated list, ignoring commas in strings.
+fn split_list(text: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut in_string = false;
+    for c in text.chars() {
+        match c {
+            '"' => {
+                in_string = !in_string;
+                item.push(c);
+            }
+            ',' if !in_string => items.push(std::mem::take(&mut item).trim().to_string()),
+            _ => item.push(c),
+        }
+    }
+    if !item.trim().is_empty() || !items.is_empty() {
+        items.push(item.trim().to_string());
+    }
+    items
+}
+
+// Index of the ')' closing the '(' at the start of |text|.
+fn matching_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::disasm;
+
+    fn bytes(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().bytes
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        let source = "
+            LDA #$05
+            LDA $20
+            LDA $20,X
+            LDX $20,Y
+            LDA $1234
+            LDA $1234,X
+            LDA $1234,Y
+            JMP ($1234)
+            LDA ($20,X)
+            LDA ($20),Y
+            ASL A
+            ASL
+            NOP
+            LDA a:$20
+            LDA $20,Y
+        ";
+        let assembly = assemble(source).unwrap();
+
+        let texts: Vec<String> = disasm::disassemble(&assembly.bytes, assembly.origin)
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                "LDA #$05",
+                "LDA $20",
+                "LDA $20,X",
+                "LDX $20,Y",
+                "LDA $1234",
+                "LDA $1234,X",
+                "LDA $1234,Y",
+                "JMP ($1234)",
+                "LDA ($20,X)",
+                "LDA ($20),Y",
+                "ASL A",
+                "ASL A",
+                "NOP",
+                "LDA $0020",
+                // LDA has no zero page,Y mode.
+                "LDA $0020,Y",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let source = "
+                .org $c000
+            start:
+                LDX #8
+            @loop:
+                DEX
+                BNE @loop
+                JMP end
+            other:
+            @loop:
+                BEQ @loop
+            end:
+                RTS
+        ";
+        let assembly = assemble(source).unwrap();
+
+        assert_eq!(assembly.origin, 0xc000);
+        assert_eq!(
+            assembly.bytes,
+            vec![0xa2, 0x08, 0xca, 0xd0, 0xfd, 0x4c, 0x0a, 0xc0, 0xf0, 0xfe, 0x60]
+        );
+        assert_eq!(assembly.symbol("start"), Some(0xc000));
+        assert_eq!(assembly.symbol("start@loop"), Some(0xc002));
+        assert_eq!(assembly.symbol("other@loop"), Some(0xc008));
+        assert_eq!(assembly.symbol("end"), Some(0xc00a));
+        assert_eq!(assembly.symbol("missing"), None);
+    }
+
+    #[test]
+    fn test_forward_reference_is_absolute() {
+        // |var| is not known when LDA is first seen.
+        let source = "
+            LDA var
+            var = $10
+            LDA var
+        ";
+
+        assert_eq!(bytes(source), vec![0xad, 0x10, 0x00, 0xa5, 0x10]);
+    }
+
+    #[test]
+    fn test_expressions() {
+        let source = "
+            PPU_CTRL = $2000
+            value = %1010 + 3 * (2 + 1) - 'A' + 65
+            LDA #<PPU_CTRL + 1
+            LDA #>PPU_CTRL
+            LDA #value
+            LDA #-1
+            LDA #1 << 4 | 1
+            LDA #$ff & ~$0f ^ 1
+            .word * + 2
+        ";
+
+        assert_eq!(
+            bytes(source),
+            vec![0xa9, 0x01, 0xa9, 0x20, 0xa9, 19, 0xa9, 0xff, 0xa9, 0x11, 0xa9, 0xf1, 0x0e, 0x80]
+        );
+    }
+
+    #[test]
+    fn test_overflowing_expressions() {
+        // Both wrap around to the lowest i64, which does not fit in a word.
+        let err = |source: &str| assemble(source).unwrap_err().as_str().to_string();
+        assert_eq!(
+            err(".word -(-$7FFFFFFFFFFFFFFF-1)"),
+            "line 1: -9223372036854775808 does not fit in a word"
+        );
+        assert_eq!(
+            err(".word (-$7FFFFFFFFFFFFFFF-1)/-1"),
+            "line 1: -9223372036854775808 does not fit in a word"
+        );
+    }
+
+    #[test]
+    fn test_indirect_x_expressions() {
+        let source = "
+            ptr = $20
+            LDA ($20 + 1,X)
+            LDA (ptr + 1,X)
+            LDA ($20, X)
+            LDA ( ptr , x )
+        ";
+
+        assert_eq!(
+            bytes(source),
+            vec![0xa1, 0x21, 0xa1, 0x21, 0xa1, 0x20, 0xa1, 0x20]
+        );
+    }
+
+    #[test]
+    fn test_directives() {
+        let source = "
+            .byte 1, $02, \"hi;\" ; comment
+            .word $1234, label
+            .res 3, $ea
+            .org $8010
+            label: .db 0
+        ";
+
+        assert_eq!(
+            bytes(source),
+            vec![1, 2, b'h', b'i', b';', 0x34, 0x12, 0x10, 0x80, 0xea, 0xea, 0xea, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |source: &str| assemble(source).unwrap_err().as_str().to_string();
+
+        assert_eq!(err("NOP\nFOO $10"), "line 2: unknown instruction FOO");
+        assert_eq!(err("LDA missing"), "line 1: undefined symbol in missing");
+        assert_eq!(err("a: NOP\na: NOP"), "line 2: a is defined twice");
+        assert_eq!(err("LDA #$100"), "line 1: 256 does not fit in a byte");
+        assert_eq!(
+            err("JMP ($10),Y"),
+            "line 1: JMP does not support this addressing mode"
+        );
+        assert_eq!(
+            err("start: BNE far\n.res 200\nfar: NOP").contains("out of range"),
+            true
+        );
+        assert_eq!(err(".org $8010\n.org $8000").contains("backwards"), true);
+        assert_eq!(err("@loop: NOP").contains("local label"), true);
+    }
+
+    #[test]
+    fn test_run_assembled_program() {
+        let source = "
+                LDX #0
+            loop:
+                LDA message,X
+                STA $0200,X
+                INX
+                CPX #5
+                BNE loop
+                BRK
+            message:
+                .byte \"hello\"
+        ";
+        let assembly = assemble(source).unwrap();
+        let mut cpu = cpu::CPU::new();
+
+        cpu.interpret(&assembly.bytes).unwrap();
+
+        let copied: Vec<u8> = (0..5).map(|i| cpu.peek_mem(0x0200 + i)).collect();
+        assert_eq!(copied, b"hello".to_vec());
+    }
+}
@@ -0,0 +1,214 @@
+/**
+ * Runner of test ROMs that report their result through PRG RAM, like most of blargg's tests
+ * (instr_test, cpu_timing, ppu_vbl_nmi, apu_test, ...).
+ *
+ * Such a ROM writes $DE $B0 $61 at $6001-$6003 once the protocol is in use, then a status at
+ * $6000: $80 while the test runs, $81 when the reset button must be pressed after at least 100 ms,
+ * and the result once done, 0 for success or an error code. A zero-terminated message from $6004
+ * explains the result.
+ *
+ * See https://github.com/christopherpow/nes-test-roms/blob/master/instr_test-v5/readme.txt
+ */
+use crate::nes::Nes;
+use simple_error::SimpleError;
+
+const STATUS_ADDR: u16 = 0x6000;
+const SIGNATURE_ADDR: u16 = 0x6001;
+const SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+const MESSAGE_ADDR: u16 = 0x6004;
+const MESSAGE_END_ADDR: u16 = 0x7fff;
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET_REQUESTED: u8 = 0x81;
+
+// Frames to wait before pressing reset, about 100 ms.
+const RESET_DELAY_FRAMES: usize = 6;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestResult {
+    // 0 if the test passed.
+    pub code: u8,
+    pub message: String,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.code == 0
+    }
+}
+
+// Runs the test ROM in |nes| for up to |max_frames| frames, pressing reset when it asks to.
+// Fails if the test does not finish in time or hits an illegal opcode.
+pub fn run(nes: &mut Nes, max_frames: usize) -> Result<TestResult, SimpleError> {
+    // Frames left before pressing reset, when the test asked for it.
+    let mut reset_in = None;
+    for _ in 0..max_frames {
+        nes.run_frame()?;
+        let status = match status(nes) {
+            Some(status) => status,
+            None => continue,
+        };
+        match status {
+            STATUS_RUNNING => {}
+            STATUS_RESET_REQUESTED => match reset_in {
+                None => reset_in = Some(RESET_DELAY_FRAMES),
+                Some(0) => {
+                    nes.reset();
+                    reset_in = None;
+                }
+                Some(frames) => reset_in = Some(frames - 1),
+            },
+            code if code < STATUS_RUNNING => {
+                return Ok(TestResult {
+                    code,
+                    message: message(nes),
+                })
+            }
+            code => {
+                return Err(SimpleError::new(format!(
+                    "unknown test status ${:02X}: {}",
+                    code,
+                    message(nes)
+                )))
+            }
+        }
+    }
+    Err(SimpleError::new(format!(
+        "test did not finish in {} frames{}",
+        max_frames,
+        match status(nes) {
+            Some(_) => format!(": {}", message(nes)),
+            None => String::new(),
+        }
+    )))
+}
+
+// The status of the test, None until the ROM writes the signature.
+fn status(nes: &Nes) -> Option<u8> {
+    let cpu = nes.cpu();
+    let signature: Vec<u8> = (0..SIGNATURE.len() as u16)
+        .map(|i| cpu.peek_mem(SIGNATURE_ADDR + i))
+        .collect();
+    if signature == SIGNATURE {
+        Some(cpu.peek_mem(STATUS_ADDR))
+    } else {
+        None
+    }
+}
+
+fn message(nes: &Nes) -> String {
+    let bytes: Vec<u8> = (MESSAGE_ADDR..=MESSAGE_END_ADDR)
+        .map(|addr| nes.cpu().peek_mem(addr))
+        .take_while(|byte| *byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).trim_end().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler;
+    use crate::cartridge::test::nrom_image;
+    use crate::cartridge::Cartridge;
+
+    // A test that asks for a reset |resets| times, then reports |code|.
+    fn test_rom(resets: u8, code: u8) -> Nes {
+        let source = format!(
+            "
+                resets = $6100
+                LDA #$80
+                STA $6000
+                LDA #$de
+                STA $6001
+                LDA #$b0
+                STA $6002
+                LDA #$61
+                STA $6003
+                LDX #0
+            copy:
+                LDA message,X
+                STA $6004,X
+                BEQ check
+                INX
+                BNE copy
+            check:
+                LDA resets
+                CMP #{}
+                BEQ done
+                INC resets
+                LDA #$81
+                STA $6000
+            wait:
+                JMP wait
+            done:
+                LDA #{}
+                STA $6000
+            end:
+                JMP end
+            message:
+                .byte \"Result \", 10, 0
+            ",
+            resets, code
+        );
+        let program = assembler::assemble(&source).unwrap();
+        Nes::new(Cartridge::parse(&nrom_image(&program.bytes)).unwrap())
+    }
+
+    #[test]
+    fn test_pass() {
+        let mut nes = test_rom(0, 0);
+
+        let result = run(&mut nes, 10).unwrap();
+
+        assert_eq!(result.passed(), true);
+        assert_eq!(result.message, "Result");
+    }
+
+    #[test]
+    fn test_fail() {
+        let mut nes = test_rom(0, 3);
+
+        assert_eq!(
+            run(&mut nes, 10),
+            Ok(TestResult {
+                code: 3,
+                message: "Result".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_reset_requested() {
+        let mut nes = test_rom(2, 0);
+
+        assert_eq!(run(&mut nes, 10).is_err(), true);
+
+        let mut nes = test_rom(2, 0);
+        assert_eq!(run(&mut nes, 30).map(|result| result.passed()), Ok(true));
+        assert_eq!(nes.cpu().peek_mem(0x6100), 2);
+    }
+
+    #[test]
+    fn test_timeout() {
+        // JMP $8000
+        let image = nrom_image(&[0x4c, 0x00, 0x80]);
+        let mut nes = Nes::new(Cartridge::parse(&image).unwrap());
+
+        assert_eq!(
+            run(&mut nes, 5),
+            Err(SimpleError::new("test did not finish in 5 frames"))
+        );
+    }
+
+    #[test]
+    fn test_illegal_opcode() {
+        // .byte $02
+        let image = nrom_image(&[0x02]);
+        let mut nes = Nes::new(Cartridge::parse(&image).unwrap());
+
+        assert_eq!(
+            run(&mut nes, 5),
+            Err(SimpleError::new("illegal opcode 0x02 at 0x8000"))
+        );
+    }
+}
@@ -0,0 +1,425 @@
+/**
+ * Breakpoints and watchpoints of the CPU.
+ *
+ * Breakpoints on the program counter and on opcodes fire before the instruction executes.
+ * Watchpoints fire when the CPU reads or writes data, and stop the CPU once the instruction that
+ * made the access is done. Fetching instructions, i.e. their opcode and operand bytes, is not a
+ * read, so a read watchpoint on code only fires when the code is read as data.
+ *
+ * A breakpoint may have a condition, in which case it only fires when the condition holds, and
+ * may log instead of stopping the CPU. Each breakpoint counts the times it fired.
+ */
+use crate::bus::{AccessKind, BusAccess};
+use crate::condition::Condition;
+use crate::cpu::{CPU, OPCODE_BRK};
+use crate::nes::Nes;
+use crate::trace;
+use simple_error::SimpleError;
+use std::cell::{Cell, RefCell};
+use std::ops::RangeInclusive;
+
+pub type BreakpointId = usize;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Breakpoint {
+    // Before executing the instruction at the address.
+    Execute(u16),
+    // When reading an address in the range.
+    Read(RangeInclusive<u16>),
+    // When writing an address in the range, only the given value if any.
+    Write(RangeInclusive<u16>, Option<u8>),
+    // Before executing the opcode.
+    Opcode(u8),
+    // Before executing BRK.
+    Brk,
+}
+
+impl Breakpoint {
+    fn is_watchpoint(&self) -> bool {
+        matches!(self, Breakpoint::Read(_) | Breakpoint::Write(_, _))
+    }
+}
+
+// What a breakpoint does when it fires.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    // Stops the CPU.
+    Break,
+    // Logs the instruction, or the memory access of a watchpoint, and goes on.
+    Log,
+}
+
+struct Entry {
+    id: BreakpointId,
+    breakpoint: Breakpoint,
+    condition: Option<Condition>,
+    action: Action,
+    hits: Cell<u64>,
+}
+
+// Why the CPU stopped running.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    // The program reached BRK, which marks its end in CPU::run().
+    Brk,
+    // The next opcode is illegal, i.e. not one the CPU implements, e.g. KIL.
+    IllegalOpcode(u8),
+    // A breakpoint fired before the instruction at the program counter.
+    Breakpoint(BreakpointId),
+    // A watchpoint fired during the last instruction.
+    Watchpoint { id: BreakpointId, access: BusAccess },
+}
+
+#[derive(Default)]
+pub struct Breakpoints {
+    breakpoints: Vec<Entry>,
+    next_id: BreakpointId,
+    // Whether any breakpoint is a watchpoint, so that memory accesses are cheap without them.
+    has_watchpoints: bool,
+    // The lines logged by the breakpoints with Action::Log, until taken.
+    log: RefCell<Vec<String>>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Breakpoints::default()
+    }
+
+    // Adds |breakpoint| and returns its id.
+    pub fn add(&mut self, breakpoint: Breakpoint) -> BreakpointId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Entry {
+            id,
+            breakpoint,
+            condition: None,
+            action: Action::Break,
+            hits: Cell::new(0),
+        });
+        self.update();
+        id
+    }
+
+    // Removes the breakpoint |id|. Returns whether it existed.
+    pub fn remove(&mut self, id: BreakpointId) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|entry| entry.id != id);
+        self.update();
+        self.breakpoints.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.update();
+    }
+
+    pub fn get(&self, id: BreakpointId) -> Option<&Breakpoint> {
+        self.entry(id).map(|entry| &entry.breakpoint)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (BreakpointId, &Breakpoint)> {
+        self.breakpoints
+            .iter()
+            .map(|entry| (entry.id, &entry.breakpoint))
+    }
+
+    pub fn condition(&self, id: BreakpointId) -> Option<&Condition> {
+        self.entry(id).and_then(|entry| entry.condition.as_ref())
+    }
+
+    // Makes the breakpoint |id| fire only when |condition| holds, or always if None. Returns
+    // whether the breakpoint exists.
+    pub fn set_condition(&mut self, id: BreakpointId, condition: Option<Condition>) -> bool {
+        match self.breakpoints.iter_mut().find(|entry| entry.id == id) {
+            Some(entry) => {
+                entry.condition = condition;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn action(&self, id: BreakpointId) -> Option<Action> {
+        self.entry(id).map(|entry| entry.action)
+    }
+
+    // Returns whether the breakpoint exists.
+    pub fn set_action(&mut self, id: BreakpointId, action: Action) -> bool {
+        match self.breakpoints.iter_mut().find(|entry| entry.id == id) {
+            Some(entry) => {
+                entry.action = action;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns how many times the breakpoint |id| fired.
+    pub fn hits(&self, id: BreakpointId) -> Option<u64> {
+        self.entry(id).map(|entry| entry.hits.get())
+    }
+
+    // Returns the lines logged since the last call.
+    pub fn take_log(&self) -> Vec<String> {
+        self.log.take()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    pub(crate) fn has_watchpoints(&self) -> bool {
+        self.has_watchpoints
+    }
+
+    // Returns the breakpoint stopping |cpu| before it executes |code| at its program counter.
+    pub(crate) fn check_execute(&self, cpu: &CPU, code: u8) -> Option<BreakpointId> {
+        self.fire(
+            cpu,
+            |breakpoint| match breakpoint {
+                Breakpoint::Execute(addr) => *addr == cpu.pc,
+                Breakpoint::Opcode(other) => *other == code,
+                Breakpoint::Brk => code == OPCODE_BRK,
+                _ => false,
+            },
+            || trace::trace_line(cpu, cpu.ppu_position()),
+        )
+    }
+
+    // Returns the watchpoint stopping |cpu| on |access|.
+    pub(crate) fn check_access(&self, cpu: &CPU, access: &BusAccess) -> Option<BreakpointId> {
+        self.fire(
+            cpu,
+            |breakpoint| match (breakpoint, access.kind) {
+                (Breakpoint::Read(range), AccessKind::Read) => range.contains(&access.addr),
+                (Breakpoint::Write(range, val), AccessKind::Write) => {
+                    range.contains(&access.addr) && val.is_none_or(|val| val == access.val)
+                }
+                _ => false,
+            },
+            || {
+                format!(
+                    "{:?} ${:04X} = ${:02X} CYC:{}",
+                    access.kind, access.addr, access.val, cpu.cycles
+                )
+            },
+        )
+    }
+
+    fn entry(&self, id: BreakpointId) -> Option<&Entry> {
+        self.breakpoints.iter().find(|entry| entry.id == id)
+    }
+
+    // Fires every breakpoint that |matches| and whose condition holds on |cpu|: counts a hit, and
+    // logs |line| for those that log. Returns the first one that breaks.
+    fn fire(
+        &self,
+        cpu: &CPU,
+        matches: impl Fn(&Breakpoint) -> bool,
+        line: impl Fn() -> String,
+    ) -> Option<BreakpointId> {
+        let mut stop = None;
+        for entry in &self.breakpoints {
+            if !matches(&entry.breakpoint) || !entry.condition.as_ref().is_none_or(|c| c.holds(cpu))
+            {
+                continue;
+            }
+            entry.hits.set(entry.hits.get() + 1);
+            match entry.action {
+                Action::Break => {
+                    stop.get_or_insert(entry.id);
+                }
+                Action::Log => self
+                    .log
+                    .borrow_mut()
+                    .push(format!("#{} {}", entry.id, line())),
+            }
+        }
+        stop
+    }
+
+    fn update(&mut self) {
+        self.has_watchpoints = self
+            .breakpoints
+            .iter()
+            .any(|entry| entry.breakpoint.is_watchpoint());
+    }
+}
+
+// What runs until a breakpoint fires: a bare CPU, or a whole console so that the PPU and the APU
+// keep up with the CPU.
+pub trait Target {
+    fn cpu(&self) -> &CPU;
+    fn cpu_mut(&mut self) -> &mut CPU;
+    // Executes the next instruction, or enters a pending interrupt handler. Fails on an illegal
+    // opcode.
+    fn step_instruction(&mut self) -> Result<(), SimpleError>;
+}
+
+impl Target for CPU {
+    fn cpu(&self) -> &CPU {
+        self
+    }
+
+    fn cpu_mut(&mut self) -> &mut CPU {
+        self
+    }
+
+    fn step_instruction(&mut self) -> Result<(), SimpleError> {
+        self.step().map(|_| ())
+    }
+}
+
+impl Target for Nes {
+    fn cpu(&self) -> &CPU {
+        Nes::cpu(self)
+    }
+
+    fn cpu_mut(&mut self) -> &mut CPU {
+        Nes::cpu_mut(self)
+    }
+
+    fn step_instruction(&mut self) -> Result<(), SimpleError> {
+        Nes::step_instruction(self)
+    }
+}
+
+// Executes instructions of |target| until a breakpoint fires, and returns why, or until |done|
+// returns true after an instruction, and returns None. |skip_first| skips the breakpoints before
+// the first instruction, so that execution goes on after stopping at the program counter.
+pub fn run_until<T: Target + ?Sized>(
+    target: &mut T,
+    skip_first: bool,
+    mut done: impl FnMut(&mut T) -> bool,
+) -> Option<StopReason> {
+    target.cpu_mut().take_watchpoint_hit();
+    let mut skip = skip_first;
+    loop {
+        let cpu = target.cpu();
+        let reason = if skip {
+            cpu.check_illegal_opcode()
+        } else {
+            cpu.check_breakpoints()
+        };
+        if reason.is_some() {
+            return reason;
+        }
+        skip = false;
+        if target.step_instruction().is_err() {
+            let cpu = target.cpu();
+            return Some(StopReason::IllegalOpcode(cpu.peek_mem(cpu.pc)));
+        }
+        if let Some(reason) = target.cpu_mut().take_watchpoint_hit() {
+            return Some(reason);
+        }
+        if done(target) {
+            return None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn access(addr: u16, val: u8, kind: AccessKind) -> BusAccess {
+        BusAccess { addr, val, kind }
+    }
+
+    fn cpu_at(pc: u16) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.pc = pc;
+        cpu
+    }
+
+    #[test]
+    fn test_add_remove() {
+        let mut breakpoints = Breakpoints::new();
+        let a = breakpoints.add(Breakpoint::Execute(0x8000));
+        let b = breakpoints.add(Breakpoint::Read(0x10..=0x1f));
+
+        assert_eq!(breakpoints.has_watchpoints(), true);
+        assert_eq!(breakpoints.get(b), Some(&Breakpoint::Read(0x10..=0x1f)));
+        assert_eq!(breakpoints.remove(b), true);
+        assert_eq!(breakpoints.remove(b), false);
+        assert_eq!(breakpoints.has_watchpoints(), false);
+        assert_eq!(breakpoints.check_execute(&cpu_at(0x8000), 0xea), Some(a));
+        assert_eq!(breakpoints.add(Breakpoint::Brk) != b, true);
+    }
+
+    #[test]
+    fn test_check() {
+        let mut breakpoints = Breakpoints::new();
+        let read = breakpoints.add(Breakpoint::Read(0x10..=0x1f));
+        let write = breakpoints.add(Breakpoint::Write(0x2000..=0x2000, Some(0x80)));
+        let opcode = breakpoints.add(Breakpoint::Opcode(0xa9));
+        let brk = breakpoints.add(Breakpoint::Brk);
+        let cpu = cpu_at(0x8000);
+
+        assert_eq!(
+            breakpoints.check_access(&cpu, &access(0x1f, 0, AccessKind::Read)),
+            Some(read)
+        );
+        assert_eq!(
+            breakpoints.check_access(&cpu, &access(0x1f, 0, AccessKind::Write)),
+            None
+        );
+        assert_eq!(
+            breakpoints.check_access(&cpu, &access(0x2000, 0x80, AccessKind::Write)),
+            Some(write)
+        );
+        assert_eq!(
+            breakpoints.check_access(&cpu, &access(0x2000, 0x00, AccessKind::Write)),
+            None
+        );
+        assert_eq!(breakpoints.check_execute(&cpu, 0xa9), Some(opcode));
+        assert_eq!(breakpoints.check_execute(&cpu, 0x00), Some(brk));
+        assert_eq!(breakpoints.check_execute(&cpu, 0xea), None);
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut breakpoints = Breakpoints::new();
+        let execute = breakpoints.add(Breakpoint::Execute(0x8000));
+        let log = breakpoints.add(Breakpoint::Write(0x10..=0x10, None));
+        let read = breakpoints.add(Breakpoint::Read(0x10..=0x10));
+        let condition = Condition::parse("A == $40").unwrap();
+        assert_eq!(
+            breakpoints.set_condition(execute, Some(condition.clone())),
+            true
+        );
+        assert_eq!(breakpoints.set_action(log, Action::Log), true);
+        assert_eq!(breakpoints.set_action(99, Action::Log), false);
+        let mut cpu = cpu_at(0x8000);
+
+        assert_eq!(breakpoints.condition(execute), Some(&condition));
+        assert_eq!(breakpoints.check_execute(&cpu, 0xea), None);
+        cpu.reg_a = 0x40;
+        assert_eq!(breakpoints.check_execute(&cpu, 0xea), Some(execute));
+        assert_eq!(breakpoints.hits(execute), Some(1));
+
+        // A logging watchpoint does not stop the CPU, and does not hide the others.
+        assert_eq!(
+            breakpoints.check_access(&cpu, &access(0x10, 0x02, AccessKind::Write)),
+            None
+        );
+        assert_eq!(
+            breakpoints.check_access(&cpu, &access(0x10, 0x02, AccessKind::Read)),
+            Some(read)
+        );
+        assert_eq!(breakpoints.hits(log), Some(1));
+        assert_eq!(breakpoints.take_log(), vec!["#1 Write $0010 = $02 CYC:0"]);
+        assert_eq!(breakpoints.take_log(), Vec::<String>::new());
+
+        breakpoints.set_action(execute, Action::Log);
+        assert_eq!(breakpoints.check_execute(&cpu, 0xea), None);
+        assert_eq!(breakpoints.hits(execute), Some(2));
+        assert_eq!(
+            breakpoints.take_log(),
+            vec![
+                "#0 8000  00        BRK                             \
+                  A:40 X:00 Y:00 P:20 SP:FD CYC:0"
+            ]
+        );
+    }
+}
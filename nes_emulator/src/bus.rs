@@ -0,0 +1,167 @@
+/**
+ * The bus the 6502 talks to.
+ *
+ * The CPU only knows about a 16-bit address space. What lives behind each address (RAM, PRG ROM,
+ * memory-mapped registers of the APU, bank switching registers, ...) is decided by the bus.
+ */
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use simple_error::SimpleError;
+use std::any::Any;
+
+// Buses are part of save states, so that the whole machine behind the CPU can be restored.
+pub trait Bus: Any + Savestate {
+    // Reads one byte at |addr|. Reads may have side effects, e.g. on memory-mapped registers.
+    fn read(&mut self, addr: u16) -> u8;
+
+    // Reads one byte at |addr| without any side effect. Used by debugging tools.
+    fn peek(&self, addr: u16) -> u8;
+
+    // Writes |val| at |addr|.
+    fn write(&mut self, addr: u16, val: u8);
+
+    // Scanline and dot of the PPU, if there is one. Used by traces.
+    fn ppu_position(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    // Where |addr| currently is in PRG ROM, if it is mapped there. Used by symbols.
+    fn prg_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    // Whether PRG ROM accesses go to a code/data log, so that the CPU tells how it uses memory.
+    fn is_logging_code_data(&self) -> bool {
+        false
+    }
+
+    // Records in the code/data log that the CPU accessed |addr| as |flags|.
+    fn log_code_data(&mut self, _addr: u16, _flags: u8) {}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub val: u8,
+    pub kind: AccessKind,
+}
+
+// Wraps a bus and records every read and write the CPU makes through it, in order. Peeks are not
+// recorded. Used to compare the bus activity of instructions with other implementations.
+pub struct RecordingBus<B: Bus> {
+    inner: B,
+    accesses: Vec<BusAccess>,
+}
+
+impl<B: Bus> RecordingBus<B> {
+    pub fn new(inner: B) -> Self {
+        RecordingBus {
+            inner,
+            accesses: Vec::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+
+    pub fn accesses(&self) -> &[BusAccess] {
+        &self.accesses
+    }
+
+    // Returns the accesses recorded so far and starts a new recording.
+    pub fn take_accesses(&mut self) -> Vec<BusAccess> {
+        std::mem::take(&mut self.accesses)
+    }
+}
+
+impl<B: Bus> Bus for RecordingBus<B> {
+    fn read(&mut self, addr: u16) -> u8 {
+        let val = self.inner.read(addr);
+        self.accesses.push(BusAccess {
+            addr,
+            val,
+            kind: AccessKind::Read,
+        });
+        val
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.inner.peek(addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.accesses.push(BusAccess {
+            addr,
+            val,
+            kind: AccessKind::Write,
+        });
+        self.inner.write(addr, val);
+    }
+
+    fn ppu_position(&self) -> Option<(usize, usize)> {
+        self.inner.ppu_position()
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.inner.prg_rom_offset(addr)
+    }
+
+    fn is_logging_code_data(&self) -> bool {
+        self.inner.is_logging_code_data()
+    }
+
+    fn log_code_data(&mut self, addr: u16, flags: u8) {
+        self.inner.log_code_data(addr, flags)
+    }
+}
+
+// Recordings are not part of the state.
+impl<B: Bus> Savestate for RecordingBus<B> {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.inner.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.inner.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{Mem, CPU};
+
+    #[test]
+    fn test_recording_bus() {
+        let mut cpu = CPU::with_bus(Box::new(RecordingBus::new(Mem::new())));
+        // INC $10
+        cpu.load(&[0xe6, 0x10]).unwrap();
+        cpu.reset();
+        cpu.peek_mem(0x8000);
+        cpu.bus_mut::<RecordingBus<Mem>>().unwrap().take_accesses();
+
+        cpu.step().unwrap();
+
+        let access = |addr, val, kind| BusAccess { addr, val, kind };
+        assert_eq!(
+            cpu.bus::<RecordingBus<Mem>>().unwrap().accesses(),
+            &[
+                access(0x8000, 0xe6, AccessKind::Read),
+                access(0x8001, 0x10, AccessKind::Read),
+                access(0x0010, 0x00, AccessKind::Read),
+                access(0x0010, 0x00, AccessKind::Write),
+                access(0x0010, 0x01, AccessKind::Write),
+            ]
+        );
+    }
+}
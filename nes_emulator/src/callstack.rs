@@ -0,0 +1,244 @@
+/**
+ * Shadow call stack of the CPU, kept from the instructions and interrupts that enter and leave
+ * routines: JSR, BRK, NMI and IRQ enter one, RTS and RTI leave one.
+ *
+ * Games play tricks with the stack, so frames are matched with the stack pointer rather than
+ * counted. Each frame remembers the stack pointer below its return address. A return leaves the
+ * frames whose return address is at or below the stack pointer, so that:
+ *   - an RTS used as a jump, after pushing an address, does not leave any routine;
+ *   - a routine that discards its return address with PLA/PLA and returns to its caller's caller
+ *     leaves both routines.
+ *
+ * A call also drops the frames whose return address it overwrites.
+ *
+ * The stack is part of save states, so that backtraces survive loading a state, e.g. rewinding.
+ */
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use simple_error::SimpleError;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameKind {
+    Jsr,
+    Brk,
+    Nmi,
+    Irq,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // Address of the routine.
+    pub entry: u16,
+    // Address of the JSR or BRK, or of the instruction the interrupt came before.
+    pub call_site: u16,
+    // The stack pointer after pushing the return address.
+    pub sp: u8,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        CallStack::default()
+    }
+
+    // Frames from the outermost.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    // Enters the routine of |frame|.
+    pub fn call(&mut self, frame: Frame) {
+        self.unwind(frame.sp);
+        self.frames.push(frame);
+    }
+
+    // Returns with the return address just above |sp|, i.e. the stack pointer before RTS or RTI
+    // pull it.
+    pub fn ret(&mut self, sp: u8) {
+        self.unwind(sp);
+    }
+
+    // Leaves the frames whose return address is at or below |sp|.
+    fn unwind(&mut self, sp: u8) {
+        while matches!(self.frames.last(), Some(frame) if frame.sp <= sp) {
+            self.frames.pop();
+        }
+    }
+
+    // Formats the stack from the innermost frame, which is at |pc|, e.g.
+    //   #0  $8010 in double
+    //   #1  $800C in add
+    //   #2  $8002
+    // Routines are named by |label| when it knows them, and tell how they were entered if not by
+    // JSR.
+    pub fn backtrace<F: Fn(u16) -> Option<String>>(&self, pc: u16, label: F) -> String {
+        let mut lines = Vec::new();
+        let mut addr = pc;
+        for (i, frame) in self.frames.iter().rev().enumerate() {
+            let name = label(frame.entry).unwrap_or_else(|| format!("${:04X}", frame.entry));
+            let kind = match frame.kind {
+                FrameKind::Jsr => String::new(),
+                kind => format!(" ({})", kind),
+            };
+            lines.push(format!("#{:<2} ${:04X} in {}{}", i, addr, name, kind));
+            addr = frame.call_site;
+        }
+        lines.push(format!("#{:<2} ${:04X}", self.frames.len(), addr));
+        lines.join("\n")
+    }
+}
+
+impl Savestate for CallStack {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.frames.len());
+        for frame in &self.frames {
+            w.write(&(frame.kind as u8));
+            w.write(&frame.entry);
+            w.write(&frame.call_site);
+            w.write(&frame.sp);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        let len: usize = r.read()?;
+        let mut frames = Vec::new();
+        for _ in 0..len {
+            let kind = match r.read::<u8>()? {
+                0 => FrameKind::Jsr,
+                1 => FrameKind::Brk,
+                2 => FrameKind::Nmi,
+                3 => FrameKind::Irq,
+                kind => {
+                    return Err(SimpleError::new(format!(
+                        "invalid frame kind {} in save state",
+                        kind
+                    )))
+                }
+            };
+            frames.push(Frame {
+                kind,
+                entry: r.read()?,
+                call_site: r.read()?,
+                sp: r.read()?,
+            });
+        }
+        self.frames = frames;
+        Ok(())
+    }
+}
+
+impl fmt::Display for FrameKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FrameKind::Jsr => "JSR",
+            FrameKind::Brk => "BRK",
+            FrameKind::Nmi => "NMI",
+            FrameKind::Irq => "IRQ",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::savestate;
+
+    fn jsr(entry: u16, call_site: u16, sp: u8) -> Frame {
+        Frame {
+            kind: FrameKind::Jsr,
+            entry,
+            call_site,
+            sp,
+        }
+    }
+
+    #[test]
+    fn test_call_and_return() {
+        let mut stack = CallStack::new();
+        stack.call(jsr(0x8010, 0x8000, 0xfb));
+        stack.call(jsr(0x8020, 0x8012, 0xf9));
+
+        assert_eq!(stack.len(), 2);
+        stack.ret(0xf9);
+        assert_eq!(stack.frames(), &[jsr(0x8010, 0x8000, 0xfb)]);
+        // RTS after pushing an address is a jump.
+        stack.ret(0xf9);
+        assert_eq!(stack.len(), 1);
+        stack.ret(0xfb);
+        assert_eq!(stack.is_empty(), true);
+    }
+
+    #[test]
+    fn test_discarded_return_address() {
+        let mut stack = CallStack::new();
+        stack.call(jsr(0x8010, 0x8000, 0xfb));
+        stack.call(jsr(0x8020, 0x8012, 0xf9));
+
+        // PLA/PLA, then RTS to the caller of 0x8010.
+        stack.ret(0xfb);
+        assert_eq!(stack.is_empty(), true);
+
+        stack.call(jsr(0x8010, 0x8000, 0xfb));
+        // PLA/PLA, then JSR overwrites the return address.
+        stack.call(jsr(0x8030, 0x8015, 0xfb));
+        assert_eq!(stack.frames(), &[jsr(0x8030, 0x8015, 0xfb)]);
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut stack = CallStack::new();
+        assert_eq!(stack.backtrace(0x8000, |_| None), "#0  $8000");
+
+        stack.call(jsr(0x8010, 0x8002, 0xfb));
+        stack.call(Frame {
+            kind: FrameKind::Nmi,
+            entry: 0xff00,
+            call_site: 0x8012,
+            sp: 0xf8,
+        });
+        let label = |addr| match addr {
+            0x8010 => Some("update".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(
+            stack.backtrace(0xff01, label),
+            "#0  $FF01 in $FF00 (NMI)\n#1  $8012 in update\n#2  $8002"
+        );
+    }
+
+    #[test]
+    fn test_save_load() {
+        let mut stack = CallStack::new();
+        stack.call(jsr(0x8010, 0x8002, 0xfb));
+        stack.call(Frame {
+            kind: FrameKind::Irq,
+            entry: 0xff00,
+            call_site: 0x8012,
+            sp: 0xf8,
+        });
+        let state = savestate::save(&stack);
+
+        let mut loaded = CallStack::new();
+        assert_eq!(savestate::load(&mut loaded, &state), Ok(()));
+        assert_eq!(loaded, stack);
+    }
+}
@@ -0,0 +1,486 @@
+/**
+ * Cartridges loaded from iNES and NES 2.0 ROM images.
+ *
+ * An image starts with a 16-byte header describing the sizes of PRG ROM and CHR ROM, the mapper
+ * and how the nametables are mirrored, optionally followed by a 512-byte trainer, then PRG ROM
+ * and CHR ROM.
+ *
+ * See https://wiki.nesdev.com/w/index.php/INES and https://wiki.nesdev.com/w/index.php/NES_2.0
+ */
+use crate::cdl::CodeDataLog;
+use crate::mapper::{new_mapper, Mapper};
+use crate::region::Region;
+use crate::savestate::{self, Savestate, StateReader, StateWriter};
+use simple_error::SimpleError;
+use std::cell::{Ref, RefCell};
+use std::path::Path;
+
+const INES_MAGIC: &[u8] = b"NES\x1a";
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+
+const PRG_ROM_UNIT_SIZE: usize = 0x4000;
+const CHR_ROM_UNIT_SIZE: usize = 0x2000;
+
+// iNES images do not tell the size of PRG RAM. Assume 8KB, as most emulators do.
+const DEFAULT_PRG_RAM_SIZE: usize = 0x2000;
+
+// Flags 6.
+const FLAG_VERTICAL_MIRRORING: u8 = 0b0000_0001;
+const FLAG_BATTERY: u8 = 0b0000_0010;
+const FLAG_TRAINER: u8 = 0b0000_0100;
+const FLAG_FOUR_SCREEN: u8 = 0b0000_1000;
+
+// Flags 7 holds 0b10 in bits 2-3 for NES 2.0.
+const NES2_ID_MASK: u8 = 0b0000_1100;
+const NES2_ID: u8 = 0b0000_1000;
+
+// Byte 12 of NES 2.0 headers tells which console the game was made for.
+const NES2_TIMING_MASK: u8 = 0b0000_0011;
+const NES2_TIMING_NTSC: u8 = 0;
+const NES2_TIMING_PAL: u8 = 1;
+const NES2_TIMING_DENDY: u8 = 3;
+
+// How the 2KB of VRAM are mapped to the 4 nametables.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    // The cartridge provides 2KB more of VRAM.
+    FourScreen,
+}
+
+// Memory of a ROM image, which the mapper takes over.
+pub struct Rom {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub prg_ram_size: usize,
+    // Only used if there is no CHR ROM.
+    pub chr_ram_size: usize,
+    pub mirroring: Mirroring,
+}
+
+pub struct Cartridge {
+    pub mapper_id: u16,
+    pub submapper: u8,
+    pub has_battery: bool,
+    pub is_nes2: bool,
+    // The console the game was made for. None if the header does not tell or if the game runs
+    // on all of them.
+    pub region: Option<Region>,
+    // CRC-32 of PRG ROM followed by CHR ROM, which identifies the game.
+    pub crc32: u32,
+    prg_rom_size: usize,
+    chr_rom_size: usize,
+    mapper: Box<dyn Mapper>,
+    // Shared since the PPU draws through a shared reference.
+    code_data_log: RefCell<Option<CodeDataLog>>,
+}
+
+impl Cartridge {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Cartridge, SimpleError> {
+        let bytes = std::fs::read(path).map_err(SimpleError::from)?;
+        Cartridge::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Cartridge, SimpleError> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..4] != INES_MAGIC {
+            return Err(SimpleError::new("not an iNES image"));
+        }
+        let header = &bytes[0..HEADER_SIZE];
+        let is_nes2 = header[7] & NES2_ID_MASK == NES2_ID;
+
+        let mut mapper_id = ((header[7] & 0xf0) | (header[6] >> 4)) as u16;
+        let mut submapper = 0;
+        let mut region = None;
+        let prg_rom_size;
+        let chr_rom_size;
+        let prg_ram_size;
+        let chr_ram_size;
+        if is_nes2 {
+            mapper_id |= ((header[8] & 0x0f) as u16) << 8;
+            submapper = header[8] >> 4;
+            prg_rom_size = nes2_rom_size(header[4], header[9] & 0x0f, PRG_ROM_UNIT_SIZE)?;
+            chr_rom_size = nes2_rom_size(header[5], header[9] >> 4, CHR_ROM_UNIT_SIZE)?;
+            // Volatile and battery-backed RAM are mapped to the same place.
+            prg_ram_size = nes2_ram_size(header[10] & 0x0f) + nes2_ram_size(header[10] >> 4);
+            chr_ram_size = nes2_ram_size(header[11] & 0x0f) + nes2_ram_size(header[11] >> 4);
+            region = match header[12] & NES2_TIMING_MASK {
+                NES2_TIMING_NTSC => Some(Region::Ntsc),
+                NES2_TIMING_PAL => Some(Region::Pal),
+                NES2_TIMING_DENDY => Some(Region::Dendy),
+                _ => None,
+            };
+        } else {
+            // Old dumping tools wrote garbage like "DiskDude!" in bytes 7-15, which corrupts the
+            // high nibble of the mapper number.
+            if header[12..HEADER_SIZE].iter().any(|b| *b != 0) {
+                mapper_id &= 0x0f;
+            }
+            prg_rom_size = header[4] as usize * PRG_ROM_UNIT_SIZE;
+            chr_rom_size = header[5] as usize * CHR_ROM_UNIT_SIZE;
+            prg_ram_size = if header[8] == 0 {
+                DEFAULT_PRG_RAM_SIZE
+            } else {
+                header[8] as usize * DEFAULT_PRG_RAM_SIZE
+            };
+            chr_ram_size = CHR_ROM_UNIT_SIZE;
+        }
+        if prg_rom_size == 0 {
+            return Err(SimpleError::new("image has no PRG ROM"));
+        }
+
+        let mirroring = if header[6] & FLAG_FOUR_SCREEN != 0 {
+            Mirroring::FourScreen
+        } else if header[6] & FLAG_VERTICAL_MIRRORING != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        let mut offset = HEADER_SIZE;
+        if header[6] & FLAG_TRAINER != 0 {
+            offset += TRAINER_SIZE;
+        }
+        let rom_size = prg_rom_size.checked_add(chr_rom_size).ok_or_else(|| {
+            SimpleError::new(format!(
+                "image ROM is too large: {} bytes of PRG ROM and {} of CHR ROM",
+                prg_rom_size, chr_rom_size
+            ))
+        })?;
+        if bytes.len().saturating_sub(offset) < rom_size {
+            return Err(SimpleError::new(format!(
+                "image is truncated: expected {} bytes of ROM, found {}",
+                rom_size,
+                bytes.len().saturating_sub(offset)
+            )));
+        }
+        let prg_rom = bytes[offset..offset + prg_rom_size].to_vec();
+        offset += prg_rom_size;
+        let chr_rom = bytes[offset..offset + chr_rom_size].to_vec();
+        let crc32 = crc32(&[&prg_rom, &chr_rom]);
+
+        let rom = Rom {
+            prg_rom,
+            chr_rom,
+            prg_ram_size,
+            chr_ram_size,
+            mirroring,
+        };
+        Ok(Cartridge {
+            mapper_id,
+            submapper,
+            has_battery: header[6] & FLAG_BATTERY != 0,
+            is_nes2,
+            region,
+            crc32,
+            prg_rom_size,
+            chr_rom_size,
+            mapper: new_mapper(mapper_id, rom)?,
+            code_data_log: RefCell::new(None),
+        })
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mapper.mirroring()
+    }
+
+    pub fn irq(&self) -> bool {
+        self.mapper.irq()
+    }
+
+    // PRG RAM on the cartridge, empty if there is none.
+    pub fn prg_ram_mut(&mut self) -> &mut [u8] {
+        self.mapper.prg_ram_mut()
+    }
+
+    pub fn cpu_read(&mut self, addr: u16) -> u8 {
+        self.mapper.cpu_read(addr)
+    }
+
+    pub fn cpu_peek(&self, addr: u16) -> u8 {
+        self.mapper.cpu_peek(addr)
+    }
+
+    pub fn cpu_write(&mut self, addr: u16, val: u8) {
+        self.mapper.cpu_write(addr, val)
+    }
+
+    pub fn cpu_write_consecutive(&mut self, addr: u16, val: u8) {
+        self.mapper.cpu_write_consecutive(addr, val)
+    }
+
+    // Where |addr| is in PRG ROM with the banks selected now, if it is in [0x8000, 0xffff].
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xffff => Some(self.mapper.prg_rom_index(addr)),
+            _ => None,
+        }
+    }
+
+    pub fn ppu_read(&self, addr: u16) -> u8 {
+        self.mapper.ppu_read(addr)
+    }
+
+    pub fn prg_rom_size(&self) -> usize {
+        self.prg_rom_size
+    }
+
+    pub fn chr_rom_size(&self) -> usize {
+        self.chr_rom_size
+    }
+
+    // Creates an empty code/data log of the size of this cartridge.
+    pub fn new_code_data_log(&self) -> CodeDataLog {
+        CodeDataLog::new(self.prg_rom_size, self.chr_rom_size)
+    }
+
+    // Starts logging how PRG ROM and CHR ROM are used into |log|, or stops if None. Returns the
+    // previous log.
+    pub fn set_code_data_log(&mut self, log: Option<CodeDataLog>) -> Option<CodeDataLog> {
+        self.code_data_log.replace(log)
+    }
+
+    pub fn code_data_log(&self) -> Option<Ref<'_, CodeDataLog>> {
+        Ref::filter_map(self.code_data_log.borrow(), |log| log.as_ref()).ok()
+    }
+
+    pub fn is_logging_code_data(&self) -> bool {
+        self.code_data_log.borrow().is_some()
+    }
+
+    // Records in the code/data log that |addr| was accessed as |flags|, if it is in PRG ROM.
+    pub fn log_prg(&self, addr: u16, flags: u8) {
+        if let (Some(log), Some(offset)) = (
+            self.code_data_log.borrow_mut().as_mut(),
+            self.prg_rom_offset(addr),
+        ) {
+            log.log_prg(offset, addr, flags);
+        }
+    }
+
+    // Records in the code/data log that the PPU accessed |addr| as |flags|, if it is in CHR ROM.
+    pub fn log_chr(&self, addr: u16, flags: u8) {
+        if let Some(log) = self.code_data_log.borrow_mut().as_mut() {
+            if let Some(offset) = self.mapper.chr_rom_index(addr) {
+                log.log_chr(offset, flags);
+            }
+        }
+    }
+
+    pub fn ppu_write(&mut self, addr: u16, val: u8) {
+        self.mapper.ppu_write(addr, val)
+    }
+}
+
+// Save states can only be loaded in the game they were saved from.
+impl Savestate for Cartridge {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.crc32);
+        savestate::write_section(w, &*self.mapper);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        let crc32 = r.read::<u32>()?;
+        if crc32 != self.crc32 {
+            return Err(SimpleError::new(format!(
+                "save state is for another game: ROM CRC-32 {:08x}, expected {:08x}",
+                crc32, self.crc32
+            )));
+        }
+        savestate::read_section(r, &mut *self.mapper)
+    }
+}
+
+// CRC-32 (IEEE 802.3) of the concatenation of |chunks|.
+pub(crate) fn crc32(chunks: &[&[u8]]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in chunks.iter().flat_map(|chunk| chunk.iter()) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// Size of ROM in NES 2.0: a 12-bit number of |unit_size| units, or an exponent-multiplier
+// notation if the upper nibble is 0xf. Fails if the size does not fit in memory.
+fn nes2_rom_size(lsb: u8, msb: u8, unit_size: usize) -> Result<usize, SimpleError> {
+    if msb == 0x0f {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        1usize
+            .checked_shl(exponent)
+            .and_then(|size| size.checked_mul(multiplier))
+            .ok_or_else(|| {
+                SimpleError::new(format!(
+                    "image ROM size 2^{} * {} is too large",
+                    exponent, multiplier
+                ))
+            })
+    } else {
+        Ok((((msb as usize) << 8) | lsb as usize) * unit_size)
+    }
+}
+
+// Size of RAM in NES 2.0: 64 << shift bytes, or none if the shift is 0.
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    // Builds an iNES image of |mapper| with |prg_rom| and |chr_rom|.
+    pub(crate) fn ines_image(mapper: u8, prg_rom: &[u8], chr_rom: &[u8]) -> Vec<u8> {
+        let mut image = INES_MAGIC.to_vec();
+        image.push((prg_rom.len() / PRG_ROM_UNIT_SIZE) as u8);
+        image.push((chr_rom.len() / CHR_ROM_UNIT_SIZE) as u8);
+        image.push((mapper << 4) | FLAG_VERTICAL_MIRRORING);
+        image.push(mapper & 0xf0);
+        image.resize(HEADER_SIZE, 0);
+        image.extend_from_slice(prg_rom);
+        image.extend_from_slice(chr_rom);
+        image
+    }
+
+    // Builds an NROM image running |program| from 0x8000 on reset. The rest of PRG ROM is NOPs,
+    // except for an RTI at 0xff00 which NMI and IRQ point to.
+    pub(crate) fn nrom_image(program: &[u8]) -> Vec<u8> {
+        let mut prg_rom = vec![0xea; PRG_ROM_UNIT_SIZE * 2];
+        prg_rom[..program.len()].copy_from_slice(program);
+        // RTI at 0xff00 is the default interrupt handler.
+        prg_rom[0x7f00] = 0x40;
+        prg_rom[0x7ffa..].copy_from_slice(&[0x00, 0xff, 0x00, 0x80, 0x00, 0xff]);
+        ines_image(0, &prg_rom, &vec![0; CHR_ROM_UNIT_SIZE])
+    }
+
+    #[test]
+    fn test_parse_ines() {
+        let mut prg_rom = vec![0; PRG_ROM_UNIT_SIZE];
+        prg_rom[0] = 0x42;
+        let image = ines_image(3, &prg_rom, &vec![0; CHR_ROM_UNIT_SIZE * 2]);
+
+        let cartridge = Cartridge::parse(&image).unwrap();
+
+        assert_eq!(cartridge.mapper_id, 3);
+        assert_eq!(cartridge.is_nes2, false);
+        assert_eq!(cartridge.region, None);
+        assert_eq!(cartridge.mirroring(), Mirroring::Vertical);
+        assert_eq!(cartridge.cpu_peek(0x8000), 0x42);
+        assert_eq!(cartridge.cpu_peek(0xc000), 0x42);
+    }
+
+    #[test]
+    fn test_parse_nes2() {
+        let mut image = ines_image(1, &vec![0; PRG_ROM_UNIT_SIZE * 2], &[]);
+        image[6] |= FLAG_BATTERY;
+        image[7] |= NES2_ID;
+        image[8] = 0x50;
+        // 32KB of battery-backed PRG RAM.
+        image[10] = 0x90;
+
+        let cartridge = Cartridge::parse(&image).unwrap();
+
+        assert_eq!(cartridge.mapper_id, 1);
+        assert_eq!(cartridge.submapper, 5);
+        assert_eq!(cartridge.is_nes2, true);
+        assert_eq!(cartridge.has_battery, true);
+        assert_eq!(cartridge.region, Some(Region::Ntsc));
+    }
+
+    #[test]
+    fn test_parse_nes2_timing() {
+        let mut image = ines_image(0, &vec![0; PRG_ROM_UNIT_SIZE], &[]);
+        image[7] |= NES2_ID;
+        let region = |image: &[u8]| Cartridge::parse(image).unwrap().region;
+
+        image[12] = 1;
+        assert_eq!(region(&image), Some(Region::Pal));
+        image[12] = 2;
+        assert_eq!(region(&image), None);
+        image[12] = 3;
+        assert_eq!(region(&image), Some(Region::Dendy));
+    }
+
+    #[test]
+    fn test_parse_trainer() {
+        let mut image = ines_image(0, &vec![0x42; PRG_ROM_UNIT_SIZE], &[]);
+        image[6] |= FLAG_TRAINER;
+        image.splice(HEADER_SIZE..HEADER_SIZE, vec![0; TRAINER_SIZE]);
+
+        let cartridge = Cartridge::parse(&image).unwrap();
+
+        assert_eq!(cartridge.cpu_peek(0x8000), 0x42);
+    }
+
+    #[test]
+    fn test_parse_diskdude() {
+        let mut image = ines_image(2, &vec![0; PRG_ROM_UNIT_SIZE], &[]);
+        image[7..HEADER_SIZE].copy_from_slice(b"DiskDude!");
+
+        let cartridge = Cartridge::parse(&image).unwrap();
+
+        assert_eq!(cartridge.mapper_id, 2);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Cartridge::parse(b"NSF").is_err(), true);
+
+        let image = ines_image(0, &vec![0; PRG_ROM_UNIT_SIZE], &[]);
+        assert_eq!(Cartridge::parse(&image[..0x100]).is_err(), true);
+
+        let image = ines_image(0x42, &vec![0; PRG_ROM_UNIT_SIZE], &[]);
+        assert_eq!(Cartridge::parse(&image).is_err(), true);
+    }
+
+    #[test]
+    fn test_nes2_rom_size() {
+        assert_eq!(nes2_rom_size(2, 0, PRG_ROM_UNIT_SIZE), Ok(0x8000));
+        assert_eq!(
+            nes2_rom_size(0x10, 1, PRG_ROM_UNIT_SIZE),
+            Ok(0x110 * PRG_ROM_UNIT_SIZE)
+        );
+        // 2^4 * 3
+        assert_eq!(nes2_rom_size(0b0001_0001, 0x0f, PRG_ROM_UNIT_SIZE), Ok(48));
+        // 2^63 * 7
+        assert_eq!(
+            nes2_rom_size(0xff, 0x0f, PRG_ROM_UNIT_SIZE),
+            Err(SimpleError::new("image ROM size 2^63 * 7 is too large"))
+        );
+    }
+
+    #[test]
+    fn test_nes2_rom_too_large() {
+        let mut image = ines_image(0, &vec![0; PRG_ROM_UNIT_SIZE], &[]);
+        // NES 2.0 with 2^63 bytes of PRG ROM and CHR ROM.
+        image[7] |= 0b0000_1000;
+        image[4] = 0b1111_1100;
+        image[5] = 0b1111_1100;
+        image[9] = 0xff;
+
+        assert_eq!(
+            Cartridge::parse(&image).err(),
+            Some(SimpleError::new(format!(
+                "image ROM is too large: {} bytes of PRG ROM and {} of CHR ROM",
+                1usize << 63,
+                1usize << 63
+            )))
+        );
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(&[b"1234", b"56789"]), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,318 @@
+/**
+ * Code/Data Logger: how each byte of PRG ROM and CHR ROM was used while the game ran, in the .cdl
+ * format of FCEUX.
+ *
+ * A .cdl file holds one byte of flags per byte of PRG ROM, followed by one per byte of CHR ROM.
+ * PRG ROM flags:
+ *   - bit 0: executed as code;
+ *   - bit 1: read as data;
+ *   - bits 2-3: the 8KB window of the CPU the byte was last accessed in, i.e. $8000, $A000,
+ *     $C000 or $E000;
+ *   - bit 4: target of an indirect jump, i.e. JMP ($nnnn);
+ *   - bit 5: read as data through a pointer, i.e. ($nn,X) or ($nn),Y;
+ *   - bit 6: played by the DMC as a sample.
+ *
+ * CHR ROM flags:
+ *   - bit 0: fetched by the PPU to draw tiles and sprites;
+ *   - bit 1: read by the CPU through PPUDATA ($2007).
+ *
+ * Games with CHR RAM have no CHR ROM flags.
+ *
+ * See https://fceux.com/web/help/CodeDataLogger.html
+ */
+use crate::cpu::{AddressingMode, CPU};
+use crate::disasm::{self, Instruction};
+use simple_error::SimpleError;
+use std::path::Path;
+
+pub const PRG_CODE: u8 = 0b0000_0001;
+pub const PRG_DATA: u8 = 0b0000_0010;
+pub const PRG_INDIRECT_CODE: u8 = 0b0001_0000;
+pub const PRG_INDIRECT_DATA: u8 = 0b0010_0000;
+pub const PRG_PCM: u8 = 0b0100_0000;
+
+pub const CHR_RENDERED: u8 = 0b0000_0001;
+pub const CHR_READ: u8 = 0b0000_0010;
+
+const PRG_WINDOW_MASK: u8 = 0b0000_1100;
+const PRG_WINDOW_SHIFT: u8 = 2;
+
+// Instructions that read the memory at their operand address.
+const READ_MNEMONICS: &[&str] = &[
+    "ADC", "AND", "ASL", "BIT", "CMP", "CPX", "CPY", "DEC", "EOR", "INC", "LDA", "LDX", "LDY",
+    "LSR", "ORA", "ROL", "ROR", "SBC", "DCP", "ISB", "LAX", "NOP", "RLA", "RRA", "SLO", "SRE",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    // Creates an empty log for |prg_rom_size| bytes of PRG ROM and |chr_rom_size| of CHR ROM.
+    pub fn new(prg_rom_size: usize, chr_rom_size: usize) -> Self {
+        CodeDataLog {
+            prg: vec![0; prg_rom_size],
+            chr: vec![0; chr_rom_size],
+        }
+    }
+
+    // Parses a .cdl file, e.g. to go on logging where a previous run stopped.
+    pub fn from_bytes(
+        bytes: &[u8],
+        prg_rom_size: usize,
+        chr_rom_size: usize,
+    ) -> Result<Self, SimpleError> {
+        if bytes.len() != prg_rom_size + chr_rom_size {
+            return Err(SimpleError::new(format!(
+                "CDL file has {} bytes, expected {} for the ROM",
+                bytes.len(),
+                prg_rom_size + chr_rom_size
+            )));
+        }
+        Ok(CodeDataLog {
+            prg: bytes[..prg_rom_size].to_vec(),
+            chr: bytes[prg_rom_size..].to_vec(),
+        })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        prg_rom_size: usize,
+        chr_rom_size: usize,
+    ) -> Result<Self, SimpleError> {
+        let bytes = std::fs::read(path).map_err(SimpleError::from)?;
+        CodeDataLog::from_bytes(&bytes, prg_rom_size, chr_rom_size)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.prg.as_slice(), self.chr.as_slice()].concat()
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), SimpleError> {
+        std::fs::write(path, self.to_bytes()).map_err(SimpleError::from)
+    }
+
+    // Flags of each byte of PRG ROM.
+    pub fn prg(&self) -> &[u8] {
+        &self.prg
+    }
+
+    // Flags of each byte of CHR ROM.
+    pub fn chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    // Records |flags| for the byte at |offset| in PRG ROM, which the CPU sees at |addr|.
+    pub fn log_prg(&mut self, offset: usize, addr: u16, flags: u8) {
+        if let Some(byte) = self.prg.get_mut(offset) {
+            let window = (((addr >> 13) & 0b11) as u8) << PRG_WINDOW_SHIFT;
+            *byte = (*byte & !PRG_WINDOW_MASK) | window | flags;
+        }
+    }
+
+    // Records |flags| for the byte at |offset| in CHR ROM.
+    pub fn log_chr(&mut self, offset: usize, flags: u8) {
+        if let Some(byte) = self.chr.get_mut(offset) {
+            *byte |= flags;
+        }
+    }
+
+    // Number of bytes of PRG ROM with any of |flags|, e.g. to measure code coverage.
+    pub fn count_prg(&self, flags: u8) -> usize {
+        self.prg.iter().filter(|byte| *byte & flags != 0).count()
+    }
+
+    // Number of bytes of CHR ROM with any of |flags|.
+    pub fn count_chr(&self, flags: u8) -> usize {
+        self.chr.iter().filter(|byte| *byte & flags != 0).count()
+    }
+}
+
+// The memory the instruction at the program counter of |cpu| will read, with its flags: the
+// bytes of the instruction as code, then the data it reads. Computed with peeks before the
+// instruction executes.
+pub(crate) fn instruction_accesses(cpu: &CPU) -> Vec<(u16, u8)> {
+    let instruction = disasm::decode(cpu.pc, |addr| cpu.peek_mem(addr));
+    let mut accesses: Vec<(u16, u8)> = (0..instruction.len())
+        .map(|i| (cpu.pc.wrapping_add(i), PRG_CODE))
+        .collect();
+    let mnemonic = match instruction.mnemonic {
+        Some(mnemonic) => mnemonic,
+        None => return accesses,
+    };
+    if mnemonic == "JMP" && instruction.mode == AddressingMode::Indirect {
+        // The pointer does not carry into the high byte, like the CPU.
+        let ptr = instruction.operand();
+        let ptr_hi = (ptr & 0xff00) | (ptr.wrapping_add(1) & 0x00ff);
+        let target = u16::from_le_bytes([cpu.peek_mem(ptr), cpu.peek_mem(ptr_hi)]);
+        accesses.extend([
+            (ptr, PRG_DATA),
+            (ptr_hi, PRG_DATA),
+            (target, PRG_INDIRECT_CODE),
+        ]);
+    } else if READ_MNEMONICS.contains(&mnemonic) {
+        if let Some(access) = data_access(cpu, &instruction) {
+            accesses.push(access);
+        }
+    }
+    accesses
+}
+
+// The address a read instruction reads its data at, with its flags.
+fn data_access(cpu: &CPU, instruction: &Instruction) -> Option<(u16, u8)> {
+    let operand = instruction.operand();
+    let zero_page16 = |ptr: u8| {
+        u16::from_le_bytes([
+            cpu.peek_mem(ptr as u16),
+            cpu.peek_mem(ptr.wrapping_add(1) as u16),
+        ])
+    };
+    Some(match instruction.mode {
+        AddressingMode::Absolute => (operand, PRG_DATA),
+        AddressingMode::AbsoluteX => (operand.wrapping_add(cpu.reg_x as u16), PRG_DATA),
+        AddressingMode::AbsoluteY => (operand.wrapping_add(cpu.reg_y as u16), PRG_DATA),
+        AddressingMode::IndirectX => (
+            zero_page16((operand as u8).wrapping_add(cpu.reg_x)),
+            PRG_DATA | PRG_INDIRECT_DATA,
+        ),
+        AddressingMode::IndirectY => (
+            zero_page16(operand as u8).wrapping_add(cpu.reg_y as u16),
+            PRG_DATA | PRG_INDIRECT_DATA,
+        ),
+        // Zero page is never PRG ROM, and the other modes read nothing or the instruction itself.
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nrom_image;
+    use crate::cartridge::Cartridge;
+    use crate::nes::Nes;
+
+    #[test]
+    fn test_log() {
+        let mut log = CodeDataLog::new(0x8000, 0x2000);
+
+        log.log_prg(0x0000, 0x8000, PRG_CODE);
+        log.log_prg(0x4001, 0xc001, PRG_DATA);
+        log.log_prg(0x4001, 0xe001, PRG_DATA | PRG_INDIRECT_DATA);
+        log.log_prg(0x8000, 0x8000, PRG_CODE);
+        log.log_chr(0x0010, CHR_RENDERED);
+        log.log_chr(0x0010, CHR_READ);
+
+        assert_eq!(log.prg()[0x0000], 0b0000_0001);
+        // The window is the last one the byte was accessed in.
+        assert_eq!(log.prg()[0x4001], 0b0010_1110);
+        assert_eq!(log.chr()[0x0010], 0b0000_0011);
+        assert_eq!(log.count_prg(PRG_CODE), 1);
+        assert_eq!(log.count_prg(PRG_CODE | PRG_DATA), 2);
+        assert_eq!(log.count_chr(CHR_READ), 1);
+    }
+
+    #[test]
+    fn test_bytes() {
+        let mut log = CodeDataLog::new(0x4000, 0x2000);
+        log.log_prg(0x3fff, 0xbfff, PRG_CODE);
+        log.log_chr(0x0000, CHR_RENDERED);
+
+        let bytes = log.to_bytes();
+
+        assert_eq!(bytes.len(), 0x6000);
+        assert_eq!(bytes[0x3fff], 0b0000_0101);
+        assert_eq!(bytes[0x4000], 0b0000_0001);
+        assert_eq!(CodeDataLog::from_bytes(&bytes, 0x4000, 0x2000), Ok(log));
+        assert_eq!(
+            CodeDataLog::from_bytes(&bytes, 0x8000, 0),
+            Err(SimpleError::new(
+                "CDL file has 24576 bytes, expected 32768 for the ROM"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_nes() {
+        // JMP ($8010)
+        // $8005: LDA $8012
+        // JMP $8005
+        // $8010: .word $8005
+        let mut program = vec![0xea; 0x13];
+        program[..3].copy_from_slice(&[0x6c, 0x10, 0x80]);
+        program[5..11].copy_from_slice(&[0xad, 0x12, 0x80, 0x4c, 0x05, 0x80]);
+        program[0x10..0x12].copy_from_slice(&[0x05, 0x80]);
+        let mut nes = Nes::new(Cartridge::parse(&nrom_image(&program)).unwrap());
+        let log = nes.cartridge().new_code_data_log();
+        nes.cartridge_mut().set_code_data_log(Some(log));
+        // Show the background, so that the PPU fetches tiles.
+        nes.cpu_mut().poke_mem(0x2001, 0x08);
+        nes.run_frame().unwrap();
+        nes.cpu_mut().nmi();
+        nes.step_instruction().unwrap();
+
+        let log = nes.cartridge_mut().set_code_data_log(None).unwrap();
+        let prg = log.prg();
+        assert_eq!(prg[0x00..0x03], [PRG_CODE; 3]);
+        assert_eq!(prg[0x03..0x05], [0; 2]);
+        assert_eq!(prg[0x05], PRG_CODE | PRG_INDIRECT_CODE);
+        assert_eq!(prg[0x06..0x0b], [PRG_CODE; 5]);
+        assert_eq!(prg[0x10..0x13], [PRG_DATA; 3]);
+        // The NMI vector, read in the window at $E000.
+        assert_eq!(prg[0x7ffa..0x7ffc], [PRG_DATA | 0b1100; 2]);
+        // RTI of the NMI handler, in the window at $E000.
+        assert_eq!(prg[0x7f00], PRG_CODE | 0b1100);
+        assert_eq!(log.count_prg(PRG_CODE), 10);
+        assert_eq!(log.chr().len(), 0x2000);
+        assert_eq!(log.count_chr(CHR_RENDERED) > 0, true);
+        assert_eq!(log.count_chr(CHR_READ), 0);
+        assert_eq!(nes.cartridge().is_logging_code_data(), false);
+    }
+
+    #[test]
+    fn test_instruction_accesses() {
+        let mut cpu = CPU::new();
+        // LDA $9000,X
+        // JMP ($9010)
+        // LDA ($10),Y
+        // STA $9000
+        cpu.load(&[
+            0xbd, 0x00, 0x90, 0x6c, 0x10, 0x90, 0xb1, 0x10, 0x8d, 0x00, 0x90,
+        ])
+        .unwrap();
+        cpu.reset();
+        cpu.reg_x = 2;
+        cpu.reg_y = 3;
+        cpu.poke_mem(0x9010, 0x34);
+        cpu.poke_mem(0x9011, 0x12);
+        cpu.poke_mem(0x0010, 0x00);
+        cpu.poke_mem(0x0011, 0xa0);
+
+        assert_eq!(
+            instruction_accesses(&cpu),
+            vec![
+                (0x8000, PRG_CODE),
+                (0x8001, PRG_CODE),
+                (0x8002, PRG_CODE),
+                (0x9002, PRG_DATA)
+            ]
+        );
+        cpu.pc = 0x8003;
+        assert_eq!(
+            instruction_accesses(&cpu)[3..],
+            [
+                (0x9010, PRG_DATA),
+                (0x9011, PRG_DATA),
+                (0x1234, PRG_INDIRECT_CODE)
+            ]
+        );
+        cpu.pc = 0x8006;
+        assert_eq!(
+            instruction_accesses(&cpu)[2..],
+            [(0xa003, PRG_DATA | PRG_INDIRECT_DATA)]
+        );
+        cpu.pc = 0x8008;
+        assert_eq!(instruction_accesses(&cpu).len(), 3);
+    }
+}
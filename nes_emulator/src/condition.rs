@@ -0,0 +1,393 @@
+/**
+ * Conditions of breakpoints, e.g. "A == $40 && [$0300] > 3 && scanline < 20".
+ *
+ * A condition is an expression evaluated on the state of the CPU when the breakpoint is reached.
+ * It holds:
+ *   - numbers: $hex, %binary or decimal;
+ *   - registers: A, X, Y, SP, P and PC, and flags C, Z, I, D, V and N as 0 or 1;
+ *   - the PPU position: scanline and dot, 0 without a PPU; and cycles, the CPU cycles elapsed;
+ *   - memory: [address] is the byte at address, read without side effects;
+ *   - labels, when parsed with symbols: the address of the label;
+ *   - the operators of C, from the lowest precedence: ||, &&, == !=, < <= > >=, |, ^, &, + -,
+ *     and unary ! - ~; and parentheses.
+ *
+ * Names of registers are case insensitive and come before labels. The condition holds if its value
+ * is not 0.
+ */
+use crate::cpu::{Status, CPU, STATUS_BIT5};
+use simple_error::SimpleError;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Condition, SimpleError> {
+        Condition::parse_with(source, |_| None)
+    }
+
+    // Parses |source|, where |label| gives the address of the labels.
+    pub fn parse_with<F: Fn(&str) -> Option<u16>>(
+        source: &str,
+        label: F,
+    ) -> Result<Condition, SimpleError> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+            label: &label,
+        };
+        let expr = parser.binary(0)?;
+        parser.skip_spaces();
+        if let Some(c) = parser.peek() {
+            return Err(SimpleError::new(format!(
+                "unexpected '{}' at column {} of condition",
+                c,
+                parser.pos + 1
+            )));
+        }
+        Ok(Condition {
+            source: source.trim().to_string(),
+            expr,
+        })
+    }
+
+    pub fn eval(&self, cpu: &CPU) -> i64 {
+        self.expr.eval(cpu)
+    }
+
+    pub fn holds(&self, cpu: &CPU) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Value {
+    A,
+    X,
+    Y,
+    Sp,
+    P,
+    Pc,
+    Flag(Status),
+    Scanline,
+    Dot,
+    Cycles,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Number(i64),
+    Value(Value),
+    Memory(Box<Expr>),
+    Unary(char, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, cpu: &CPU) -> i64 {
+        match self {
+            Expr::Number(val) => *val,
+            Expr::Value(value) => match value {
+                Value::A => cpu.reg_a as i64,
+                Value::X => cpu.reg_x as i64,
+                Value::Y => cpu.reg_y as i64,
+                Value::Sp => cpu.sp as i64,
+                Value::P => (cpu.reg_status.bits() | STATUS_BIT5) as i64,
+                Value::Pc => cpu.pc as i64,
+                Value::Flag(flag) => cpu.reg_status.contains(*flag) as i64,
+                Value::Scanline => cpu
+                    .ppu_position()
+                    .map_or(0, |(scanline, _)| scanline as i64),
+                Value::Dot => cpu.ppu_position().map_or(0, |(_, dot)| dot as i64),
+                Value::Cycles => cpu.cycles as i64,
+            },
+            Expr::Memory(addr) => cpu.peek_mem(addr.eval(cpu) as u16) as i64,
+            Expr::Unary(op, expr) => {
+                let val = expr.eval(cpu);
+                match op {
+                    '!' => (val == 0) as i64,
+                    '-' => val.wrapping_neg(),
+                    _ => !val,
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(cpu);
+                // Like in C, && and || do not evaluate their right side when not needed.
+                match *op {
+                    "&&" => return (lhs != 0 && rhs.eval(cpu) != 0) as i64,
+                    "||" => return (lhs != 0 || rhs.eval(cpu) != 0) as i64,
+                    _ => {}
+                }
+                let rhs = rhs.eval(cpu);
+                match *op {
+                    "==" => (lhs == rhs) as i64,
+                    "!=" => (lhs != rhs) as i64,
+                    "<" => (lhs < rhs) as i64,
+                    "<=" => (lhs <= rhs) as i64,
+                    ">" => (lhs > rhs) as i64,
+                    ">=" => (lhs >= rhs) as i64,
+                    "|" => lhs | rhs,
+                    "^" => lhs ^ rhs,
+                    "&" => lhs & rhs,
+                    "+" => lhs.wrapping_add(rhs),
+                    _ => lhs.wrapping_sub(rhs),
+                }
+            }
+        }
+    }
+}
+
+// Binary operators from the lowest precedence to the highest. Longer operators come first so
+// that "<=" is not read as "<".
+const BINARY_OPERATORS: [&[&str]; 8] = [
+    &["||"],
+    &["&&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["+", "-"],
+];
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    label: &'a dyn Fn(&str) -> Option<u16>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(c) if c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    // Consumes |op| if it comes next, but not "|" or "&" when they start "||" or "&&".
+    fn eat(&mut self, op: &str) -> bool {
+        self.skip_spaces();
+        let len = op.chars().count();
+        let matches = self.chars[self.pos..]
+            .iter()
+            .take(len)
+            .copied()
+            .eq(op.chars());
+        let doubled = len == 1
+            && matches!(op, "|" | "&")
+            && self.chars.get(self.pos + 1) == op.chars().next().as_ref();
+        if matches && !doubled {
+            self.pos += len;
+        }
+        matches && !doubled
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, SimpleError> {
+        if level == BINARY_OPERATORS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        'outer: loop {
+            for op in BINARY_OPERATORS[level] {
+                if self.eat(op) {
+                    let rhs = self.binary(level + 1)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, SimpleError> {
+        self.skip_spaces();
+        match self.peek() {
+            Some(op @ ('!' | '-' | '~')) => {
+                self.pos += 1;
+                Ok(Expr::Unary(op, Box::new(self.unary()?)))
+            }
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.binary(0)?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some('[') => {
+                self.pos += 1;
+                let expr = self.binary(0)?;
+                self.expect(']')?;
+                Ok(Expr::Memory(Box::new(expr)))
+            }
+            Some('$') => {
+                self.pos += 1;
+                self.number(16)
+            }
+            Some('%') => {
+                self.pos += 1;
+                self.number(2)
+            }
+            Some(c) if c.is_ascii_digit() => self.number(10),
+            Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '@' => {
+                let start = self.pos;
+                self.pos += 1;
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_') {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+                value(&name).map(Expr::Value).or_else(|e| {
+                    (self.label)(&name)
+                        .map(|addr| Expr::Number(addr as i64))
+                        .ok_or(e)
+                })
+            }
+            Some(c) => Err(SimpleError::new(format!(
+                "unexpected '{}' at column {} of condition",
+                c,
+                self.pos + 1
+            ))),
+            None => Err(SimpleError::new("condition ends too early")),
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), SimpleError> {
+        self.skip_spaces();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(SimpleError::new(format!(
+                "missing '{}' at column {} of condition",
+                c,
+                self.pos + 1
+            )))
+        }
+    }
+
+    fn number(&mut self, radix: u32) -> Result<Expr, SimpleError> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_digit(radix)) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        i64::from_str_radix(&digits, radix)
+            .map(Expr::Number)
+            .map_err(|_| SimpleError::new(format!("invalid number at column {}", start + 1)))
+    }
+}
+
+fn value(name: &str) -> Result<Value, SimpleError> {
+    Ok(match name.to_ascii_lowercase().as_str() {
+        "a" => Value::A,
+        "x" => Value::X,
+        "y" => Value::Y,
+        "sp" => Value::Sp,
+        "p" => Value::P,
+        "pc" => Value::Pc,
+        "c" => Value::Flag(Status::C),
+        "z" => Value::Flag(Status::Z),
+        "i" => Value::Flag(Status::I),
+        "d" => Value::Flag(Status::D),
+        "v" => Value::Flag(Status::V),
+        "n" => Value::Flag(Status::N),
+        "scanline" => Value::Scanline,
+        "dot" => Value::Dot,
+        "cycles" => Value::Cycles,
+        _ => return Err(SimpleError::new(format!("unknown name {}", name))),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nrom_image;
+    use crate::cartridge::Cartridge;
+    use crate::nes::Nes;
+
+    fn eval(source: &str, cpu: &CPU) -> i64 {
+        Condition::parse(source).unwrap().eval(cpu)
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut cpu = CPU::new();
+        // LDA #$40
+        // STA $0300
+        // SEC
+        cpu.interpret(&[0xa9, 0x40, 0x8d, 0x00, 0x03, 0x38])
+            .unwrap();
+
+        assert_eq!(eval("A == $40 && [$0300] > 3", &cpu), 1);
+        assert_eq!(eval("a == $41 || c", &cpu), 1);
+        assert_eq!(eval("[$02FF + 1] - %1000000", &cpu), 0);
+        assert_eq!(eval("!(x == 0) | z", &cpu), 0);
+        assert_eq!(eval("(A & $0f) ^ 1", &cpu), 1);
+        assert_eq!(eval("-1 < 0 && ~0 == -1", &cpu), 1);
+        assert_eq!(eval("PC >= $8006 && P == $21 && SP == $fd", &cpu), 1);
+        assert_eq!(eval("scanline + dot", &cpu), 0);
+        assert_eq!(eval("cycles", &cpu), 7 + 2 + 4 + 2);
+    }
+
+    #[test]
+    fn test_ppu_position() {
+        // JMP $8000
+        let image = nrom_image(&[0x4c, 0x00, 0x80]);
+        let mut nes = Nes::new(Cartridge::parse(&image).unwrap());
+        for _ in 0..500 {
+            nes.step_instruction().unwrap();
+        }
+
+        let cpu = nes.cpu();
+        assert_eq!(eval("scanline", cpu), nes.ppu().scanline() as i64);
+        assert_eq!(eval("DOT", cpu), nes.ppu().dot() as i64);
+        assert_eq!(eval("scanline > 0 && scanline < 20", cpu), 1);
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut cpu = CPU::new();
+        cpu.pc = 0x8010;
+        let label = |name: &str| match name {
+            "main_loop" => Some(0x8010),
+            "x" | "@done" => Some(0x9000),
+            _ => None,
+        };
+        let eval = |source| Condition::parse_with(source, label).unwrap().eval(&cpu);
+
+        assert_eq!(eval("PC == main_loop"), 1);
+        assert_eq!(eval("@done"), 0x9000);
+        // Registers come first.
+        assert_eq!(eval("X"), 0);
+        assert_eq!(
+            Condition::parse_with("other", label),
+            Err(SimpleError::new("unknown name other"))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |source| Condition::parse(source).unwrap_err().as_str().to_string();
+
+        assert_eq!(error("A =="), "condition ends too early");
+        assert_eq!(error("A = 1"), "unexpected '=' at column 3 of condition");
+        assert_eq!(error("[$10 > 1"), "missing ']' at column 9 of condition");
+        assert_eq!(error("Q > 1"), "unknown name Q");
+        assert_eq!(error("$ > 1"), "invalid number at column 2");
+        assert_eq!(error("@loop"), "unknown name @loop");
+        assert_eq!(
+            Condition::parse(" A==1 ").unwrap().to_string(),
+            "A==1".to_string()
+        );
+    }
+}
@@ -1,11 +1,22 @@
-use bitflags::bitflags;
 /**
  * Simulator of 6502.
  *
  * For 6502 instruction references, see http://www.obelisk.me.uk/6502/reference.html and http://www.6502.org/tutorials/6502opcodes.html
  */
+use crate::breakpoint::{self, Breakpoints, StopReason};
+use crate::bus::{AccessKind, Bus, BusAccess};
+use crate::callstack::{CallStack, Frame, FrameKind};
+use crate::cdl::{self, PRG_DATA};
+use crate::profiler::Profiler;
+use crate::ram_pattern::RamPattern;
+use crate::savestate::{self, Savestate, StateReader, StateWriter};
+use crate::symbols::{Symbols, PRG_BANK_SIZE};
+use crate::trace;
+use bitflags::bitflags;
 use simple_error::SimpleError;
+use std::any::Any;
 use std::collections::HashMap;
+use std::io::Write;
 use std::result::Result;
 
 // NES platform has a special mechanism to mark where the CPU should start the execution.
@@ -13,6 +24,15 @@ use std::result::Result;
 // that instructs CPU to set pc to 0xfffc.
 const INIT_PROGRAM_COUNTER_ADDR: u16 = 0xfffc;
 
+// Upon NMI, the CPU jumps to the 16-bit address stored at 0xfffa.
+const NMI_VECTOR_ADDR: u16 = 0xfffa;
+
+// Upon BRK or IRQ, the CPU jumps to the 16-bit address stored at 0xfffe.
+const IRQ_BRK_VECTOR_ADDR: u16 = 0xfffe;
+
+// Entering an interrupt handler takes 7 cycles.
+const INTERRUPT_CYCLES: u64 = 7;
+
 // Memory layout.
 
 // Max address.
@@ -20,8 +40,12 @@ const MEM_ADDR_MAX: u16 = 0xffff;
 const MEM_ADDR_SPACE_SIZE: usize = MEM_ADDR_MAX as usize + 1;
 // Program ROM address.
 const MEM_PRG_ROM_ADDR_START: u16 = 0x8000;
-const MEM_PRG_ROM_ADDR_END: u16 = 0xffff;
-const MEM_PRG_ROM_SIZE: usize = (MEM_PRG_ROM_ADDR_END - MEM_PRG_ROM_ADDR_START) as usize + 1;
+// Stack lives in page 1, i.e. [0x0100, 0x01ff]. It grows downwards.
+const MEM_STACK_ADDR_START: u16 = 0x0100;
+const STACK_POINTER_INIT: u8 = 0xfd;
+
+// Bit 5 of the status register is always pushed as 1.
+pub(crate) const STATUS_BIT5: u8 = 0b0010_0000;
 
 const DEBUG_ADDR: u16 = 0xffff;
 
@@ -75,7 +99,7 @@ const OPCODE_BNE: u8 = 0xd0;
 const OPCODE_BPL: u8 = 0x10;
 
 // BRK
-const OPCODE_BRK: u8 = 0x00;
+pub(crate) const OPCODE_BRK: u8 = 0x00;
 
 // BVC
 const OPCODE_BVC: u8 = 0x50;
@@ -95,6 +119,38 @@ const OPCODE_CLI: u8 = 0x58;
 // CLV
 const OPCODE_CLV: u8 = 0xb8;
 
+// CMP
+const OPCODE_CMP_IMMEDIATE: u8 = 0xc9;
+const OPCODE_CMP_ZEROPAGE: u8 = 0xc5;
+const OPCODE_CMP_ZEROPAGEX: u8 = 0xd5;
+const OPCODE_CMP_ABSOLUTE: u8 = 0xcd;
+const OPCODE_CMP_ABSOLUTEX: u8 = 0xdd;
+const OPCODE_CMP_ABSOLUTEY: u8 = 0xd9;
+const OPCODE_CMP_INDIRECTX: u8 = 0xc1;
+const OPCODE_CMP_INDIRECTY: u8 = 0xd1;
+
+// CPX
+const OPCODE_CPX_IMMEDIATE: u8 = 0xe0;
+const OPCODE_CPX_ZEROPAGE: u8 = 0xe4;
+const OPCODE_CPX_ABSOLUTE: u8 = 0xec;
+
+// CPY
+const OPCODE_CPY_IMMEDIATE: u8 = 0xc0;
+const OPCODE_CPY_ZEROPAGE: u8 = 0xc4;
+const OPCODE_CPY_ABSOLUTE: u8 = 0xcc;
+
+// DEC
+const OPCODE_DEC_ZEROPAGE: u8 = 0xc6;
+const OPCODE_DEC_ZEROPAGEX: u8 = 0xd6;
+const OPCODE_DEC_ABSOLUTE: u8 = 0xce;
+const OPCODE_DEC_ABSOLUTEX: u8 = 0xde;
+
+// DEX
+const OPCODE_DEX: u8 = 0xca;
+
+// DEY
+const OPCODE_DEY: u8 = 0x88;
+
 // EOR
 const OPCODE_EOR_IMMEDIATE: u8 = 0x49;
 const OPCODE_EOR_ZEROPAGE: u8 = 0x45;
@@ -105,6 +161,12 @@ const OPCODE_EOR_ABSOLUTEY: u8 = 0x59;
 const OPCODE_EOR_INDIRECTX: u8 = 0x41;
 const OPCODE_EOR_INDIRECTY: u8 = 0x51;
 
+// INC
+const OPCODE_INC_ZEROPAGE: u8 = 0xe6;
+const OPCODE_INC_ZEROPAGEX: u8 = 0xf6;
+const OPCODE_INC_ABSOLUTE: u8 = 0xee;
+const OPCODE_INC_ABSOLUTEX: u8 = 0xfe;
+
 // LDA
 const OPCODE_LDA_IMMEDIATE: u8 = 0xa9;
 const OPCODE_LDA_ZEROPAGE: u8 = 0xa5;
@@ -136,6 +198,9 @@ const OPCODE_LSR_ZEROPAGEX: u8 = 0x56;
 const OPCODE_LSR_ABSOLUTE: u8 = 0x4e;
 const OPCODE_LSR_ABSOLUTEX: u8 = 0x5e;
 
+// NOP
+const OPCODE_NOP: u8 = 0xea;
+
 // ORA
 const OPCODE_ORA_IMMEDIATE: u8 = 0x09;
 const OPCODE_ORA_ZEROPAGE: u8 = 0x05;
@@ -146,6 +211,18 @@ const OPCODE_ORA_ABSOLUTEY: u8 = 0x19;
 const OPCODE_ORA_INDIRECTX: u8 = 0x01;
 const OPCODE_ORA_INDIRECTY: u8 = 0x11;
 
+// PHA
+const OPCODE_PHA: u8 = 0x48;
+
+// PHP
+const OPCODE_PHP: u8 = 0x08;
+
+// PLA
+const OPCODE_PLA: u8 = 0x68;
+
+// PLP
+const OPCODE_PLP: u8 = 0x28;
+
 // ROL
 const OPCODE_ROL_ACCUMULATOR: u8 = 0x2a;
 const OPCODE_ROL_ZEROPAGE: u8 = 0x26;
@@ -160,6 +237,12 @@ const OPCODE_ROR_ZEROPAGEX: u8 = 0x76;
 const OPCODE_ROR_ABSOLUTE: u8 = 0x6e;
 const OPCODE_ROR_ABSOLUTEX: u8 = 0x7e;
 
+// RTI
+const OPCODE_RTI: u8 = 0x40;
+
+// RTS
+const OPCODE_RTS: u8 = 0x60;
+
 // STA
 const OPCODE_STA_ZEROPAGE: u8 = 0x85;
 const OPCODE_STA_ZEROPAGEX: u8 = 0x95;
@@ -183,18 +266,30 @@ const OPCODE_STY_ABSOLUTE: u8 = 0x8c;
 const OPCODE_JMP_ABSOLUTE: u8 = 0x4c;
 const OPCODE_JMP_INDIRECT: u8 = 0x6c;
 
+// JSR
+const OPCODE_JSR: u8 = 0x20;
+
 // INX
 const OPCODE_INX: u8 = 0xe8;
 
+// INY
+const OPCODE_INY: u8 = 0xc8;
+
 // TAX
 const OPCODE_TAX: u8 = 0xaa;
 
 // TAY
 const OPCODE_TAY: u8 = 0xa8;
 
+// TSX
+const OPCODE_TSX: u8 = 0xba;
+
 // TXA
 const OPCODE_TXA: u8 = 0x8a;
 
+// TXS
+const OPCODE_TXS: u8 = 0x9a;
+
 // TYA
 const OPCODE_TYA: u8 = 0x98;
 
@@ -217,13 +312,95 @@ const OPCODE_SED: u8 = 0xf8;
 // SEI
 const OPCODE_SEI: u8 = 0x78;
 
+// Unofficial opcodes, the stable ones that nestest uses.
+
+// DCP
+const OPCODE_DCP_ZEROPAGE: u8 = 0xc7;
+const OPCODE_DCP_ZEROPAGEX: u8 = 0xd7;
+const OPCODE_DCP_ABSOLUTE: u8 = 0xcf;
+const OPCODE_DCP_ABSOLUTEX: u8 = 0xdf;
+const OPCODE_DCP_ABSOLUTEY: u8 = 0xdb;
+const OPCODE_DCP_INDIRECTX: u8 = 0xc3;
+const OPCODE_DCP_INDIRECTY: u8 = 0xd3;
+
+// ISB
+const OPCODE_ISB_ZEROPAGE: u8 = 0xe7;
+const OPCODE_ISB_ZEROPAGEX: u8 = 0xf7;
+const OPCODE_ISB_ABSOLUTE: u8 = 0xef;
+const OPCODE_ISB_ABSOLUTEX: u8 = 0xff;
+const OPCODE_ISB_ABSOLUTEY: u8 = 0xfb;
+const OPCODE_ISB_INDIRECTX: u8 = 0xe3;
+const OPCODE_ISB_INDIRECTY: u8 = 0xf3;
+
+// LAX
+const OPCODE_LAX_ZEROPAGE: u8 = 0xa7;
+const OPCODE_LAX_ZEROPAGEY: u8 = 0xb7;
+const OPCODE_LAX_ABSOLUTE: u8 = 0xaf;
+const OPCODE_LAX_ABSOLUTEY: u8 = 0xbf;
+const OPCODE_LAX_INDIRECTX: u8 = 0xa3;
+const OPCODE_LAX_INDIRECTY: u8 = 0xb3;
+
+// RLA
+const OPCODE_RLA_ZEROPAGE: u8 = 0x27;
+const OPCODE_RLA_ZEROPAGEX: u8 = 0x37;
+const OPCODE_RLA_ABSOLUTE: u8 = 0x2f;
+const OPCODE_RLA_ABSOLUTEX: u8 = 0x3f;
+const OPCODE_RLA_ABSOLUTEY: u8 = 0x3b;
+const OPCODE_RLA_INDIRECTX: u8 = 0x23;
+const OPCODE_RLA_INDIRECTY: u8 = 0x33;
+
+// RRA
+const OPCODE_RRA_ZEROPAGE: u8 = 0x67;
+const OPCODE_RRA_ZEROPAGEX: u8 = 0x77;
+const OPCODE_RRA_ABSOLUTE: u8 = 0x6f;
+const OPCODE_RRA_ABSOLUTEX: u8 = 0x7f;
+const OPCODE_RRA_ABSOLUTEY: u8 = 0x7b;
+const OPCODE_RRA_INDIRECTX: u8 = 0x63;
+const OPCODE_RRA_INDIRECTY: u8 = 0x73;
+
+// SAX
+const OPCODE_SAX_ZEROPAGE: u8 = 0x87;
+const OPCODE_SAX_ZEROPAGEY: u8 = 0x97;
+const OPCODE_SAX_ABSOLUTE: u8 = 0x8f;
+const OPCODE_SAX_INDIRECTX: u8 = 0x83;
+
+// SLO
+const OPCODE_SLO_ZEROPAGE: u8 = 0x07;
+const OPCODE_SLO_ZEROPAGEX: u8 = 0x17;
+const OPCODE_SLO_ABSOLUTE: u8 = 0x0f;
+const OPCODE_SLO_ABSOLUTEX: u8 = 0x1f;
+const OPCODE_SLO_ABSOLUTEY: u8 = 0x1b;
+const OPCODE_SLO_INDIRECTX: u8 = 0x03;
+const OPCODE_SLO_INDIRECTY: u8 = 0x13;
+
+// SRE
+const OPCODE_SRE_ZEROPAGE: u8 = 0x47;
+const OPCODE_SRE_ZEROPAGEX: u8 = 0x57;
+const OPCODE_SRE_ABSOLUTE: u8 = 0x4f;
+const OPCODE_SRE_ABSOLUTEX: u8 = 0x5f;
+const OPCODE_SRE_ABSOLUTEY: u8 = 0x5b;
+const OPCODE_SRE_INDIRECTX: u8 = 0x43;
+const OPCODE_SRE_INDIRECTY: u8 = 0x53;
+
+// NOP
+const OPCODES_NOP_IMPLIED: [u8; 6] = [0x1a, 0x3a, 0x5a, 0x7a, 0xda, 0xfa];
+const OPCODES_NOP_IMMEDIATE: [u8; 5] = [0x80, 0x82, 0x89, 0xc2, 0xe2];
+const OPCODES_NOP_ZEROPAGE: [u8; 3] = [0x04, 0x44, 0x64];
+const OPCODES_NOP_ZEROPAGEX: [u8; 6] = [0x14, 0x34, 0x54, 0x74, 0xd4, 0xf4];
+const OPCODES_NOP_ABSOLUTE: [u8; 1] = [0x0c];
+const OPCODES_NOP_ABSOLUTEX: [u8; 6] = [0x1c, 0x3c, 0x5c, 0x7c, 0xdc, 0xfc];
+
+// SBC
+const OPCODE_SBC_IMMEDIATE_UNOFFICIAL: u8 = 0xeb;
+
 // Represents a 6502 CPU opcodes.
-struct OpCode {
+pub(crate) struct OpCode {
     pub code: u8,
     pub name: &'static str,
     pub bytes: u8,
     pub cycles: u8,
     pub addressing_mode: AddressingMode,
+    pub official: bool,
 }
 
 impl OpCode {
@@ -235,11 +412,25 @@ impl OpCode {
         addressing_mode: AddressingMode,
     ) -> Self {
         OpCode {
-            code: code,
-            name: name,
-            bytes: bytes,
-            cycles: cycles,
-            addressing_mode: addressing_mode,
+            code,
+            name,
+            bytes,
+            cycles,
+            addressing_mode,
+            official: true,
+        }
+    }
+
+    fn unofficial(
+        code: u8,
+        name: &'static str,
+        bytes: u8,
+        cycles: u8,
+        addressing_mode: AddressingMode,
+    ) -> Self {
+        OpCode {
+            official: false,
+            ..OpCode::new(code, name, bytes, cycles, addressing_mode)
         }
     }
 }
@@ -318,7 +509,7 @@ lazy_static! {
 
         // BVS
         // Cycles + 1 if branch succeeds, +2 if to a new page.
-        OpCode::new(OPCODE_BVS, "BPL", 2, 2, AddressingMode::Relative),
+        OpCode::new(OPCODE_BVS, "BVS", 2, 2, AddressingMode::Relative),
 
         // CLC
         OpCode::new(OPCODE_CLC, "CLC", 1, 2, AddressingMode::NoneAddressing),
@@ -332,6 +523,41 @@ lazy_static! {
         // CLV
         OpCode::new(OPCODE_CLV, "CLV", 1, 2, AddressingMode::NoneAddressing),
 
+        // CMP
+        OpCode::new(OPCODE_CMP_IMMEDIATE, "CMP", 2, 2, AddressingMode::Immediate),
+        OpCode::new(OPCODE_CMP_ZEROPAGE, "CMP", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new(OPCODE_CMP_ZEROPAGEX, "CMP", 2, 4, AddressingMode::ZeroPageX),
+        OpCode::new(OPCODE_CMP_ABSOLUTE, "CMP", 3, 4, AddressingMode::Absolute),
+        // Cycles +1 if page crossed.
+        OpCode::new(OPCODE_CMP_ABSOLUTEX, "CMP", 3, 4, AddressingMode::AbsoluteX),
+        // Cycles +1 if page crossed.
+        OpCode::new(OPCODE_CMP_ABSOLUTEY, "CMP", 3, 4, AddressingMode::AbsoluteY),
+        OpCode::new(OPCODE_CMP_INDIRECTX, "CMP", 2, 6, AddressingMode::IndirectX),
+        // Cycles +1 if page crossed.
+        OpCode::new(OPCODE_CMP_INDIRECTY, "CMP", 2, 5, AddressingMode::IndirectY),
+
+        // CPX
+        OpCode::new(OPCODE_CPX_IMMEDIATE, "CPX", 2, 2, AddressingMode::Immediate),
+        OpCode::new(OPCODE_CPX_ZEROPAGE, "CPX", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new(OPCODE_CPX_ABSOLUTE, "CPX", 3, 4, AddressingMode::Absolute),
+
+        // CPY
+        OpCode::new(OPCODE_CPY_IMMEDIATE, "CPY", 2, 2, AddressingMode::Immediate),
+        OpCode::new(OPCODE_CPY_ZEROPAGE, "CPY", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new(OPCODE_CPY_ABSOLUTE, "CPY", 3, 4, AddressingMode::Absolute),
+
+        // DEC
+        OpCode::new(OPCODE_DEC_ZEROPAGE, "DEC", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(OPCODE_DEC_ZEROPAGEX, "DEC", 2, 6, AddressingMode::ZeroPageX),
+        OpCode::new(OPCODE_DEC_ABSOLUTE, "DEC", 3, 6, AddressingMode::Absolute),
+        OpCode::new(OPCODE_DEC_ABSOLUTEX, "DEC", 3, 7, AddressingMode::AbsoluteX),
+
+        // DEX
+        OpCode::new(OPCODE_DEX, "DEX", 1, 2, AddressingMode::NoneAddressing),
+
+        // DEY
+        OpCode::new(OPCODE_DEY, "DEY", 1, 2, AddressingMode::NoneAddressing),
+
         // EOR
         OpCode::new(OPCODE_EOR_IMMEDIATE, "EOR", 2, 2, AddressingMode::Immediate),
         OpCode::new(OPCODE_EOR_ZEROPAGE, "EOR", 2, 3, AddressingMode::ZeroPage),
@@ -345,14 +571,23 @@ lazy_static! {
         // Cycles +1 if page crossed.
         OpCode::new(OPCODE_EOR_INDIRECTY, "EOR", 2, 5, AddressingMode::IndirectY),
 
+        // INC
+        OpCode::new(OPCODE_INC_ZEROPAGE, "INC", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(OPCODE_INC_ZEROPAGEX, "INC", 2, 6, AddressingMode::ZeroPageX),
+        OpCode::new(OPCODE_INC_ABSOLUTE, "INC", 3, 6, AddressingMode::Absolute),
+        OpCode::new(OPCODE_INC_ABSOLUTEX, "INC", 3, 7, AddressingMode::AbsoluteX),
+
         // JMP
         OpCode::new(OPCODE_JMP_ABSOLUTE, "JMP", 3, 3, AddressingMode::Absolute),
         OpCode::new(OPCODE_JMP_INDIRECT, "JMP", 3, 5, AddressingMode::Indirect),
 
+        // JSR
+        OpCode::new(OPCODE_JSR, "JSR", 3, 6, AddressingMode::Absolute),
+
         // LDA
         OpCode::new(OPCODE_LDA_IMMEDIATE, "LDA", 2, 2, AddressingMode::Immediate),
-        OpCode::new(OPCODE_LDA_ZEROPAGE, "LDA", 2, 2, AddressingMode::ZeroPage),
-        OpCode::new(OPCODE_LDA_ZEROPAGEX, "LDA", 2, 2, AddressingMode::ZeroPageX),
+        OpCode::new(OPCODE_LDA_ZEROPAGE, "LDA", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new(OPCODE_LDA_ZEROPAGEX, "LDA", 2, 4, AddressingMode::ZeroPageX),
         OpCode::new(OPCODE_LDA_ABSOLUTE, "LDA", 3, 4, AddressingMode::Absolute),
         // Cycles +1 if page crossed.
         OpCode::new(OPCODE_LDA_ABSOLUTEX, "LDA", 3, 4, AddressingMode::AbsoluteX),
@@ -385,6 +620,9 @@ lazy_static! {
         OpCode::new(OPCODE_LSR_ABSOLUTE, "LSR", 3, 6, AddressingMode::Absolute),
         OpCode::new(OPCODE_LSR_ABSOLUTEX, "LSR", 3, 7, AddressingMode::AbsoluteX),
 
+        // NOP
+        OpCode::new(OPCODE_NOP, "NOP", 1, 2, AddressingMode::NoneAddressing),
+
         // ORA
         OpCode::new(OPCODE_ORA_IMMEDIATE, "ORA", 2, 2, AddressingMode::Immediate),
         OpCode::new(OPCODE_ORA_ZEROPAGE, "ORA", 2, 3, AddressingMode::ZeroPage),
@@ -398,6 +636,18 @@ lazy_static! {
         // Cycles +1 if page crossed.
         OpCode::new(OPCODE_ORA_INDIRECTY, "ORA", 2, 5, AddressingMode::IndirectY),
 
+        // PHA
+        OpCode::new(OPCODE_PHA, "PHA", 1, 3, AddressingMode::NoneAddressing),
+
+        // PHP
+        OpCode::new(OPCODE_PHP, "PHP", 1, 3, AddressingMode::NoneAddressing),
+
+        // PLA
+        OpCode::new(OPCODE_PLA, "PLA", 1, 4, AddressingMode::NoneAddressing),
+
+        // PLP
+        OpCode::new(OPCODE_PLP, "PLP", 1, 4, AddressingMode::NoneAddressing),
+
         // ROL
         OpCode::new(OPCODE_ROL_ACCUMULATOR, "ROL", 1, 2, AddressingMode::Accumulator),
         OpCode::new(OPCODE_ROL_ZEROPAGE, "ROL", 2, 5, AddressingMode::ZeroPage),
@@ -412,6 +662,12 @@ lazy_static! {
         OpCode::new(OPCODE_ROR_ABSOLUTE, "ROR", 3, 6, AddressingMode::Absolute),
         OpCode::new(OPCODE_ROR_ABSOLUTEX, "ROR", 3, 7, AddressingMode::AbsoluteX),
 
+        // RTI
+        OpCode::new(OPCODE_RTI, "RTI", 1, 6, AddressingMode::NoneAddressing),
+
+        // RTS
+        OpCode::new(OPCODE_RTS, "RTS", 1, 6, AddressingMode::NoneAddressing),
+
         // STA
         OpCode::new(OPCODE_STA_ZEROPAGE, "STA", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(OPCODE_STA_ZEROPAGEX, "STA", 2, 4, AddressingMode::ZeroPageX),
@@ -434,15 +690,24 @@ lazy_static! {
         // INX
         OpCode::new(OPCODE_INX, "INX", 1, 2, AddressingMode::NoneAddressing),
 
+        // INY
+        OpCode::new(OPCODE_INY, "INY", 1, 2, AddressingMode::NoneAddressing),
+
         // TAX
         OpCode::new(OPCODE_TAX, "TAX", 1, 2, AddressingMode::NoneAddressing),
 
         // TAY
         OpCode::new(OPCODE_TAY, "TAY", 1, 2, AddressingMode::NoneAddressing),
 
+        // TSX
+        OpCode::new(OPCODE_TSX, "TSX", 1, 2, AddressingMode::NoneAddressing),
+
         // TXA
         OpCode::new(OPCODE_TXA, "TXA", 1, 2, AddressingMode::NoneAddressing),
 
+        // TXS
+        OpCode::new(OPCODE_TXS, "TXS", 1, 2, AddressingMode::NoneAddressing),
+
         // TYA
         OpCode::new(OPCODE_TYA, "TYA", 1, 2, AddressingMode::NoneAddressing),
 
@@ -469,17 +734,124 @@ lazy_static! {
         OpCode::new(OPCODE_SEI, "SEI", 1, 2, AddressingMode::NoneAddressing),
     ];
 
+    // The unofficial opcodes the CPU implements. The others, e.g. KIL, are illegal.
+    static ref UNOFFICIAL_OPCODES : Vec<OpCode> = {
+        let mut opcodes = vec![
+            // DCP
+            OpCode::unofficial(OPCODE_DCP_ZEROPAGE, "DCP", 2, 5, AddressingMode::ZeroPage),
+            OpCode::unofficial(OPCODE_DCP_ZEROPAGEX, "DCP", 2, 6, AddressingMode::ZeroPageX),
+            OpCode::unofficial(OPCODE_DCP_ABSOLUTE, "DCP", 3, 6, AddressingMode::Absolute),
+            OpCode::unofficial(OPCODE_DCP_ABSOLUTEX, "DCP", 3, 7, AddressingMode::AbsoluteX),
+            OpCode::unofficial(OPCODE_DCP_ABSOLUTEY, "DCP", 3, 7, AddressingMode::AbsoluteY),
+            OpCode::unofficial(OPCODE_DCP_INDIRECTX, "DCP", 2, 8, AddressingMode::IndirectX),
+            OpCode::unofficial(OPCODE_DCP_INDIRECTY, "DCP", 2, 8, AddressingMode::IndirectY),
+
+            // ISB
+            OpCode::unofficial(OPCODE_ISB_ZEROPAGE, "ISB", 2, 5, AddressingMode::ZeroPage),
+            OpCode::unofficial(OPCODE_ISB_ZEROPAGEX, "ISB", 2, 6, AddressingMode::ZeroPageX),
+            OpCode::unofficial(OPCODE_ISB_ABSOLUTE, "ISB", 3, 6, AddressingMode::Absolute),
+            OpCode::unofficial(OPCODE_ISB_ABSOLUTEX, "ISB", 3, 7, AddressingMode::AbsoluteX),
+            OpCode::unofficial(OPCODE_ISB_ABSOLUTEY, "ISB", 3, 7, AddressingMode::AbsoluteY),
+            OpCode::unofficial(OPCODE_ISB_INDIRECTX, "ISB", 2, 8, AddressingMode::IndirectX),
+            OpCode::unofficial(OPCODE_ISB_INDIRECTY, "ISB", 2, 8, AddressingMode::IndirectY),
+
+            // LAX
+            OpCode::unofficial(OPCODE_LAX_ZEROPAGE, "LAX", 2, 3, AddressingMode::ZeroPage),
+            OpCode::unofficial(OPCODE_LAX_ZEROPAGEY, "LAX", 2, 4, AddressingMode::ZeroPageY),
+            OpCode::unofficial(OPCODE_LAX_ABSOLUTE, "LAX", 3, 4, AddressingMode::Absolute),
+            // Cycles +1 if page crossed.
+            OpCode::unofficial(OPCODE_LAX_ABSOLUTEY, "LAX", 3, 4, AddressingMode::AbsoluteY),
+            OpCode::unofficial(OPCODE_LAX_INDIRECTX, "LAX", 2, 6, AddressingMode::IndirectX),
+            // Cycles +1 if page crossed.
+            OpCode::unofficial(OPCODE_LAX_INDIRECTY, "LAX", 2, 5, AddressingMode::IndirectY),
+
+            // RLA
+            OpCode::unofficial(OPCODE_RLA_ZEROPAGE, "RLA", 2, 5, AddressingMode::ZeroPage),
+            OpCode::unofficial(OPCODE_RLA_ZEROPAGEX, "RLA", 2, 6, AddressingMode::ZeroPageX),
+            OpCode::unofficial(OPCODE_RLA_ABSOLUTE, "RLA", 3, 6, AddressingMode::Absolute),
+            OpCode::unofficial(OPCODE_RLA_ABSOLUTEX, "RLA", 3, 7, AddressingMode::AbsoluteX),
+            OpCode::unofficial(OPCODE_RLA_ABSOLUTEY, "RLA", 3, 7, AddressingMode::AbsoluteY),
+            OpCode::unofficial(OPCODE_RLA_INDIRECTX, "RLA", 2, 8, AddressingMode::IndirectX),
+            OpCode::unofficial(OPCODE_RLA_INDIRECTY, "RLA", 2, 8, AddressingMode::IndirectY),
+
+            // RRA
+            OpCode::unofficial(OPCODE_RRA_ZEROPAGE, "RRA", 2, 5, AddressingMode::ZeroPage),
+            OpCode::unofficial(OPCODE_RRA_ZEROPAGEX, "RRA", 2, 6, AddressingMode::ZeroPageX),
+            OpCode::unofficial(OPCODE_RRA_ABSOLUTE, "RRA", 3, 6, AddressingMode::Absolute),
+            OpCode::unofficial(OPCODE_RRA_ABSOLUTEX, "RRA", 3, 7, AddressingMode::AbsoluteX),
+            OpCode::unofficial(OPCODE_RRA_ABSOLUTEY, "RRA", 3, 7, AddressingMode::AbsoluteY),
+            OpCode::unofficial(OPCODE_RRA_INDIRECTX, "RRA", 2, 8, AddressingMode::IndirectX),
+            OpCode::unofficial(OPCODE_RRA_INDIRECTY, "RRA", 2, 8, AddressingMode::IndirectY),
+
+            // SAX
+            OpCode::unofficial(OPCODE_SAX_ZEROPAGE, "SAX", 2, 3, AddressingMode::ZeroPage),
+            OpCode::unofficial(OPCODE_SAX_ZEROPAGEY, "SAX", 2, 4, AddressingMode::ZeroPageY),
+            OpCode::unofficial(OPCODE_SAX_ABSOLUTE, "SAX", 3, 4, AddressingMode::Absolute),
+            OpCode::unofficial(OPCODE_SAX_INDIRECTX, "SAX", 2, 6, AddressingMode::IndirectX),
+
+            // SLO
+            OpCode::unofficial(OPCODE_SLO_ZEROPAGE, "SLO", 2, 5, AddressingMode::ZeroPage),
+            OpCode::unofficial(OPCODE_SLO_ZEROPAGEX, "SLO", 2, 6, AddressingMode::ZeroPageX),
+            OpCode::unofficial(OPCODE_SLO_ABSOLUTE, "SLO", 3, 6, AddressingMode::Absolute),
+            OpCode::unofficial(OPCODE_SLO_ABSOLUTEX, "SLO", 3, 7, AddressingMode::AbsoluteX),
+            OpCode::unofficial(OPCODE_SLO_ABSOLUTEY, "SLO", 3, 7, AddressingMode::AbsoluteY),
+            OpCode::unofficial(OPCODE_SLO_INDIRECTX, "SLO", 2, 8, AddressingMode::IndirectX),
+            OpCode::unofficial(OPCODE_SLO_INDIRECTY, "SLO", 2, 8, AddressingMode::IndirectY),
+
+            // SRE
+            OpCode::unofficial(OPCODE_SRE_ZEROPAGE, "SRE", 2, 5, AddressingMode::ZeroPage),
+            OpCode::unofficial(OPCODE_SRE_ZEROPAGEX, "SRE", 2, 6, AddressingMode::ZeroPageX),
+            OpCode::unofficial(OPCODE_SRE_ABSOLUTE, "SRE", 3, 6, AddressingMode::Absolute),
+            OpCode::unofficial(OPCODE_SRE_ABSOLUTEX, "SRE", 3, 7, AddressingMode::AbsoluteX),
+            OpCode::unofficial(OPCODE_SRE_ABSOLUTEY, "SRE", 3, 7, AddressingMode::AbsoluteY),
+            OpCode::unofficial(OPCODE_SRE_INDIRECTX, "SRE", 2, 8, AddressingMode::IndirectX),
+            OpCode::unofficial(OPCODE_SRE_INDIRECTY, "SRE", 2, 8, AddressingMode::IndirectY),
+
+            // SBC
+            OpCode::unofficial(
+                OPCODE_SBC_IMMEDIATE_UNOFFICIAL, "SBC", 2, 2, AddressingMode::Immediate
+            ),
+        ];
+
+        // NOP
+        let nops = [
+            (&OPCODES_NOP_IMPLIED[..], 1, 2, AddressingMode::NoneAddressing),
+            (&OPCODES_NOP_IMMEDIATE, 2, 2, AddressingMode::Immediate),
+            (&OPCODES_NOP_ZEROPAGE, 2, 3, AddressingMode::ZeroPage),
+            (&OPCODES_NOP_ZEROPAGEX, 2, 4, AddressingMode::ZeroPageX),
+            (&OPCODES_NOP_ABSOLUTE, 3, 4, AddressingMode::Absolute),
+            // Cycles +1 if page crossed.
+            (&OPCODES_NOP_ABSOLUTEX, 3, 4, AddressingMode::AbsoluteX),
+        ];
+        for (codes, bytes, cycles, mode) in nops {
+            for code in codes {
+                opcodes.push(OpCode::unofficial(*code, "NOP", bytes, cycles, mode));
+            }
+        }
+        opcodes
+    };
+
     static ref OPCODE_MAP: HashMap<u8, &'static OpCode> = {
         let mut map: HashMap<u8, &'static OpCode> = HashMap::new();
-        for opcode in &*OPCODES {
+        for opcode in OPCODES.iter().chain(UNOFFICIAL_OPCODES.iter()) {
             map.insert(opcode.code, opcode);
         }
         map
     };
 }
 
+// All the official opcodes.
+pub(crate) fn opcodes() -> &'static [OpCode] {
+    &OPCODES
+}
+
+// Returns the metadata of |code|. None for illegal opcodes.
+pub(crate) fn opcode(code: u8) -> Option<&'static OpCode> {
+    OPCODE_MAP.get(&code).copied()
+}
+
 // Represents the memory of 6502.
-struct Mem {
+pub struct Mem {
     // The maximum addressable memory is 64KB.
     data: [u8; MEM_ADDR_SPACE_SIZE],
 }
@@ -490,6 +862,14 @@ impl Mem {
             data: [0; MEM_ADDR_SPACE_SIZE],
         }
     }
+
+    // Creates a memory holding |pattern|, like RAM at power on.
+    pub fn with_pattern(pattern: RamPattern) -> Self {
+        let mut mem = Mem::new();
+        pattern.fill(&mut [&mut mem.data]);
+        mem
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         self.data[addr as usize]
     }
@@ -539,15 +919,45 @@ impl Mem {
             )));
         }
 
-        for i in 0..val.len() {
-            self.write(start_addr + (i as u16), val[i]);
+        for (i, byte) in val.iter().enumerate() {
+            self.write(start_addr + (i as u16), *byte);
         }
         Ok(())
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum AddressingMode {
+impl Default for Mem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for Mem {
+    fn read(&mut self, addr: u16) -> u8 {
+        Mem::read(self, addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        Mem::read(self, addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        Mem::write(self, addr, val)
+    }
+}
+
+impl Savestate for Mem {
+    fn save_state(&self, w: &mut StateWriter) {
+        savestate::write_buffer(w, &self.data);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        savestate::read_buffer(r, &mut self.data)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressingMode {
     Immediate,
     ZeroPage,
     ZeroPageX,
@@ -619,6 +1029,32 @@ lazy_static! {
         map.insert(OPCODE_CLI, CPU::cli);
         map.insert(OPCODE_CLV, CPU::clv);
 
+        map.insert(OPCODE_CMP_IMMEDIATE, CPU::cmp);
+        map.insert(OPCODE_CMP_ZEROPAGE, CPU::cmp);
+        map.insert(OPCODE_CMP_ZEROPAGEX, CPU::cmp);
+        map.insert(OPCODE_CMP_ABSOLUTE, CPU::cmp);
+        map.insert(OPCODE_CMP_ABSOLUTEX, CPU::cmp);
+        map.insert(OPCODE_CMP_ABSOLUTEY, CPU::cmp);
+        map.insert(OPCODE_CMP_INDIRECTX, CPU::cmp);
+        map.insert(OPCODE_CMP_INDIRECTY, CPU::cmp);
+
+        map.insert(OPCODE_CPX_IMMEDIATE, CPU::cpx);
+        map.insert(OPCODE_CPX_ZEROPAGE, CPU::cpx);
+        map.insert(OPCODE_CPX_ABSOLUTE, CPU::cpx);
+
+        map.insert(OPCODE_CPY_IMMEDIATE, CPU::cpy);
+        map.insert(OPCODE_CPY_ZEROPAGE, CPU::cpy);
+        map.insert(OPCODE_CPY_ABSOLUTE, CPU::cpy);
+
+        map.insert(OPCODE_DEC_ZEROPAGE, CPU::dec);
+        map.insert(OPCODE_DEC_ZEROPAGEX, CPU::dec);
+        map.insert(OPCODE_DEC_ABSOLUTE, CPU::dec);
+        map.insert(OPCODE_DEC_ABSOLUTEX, CPU::dec);
+
+        map.insert(OPCODE_DEX, CPU::dex);
+
+        map.insert(OPCODE_DEY, CPU::dey);
+
         map.insert(OPCODE_EOR_IMMEDIATE, CPU::eor);
         map.insert(OPCODE_EOR_ZEROPAGE, CPU::eor);
         map.insert(OPCODE_EOR_ZEROPAGEX, CPU::eor);
@@ -628,6 +1064,11 @@ lazy_static! {
         map.insert(OPCODE_EOR_INDIRECTX, CPU::eor);
         map.insert(OPCODE_EOR_INDIRECTY, CPU::eor);
 
+        map.insert(OPCODE_INC_ZEROPAGE, CPU::inc);
+        map.insert(OPCODE_INC_ZEROPAGEX, CPU::inc);
+        map.insert(OPCODE_INC_ABSOLUTE, CPU::inc);
+        map.insert(OPCODE_INC_ABSOLUTEX, CPU::inc);
+
         map.insert(OPCODE_LDA_IMMEDIATE, CPU::lda);
         map.insert(OPCODE_LDA_ZEROPAGE, CPU::lda);
         map.insert(OPCODE_LDA_ZEROPAGEX, CPU::lda);
@@ -655,6 +1096,8 @@ lazy_static! {
         map.insert(OPCODE_LSR_ABSOLUTE, CPU::lsr);
         map.insert(OPCODE_LSR_ABSOLUTEX, CPU::lsr);
 
+        map.insert(OPCODE_NOP, CPU::nop);
+
         map.insert(OPCODE_ORA_IMMEDIATE, CPU::ora);
         map.insert(OPCODE_ORA_ZEROPAGE, CPU::ora);
         map.insert(OPCODE_ORA_ZEROPAGEX, CPU::ora);
@@ -664,6 +1107,14 @@ lazy_static! {
         map.insert(OPCODE_ORA_INDIRECTX, CPU::ora);
         map.insert(OPCODE_ORA_INDIRECTY, CPU::ora);
 
+        map.insert(OPCODE_PHA, CPU::pha);
+
+        map.insert(OPCODE_PHP, CPU::php);
+
+        map.insert(OPCODE_PLA, CPU::pla);
+
+        map.insert(OPCODE_PLP, CPU::plp);
+
         map.insert(OPCODE_ROL_ACCUMULATOR, CPU::rol);
         map.insert(OPCODE_ROL_ZEROPAGE, CPU::rol);
         map.insert(OPCODE_ROL_ZEROPAGEX, CPU::rol);
@@ -676,6 +1127,10 @@ lazy_static! {
         map.insert(OPCODE_ROR_ABSOLUTE, CPU::ror);
         map.insert(OPCODE_ROR_ABSOLUTEX, CPU::ror);
 
+        map.insert(OPCODE_RTI, CPU::rti);
+
+        map.insert(OPCODE_RTS, CPU::rts);
+
         map.insert(OPCODE_STA_ZEROPAGE, CPU::sta);
         map.insert(OPCODE_STA_ZEROPAGEX, CPU::sta);
         map.insert(OPCODE_STA_ABSOLUTE, CPU::sta);
@@ -695,21 +1150,28 @@ lazy_static! {
         map.insert(OPCODE_JMP_ABSOLUTE, CPU::jmp);
         map.insert(OPCODE_JMP_INDIRECT, CPU::jmp);
 
+        map.insert(OPCODE_JSR, CPU::jsr);
+
         map.insert(OPCODE_INX, CPU::inx);
 
+        map.insert(OPCODE_INY, CPU::iny);
+
         map.insert(OPCODE_TAX, CPU::tax);
 
         map.insert(OPCODE_TAY, CPU::tay);
 
+        map.insert(OPCODE_TSX, CPU::tsx);
+
         map.insert(OPCODE_TXA, CPU::txa);
 
+        map.insert(OPCODE_TXS, CPU::txs);
+
         map.insert(OPCODE_TYA, CPU::tya);
 
         map.insert(OPCODE_SBC_IMMEDIATE, CPU::sbc);
         map.insert(OPCODE_SBC_ZEROPAGE, CPU::sbc);
         map.insert(OPCODE_SBC_ZEROPAGEX, CPU::sbc);
         map.insert(OPCODE_SBC_ABSOLUTE, CPU::sbc);
-        map.insert(OPCODE_SBC_ABSOLUTE, CPU::sbc);
         map.insert(OPCODE_SBC_ABSOLUTEX, CPU::sbc);
         map.insert(OPCODE_SBC_ABSOLUTEY, CPU::sbc);
         map.insert(OPCODE_SBC_INDIRECTX, CPU::sbc);
@@ -721,6 +1183,82 @@ lazy_static! {
 
         map.insert(OPCODE_SEI, CPU::sei);
 
+        map.insert(OPCODE_DCP_ZEROPAGE, CPU::dcp);
+        map.insert(OPCODE_DCP_ZEROPAGEX, CPU::dcp);
+        map.insert(OPCODE_DCP_ABSOLUTE, CPU::dcp);
+        map.insert(OPCODE_DCP_ABSOLUTEX, CPU::dcp);
+        map.insert(OPCODE_DCP_ABSOLUTEY, CPU::dcp);
+        map.insert(OPCODE_DCP_INDIRECTX, CPU::dcp);
+        map.insert(OPCODE_DCP_INDIRECTY, CPU::dcp);
+
+        map.insert(OPCODE_ISB_ZEROPAGE, CPU::isb);
+        map.insert(OPCODE_ISB_ZEROPAGEX, CPU::isb);
+        map.insert(OPCODE_ISB_ABSOLUTE, CPU::isb);
+        map.insert(OPCODE_ISB_ABSOLUTEX, CPU::isb);
+        map.insert(OPCODE_ISB_ABSOLUTEY, CPU::isb);
+        map.insert(OPCODE_ISB_INDIRECTX, CPU::isb);
+        map.insert(OPCODE_ISB_INDIRECTY, CPU::isb);
+
+        map.insert(OPCODE_LAX_ZEROPAGE, CPU::lax);
+        map.insert(OPCODE_LAX_ZEROPAGEY, CPU::lax);
+        map.insert(OPCODE_LAX_ABSOLUTE, CPU::lax);
+        map.insert(OPCODE_LAX_ABSOLUTEY, CPU::lax);
+        map.insert(OPCODE_LAX_INDIRECTX, CPU::lax);
+        map.insert(OPCODE_LAX_INDIRECTY, CPU::lax);
+
+        map.insert(OPCODE_RLA_ZEROPAGE, CPU::rla);
+        map.insert(OPCODE_RLA_ZEROPAGEX, CPU::rla);
+        map.insert(OPCODE_RLA_ABSOLUTE, CPU::rla);
+        map.insert(OPCODE_RLA_ABSOLUTEX, CPU::rla);
+        map.insert(OPCODE_RLA_ABSOLUTEY, CPU::rla);
+        map.insert(OPCODE_RLA_INDIRECTX, CPU::rla);
+        map.insert(OPCODE_RLA_INDIRECTY, CPU::rla);
+
+        map.insert(OPCODE_RRA_ZEROPAGE, CPU::rra);
+        map.insert(OPCODE_RRA_ZEROPAGEX, CPU::rra);
+        map.insert(OPCODE_RRA_ABSOLUTE, CPU::rra);
+        map.insert(OPCODE_RRA_ABSOLUTEX, CPU::rra);
+        map.insert(OPCODE_RRA_ABSOLUTEY, CPU::rra);
+        map.insert(OPCODE_RRA_INDIRECTX, CPU::rra);
+        map.insert(OPCODE_RRA_INDIRECTY, CPU::rra);
+
+        map.insert(OPCODE_SAX_ZEROPAGE, CPU::sax);
+        map.insert(OPCODE_SAX_ZEROPAGEY, CPU::sax);
+        map.insert(OPCODE_SAX_ABSOLUTE, CPU::sax);
+        map.insert(OPCODE_SAX_INDIRECTX, CPU::sax);
+
+        map.insert(OPCODE_SLO_ZEROPAGE, CPU::slo);
+        map.insert(OPCODE_SLO_ZEROPAGEX, CPU::slo);
+        map.insert(OPCODE_SLO_ABSOLUTE, CPU::slo);
+        map.insert(OPCODE_SLO_ABSOLUTEX, CPU::slo);
+        map.insert(OPCODE_SLO_ABSOLUTEY, CPU::slo);
+        map.insert(OPCODE_SLO_INDIRECTX, CPU::slo);
+        map.insert(OPCODE_SLO_INDIRECTY, CPU::slo);
+
+        map.insert(OPCODE_SRE_ZEROPAGE, CPU::sre);
+        map.insert(OPCODE_SRE_ZEROPAGEX, CPU::sre);
+        map.insert(OPCODE_SRE_ABSOLUTE, CPU::sre);
+        map.insert(OPCODE_SRE_ABSOLUTEX, CPU::sre);
+        map.insert(OPCODE_SRE_ABSOLUTEY, CPU::sre);
+        map.insert(OPCODE_SRE_INDIRECTX, CPU::sre);
+        map.insert(OPCODE_SRE_INDIRECTY, CPU::sre);
+
+        map.insert(OPCODE_SBC_IMMEDIATE_UNOFFICIAL, CPU::sbc);
+
+        for code in OPCODES_NOP_IMPLIED {
+            map.insert(code, CPU::nop);
+        }
+        let nops_with_operand = [
+            &OPCODES_NOP_IMMEDIATE[..],
+            &OPCODES_NOP_ZEROPAGE,
+            &OPCODES_NOP_ZEROPAGEX,
+            &OPCODES_NOP_ABSOLUTE,
+            &OPCODES_NOP_ABSOLUTEX,
+        ];
+        for code in nops_with_operand.concat() {
+            map.insert(code, CPU::nop_read);
+        }
+
         map
     };
 }
@@ -748,26 +1286,68 @@ pub struct CPU {
     pub reg_y: u8,          // register Y.
     pub reg_status: Status, // program status register.
     pub pc: u16,            // program counter.
-    mem: Mem,               // Memory.
+    pub sp: u8,             // stack pointer.
+    pub cycles: u64,        // number of CPU cycles elapsed.
+    bus: Box<dyn Bus>,      // Everything behind the address bus.
+    page_crossed: bool,     // Whether the last operand address computation crossed a page.
+    jumped: bool,           // Whether the current instruction changed the program counter.
+    // Where to log each instruction before it executes.
+    tracer: Option<Box<dyn Write>>,
+    profiler: Option<Profiler>,
+    // Routines the program is in, from JSR, BRK and interrupts.
+    call_stack: CallStack,
+    // Whether ADC and SBC honor the D flag. The 2A03 of the NES has no decimal mode.
+    decimal_mode: bool,
+    breakpoints: Breakpoints,
+    // Labels of the program, for traces and debuggers.
+    symbols: Symbols,
+    // The first watchpoint that fired during the current instruction.
+    watchpoint_hit: Option<StopReason>,
 }
 
 impl CPU {
     pub fn new() -> Self {
+        CPU::with_bus(Box::new(Mem::new()))
+    }
+
+    // Creates a CPU that is connected to |bus| instead of the flat 64KB memory.
+    pub fn with_bus(bus: Box<dyn Bus>) -> Self {
         CPU {
             reg_a: 0,
             reg_x: 0,
             reg_y: 0,
             reg_status: Status::empty(),
             pc: 0,
-            mem: Mem::new(),
+            sp: STACK_POINTER_INIT,
+            cycles: 0,
+            bus,
+            page_crossed: false,
+            jumped: false,
+            tracer: None,
+            profiler: None,
+            call_stack: CallStack::new(),
+            decimal_mode: false,
+            breakpoints: Breakpoints::new(),
+            symbols: Symbols::new(),
+            watchpoint_hit: None,
         }
     }
 
+    // Returns the bus if it is a |T|.
+    pub fn bus<T: Bus>(&self) -> Option<&T> {
+        (&*self.bus as &dyn Any).downcast_ref::<T>()
+    }
+
+    // Returns the bus if it is a |T|.
+    pub fn bus_mut<T: Bus>(&mut self) -> Option<&mut T> {
+        (&mut *self.bus as &mut dyn Any).downcast_mut::<T>()
+    }
+
     // Loads the program into PRG ROM.
     pub fn load(&mut self, program: &[u8]) -> Result<(), SimpleError> {
-        self.mem.write_range(MEM_PRG_ROM_ADDR_START, program)?;
-        self.mem
-            .write16(INIT_PROGRAM_COUNTER_ADDR, MEM_PRG_ROM_ADDR_START)
+        self.write_range(MEM_PRG_ROM_ADDR_START, program)?;
+        self.write_mem16(INIT_PROGRAM_COUNTER_ADDR, MEM_PRG_ROM_ADDR_START);
+        Ok(())
     }
 
     // NES platform has a special mechanism to mark where the CPU should start the execution. Upon inserting a new cartridge, the CPU receives a special signal called "Reset interrupt" that instructs CPU to:
@@ -778,27 +1358,194 @@ impl CPU {
         self.reg_x = 0;
         self.reg_y = 0;
         self.reg_status = Status::empty();
-        self.pc = self.mem.read16(INIT_PROGRAM_COUNTER_ADDR).unwrap();
+        self.sp = STACK_POINTER_INIT;
+        self.pc = self.read_mem16(INIT_PROGRAM_COUNTER_ADDR);
+        // The reset sequence takes 7 cycles.
+        self.cycles = 7;
+        self.call_stack.clear();
+    }
+
+    // Does what the reset button does. Unlike at power on, A, X and Y are kept; the reset sequence
+    // moves the stack pointer down by 3 without writing, and disables interrupts.
+    pub fn soft_reset(&mut self) {
+        self.sp = self.sp.wrapping_sub(3);
+        self.reg_status.insert(Status::I);
+        self.pc = self.read_mem16(INIT_PROGRAM_COUNTER_ADDR);
+        self.cycles += 7;
+        self.call_stack.clear();
     }
 
-    // Runs the program started at PRG ROM.
-    pub fn run(&mut self) {
+    // Runs the program started at PRG ROM until it reaches BRK or a breakpoint fires.
+    pub fn run(&mut self) -> StopReason {
         self.pc = MEM_PRG_ROM_ADDR_START;
+        self.run_until_stop(true, false)
+    }
+
+    // Runs from the program counter until a breakpoint fires. A breakpoint at the program
+    // counter does not fire again, so that execution can go on after stopping there.
+    pub fn resume(&mut self) -> StopReason {
+        self.run_until_stop(false, true)
+    }
+
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn symbols_mut(&mut self) -> &mut Symbols {
+        &mut self.symbols
+    }
+
+    // Returns the label at |addr|, in the PRG ROM bank mapped there now.
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        if self.symbols.is_empty() {
+            return None;
+        }
+        let bank = self
+            .bus
+            .prg_rom_offset(addr)
+            .map(|offset| offset / PRG_BANK_SIZE);
+        self.symbols.label(addr, bank)
+    }
+
+    // Returns the address of the label |name|. A label in PRG ROM is looked for where its bank
+    // is mapped now, else at the address it was given.
+    pub fn label_address(&self, name: &str) -> Option<u16> {
+        let symbol = self.symbols.get(name)?;
+        let bank = match symbol.bank {
+            Some(bank) => bank,
+            None => return Some(symbol.addr),
+        };
+        let offset = symbol.addr % PRG_BANK_SIZE as u16;
+        let target = Some(bank * PRG_BANK_SIZE + offset as usize);
+        let windows = (0x8000..=0xffff)
+            .step_by(PRG_BANK_SIZE)
+            .map(|window: u16| window + offset);
+        let mapped = std::iter::once(symbol.addr)
+            .chain(windows)
+            .find(|addr| self.bus.prg_rom_offset(*addr) == target);
+        Some(mapped.unwrap_or(symbol.addr))
+    }
+
+    // Returns why the CPU should stop before executing the instruction at the program counter.
+    // The breakpoints that fire count a hit.
+    pub fn check_breakpoints(&self) -> Option<StopReason> {
+        self.check_illegal_opcode().or_else(|| {
+            self.breakpoints
+                .check_execute(self, self.peek_mem(self.pc))
+                .map(StopReason::Breakpoint)
+        })
+    }
 
-        loop {
-            if !self.step() {
-                break;
+    // Returns why the CPU cannot execute the instruction at the program counter.
+    pub(crate) fn check_illegal_opcode(&self) -> Option<StopReason> {
+        let code = self.peek_mem(self.pc);
+        match OPCODE_MAP.get(&code) {
+            Some(_) => None,
+            None => Some(StopReason::IllegalOpcode(code)),
+        }
+    }
+
+    // Returns the first watchpoint that fired since the last call.
+    pub fn take_watchpoint_hit(&mut self) -> Option<StopReason> {
+        self.watchpoint_hit.take()
+    }
+
+    // Executes instructions until one of the breakpoints fires. |stop_at_brk| makes BRK the end
+    // of the program. |skip_first| skips the breakpoints before the first instruction.
+    fn run_until_stop(&mut self, stop_at_brk: bool, skip_first: bool) -> StopReason {
+        // BRK marks the end of the program, so do not execute it.
+        let at_brk = |cpu: &mut CPU| stop_at_brk && cpu.peek_mem(cpu.pc) == OPCODE_BRK;
+        if at_brk(self) {
+            return StopReason::Brk;
+        }
+        breakpoint::run_until(self, skip_first, at_brk).unwrap_or(StopReason::Brk)
+    }
+
+    // Makes ADC and SBC work on BCD numbers when the D flag is set, like a NMOS 6502 outside of
+    // the NES, e.g. to run test suites written for it.
+    pub fn set_decimal_mode(&mut self, enabled: bool) {
+        self.decimal_mode = enabled;
+    }
+
+    // Logs every following instruction to |tracer| in the format of nestest.log, or stops logging
+    // if |tracer| is None. Returns the previous tracer.
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Write>>) -> Option<Box<dyn Write>> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    // Profiles every following instruction with |profiler|, or stops profiling if None. Returns
+    // the previous profiler.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) -> Option<Profiler> {
+        std::mem::replace(&mut self.profiler, profiler)
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    // Formats the call stack from the program counter, with routines named by their labels.
+    pub fn backtrace(&self) -> String {
+        self.call_stack
+            .backtrace(self.pc, |addr| self.label(addr).map(String::from))
+    }
+
+    // Executes the next instruction, return true to continue. Fails without executing anything
+    // if the opcode is illegal, with the backtrace in the error.
+    pub fn step(&mut self) -> Result<bool, SimpleError> {
+        let opcode = match OPCODE_MAP.get(&self.peek_mem(self.pc)) {
+            Some(opcode) => *opcode,
+            None => return Err(self.illegal_opcode_error(self.peek_mem(self.pc))),
+        };
+        if self.tracer.is_some() {
+            self.trace();
+        }
+        if let Some(mut profiler) = self.profiler.take() {
+            profiler.instruction(self);
+            self.profiler = Some(profiler);
+        }
+        if self.bus.is_logging_code_data() {
+            for (addr, flags) in cdl::instruction_accesses(self) {
+                self.bus.log_code_data(addr, flags);
             }
         }
+        self.fetch_mem(self.pc);
+        Ok(self.dispatch_instruction(opcode))
+    }
+
+    // Describes the illegal opcode |code| at the program counter, with the backtrace if the
+    // program is in a routine.
+    fn illegal_opcode_error(&self, code: u8) -> SimpleError {
+        let message = format!("illegal opcode 0x{:02x} at 0x{:04x}", code, self.pc);
+        if self.call_stack.is_empty() {
+            SimpleError::new(message)
+        } else {
+            SimpleError::new(format!("{}\n{}", message, self.backtrace()))
+        }
     }
 
-    // Executes the next instruction, return true to continue.
-    pub fn step(&mut self) -> bool {
-        let val = self.read_mem(self.pc);
-        match OPCODE_MAP.get(&val) {
-            Some(opcode) => return self.dispatch_instruction(opcode),
-            None => {
-                panic!(todo!("opcode 0x{:02x} not yet implemented", val));
+    // Logs the instruction at the program counter. Tracing stops if the tracer fails, rather than
+    // failing the program it traces.
+    fn trace(&mut self) {
+        let line = trace::trace_line(self, self.ppu_position());
+        if let Some(tracer) = self.tracer.as_mut() {
+            if writeln!(tracer, "{}", line).is_err() {
+                self.tracer = None;
             }
         }
     }
@@ -806,57 +1553,173 @@ impl CPU {
     pub fn interpret(&mut self, program: &[u8]) -> Result<(), SimpleError> {
         self.load(program)?;
         self.reset();
-        self.run();
+        match self.run() {
+            StopReason::IllegalOpcode(code) => Err(self.illegal_opcode_error(code)),
+            _ => Ok(()),
+        }
+    }
 
-        Ok(())
+    // Returns the scanline and dot of the PPU on the bus, if any.
+    pub fn ppu_position(&self) -> Option<(usize, usize)> {
+        self.bus.ppu_position()
+    }
+
+    // Where |addr| is in PRG ROM with the banks selected now, if the bus maps it there.
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.bus.prg_rom_offset(addr)
+    }
+
+    // Reads the memory at |addr| without any side effect.
+    pub fn peek_mem(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
+    }
+
+    // Writes |val| at |addr| on behalf of a debugger. Watchpoints do not fire.
+    pub fn poke_mem(&mut self, addr: u16, val: u8) {
+        self.bus.write(addr, val)
+    }
+
+    // Enters the non-maskable interrupt handler, e.g. when the PPU enters vertical blank.
+    pub fn nmi(&mut self) {
+        self.interrupt(NMI_VECTOR_ADDR);
+    }
+
+    // Enters the interrupt handler if interrupts are enabled. Returns whether it did.
+    pub fn irq(&mut self) -> bool {
+        if self.reg_status.contains(Status::I) {
+            return false;
+        }
+        self.interrupt(IRQ_BRK_VECTOR_ADDR);
+        true
+    }
+
+    // Pushes the program counter and the status with B clear, then jumps through |vector|.
+    fn interrupt(&mut self, vector: u16) {
+        // The 6502 reads the next opcode twice before discarding it.
+        self.fetch_mem(self.pc);
+        self.fetch_mem(self.pc);
+        let call_site = self.pc;
+        self.push16(self.pc);
+        self.push((self.reg_status - Status::B).bits() | STATUS_BIT5);
+        self.reg_status.insert(Status::I);
+        if self.bus.is_logging_code_data() {
+            self.bus.log_code_data(vector, PRG_DATA);
+            self.bus.log_code_data(vector + 1, PRG_DATA);
+        }
+        self.pc = self.read_mem16(vector);
+        self.call_stack.call(Frame {
+            kind: match vector {
+                NMI_VECTOR_ADDR => FrameKind::Nmi,
+                _ => FrameKind::Irq,
+            },
+            entry: self.pc,
+            call_site,
+            sp: self.sp,
+        });
+        if let Some(mut profiler) = self.profiler.take() {
+            profiler.interrupt(self);
+            self.profiler = Some(profiler);
+        }
+        self.cycles += INTERRUPT_CYCLES;
     }
 
     fn dispatch_instruction(&mut self, opcode: &OpCode) -> bool {
-        let curr_pc = self.pc;
+        self.page_crossed = false;
+        self.jumped = false;
+        let (pc, sp) = (self.pc, self.sp);
+
+        // One-byte instructions read the next byte anyway.
+        if matches!(
+            opcode.addressing_mode,
+            AddressingMode::NoneAddressing | AddressingMode::Accumulator
+        ) {
+            self.fetch_mem(self.get_operand_address());
+        }
+
         let handler = INSTRUCTION_HANDLERS.get(&opcode.code).unwrap();
         handler(self, &opcode.addressing_mode);
 
         // Advance program counters if no jump happens.
-        if curr_pc == self.pc {
+        if !self.jumped {
             self.pc = self.pc.wrapping_add(opcode.bytes as u16);
         }
+        self.update_call_stack(opcode.code, pc, sp);
 
-        if opcode.code == OPCODE_BRK {
-            false // stop
-        } else {
-            true // continue
-        }
+        // Handlers account for the extra cycles themselves, e.g. page crossing and taken branches.
+        self.cycles += opcode.cycles as u64;
+
+        opcode.code != OPCODE_BRK
+    }
+
+    // Enters or leaves routines after the instruction |code| at |pc| executed with the stack
+    // pointer at |sp|.
+    fn update_call_stack(&mut self, code: u8, pc: u16, sp: u8) {
+        let kind = match code {
+            OPCODE_JSR => FrameKind::Jsr,
+            OPCODE_BRK => FrameKind::Brk,
+            OPCODE_RTS | OPCODE_RTI => return self.call_stack.ret(sp),
+            _ => return,
+        };
+        self.call_stack.call(Frame {
+            kind,
+            entry: self.pc,
+            call_site: pc,
+            sp: self.sp,
+        });
     }
 
-    fn read_mem_operand(&self, addr: u16, addr_mode: &AddressingMode) -> u16 {
+    fn read_mem_operand(&mut self, addr: u16, addr_mode: &AddressingMode) -> u16 {
         match addr_mode {
             AddressingMode::Immediate => addr,
 
-            AddressingMode::ZeroPage => self.read_mem(addr) as u16,
+            AddressingMode::ZeroPage => self.fetch_mem(addr) as u16,
 
-            AddressingMode::ZeroPageX => self.read_mem(addr).wrapping_add(self.reg_x) as u16,
+            // 6502 reads the base address while adding the index.
+            AddressingMode::ZeroPageX => {
+                let base = self.fetch_mem(addr);
+                self.read_mem(base as u16);
+                base.wrapping_add(self.reg_x) as u16
+            }
 
-            AddressingMode::ZeroPageY => self.read_mem(addr).wrapping_add(self.reg_y) as u16,
+            AddressingMode::ZeroPageY => {
+                let base = self.fetch_mem(addr);
+                self.read_mem(base as u16);
+                base.wrapping_add(self.reg_y) as u16
+            }
 
-            AddressingMode::Absolute => self.read_mem16(addr),
+            AddressingMode::Absolute => self.fetch_mem16(addr),
 
-            AddressingMode::AbsoluteX => self.read_mem16(addr).wrapping_add(self.reg_x as u16),
+            AddressingMode::AbsoluteX => {
+                let base = self.fetch_mem16(addr);
+                self.index_address(base, self.reg_x)
+            }
 
-            AddressingMode::AbsoluteY => self.read_mem16(addr).wrapping_add(self.reg_y as u16),
+            AddressingMode::AbsoluteY => {
+                let base = self.fetch_mem16(addr);
+                self.index_address(base, self.reg_y)
+            }
 
             AddressingMode::Indirect => {
-                let addr_of_addr = self.read_mem16(addr);
-                self.read_mem16(addr_of_addr)
+                // 6502 does not carry into the high byte when the pointer sits at the end of a
+                // page, e.g. JMP ($10ff) reads the high byte from 0x1000 instead of 0x1100.
+                let addr_of_addr = self.fetch_mem16(addr);
+                let lo = self.read_mem(addr_of_addr) as u16;
+                let hi = self
+                    .read_mem((addr_of_addr & 0xff00) | (addr_of_addr.wrapping_add(1) & 0x00ff))
+                    as u16;
+                (hi << 8) | lo
             }
 
             AddressingMode::IndirectX => {
-                let addr = self.read_mem(addr).wrapping_add(self.reg_x) as u16;
-                self.read_mem16(addr)
+                let ptr = self.fetch_mem(addr);
+                self.read_mem(ptr as u16);
+                self.read_zero_page16(ptr.wrapping_add(self.reg_x))
             }
 
             AddressingMode::IndirectY => {
-                let addr = self.read_mem16(addr);
-                self.read_mem16(addr).wrapping_add(self.reg_y as u16)
+                let ptr = self.fetch_mem(addr);
+                let base = self.read_zero_page16(ptr);
+                self.index_address(base, self.reg_y)
             }
 
             AddressingMode::Relative => addr,
@@ -876,24 +1739,161 @@ impl CPU {
         }
     }
 
-    fn read_mem(&self, addr: u16) -> u8 {
-        self.mem.read(addr)
+    // Adds |index| to |base|. When that crosses a page, 6502 reads the address before carrying
+    // into the high byte.
+    fn index_address(&mut self, base: u16, index: u8) -> u16 {
+        let addr = base.wrapping_add(index as u16);
+        self.page_crossed = is_page_crossed(base, addr);
+        if self.page_crossed {
+            self.read_mem((base & 0xff00) | (addr & 0x00ff));
+        }
+        addr
+    }
+
+    // Computes the operand address of a store or read-modify-write instruction. These always take
+    // the cycle to carry into the high byte of indexed addresses, reading the address before it.
+    fn write_operand_address(&mut self, addr_mode: &AddressingMode) -> u16 {
+        let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
+        let indexed = matches!(
+            addr_mode,
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY | AddressingMode::IndirectY
+        );
+        if indexed && !self.page_crossed {
+            self.read_mem(addr);
+        }
+        addr
+    }
+
+    // Reads the operand of a read-modify-write instruction and returns its address and value.
+    // Like 6502, writes the value back unmodified while modifying it.
+    fn read_modify_operand(&mut self, addr_mode: &AddressingMode) -> (u16, u8) {
+        let addr = self.write_operand_address(addr_mode);
+        let val = self.read_mem(addr);
+        self.write_mem(addr, val);
+        (addr, val)
+    }
+
+    // Reads the operand of a read instruction. Indexing across a page costs one more cycle.
+    fn read_operand(&mut self, addr_mode: &AddressingMode) -> u8 {
+        let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
+        if self.page_crossed {
+            self.cycles += 1;
+        }
+        match addr_mode {
+            AddressingMode::Immediate => self.fetch_mem(addr),
+            _ => self.read_mem(addr),
+        }
+    }
+
+    fn read_mem(&mut self, addr: u16) -> u8 {
+        let val = self.bus.read(addr);
+        if self.breakpoints.has_watchpoints() {
+            self.watch(BusAccess {
+                addr,
+                val,
+                kind: AccessKind::Read,
+            });
+        }
+        val
+    }
+
+    // Reads the instruction stream at |addr|: opcodes, operands and the dummy reads of the next
+    // opcode. These are execution accesses, which Breakpoint::Execute covers, so watchpoints do
+    // not see them.
+    fn fetch_mem(&mut self, addr: u16) -> u8 {
+        self.bus.read(addr)
+    }
+
+    fn fetch_mem16(&mut self, addr: u16) -> u16 {
+        let lo = self.fetch_mem(addr) as u16;
+        let hi = self.fetch_mem(addr.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
+    fn read_mem16(&mut self, addr: u16) -> u16 {
+        let lo = self.read_mem(addr) as u16;
+        let hi = self.read_mem(addr.wrapping_add(1)) as u16;
+        (hi << 8) | lo
     }
 
-    fn read_mem16(&self, addr: u16) -> u16 {
-        self.mem.read16(addr).unwrap()
+    // Reads a pointer stored in zero page. The high byte wraps around within zero page.
+    fn read_zero_page16(&mut self, ptr: u8) -> u16 {
+        let lo = self.read_mem(ptr as u16) as u16;
+        let hi = self.read_mem(ptr.wrapping_add(1) as u16) as u16;
+        (hi << 8) | lo
     }
 
     fn write_mem(&mut self, addr: u16, val: u8) {
-        self.mem.write(addr, val)
+        if self.breakpoints.has_watchpoints() {
+            self.watch(BusAccess {
+                addr,
+                val,
+                kind: AccessKind::Write,
+            });
+        }
+        self.bus.write(addr, val)
+    }
+
+    // Records the first watchpoint firing on |access|.
+    fn watch(&mut self, access: BusAccess) {
+        if self.watchpoint_hit.is_some() {
+            return;
+        }
+        if let Some(id) = self.breakpoints.check_access(self, &access) {
+            self.watchpoint_hit = Some(StopReason::Watchpoint { id, access });
+        }
     }
 
     fn write_mem16(&mut self, addr: u16, val: u16) {
-        self.mem.write16(addr, val).unwrap()
+        self.write_mem(addr, val as u8);
+        self.write_mem(addr.wrapping_add(1), (val >> 8) as u8);
+    }
+
+    fn write_range(&mut self, start_addr: u16, val: &[u8]) -> Result<(), SimpleError> {
+        if start_addr as usize + val.len() > MEM_ADDR_SPACE_SIZE {
+            return Err(SimpleError::new(format!(
+                "Range exceeds the memory space: start_addr = 0x{:x}, range_length = {}",
+                start_addr,
+                val.len()
+            )));
+        }
+
+        for (i, byte) in val.iter().enumerate() {
+            self.write_mem(start_addr + (i as u16), *byte);
+        }
+        Ok(())
+    }
+
+    // Reads the top of the stack without pulling it, like 6502 does before pulling and in JSR.
+    fn read_stack(&mut self) {
+        self.read_mem(MEM_STACK_ADDR_START + self.sp as u16);
+    }
+
+    fn push(&mut self, val: u8) {
+        self.write_mem(MEM_STACK_ADDR_START + self.sp as u16, val);
+        self.sp = self.sp.wrapping_sub(1);
+    }
+
+    fn pop(&mut self) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
+        self.read_mem(MEM_STACK_ADDR_START + self.sp as u16)
+    }
+
+    pub(crate) fn push16(&mut self, val: u16) {
+        self.push((val >> 8) as u8);
+        self.push(val as u8);
+    }
+
+    fn pop16(&mut self) -> u16 {
+        let lo = self.pop() as u16;
+        let hi = self.pop() as u16;
+        (hi << 8) | lo
     }
 
-    fn write_range(&mut self, start_addr: u16, val: &[u8]) {
-        self.mem.write_range(start_addr, val).unwrap()
+    // Sets the program counter. The dispatcher will not advance it past the current instruction.
+    fn jump_to(&mut self, addr: u16) {
+        self.pc = addr;
+        self.jumped = true;
     }
 
     // Sets the N bit of status register based on the value of |register|.
@@ -941,6 +1941,53 @@ impl CPU {
         self.set_reg_a(unsigned_result as u8);
     }
 
+    // Adds |val| and the carry to A as BCD numbers. N and V come from the high digits before the
+    // decimal adjustment and Z from the binary sum, as on a NMOS 6502.
+    //
+    // See http://www.6502.org/tutorials/decimal_mode.html#A
+    fn add_decimal_to_reg_a(&mut self, val: u8) {
+        let carrier = self.reg_status.contains(Status::C) as i16;
+        let binary_result = self.reg_a.wrapping_add(val).wrapping_add(carrier as u8);
+
+        let mut lo = (self.reg_a & 0x0f) as i16 + (val & 0x0f) as i16 + carrier;
+        if lo >= 0x0a {
+            lo = ((lo + 0x06) & 0x0f) + 0x10;
+        }
+        let signed_result = ((self.reg_a & 0xf0) as i8) as i16 + ((val & 0xf0) as i8) as i16 + lo;
+        let mut result = (self.reg_a & 0xf0) as i16 + (val & 0xf0) as i16 + lo;
+        if result >= 0xa0 {
+            result += 0x60;
+        }
+
+        self.reg_a = result as u8;
+        self.reg_status.set(Status::C, result >= 0x100);
+        self.reg_status.set(
+            Status::V,
+            signed_result < i8::MIN as i16 || signed_result > i8::MAX as i16,
+        );
+        self.reg_status.set(Status::N, signed_result & 0x80 != 0);
+        self.set_zero_flag(binary_result);
+    }
+
+    // Subtracts |val| and the borrow from A as BCD numbers. Flags are the ones of the binary
+    // subtraction, as on a NMOS 6502.
+    //
+    // See http://www.6502.org/tutorials/decimal_mode.html#A
+    fn subtract_decimal_from_reg_a(&mut self, val: u8) {
+        let borrow = 1 - self.reg_status.contains(Status::C) as i16;
+        let mut lo = (self.reg_a & 0x0f) as i16 - (val & 0x0f) as i16 - borrow;
+        if lo < 0 {
+            lo = ((lo - 0x06) & 0x0f) - 0x10;
+        }
+        let mut result = (self.reg_a & 0xf0) as i16 - (val & 0xf0) as i16 + lo;
+        if result < 0 {
+            result -= 0x60;
+        }
+
+        self.add_to_reg_a(!val);
+        self.reg_a = result as u8;
+    }
+
     fn set_reg_a(&mut self, val: u8) {
         self.reg_a = val;
 
@@ -948,47 +1995,62 @@ impl CPU {
         self.set_zero_flag(self.reg_a);
     }
 
+    // Compares |register| with |val| the way CMP, CPX and CPY do.
+    fn compare(&mut self, register: u8, val: u8) {
+        if register >= val {
+            self.reg_status.insert(Status::C);
+        } else {
+            self.reg_status.remove(Status::C);
+        }
+
+        let result = register.wrapping_sub(val);
+        self.set_negative_flag(result);
+        self.set_zero_flag(result);
+    }
+
     fn get_operand_address(&self) -> u16 {
         self.pc.wrapping_add(1)
     }
 
-    fn calc_new_pc(&self, relative_addr: i8) -> (u16, bool) {
-        let new_pc: u16 = 0;
-        let overflow: bool = false;
-        if relative_addr > 0 {
-            let (new_pc, overflow) = self.pc.overflowing_add(relative_addr as u16);
-        } else {
-            let (new_pc, overflow) = self.pc.overflowing_sub((-relative_addr) as u16);
+    // Branches relative to the next instruction if |condition| holds. A taken branch costs one
+    // more cycle, reading the next opcode, and one more if it lands on another page, reading the
+    // target before carrying into the high byte.
+    fn branch(&mut self, condition: bool) {
+        let addr = self.read_mem_operand(self.get_operand_address(), &AddressingMode::Relative);
+        let relative_addr = self.fetch_mem(addr) as i8;
+        if !condition {
+            return;
         }
-        (new_pc, overflow)
-    }
 
-    fn branch(&mut self) {
-        let relative_addr: i8 =
-            self.read_mem_operand(self.get_operand_address(), &AddressingMode::Relative) as i8;
-        let (new_pc, overflow) = self.calc_new_pc(relative_addr);
-        if overflow {
-            panic!(
-                "branch with a relative address {} that causes program counter {} to overflow",
-                relative_addr, self.pc
-            )
+        let next_pc = self.pc.wrapping_add(2);
+        let new_pc = next_pc.wrapping_add(relative_addr as u16);
+
+        self.fetch_mem(next_pc);
+        self.cycles += 1;
+        if is_page_crossed(next_pc, new_pc) {
+            self.fetch_mem((next_pc & 0xff00) | (new_pc & 0x00ff));
+            self.cycles += 1;
         }
 
-        self.pc = new_pc;
+        self.jump_to(new_pc);
     }
 
     fn adc(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
-
-        let val: u8 = self.read_mem(addr);
+        let val: u8 = self.read_operand(addr_mode);
+        self.add_with_carry(val);
+    }
 
-        self.add_to_reg_a(val);
+    // Adds |val| and the carry to A, in decimal when the decimal flag is set and supported.
+    fn add_with_carry(&mut self, val: u8) {
+        if self.decimal_mode && self.reg_status.contains(Status::D) {
+            self.add_decimal_to_reg_a(val);
+        } else {
+            self.add_to_reg_a(val);
+        }
     }
 
     fn and(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
-
-        let val: u8 = self.read_mem(addr);
+        let val: u8 = self.read_operand(addr_mode);
 
         self.set_reg_a(self.reg_a & val);
     }
@@ -1004,14 +2066,13 @@ impl CPU {
                 self.set_reg_a(self.reg_a << 1);
             }
             _ => {
-                let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
-                let mut val: u8 = self.read_mem(addr);
+                let (addr, mut val) = self.read_modify_operand(addr_mode);
                 if (val & 0b1000_0000) != 0 {
                     self.reg_status.insert(Status::C);
                 } else {
                     self.reg_status.remove(Status::C);
                 }
-                val = val << 1;
+                val <<= 1;
                 self.write_mem(addr, val);
                 self.set_zero_flag(val);
                 self.set_negative_flag(val);
@@ -1022,31 +2083,19 @@ impl CPU {
     fn bcc(&mut self, addr_mode: &AddressingMode) {
         assert_eq!(*addr_mode, AddressingMode::Relative);
 
-        if self.reg_status.contains(Status::C) {
-            return;
-        }
-
-        self.branch()
+        self.branch(!self.reg_status.contains(Status::C));
     }
 
     fn bcs(&mut self, addr_mode: &AddressingMode) {
         assert_eq!(*addr_mode, AddressingMode::Relative);
 
-        if !self.reg_status.contains(Status::C) {
-            return;
-        }
-
-        self.branch();
+        self.branch(self.reg_status.contains(Status::C));
     }
 
     fn beq(&mut self, addr_mode: &AddressingMode) {
         assert_eq!(*addr_mode, AddressingMode::Relative);
 
-        if !self.reg_status.contains(Status::Z) {
-            return;
-        }
-
-        self.branch();
+        self.branch(self.reg_status.contains(Status::Z));
     }
 
     fn bit(&mut self, addr_mode: &AddressingMode) {
@@ -1075,53 +2124,42 @@ impl CPU {
     fn bmi(&mut self, addr_mode: &AddressingMode) {
         assert_eq!(*addr_mode, AddressingMode::Relative);
 
-        if !self.reg_status.contains(Status::N) {
-            return;
-        }
-
-        self.branch();
+        self.branch(self.reg_status.contains(Status::N));
     }
 
     fn bne(&mut self, addr_mode: &AddressingMode) {
         assert_eq!(*addr_mode, AddressingMode::Relative);
 
-        if self.reg_status.contains(Status::Z) {
-            return;
-        }
-
-        self.branch();
+        self.branch(!self.reg_status.contains(Status::Z));
     }
 
     fn bpl(&mut self, addr_mode: &AddressingMode) {
         assert_eq!(*addr_mode, AddressingMode::Relative);
 
-        if self.reg_status.contains(Status::N) {
-            return;
-        }
-
-        self.branch();
+        self.branch(!self.reg_status.contains(Status::N));
     }
 
-    fn brk(&mut self, _addr_mode: &AddressingMode) {}
+    // BRK pushes the address of the byte after its padding byte and the status with B set, then
+    // jumps through the IRQ/BRK vector.
+    fn brk(&mut self, _addr_mode: &AddressingMode) {
+        self.push16(self.pc.wrapping_add(2));
+        self.push(self.reg_status.bits() | Status::B.bits() | STATUS_BIT5);
+        self.reg_status.insert(Status::I);
+
+        let addr = self.read_mem16(IRQ_BRK_VECTOR_ADDR);
+        self.jump_to(addr);
+    }
 
     fn bvc(&mut self, addr_mode: &AddressingMode) {
         assert_eq!(*addr_mode, AddressingMode::Relative);
 
-        if self.reg_status.contains(Status::V) {
-            return;
-        }
-
-        self.branch();
+        self.branch(!self.reg_status.contains(Status::V));
     }
 
     fn bvs(&mut self, addr_mode: &AddressingMode) {
         assert_eq!(*addr_mode, AddressingMode::Relative);
 
-        if !self.reg_status.contains(Status::V) {
-            return;
-        }
-
-        self.branch();
+        self.branch(self.reg_status.contains(Status::V));
     }
 
     fn clc(&mut self, _addr_mode: &AddressingMode) {
@@ -1140,12 +2178,58 @@ impl CPU {
         self.reg_status.remove(Status::V);
     }
 
+    fn cmp(&mut self, addr_mode: &AddressingMode) {
+        let val = self.read_operand(addr_mode);
+        self.compare(self.reg_a, val);
+    }
+
+    fn cpx(&mut self, addr_mode: &AddressingMode) {
+        let val = self.read_operand(addr_mode);
+        self.compare(self.reg_x, val);
+    }
+
+    fn cpy(&mut self, addr_mode: &AddressingMode) {
+        let val = self.read_operand(addr_mode);
+        self.compare(self.reg_y, val);
+    }
+
+    fn dec(&mut self, addr_mode: &AddressingMode) {
+        let (addr, val) = self.read_modify_operand(addr_mode);
+        let val = val.wrapping_sub(1);
+        self.write_mem(addr, val);
+
+        self.set_negative_flag(val);
+        self.set_zero_flag(val);
+    }
+
+    fn dex(&mut self, _addr_mode: &AddressingMode) {
+        self.reg_x = self.reg_x.wrapping_sub(1);
+
+        self.set_negative_flag(self.reg_x);
+        self.set_zero_flag(self.reg_x);
+    }
+
+    fn dey(&mut self, _addr_mode: &AddressingMode) {
+        self.reg_y = self.reg_y.wrapping_sub(1);
+
+        self.set_negative_flag(self.reg_y);
+        self.set_zero_flag(self.reg_y);
+    }
+
     fn eor(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
-        let val = self.read_mem(addr);
+        let val = self.read_operand(addr_mode);
         self.set_reg_a(self.reg_a ^ val);
     }
 
+    fn inc(&mut self, addr_mode: &AddressingMode) {
+        let (addr, val) = self.read_modify_operand(addr_mode);
+        let val = val.wrapping_add(1);
+        self.write_mem(addr, val);
+
+        self.set_negative_flag(val);
+        self.set_zero_flag(val);
+    }
+
     fn inx(&mut self, _addr_mode: &AddressingMode) {
         let (val_x, _overflow) = self.reg_x.overflowing_add(1);
         self.reg_x = val_x;
@@ -1154,34 +2238,48 @@ impl CPU {
         self.set_zero_flag(self.reg_x);
     }
 
+    fn iny(&mut self, _addr_mode: &AddressingMode) {
+        self.reg_y = self.reg_y.wrapping_add(1);
+
+        self.set_negative_flag(self.reg_y);
+        self.set_zero_flag(self.reg_y);
+    }
+
     fn jmp(&mut self, addr_mode: &AddressingMode) {
         let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
 
-        self.pc = addr;
+        self.jump_to(addr);
     }
 
-    fn lda(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
+    // JSR pushes the address of its last byte, RTS adds one back when returning.
+    fn jsr(&mut self, _addr_mode: &AddressingMode) {
+        // Like the 6502, read the high byte of the target after pushing the return address, which
+        // matters when the operand sits on the stack.
+        let operand_addr = self.get_operand_address();
+        let lo = self.fetch_mem(operand_addr);
+        self.read_stack();
+        self.push16(self.pc.wrapping_add(2));
+        let hi = self.fetch_mem(operand_addr.wrapping_add(1));
+
+        self.jump_to(u16::from_le_bytes([lo, hi]));
+    }
 
-        self.reg_a = self.read_mem(addr);
+    fn lda(&mut self, addr_mode: &AddressingMode) {
+        self.reg_a = self.read_operand(addr_mode);
 
         self.set_negative_flag(self.reg_a);
         self.set_zero_flag(self.reg_a);
     }
 
     fn ldx(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
-
-        self.reg_x = self.read_mem(addr);
+        self.reg_x = self.read_operand(addr_mode);
 
         self.set_negative_flag(self.reg_x);
         self.set_zero_flag(self.reg_x);
     }
 
     fn ldy(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
-
-        self.reg_y = self.read_mem(addr);
+        self.reg_y = self.read_operand(addr_mode);
 
         self.set_negative_flag(self.reg_y);
         self.set_zero_flag(self.reg_y);
@@ -1198,14 +2296,13 @@ impl CPU {
                 self.set_reg_a(self.reg_a >> 1);
             }
             _ => {
-                let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
-                let mut val: u8 = self.read_mem(addr);
-                if (val & 0b1000_0001) != 0 {
+                let (addr, mut val) = self.read_modify_operand(addr_mode);
+                if (val & 0b0000_0001) != 0 {
                     self.reg_status.insert(Status::C);
                 } else {
                     self.reg_status.remove(Status::C);
                 }
-                val = val >> 1;
+                val >>= 1;
                 self.write_mem(addr, val);
                 self.set_zero_flag(val);
                 self.set_negative_flag(val);
@@ -1213,20 +2310,42 @@ impl CPU {
         }
     }
 
+    fn nop(&mut self, _addr_mode: &AddressingMode) {}
+
     fn ora(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
-        let val = self.read_mem(addr);
+        let val = self.read_operand(addr_mode);
 
         self.set_reg_a(val | self.reg_a);
     }
 
+    fn pha(&mut self, _addr_mode: &AddressingMode) {
+        self.push(self.reg_a);
+    }
+
+    // PHP always pushes B and bit 5 as 1.
+    fn php(&mut self, _addr_mode: &AddressingMode) {
+        self.push(self.reg_status.bits() | Status::B.bits() | STATUS_BIT5);
+    }
+
+    fn pla(&mut self, _addr_mode: &AddressingMode) {
+        self.read_stack();
+        let val = self.pop();
+        self.set_reg_a(val);
+    }
+
+    // B and bit 5 only exist on the stack, so they are dropped when pulled.
+    fn plp(&mut self, _addr_mode: &AddressingMode) {
+        self.read_stack();
+        let val = self.pop();
+        self.reg_status = Status::from_bits_truncate(val) - Status::B;
+    }
+
     fn rol(&mut self, addr_mode: &AddressingMode) {
-        let mut carrier = 0;
-        if self.reg_status.contains(Status::C) {
-            carrier = 0b0000_0001;
+        let carrier = if self.reg_status.contains(Status::C) {
+            0b0000_0001
         } else {
-            carrier = 0b0000_0000;
-        }
+            0b0000_0000
+        };
         match addr_mode {
             AddressingMode::Accumulator => {
                 if (self.reg_a & 0b1000_0000) != 0 {
@@ -1237,8 +2356,7 @@ impl CPU {
                 self.set_reg_a((self.reg_a << 1) | carrier);
             }
             _ => {
-                let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
-                let mut val: u8 = self.read_mem(addr);
+                let (addr, mut val) = self.read_modify_operand(addr_mode);
                 if (val & 0b1000_0000) != 0 {
                     self.reg_status.insert(Status::C);
                 } else {
@@ -1253,12 +2371,11 @@ impl CPU {
     }
 
     fn ror(&mut self, addr_mode: &AddressingMode) {
-        let mut carrier = 0;
-        if self.reg_status.contains(Status::C) {
-            carrier = 0b1000_0000;
+        let carrier = if self.reg_status.contains(Status::C) {
+            0b1000_0000
         } else {
-            carrier = 0b0000_0000;
-        }
+            0b0000_0000
+        };
         match addr_mode {
             AddressingMode::Accumulator => {
                 if (self.reg_a & 0b0000_0001) != 0 {
@@ -1269,8 +2386,7 @@ impl CPU {
                 self.set_reg_a((self.reg_a >> 1) | carrier);
             }
             _ => {
-                let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
-                let mut val: u8 = self.read_mem(addr);
+                let (addr, mut val) = self.read_modify_operand(addr_mode);
                 if (val & 0b000_0001) != 0 {
                     self.reg_status.insert(Status::C);
                 } else {
@@ -1284,73 +2400,229 @@ impl CPU {
         }
     }
 
+    fn rti(&mut self, _addr_mode: &AddressingMode) {
+        self.read_stack();
+        let val = self.pop();
+        self.reg_status = Status::from_bits_truncate(val) - Status::B;
+
+        let addr = self.pop16();
+        self.jump_to(addr);
+    }
+
+    // RTS reads the pulled address before adding one to it.
+    fn rts(&mut self, _addr_mode: &AddressingMode) {
+        self.read_stack();
+        let addr = self.pop16();
+        self.fetch_mem(addr);
+        self.jump_to(addr.wrapping_add(1));
+    }
+
     fn sta(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
+        let addr = self.write_operand_address(addr_mode);
 
         self.write_mem(addr, self.reg_a);
     }
 
     fn stx(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
+        let addr = self.write_operand_address(addr_mode);
 
         self.write_mem(addr, self.reg_x);
     }
 
-    fn sty(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
+    fn sty(&mut self, addr_mode: &AddressingMode) {
+        let addr = self.write_operand_address(addr_mode);
+
+        self.write_mem(addr, self.reg_y);
+    }
+
+    fn tax(&mut self, _addr_mode: &AddressingMode) {
+        self.reg_x = self.reg_a;
+
+        self.set_negative_flag(self.reg_x);
+        self.set_zero_flag(self.reg_x);
+    }
+
+    fn tay(&mut self, _addr_mode: &AddressingMode) {
+        self.reg_y = self.reg_a;
+
+        self.set_negative_flag(self.reg_y);
+        self.set_zero_flag(self.reg_y);
+    }
+
+    fn tsx(&mut self, _addr_mode: &AddressingMode) {
+        self.reg_x = self.sp;
+
+        self.set_negative_flag(self.reg_x);
+        self.set_zero_flag(self.reg_x);
+    }
+
+    fn txa(&mut self, _addr_mode: &AddressingMode) {
+        self.reg_a = self.reg_x;
+
+        self.set_negative_flag(self.reg_a);
+        self.set_zero_flag(self.reg_a);
+    }
+
+    // TXS is the only transfer that does not touch the flags.
+    fn txs(&mut self, _addr_mode: &AddressingMode) {
+        self.sp = self.reg_x;
+    }
+
+    fn tya(&mut self, _addr_mode: &AddressingMode) {
+        self.reg_a = self.reg_y;
+
+        self.set_negative_flag(self.reg_a);
+        self.set_zero_flag(self.reg_a);
+    }
+
+    fn sbc(&mut self, addr_mode: &AddressingMode) {
+        let val = self.read_operand(addr_mode);
+        self.subtract_with_borrow(val);
+    }
+
+    // Subtracts |val| and the borrow from A, in decimal when the decimal flag is set and
+    // supported.
+    fn subtract_with_borrow(&mut self, val: u8) {
+        if self.decimal_mode && self.reg_status.contains(Status::D) {
+            self.subtract_decimal_from_reg_a(val);
+        } else {
+            self.add_to_reg_a(!val);
+        }
+    }
+
+    fn sec(&mut self, _addr_mode: &AddressingMode) {
+        self.reg_status.insert(Status::C);
+    }
+
+    fn sed(&mut self, _addr_mode: &AddressingMode) {
+        self.reg_status.insert(Status::D);
+    }
+
+    fn sei(&mut self, _addr_mode: &AddressingMode) {
+        self.reg_status.insert(Status::I);
+    }
+
+    // Unofficial opcodes.
+
+    // DEC then CMP.
+    fn dcp(&mut self, addr_mode: &AddressingMode) {
+        let (addr, val) = self.read_modify_operand(addr_mode);
+        let val = val.wrapping_sub(1);
+        self.write_mem(addr, val);
+
+        self.compare(self.reg_a, val);
+    }
+
+    // INC then SBC.
+    fn isb(&mut self, addr_mode: &AddressingMode) {
+        let (addr, val) = self.read_modify_operand(addr_mode);
+        let val = val.wrapping_add(1);
+        self.write_mem(addr, val);
+
+        self.subtract_with_borrow(val);
+    }
+
+    // LDA and LDX at once.
+    fn lax(&mut self, addr_mode: &AddressingMode) {
+        let val = self.read_operand(addr_mode);
+        self.reg_x = val;
 
-        self.write_mem(addr, self.reg_y);
+        self.set_reg_a(val);
     }
 
-    fn tax(&mut self, _addr_mode: &AddressingMode) {
-        self.reg_x = self.reg_a;
-
-        self.set_negative_flag(self.reg_x);
-        self.set_zero_flag(self.reg_x);
+    // NOPs with an operand read it, like LDA.
+    fn nop_read(&mut self, addr_mode: &AddressingMode) {
+        self.read_operand(addr_mode);
     }
 
-    fn tay(&mut self, _addr_mode: &AddressingMode) {
-        self.reg_y = self.reg_a;
+    // ROL then AND.
+    fn rla(&mut self, addr_mode: &AddressingMode) {
+        let carrier = self.reg_status.contains(Status::C) as u8;
+        let (addr, val) = self.read_modify_operand(addr_mode);
+        self.reg_status.set(Status::C, val & 0b1000_0000 != 0);
+        let val = (val << 1) | carrier;
+        self.write_mem(addr, val);
 
-        self.set_negative_flag(self.reg_y);
-        self.set_zero_flag(self.reg_y);
+        self.set_reg_a(self.reg_a & val);
     }
 
-    fn txa(&mut self, _addr_mode: &AddressingMode) {
-        self.reg_a = self.reg_x;
+    // ROR then ADC, which adds the carry ROR shifted out.
+    fn rra(&mut self, addr_mode: &AddressingMode) {
+        let carrier = (self.reg_status.contains(Status::C) as u8) << 7;
+        let (addr, val) = self.read_modify_operand(addr_mode);
+        self.reg_status.set(Status::C, val & 0b0000_0001 != 0);
+        let val = (val >> 1) | carrier;
+        self.write_mem(addr, val);
 
-        self.set_negative_flag(self.reg_a);
-        self.set_zero_flag(self.reg_a);
+        self.add_with_carry(val);
     }
 
-    fn tya(&mut self, _addr_mode: &AddressingMode) {
-        self.reg_a = self.reg_y;
+    // Stores A AND X, without touching the flags.
+    fn sax(&mut self, addr_mode: &AddressingMode) {
+        let addr = self.write_operand_address(addr_mode);
 
-        self.set_negative_flag(self.reg_a);
-        self.set_zero_flag(self.reg_a);
+        self.write_mem(addr, self.reg_a & self.reg_x);
     }
 
-    fn sbc(&mut self, addr_mode: &AddressingMode) {
-        let addr = self.read_mem_operand(self.get_operand_address(), addr_mode);
+    // ASL then ORA.
+    fn slo(&mut self, addr_mode: &AddressingMode) {
+        let (addr, val) = self.read_modify_operand(addr_mode);
+        self.reg_status.set(Status::C, val & 0b1000_0000 != 0);
+        let val = val << 1;
+        self.write_mem(addr, val);
 
-        let val = self.read_mem(addr);
+        self.set_reg_a(self.reg_a | val);
+    }
 
-        self.add_to_reg_a(!val);
+    // LSR then EOR.
+    fn sre(&mut self, addr_mode: &AddressingMode) {
+        let (addr, val) = self.read_modify_operand(addr_mode);
+        self.reg_status.set(Status::C, val & 0b0000_0001 != 0);
+        let val = val >> 1;
+        self.write_mem(addr, val);
+
+        self.set_reg_a(self.reg_a ^ val);
     }
+}
 
-    fn sec(&mut self, _addr_mode: &AddressingMode) {
-        self.reg_status.insert(Status::C);
+// The state of the CPU includes everything behind its bus.
+impl Savestate for CPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.reg_a);
+        w.write(&self.reg_x);
+        w.write(&self.reg_y);
+        w.write(&self.reg_status.bits());
+        w.write(&self.pc);
+        w.write(&self.sp);
+        w.write(&self.cycles);
+        self.call_stack.save_state(w);
+        self.bus.save_state(w);
     }
 
-    fn sed(&mut self, _addr_mode: &AddressingMode) {
-        self.reg_status.insert(Status::D);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.reg_a = r.read()?;
+        self.reg_x = r.read()?;
+        self.reg_y = r.read()?;
+        self.reg_status = Status::from_bits_truncate(r.read()?);
+        self.pc = r.read()?;
+        self.sp = r.read()?;
+        self.cycles = r.read()?;
+        self.call_stack.load_state(r)?;
+        self.bus.load_state(r)
     }
+}
 
-    fn sei(&mut self, _addr_mode: &AddressingMode) {
-        self.reg_status.insert(Status::I);
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+// Returns true if |a| and |b| are on different pages.
+fn is_page_crossed(a: u16, b: u16) -> bool {
+    a & 0xff00 != b & 0xff00
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1364,6 +2636,18 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_mem_with_pattern() {
+        let mem = Mem::with_pattern(RamPattern::Ones);
+        assert_eq!(mem.read(0x0000), 0xff);
+        assert_eq!(mem.read(0xffff), 0xff);
+
+        let mem = Mem::with_pattern(RamPattern::Alternating);
+        assert_eq!(mem.read(0x0003), 0x00);
+        assert_eq!(mem.read(0x0004), 0xff);
+        assert_eq!(mem.read(0x0008), 0x00);
+    }
+
     #[test]
     fn test_mem_read_write() {
         let mut mem = Mem::new();
@@ -1749,8 +3033,8 @@ mod test {
     fn test_inx_zero_flag() {
         let mut cpu = CPU::new();
         let mut program = vec![0; 8000];
-        for i in 0..0x100 {
-            program[i] = 0xe8;
+        for byte in program.iter_mut().take(0x100) {
+            *byte = 0xe8;
         }
 
         // INX * 256
@@ -1765,8 +3049,8 @@ mod test {
     fn test_inx_negative_flag() {
         let mut cpu = CPU::new();
         let mut program = vec![0; 8000];
-        for i in 0..0xf0 {
-            program[i] = 0xe8;
+        for byte in program.iter_mut().take(0xf0) {
+            *byte = 0xe8;
         }
         // INX * 0xf0
         // BRK
@@ -1780,8 +3064,8 @@ mod test {
     fn test_inx_overflow() {
         let mut cpu = CPU::new();
         let mut program = vec![0; 8000];
-        for i in 0..0x101 {
-            program[i] = 0xe8;
+        for byte in program.iter_mut().take(0x101) {
+            *byte = 0xe8;
         }
 
         // INX * 257
@@ -3056,3 +4340,726 @@ fn test_bvs_overflow_set_backward() {
     assert_eq!(cpu.reg_a, 0x80);
     assert_eq!(cpu.reg_status.contains(Status::V), true);
 }
+
+#[test]
+fn test_cmp_equal() {
+    let mut cpu = CPU::new();
+    // LDA #$40
+    // CMP #$40
+    // BRK
+    let program = vec![0xa9, 0x40, 0xc9, 0x40, 0x00];
+
+    assert_eq!(cpu.interpret(&program), Ok(()));
+
+    assert_eq!(cpu.reg_status.contains(Status::C), true);
+    assert_eq!(cpu.reg_status.contains(Status::Z), true);
+    assert_eq!(cpu.reg_status.contains(Status::N), false);
+}
+
+#[test]
+fn test_cmp_less() {
+    let mut cpu = CPU::new();
+    // LDA #$10
+    // CMP #$20
+    // BRK
+    let program = vec![0xa9, 0x10, 0xc9, 0x20, 0x00];
+
+    assert_eq!(cpu.interpret(&program), Ok(()));
+
+    assert_eq!(cpu.reg_status.contains(Status::C), false);
+    assert_eq!(cpu.reg_status.contains(Status::Z), false);
+    assert_eq!(cpu.reg_status.contains(Status::N), true);
+}
+
+#[test]
+fn test_cpx_cpy() {
+    let mut cpu = CPU::new();
+    // LDX #$05
+    // CPX #$04
+    // LDY #$05
+    // CPY #$05
+    // BRK
+    let program = vec![0xa2, 0x05, 0xe0, 0x04, 0xa0, 0x05, 0xc0, 0x05, 0x00];
+
+    assert_eq!(cpu.interpret(&program), Ok(()));
+
+    assert_eq!(cpu.reg_status.contains(Status::C), true);
+    assert_eq!(cpu.reg_status.contains(Status::Z), true);
+}
+
+#[test]
+fn test_inc_dec_zeropage() {
+    let mut cpu = CPU::new();
+    // INC $10
+    // INC $10
+    // DEC $11
+    // BRK
+    let program = vec![0xe6, 0x10, 0xe6, 0x10, 0xc6, 0x11, 0x00];
+
+    assert_eq!(cpu.interpret(&program), Ok(()));
+
+    assert_eq!(cpu.read_mem(0x0010), 0x02);
+    assert_eq!(cpu.read_mem(0x0011), 0xff);
+    assert_eq!(cpu.reg_status.contains(Status::N), true);
+}
+
+#[test]
+fn test_iny_dex_dey() {
+    let mut cpu = CPU::new();
+    // INY
+    // DEX
+    // DEY
+    // DEY
+    // BRK
+    let program = vec![0xc8, 0xca, 0x88, 0x88, 0x00];
+
+    assert_eq!(cpu.interpret(&program), Ok(()));
+
+    assert_eq!(cpu.reg_x, 0xff);
+    assert_eq!(cpu.reg_y, 0xff);
+    assert_eq!(cpu.reg_status, Status::N);
+}
+
+#[test]
+fn test_jsr_rts() {
+    let mut cpu = CPU::new();
+    // JSR $8005    <= 0x8000
+    // INX          <= 0x8003
+    // BRK          <= 0x8004
+    // LDX #$10     <= 0x8005
+    // RTS          <= 0x8007
+    let program = vec![0x20, 0x05, 0x80, 0xe8, 0x00, 0xa2, 0x10, 0x60];
+
+    assert_eq!(cpu.interpret(&program), Ok(()));
+
+    assert_eq!(cpu.reg_x, 0x11);
+    assert_eq!(cpu.sp, 0xfd);
+    assert_eq!(cpu.read_mem(0x01fd), 0x80);
+    assert_eq!(cpu.read_mem(0x01fc), 0x02);
+}
+
+#[test]
+fn test_pha_pla() {
+    let mut cpu = CPU::new();
+    // LDA #$80
+    // PHA
+    // LDA #$00
+    // PLA
+    // BRK
+    let program = vec![0xa9, 0x80, 0x48, 0xa9, 0x00, 0x68, 0x00];
+
+    assert_eq!(cpu.interpret(&program), Ok(()));
+
+    assert_eq!(cpu.reg_a, 0x80);
+    assert_eq!(cpu.reg_status, Status::N);
+    assert_eq!(cpu.sp, 0xfd);
+}
+
+#[test]
+fn test_php_plp() {
+    let mut cpu = CPU::new();
+    // SEC
+    // PHP
+    // CLC
+    // PLP
+    // BRK
+    let program = vec![0x38, 0x08, 0x18, 0x28, 0x00];
+
+    assert_eq!(cpu.interpret(&program), Ok(()));
+
+    // B and bit 5 are pushed but not pulled back.
+    assert_eq!(cpu.read_mem(0x01fd), 0b0011_0001);
+    assert_eq!(cpu.reg_status, Status::C);
+}
+
+#[test]
+fn test_tsx_txs() {
+    let mut cpu = CPU::new();
+    // LDX #$80
+    // TXS
+    // LDX #$00
+    // TSX
+    // BRK
+    let program = vec![0xa2, 0x80, 0x9a, 0xa2, 0x00, 0xba, 0x00];
+
+    assert_eq!(cpu.interpret(&program), Ok(()));
+
+    assert_eq!(cpu.sp, 0x80);
+    assert_eq!(cpu.reg_x, 0x80);
+    assert_eq!(cpu.reg_status, Status::N);
+}
+
+#[test]
+fn test_brk_rti() {
+    let mut cpu = CPU::new();
+    // LDA #$01     <= 0x8000
+    // BRK          <= 0x8002
+    // NOP          <= 0x8003, skipped by RTI.
+    // INX          <= 0x8004
+    // .. padding
+    // RTI          <= 0x8010
+    let mut program = vec![0xa9, 0x01, 0x00, 0xea, 0xe8];
+    program.resize(0x10, 0xea);
+    program.push(0x40);
+    cpu.load(&program).unwrap();
+    cpu.write_mem16(0xfffe, 0x8010);
+    cpu.reset();
+
+    for _ in 0..4 {
+        cpu.step().unwrap();
+    }
+
+    assert_eq!(cpu.reg_x, 0x01);
+    assert_eq!(cpu.pc, 0x8005);
+    assert_eq!(cpu.reg_status.contains(Status::I), false);
+}
+
+#[test]
+fn test_nmi_irq() {
+    let mut cpu = CPU::new();
+    // INX          <= 0x8000
+    // .. padding
+    // RTI          <= 0x8010
+    let mut program = vec![0xe8];
+    program.resize(0x10, 0xea);
+    program.push(0x40);
+    cpu.load(&program).unwrap();
+    cpu.write_mem16(0xfffa, 0x8010);
+    cpu.write_mem16(0xfffe, 0x8010);
+    cpu.reset();
+    cpu.reg_status.insert(Status::C);
+
+    cpu.nmi();
+
+    assert_eq!(cpu.pc, 0x8010);
+    assert_eq!(cpu.cycles, 14);
+    assert_eq!(cpu.peek_mem(0x01fb), 0b0010_0001);
+    assert_eq!(cpu.reg_status.contains(Status::I), true);
+    // Interrupts are disabled inside the handler.
+    assert_eq!(cpu.irq(), false);
+
+    cpu.step().unwrap();
+
+    assert_eq!(cpu.pc, 0x8000);
+    assert_eq!(cpu.reg_status.contains(Status::I), false);
+    assert_eq!(cpu.irq(), true);
+    assert_eq!(cpu.pc, 0x8010);
+}
+
+#[test]
+fn test_jmp_indirect_page_boundary() {
+    let mut cpu = CPU::new();
+    // JMP ($80ff)
+    let mut program = vec![0x6c, 0xff, 0x80];
+    program.resize(0x100, 0x00);
+    // The high byte is read from 0x8000 rather than 0x8100.
+    program[0xff] = 0x34;
+    cpu.load(&program).unwrap();
+    cpu.reset();
+
+    cpu.step().unwrap();
+
+    assert_eq!(cpu.pc, 0x6c34);
+}
+
+#[test]
+fn test_lda_indirecty() {
+    let mut cpu = CPU::new();
+    // LDA #$00
+    // STA $10
+    // LDA #$02
+    // STA $11
+    // LDA #$7f
+    // STA $0205
+    // LDY #$05
+    // LDA #$00
+    // LDA ($10),Y
+    // BRK
+    let program = vec![
+        0xa9, 0x00, 0x85, 0x10, 0xa9, 0x02, 0x85, 0x11, 0xa9, 0x7f, 0x8d, 0x05, 0x02, 0xa0, 0x05,
+        0xa9, 0x00, 0xb1, 0x10, 0x00,
+    ];
+
+    assert_eq!(cpu.interpret(&program), Ok(()));
+
+    assert_eq!(cpu.reg_a, 0x7f);
+}
+
+#[test]
+fn test_cycles() {
+    let mut cpu = CPU::new();
+    // LDX #$01       2 cycles
+    // LDA $80ff,X    4 + 1 cycles, page crossed
+    // BEQ LABEL      2 + 1 cycles, taken
+    // LABEL: BRK
+    let program = vec![0xa2, 0x01, 0xbd, 0xff, 0x80, 0xf0, 0x00, 0x00];
+
+    assert_eq!(cpu.interpret(&program), Ok(()));
+
+    // 7 cycles for the reset sequence.
+    assert_eq!(cpu.cycles, 7 + 2 + 5 + 3);
+}
+
+#[test]
+fn test_save_load_state() {
+    let mut cpu = CPU::new();
+    // LDA #$42
+    // STA $10
+    // SEC
+    cpu.load(&[0xa9, 0x42, 0x85, 0x10, 0x38]).unwrap();
+    cpu.reset();
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    let state = crate::savestate::save(&cpu);
+    cpu.step().unwrap();
+    cpu.write_mem(0x10, 0);
+
+    assert_eq!(crate::savestate::load(&mut cpu, &state), Ok(()));
+
+    assert_eq!(cpu.reg_a, 0x42);
+    assert_eq!(cpu.pc, 0x8004);
+    assert_eq!(cpu.cycles, 7 + 2 + 3);
+    assert_eq!(cpu.reg_status.contains(Status::C), false);
+    assert_eq!(cpu.peek_mem(0x10), 0x42);
+}
+
+#[test]
+fn test_decimal_mode() {
+    let run = |decimal_mode: bool, program: &[u8]| {
+        let mut cpu = CPU::new();
+        cpu.set_decimal_mode(decimal_mode);
+        cpu.interpret(program).unwrap();
+        cpu
+    };
+    // SED
+    // CLC
+    // LDA #$58
+    // ADC #$46
+    let adc = [0xf8, 0x18, 0xa9, 0x58, 0x69, 0x46, 0x00];
+    // SED
+    // SEC
+    // LDA #$12
+    // SBC #$21
+    let sbc = [0xf8, 0x38, 0xa9, 0x12, 0xe9, 0x21, 0x00];
+
+    let cpu = run(true, &adc);
+    assert_eq!(cpu.reg_a, 0x04);
+    assert_eq!(cpu.reg_status.contains(Status::C), true);
+
+    let cpu = run(true, &sbc);
+    assert_eq!(cpu.reg_a, 0x91);
+    assert_eq!(cpu.reg_status.contains(Status::C), false);
+
+    // The NES ignores the D flag.
+    assert_eq!(run(false, &adc).reg_a, 0x9e);
+}
+
+#[test]
+fn test_breakpoints() {
+    use crate::breakpoint::Breakpoint;
+
+    let mut cpu = CPU::new();
+    // LDA #$01
+    // STA $10
+    // INC $10
+    // LDA $10
+    // BRK
+    cpu.load(&[0xa9, 0x01, 0x85, 0x10, 0xe6, 0x10, 0xa5, 0x10, 0x00])
+        .unwrap();
+    cpu.reset();
+    let execute = cpu.breakpoints_mut().add(Breakpoint::Execute(0x8002));
+    let write = cpu
+        .breakpoints_mut()
+        .add(Breakpoint::Write(0x10..=0x10, Some(0x02)));
+    let read = cpu.breakpoints_mut().add(Breakpoint::Read(0x10..=0x1f));
+
+    assert_eq!(cpu.run(), StopReason::Breakpoint(execute));
+    assert_eq!(cpu.pc, 0x8002);
+
+    // The read of INC fires before its write.
+    assert_eq!(
+        cpu.resume(),
+        StopReason::Watchpoint {
+            id: read,
+            access: BusAccess {
+                addr: 0x10,
+                val: 0x01,
+                kind: AccessKind::Read
+            }
+        }
+    );
+    assert_eq!(cpu.pc, 0x8006);
+
+    cpu.breakpoints_mut().remove(read);
+    cpu.breakpoints_mut().remove(execute);
+    cpu.pc = 0x8000;
+    assert_eq!(
+        cpu.resume(),
+        StopReason::Watchpoint {
+            id: write,
+            access: BusAccess {
+                addr: 0x10,
+                val: 0x02,
+                kind: AccessKind::Write
+            }
+        }
+    );
+
+    // BRK only stops run(), unless there is a breakpoint on it.
+    let brk = cpu.breakpoints_mut().add(Breakpoint::Brk);
+    assert_eq!(cpu.resume(), StopReason::Breakpoint(brk));
+    assert_eq!(cpu.reg_a, 0x02);
+}
+
+#[test]
+fn test_read_watchpoint_on_code() {
+    use crate::breakpoint::Breakpoint;
+
+    let mut cpu = CPU::new();
+    // LDX #$00
+    // LDA $8009,X
+    // JMP $8000
+    // .byte $42
+    cpu.load(&[0xa2, 0x00, 0xbd, 0x09, 0x80, 0x4c, 0x00, 0x80, 0xea, 0x42])
+        .unwrap();
+    cpu.reset();
+    let read = cpu.breakpoints_mut().add(Breakpoint::Read(0x8000..=0x8009));
+
+    // Fetching the instructions does not fire, reading the table does.
+    assert_eq!(
+        cpu.resume(),
+        StopReason::Watchpoint {
+            id: read,
+            access: BusAccess {
+                addr: 0x8009,
+                val: 0x42,
+                kind: AccessKind::Read
+            }
+        }
+    );
+    assert_eq!(cpu.pc, 0x8005);
+}
+
+#[test]
+fn test_opcode_breakpoints() {
+    use crate::breakpoint::Breakpoint;
+
+    let mut cpu = CPU::new();
+    // INX
+    // INX
+    // .byte $02
+    cpu.load(&[0xe8, 0xe8, 0x02]).unwrap();
+    cpu.reset();
+    let inx = cpu.breakpoints_mut().add(Breakpoint::Opcode(0xe8));
+
+    assert_eq!(cpu.run(), StopReason::Breakpoint(inx));
+    assert_eq!(cpu.resume(), StopReason::Breakpoint(inx));
+    assert_eq!(cpu.pc, 0x8001);
+    assert_eq!(cpu.resume(), StopReason::IllegalOpcode(0x02));
+    assert_eq!(cpu.reg_x, 2);
+
+    assert_eq!(
+        CPU::new().interpret(&[0xe8, 0x02]),
+        Err(SimpleError::new("illegal opcode 0x02 at 0x8001"))
+    );
+}
+
+#[test]
+fn test_conditional_breakpoints() {
+    use crate::breakpoint::{Action, Breakpoint};
+    use crate::condition::Condition;
+
+    let mut cpu = CPU::new();
+    // loop:
+    // INX
+    // STX $0300
+    // JMP loop
+    cpu.load(&[0xe8, 0x8e, 0x00, 0x03, 0x4c, 0x00, 0x80])
+        .unwrap();
+    cpu.reset();
+    let jmp = cpu.breakpoints_mut().add(Breakpoint::Execute(0x8004));
+    let condition = Condition::parse("[$0300] >= 3 && !z").unwrap();
+    cpu.breakpoints_mut().set_condition(jmp, Some(condition));
+    let write = cpu
+        .breakpoints_mut()
+        .add(Breakpoint::Write(0x0300..=0x0300, None));
+    cpu.breakpoints_mut().set_action(write, Action::Log);
+
+    assert_eq!(cpu.run(), StopReason::Breakpoint(jmp));
+    assert_eq!(cpu.reg_x, 3);
+    assert_eq!(cpu.resume(), StopReason::Breakpoint(jmp));
+    assert_eq!(cpu.reg_x, 4);
+    // Resuming from a breakpoint does not count it again.
+    assert_eq!(cpu.breakpoints().hits(jmp), Some(2));
+    assert_eq!(cpu.breakpoints().hits(write), Some(4));
+    assert_eq!(cpu.breakpoints().take_log().len(), 4);
+}
+
+#[test]
+fn test_labels_in_banks() {
+    use crate::cartridge::test::ines_image;
+    use crate::cartridge::Cartridge;
+    use crate::nes::Nes;
+
+    // UxROM with 4 banks: bank 3 is fixed at $C000 and bank 0 is at $8000 at power on.
+    let image = ines_image(2, &vec![0xea; 0x10000], &[]);
+    let mut nes = Nes::new(Cartridge::parse(&image).unwrap());
+    let symbols = nes.cpu_mut().symbols_mut();
+    symbols.add("start", 0x8000, Some(0));
+    symbols.add("level_data", 0x8100, Some(1));
+    symbols.add("nmi", 0xc000, Some(3));
+    symbols.add("frame", 0x0010, None);
+    // Mesen gives no window, only the bank.
+    symbols.add("irq", 0x8004, Some(3));
+
+    let cpu = nes.cpu();
+    assert_eq!(cpu.label(0x8000), Some("start"));
+    assert_eq!(cpu.label(0x8100), None);
+    assert_eq!(cpu.label(0xc000), Some("nmi"));
+    assert_eq!(cpu.label(0x0010), Some("frame"));
+    assert_eq!(cpu.label_address("level_data"), Some(0x8100));
+    assert_eq!(cpu.label_address("irq"), Some(0xc004));
+
+    nes.cpu_mut().poke_mem(0x8000, 1);
+
+    let cpu = nes.cpu();
+    assert_eq!(cpu.label(0x8000), None);
+    assert_eq!(cpu.label(0x8100), Some("level_data"));
+    assert_eq!(cpu.label_address("nmi"), Some(0xc000));
+    assert_eq!(cpu.label_address("missing"), None);
+}
+
+#[test]
+fn test_call_stack() {
+    let mut cpu = CPU::new();
+    // JSR $8004
+    // BRK
+    // PLA
+    // PLA
+    // RTS
+    let mut program = vec![0x20, 0x04, 0x80, 0x00, 0x68, 0x68, 0x60];
+    program.resize(0x10, 0xea);
+    // RTI
+    program.push(0x40);
+    cpu.load(&program).unwrap();
+    cpu.write_mem16(NMI_VECTOR_ADDR, 0x8010);
+    cpu.write_mem16(IRQ_BRK_VECTOR_ADDR, 0x8010);
+    cpu.reset();
+
+    cpu.step().unwrap();
+    cpu.nmi();
+    assert_eq!(
+        cpu.backtrace(),
+        "#0  $8010 in $8010 (NMI)\n#1  $8004 in $8004\n#2  $8000"
+    );
+    // RTI
+    cpu.step().unwrap();
+    assert_eq!(cpu.call_stack().len(), 1);
+    // PLA, PLA and RTS to wherever the stack says.
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    assert_eq!(cpu.call_stack().is_empty(), true);
+
+    cpu.pc = 0x8003;
+    cpu.step().unwrap();
+    let frame = cpu.call_stack().frames()[0];
+    assert_eq!(
+        (frame.kind, frame.entry, frame.call_site),
+        (FrameKind::Brk, 0x8010, 0x8003)
+    );
+    // RTI
+    cpu.step().unwrap();
+    assert_eq!(cpu.call_stack().is_empty(), true);
+}
+
+#[test]
+fn test_illegal_opcode_backtrace() {
+    let mut cpu = CPU::new();
+    // JSR $8004
+    // .byte $02
+    // JSR $8007
+    // LDA #$80
+    // PHA
+    // LDA #$02
+    // PHA
+    // RTS, which jumps to $8003.
+    let program = [
+        0x20, 0x04, 0x80, 0x02, 0x20, 0x07, 0x80, 0xa9, 0x80, 0x48, 0xa9, 0x02, 0x48, 0x60,
+    ];
+    cpu.symbols_mut().add("jump", 0x8007, None);
+
+    assert_eq!(
+        cpu.interpret(&program),
+        Err(SimpleError::new(
+            "illegal opcode 0x02 at 0x8003\n#0  $8003 in jump\n#1  $8004 in $8004\n#2  $8000"
+        ))
+    );
+}
+
+#[test]
+fn test_bus_access_per_cycle() {
+    use crate::bus::RecordingBus;
+
+    // Registers and flags that take branches or not and index within or across pages.
+    for (reg, status) in [(0x00, 0x00), (0xff, 0xff)] {
+        for opcode in OPCODES.iter().chain(UNOFFICIAL_OPCODES.iter()) {
+            let mut cpu = CPU::with_bus(Box::new(RecordingBus::new(Mem::new())));
+            cpu.load(&[opcode.code, 0xf0, 0x80]).unwrap();
+            cpu.reset();
+            // Pointers in zero page for the indirect modes.
+            cpu.write_mem16(0x00f0, 0x80f0);
+            cpu.write_mem16(0x00ef, 0x80f0);
+            cpu.reg_x = reg;
+            cpu.reg_y = reg;
+            cpu.reg_status = Status::from_bits_truncate(status);
+            cpu.bus_mut::<RecordingBus<Mem>>().unwrap().take_accesses();
+            let cycles = cpu.cycles;
+
+            cpu.step().unwrap();
+
+            assert_eq!(
+                cpu.bus::<RecordingBus<Mem>>().unwrap().accesses().len() as u64,
+                cpu.cycles - cycles,
+                "{} (0x{:02x}) with registers 0x{:02x}",
+                opcode.name,
+                opcode.code,
+                reg
+            );
+        }
+    }
+}
+
+#[test]
+fn test_dummy_accesses() {
+    use crate::bus::RecordingBus;
+
+    let mut cpu = CPU::with_bus(Box::new(RecordingBus::new(Mem::new())));
+    // STA $80f0,X
+    // INC $10
+    // RTS
+    let mut program = vec![0x9d, 0xf0, 0x80, 0xe6, 0x10, 0x60];
+    // BNE $810e
+    program.resize(0xfc, 0xea);
+    program.extend([0xd0, 0x10]);
+    cpu.load(&program).unwrap();
+    cpu.reset();
+    cpu.reg_x = 0x20;
+    cpu.sp = 0xfd;
+    cpu.write_mem16(0x01fe, 0x8101);
+    cpu.bus_mut::<RecordingBus<Mem>>().unwrap().take_accesses();
+    let mut step = |pc| {
+        cpu.pc = pc;
+        cpu.step().unwrap();
+        let accesses = cpu.bus_mut::<RecordingBus<Mem>>().unwrap().take_accesses();
+        let accesses: Vec<_> = accesses.iter().map(|a| (a.addr, a.kind)).collect();
+        (accesses, cpu.pc)
+    };
+    let read = |addr| (addr, AccessKind::Read);
+    let write = |addr| (addr, AccessKind::Write);
+
+    // The store reads before carrying into the high byte.
+    assert_eq!(
+        step(0x8000),
+        (
+            vec![
+                read(0x8000),
+                read(0x8001),
+                read(0x8002),
+                read(0x8010),
+                write(0x8110)
+            ],
+            0x8003
+        )
+    );
+    // INC writes the old value first.
+    assert_eq!(
+        step(0x8003),
+        (
+            vec![
+                read(0x8003),
+                read(0x8004),
+                read(0x0010),
+                write(0x0010),
+                write(0x0010)
+            ],
+            0x8005
+        )
+    );
+    assert_eq!(
+        step(0x8005),
+        (
+            vec![
+                read(0x8005),
+                read(0x8006),
+                read(0x01fd),
+                read(0x01fe),
+                read(0x01ff),
+                read(0x8101)
+            ],
+            0x8102
+        )
+    );
+    assert_eq!(
+        step(0x80fc),
+        (
+            vec![read(0x80fc), read(0x80fd), read(0x80fe), read(0x800e)],
+            0x810e
+        )
+    );
+}
+
+#[test]
+fn test_unofficial_opcodes() {
+    let run = |program: &[u8], setup: &dyn Fn(&mut CPU)| {
+        let mut cpu = CPU::new();
+        cpu.load(program).unwrap();
+        cpu.reset();
+        setup(&mut cpu);
+        cpu.step().unwrap();
+        cpu
+    };
+    let memory = |cpu: &mut CPU| {
+        cpu.write_mem(0x0010, 0b1000_0001);
+        cpu.reg_a = 0b0000_0011;
+        cpu.reg_x = 0b0000_0110;
+        cpu.reg_status.insert(Status::C);
+    };
+
+    // LAX $10
+    let cpu = run(&[0xa7, 0x10], &memory);
+    assert_eq!((cpu.reg_a, cpu.reg_x), (0x81, 0x81));
+    assert_eq!(cpu.reg_status.contains(Status::N), true);
+    // SAX $10
+    let cpu = run(&[0x87, 0x10], &memory);
+    assert_eq!(cpu.peek_mem(0x0010), 0b0000_0010);
+    // DCP $10
+    let cpu = run(&[0xc7, 0x10], &memory);
+    assert_eq!(cpu.peek_mem(0x0010), 0x80);
+    assert_eq!(cpu.reg_status.contains(Status::C), false);
+    // ISB $10: 0x03 - 0x82
+    let cpu = run(&[0xe7, 0x10], &memory);
+    assert_eq!((cpu.peek_mem(0x0010), cpu.reg_a), (0x82, 0x81));
+    assert_eq!(cpu.reg_status.contains(Status::C), false);
+    // SLO $10
+    let cpu = run(&[0x07, 0x10], &memory);
+    assert_eq!((cpu.peek_mem(0x0010), cpu.reg_a), (0x02, 0x03));
+    assert_eq!(cpu.reg_status.contains(Status::C), true);
+    // RLA $10
+    let cpu = run(&[0x27, 0x10], &memory);
+    assert_eq!((cpu.peek_mem(0x0010), cpu.reg_a), (0x03, 0x03));
+    assert_eq!(cpu.reg_status.contains(Status::C), true);
+    // SRE $10
+    let cpu = run(&[0x47, 0x10], &memory);
+    assert_eq!((cpu.peek_mem(0x0010), cpu.reg_a), (0x40, 0x43));
+    assert_eq!(cpu.reg_status.contains(Status::C), true);
+    // RRA $10: 0x03 + 0xc0 + the carry shifted out.
+    let cpu = run(&[0x67, 0x10], &memory);
+    assert_eq!((cpu.peek_mem(0x0010), cpu.reg_a), (0xc0, 0xc4));
+    // SBC #$01
+    let cpu = run(&[0xeb, 0x01], &memory);
+    assert_eq!(cpu.reg_a, 0x02);
+    // NOP $80fe,X takes one more cycle across a page, and changes nothing.
+    let cpu = run(&[0x1c, 0xfe, 0x80], &memory);
+    assert_eq!((cpu.pc, cpu.cycles, cpu.reg_a), (0x8003, 12, 0x03));
+}
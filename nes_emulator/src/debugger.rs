@@ -0,0 +1,1058 @@
+/**
+ * Command-line debugger of a running console, driven by lines of text so that it works from a
+ * terminal, over SSH or from a script piped to stdin.
+ *
+ * Numbers and addresses are expressions in the syntax of breakpoint conditions, e.g. $8000,
+ * %1010, 12, [$FFFC] + 3 or a label of the symbols loaded with the symbols command, which are
+ * also shown in disassembly. In the range of a watchpoint, e.g. $2000-$2007, '-' separates the
+ * start from the end, so the addresses there cannot subtract. Breakpoints fire between
+ * instructions, including when the console enters an interrupt handler. An empty line repeats the
+ * last command that runs the console, e.g. to keep stepping.
+ *
+ * Running stops after a number of frames if no breakpoint fired, e.g. when continuing a game that
+ * waits for input or stepping over a subroutine that never returns, since stdin cannot interrupt
+ * it.
+ */
+use crate::breakpoint::{self, Action, Breakpoint, BreakpointId, StopReason};
+use crate::cdl::{CodeDataLog, CHR_READ, CHR_RENDERED, PRG_CODE, PRG_DATA};
+use crate::condition::Condition;
+use crate::cpu::{Status, CPU, STATUS_BIT5};
+use crate::disasm::{self, Instruction};
+use crate::nes::Nes;
+use crate::profiler::Profiler;
+use crate::trace;
+use simple_error::SimpleError;
+use std::io::{self, BufRead, Write};
+
+const PROMPT: &str = "(nes) ";
+
+// Instructions shown by disasm without arguments, before and from the program counter.
+const DISASM_BEFORE: usize = 4;
+const DISASM_AFTER: usize = 8;
+const MEM_DUMP_LEN: u16 = 64;
+const MEM_DUMP_WIDTH: u16 = 16;
+// Routines and instructions shown by profile.
+const PROFILE_REPORT_LEN: usize = 10;
+// Commands repeated by an empty line.
+const REPEATED_COMMANDS: [&str; 10] = [
+    "step", "s", "next", "n", "finish", "o", "continue", "c", "frame", "f",
+];
+// Frames run at most by a command, 10 seconds of the game on NTSC.
+const DEFAULT_FRAME_LIMIT: u64 = 600;
+
+const HELP: &str = "\
+step, s [count]          execute count instructions, 1 by default
+next, n                  step over a subroutine call
+finish, o                step out of the current subroutine or interrupt handler
+continue, c              run until a breakpoint fires, for a limited number of frames
+frame, f [count]         run count frames, unless a breakpoint fires
+break, b addr [if cond]  stop before executing addr
+watch range [if cond]    stop after writing memory in range, e.g. $2000-$2007
+rwatch range [if cond]   stop after reading memory in range
+awatch range [if cond]   stop after reading or writing memory in range
+condition id [cond]      set or remove the condition of breakpoint id
+action id break|log      stop, or log and go on, when breakpoint id fires
+delete id                remove breakpoint id
+breakpoints, bl          list breakpoints
+regs, r                  show registers
+backtrace, bt            show the routines being executed, innermost first
+set reg value            set A, X, Y, SP, PC, P or a flag C, Z, I, D, V, N
+mem, m addr [len]        dump memory
+disasm, u [addr [count]] disassemble around the program counter or from addr
+symbols file             load labels from a .dbg, .nl or .mlb file
+cdl [start [file]|stop]  log how the ROM is used as code and data, or show the coverage
+cdl save file            save the code/data log in the .cdl format of FCEUX
+profile [start|stop]     profile cycles per routine and instruction, or show the top ones
+profile save file        save the call stacks profiled for flamegraph.pl
+reset                    press the reset button
+quit, q                  exit";
+
+// Why running stopped, if not because of the command itself.
+enum Stop {
+    Breakpoint(StopReason),
+    Done,
+    // No breakpoint fired in the number of frames.
+    FrameLimit(u64),
+}
+
+pub struct Debugger {
+    nes: Nes,
+    last_command: String,
+    quit: bool,
+    frame_limit: u64,
+}
+
+impl Debugger {
+    pub fn new(nes: Nes) -> Self {
+        Debugger {
+            nes,
+            last_command: String::new(),
+            quit: false,
+            frame_limit: DEFAULT_FRAME_LIMIT,
+        }
+    }
+
+    pub fn nes(&self) -> &Nes {
+        &self.nes
+    }
+
+    pub fn nes_mut(&mut self) -> &mut Nes {
+        &mut self.nes
+    }
+
+    // Sets how many frames a command runs at most when no breakpoint fires. The frame command
+    // runs the frames it is asked for regardless.
+    pub fn set_frame_limit(&mut self, frames: u64) {
+        self.frame_limit = frames;
+    }
+
+    // Whether the quit command was executed.
+    pub fn is_done(&self) -> bool {
+        self.quit
+    }
+
+    // Executes the commands read from |input| until it ends or the quit command, and writes
+    // their output to |output|.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()> {
+        writeln!(output, "{}", self.current_instruction())?;
+        let mut lines = input.lines();
+        while !self.quit {
+            write!(output, "{}", PROMPT)?;
+            output.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+            match self.execute(&line) {
+                Ok(text) if text.is_empty() => {}
+                Ok(text) => writeln!(output, "{}", text)?,
+                Err(e) => writeln!(output, "error: {}", e)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Executes the command |line| and returns its output. An empty line repeats the last command
+    // that runs the console.
+    pub fn execute(&mut self, line: &str) -> Result<String, SimpleError> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        let (command, args) = match line.find(char::is_whitespace) {
+            Some(pos) => (&line[..pos], line[pos..].trim()),
+            None => (line.as_str(), ""),
+        };
+        if REPEATED_COMMANDS.contains(&command) {
+            self.last_command = line.clone();
+        }
+        match command {
+            "" => Ok(String::new()),
+            "help" | "h" | "?" => Ok(HELP.to_string()),
+            "step" | "s" => {
+                let count = self.optional_number(args, 1)?;
+                let mut stop = Stop::Done;
+                for i in 0..count {
+                    stop = self.run_until(i == 0, |_| true);
+                    if let Stop::Breakpoint(_) = stop {
+                        break;
+                    }
+                }
+                Ok(self.stopped(stop))
+            }
+            "next" | "n" => {
+                let stop = self.step_over();
+                Ok(self.stopped(stop))
+            }
+            "finish" | "o" => {
+                let stop = self.step_out();
+                Ok(self.stopped(stop))
+            }
+            "continue" | "c" => {
+                let stop = self.run_until(true, |_| false);
+                Ok(self.stopped(stop))
+            }
+            "frame" | "f" => {
+                let count = self.optional_number(args, 1)?;
+                let stop = match self.run_frames(true, count as u64, |_| false) {
+                    Stop::FrameLimit(_) => Stop::Done,
+                    stop => stop,
+                };
+                Ok(self.stopped(stop))
+            }
+            "break" | "b" => {
+                let (addr, condition) = self.split_condition(args)?;
+                let addr = self.address(addr)?;
+                Ok(self.add_breakpoint(Breakpoint::Execute(addr), condition))
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let (range, condition) = self.split_condition(args)?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (self.address(start)?, self.address(end)?),
+                    None => (self.address(range)?, self.address(range)?),
+                };
+                if start > end {
+                    return bail(format!("range ${:04X}-${:04X} is reversed", start, end));
+                }
+                let mut text = Vec::new();
+                if command != "watch" {
+                    text.push(
+                        self.add_breakpoint(Breakpoint::Read(start..=end), condition.clone()),
+                    );
+                }
+                if command != "rwatch" {
+                    text.push(self.add_breakpoint(Breakpoint::Write(start..=end, None), condition));
+                }
+                Ok(text.join("\n"))
+            }
+            "condition" => {
+                let (id, condition) = match args.split_once(char::is_whitespace) {
+                    Some((id, condition)) => (id, Some(self.condition(condition)?)),
+                    None => (args, None),
+                };
+                let id = self.breakpoint_id(id)?;
+                self.nes
+                    .cpu_mut()
+                    .breakpoints_mut()
+                    .set_condition(id, condition);
+                Ok(self.describe(id))
+            }
+            "action" => {
+                let (id, action) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+                let id = self.breakpoint_id(id)?;
+                let action = match action.trim() {
+                    "break" => Action::Break,
+                    "log" => Action::Log,
+                    action => bail(format!("unknown action '{}', use break or log", action))?,
+                };
+                self.nes.cpu_mut().breakpoints_mut().set_action(id, action);
+                Ok(self.describe(id))
+            }
+            "delete" => {
+                let id = self.breakpoint_id(args)?;
+                self.nes.cpu_mut().breakpoints_mut().remove(id);
+                Ok(String::new())
+            }
+            "breakpoints" | "bl" => {
+                let ids: Vec<BreakpointId> = self
+                    .nes
+                    .cpu()
+                    .breakpoints()
+                    .iter()
+                    .map(|(id, _)| id)
+                    .collect();
+                let lines: Vec<String> = ids.iter().map(|id| self.describe(*id)).collect();
+                Ok(lines.join("\n"))
+            }
+            "regs" | "r" => Ok(self.registers()),
+            "backtrace" | "bt" => Ok(self.nes.cpu().backtrace()),
+            "set" => {
+                let (register, value) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+                let value = self.number(value)?;
+                self.set_register(register, value)?;
+                Ok(self.registers())
+            }
+            "mem" | "m" => {
+                let (addr, len) = self.split_count(args);
+                let addr = self.address(addr)?;
+                let len = self.optional_number(len, MEM_DUMP_LEN as usize)?;
+                Ok(self.dump(addr, len))
+            }
+            "disasm" | "u" => {
+                let (addr, count) = self.split_count(args);
+                let cpu = self.nes.cpu();
+                let instructions = if addr.is_empty() {
+                    let mut instructions = disassemble_before(cpu, cpu.pc, DISASM_BEFORE);
+                    instructions.extend(disasm::disassemble_cpu(cpu, cpu.pc, DISASM_AFTER));
+                    instructions
+                } else {
+                    let addr = self.address(addr)?;
+                    let count = self.optional_number(count, DISASM_AFTER)?;
+                    disasm::disassemble_cpu(self.nes.cpu(), addr, count)
+                };
+                let mut lines = Vec::new();
+                for instruction in instructions {
+                    if let Some(label) = cpu.label(instruction.addr) {
+                        lines.push(format!("{}:", label));
+                    }
+                    let marker = if instruction.addr == cpu.pc { '>' } else { ' ' };
+                    let text =
+                        instruction.to_string_with(|addr| cpu.label(addr).map(str::to_string));
+                    lines.push(format!("{} {:04X}  {}", marker, instruction.addr, text));
+                }
+                Ok(lines.join("\n"))
+            }
+            "symbols" => {
+                if args.is_empty() {
+                    return bail("missing symbol file".to_string());
+                }
+                let count = self.nes.cpu_mut().symbols_mut().load_file(args)?;
+                Ok(format!("loaded {} labels from {}", count, args))
+            }
+            "cdl" => self.code_data_log(args),
+            "profile" => self.profile(args),
+            "reset" => {
+                self.nes.reset();
+                Ok(self.current_instruction())
+            }
+            "quit" | "q" => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            _ => bail(format!("unknown command '{}', try help", command)),
+        }
+    }
+
+    // Runs instructions until a breakpoint fires or |done| returns true after an instruction,
+    // for the frame limit at most. |first| skips the breakpoints at the program counter, so that
+    // running goes on after stopping there.
+    fn run_until<F: FnMut(&Nes) -> bool>(&mut self, first: bool, done: F) -> Stop {
+        self.run_frames(first, self.frame_limit, done)
+    }
+
+    // Like run_until(), for |frames| at most.
+    fn run_frames<F: FnMut(&Nes) -> bool>(
+        &mut self,
+        first: bool,
+        frames: u64,
+        mut done: F,
+    ) -> Stop {
+        let end = self.nes.ppu().frame_count() + frames;
+        let mut limited = false;
+        let reason = breakpoint::run_until(&mut self.nes, first, |nes| {
+            limited = nes.ppu().frame_count() >= end;
+            done(nes) || limited
+        });
+        match reason {
+            Some(reason) => Stop::Breakpoint(reason),
+            None if limited => Stop::FrameLimit(frames),
+            None => Stop::Done,
+        }
+    }
+
+    // Runs until the subroutine called by the instruction at the program counter returns, or
+    // executes the instruction if it is not JSR.
+    fn step_over(&mut self) -> Stop {
+        let cpu = self.nes.cpu();
+        let instruction = disasm::decode(cpu.pc, |addr| cpu.peek_mem(addr));
+        if instruction.mnemonic != Some("JSR") {
+            return self.run_until(true, |_| true);
+        }
+        let (ret, sp) = (cpu.pc.wrapping_add(instruction.len()), cpu.sp);
+        self.run_until(true, |nes| nes.cpu().pc == ret && nes.cpu().sp == sp)
+    }
+
+    // Runs until RTS or RTI returns from the current subroutine or interrupt handler, i.e. pops
+    // above the stack pointer it started with.
+    fn step_out(&mut self) -> Stop {
+        let sp = self.nes.cpu().sp;
+        let mut returning = is_return(self.nes.cpu());
+        self.run_until(true, |nes| {
+            let done = returning && nes.cpu().sp > sp;
+            returning = is_return(nes.cpu());
+            done
+        })
+    }
+
+    // Describes why running stopped, followed by the lines logged by breakpoints and the next
+    // instruction.
+    fn stopped(&mut self, stop: Stop) -> String {
+        let mut lines = self.nes.cpu().breakpoints().take_log();
+        match stop {
+            Stop::Breakpoint(reason) => lines.push(match reason {
+                StopReason::Brk => "BRK".to_string(),
+                StopReason::IllegalOpcode(code) => format!("illegal opcode ${:02X}", code),
+                StopReason::Breakpoint(id) => format!("breakpoint #{}", id),
+                StopReason::Watchpoint { id, access } => format!(
+                    "watchpoint #{}: {:?} ${:04X} = ${:02X}",
+                    id, access.kind, access.addr, access.val
+                ),
+            }),
+            Stop::FrameLimit(frames) => {
+                lines.push(format!("no breakpoint fired in {} frames", frames))
+            }
+            Stop::Done => {}
+        }
+        lines.push(self.current_instruction());
+        lines.join("\n")
+    }
+
+    fn current_instruction(&self) -> String {
+        let cpu = self.nes.cpu();
+        let line = trace::trace_line(cpu, cpu.ppu_position());
+        match cpu.label(cpu.pc) {
+            Some(label) => format!("{}:\n{}", label, line),
+            None => line,
+        }
+    }
+
+    // Starts, saves or stops the code/data log, then tells how much of the ROM it covers.
+    fn code_data_log(&mut self, args: &str) -> Result<String, SimpleError> {
+        let (command, path) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        let path = path.trim();
+        let cartridge = self.nes.cartridge_mut();
+        match command {
+            "" => {}
+            "start" if path.is_empty() => {
+                let log = cartridge.new_code_data_log();
+                cartridge.set_code_data_log(Some(log));
+            }
+            "start" => {
+                let log = CodeDataLog::from_file(
+                    path,
+                    cartridge.prg_rom_size(),
+                    cartridge.chr_rom_size(),
+                )?;
+                cartridge.set_code_data_log(Some(log));
+            }
+            "save" if path.is_empty() => return bail("missing CDL file".to_string()),
+            "save" => match cartridge.code_data_log() {
+                Some(log) => log.save_to_file(path)?,
+                None => return bail("not logging code and data".to_string()),
+            },
+            "stop" => {
+                cartridge.set_code_data_log(None);
+                return Ok("stopped logging code and data".to_string());
+            }
+            _ => return bail(format!("unknown cdl command '{}'", command)),
+        }
+        let log = match cartridge.code_data_log() {
+            Some(log) => log,
+            None => return Ok("not logging code and data".to_string()),
+        };
+        let mut text = format!(
+            "PRG ROM: {} code, {} data, {} of {} bytes used",
+            log.count_prg(PRG_CODE),
+            log.count_prg(PRG_DATA),
+            log.count_prg(PRG_CODE | PRG_DATA),
+            log.prg().len()
+        );
+        if !log.chr().is_empty() {
+            text.push_str(&format!(
+                "\nCHR ROM: {} rendered, {} read, {} of {} bytes used",
+                log.count_chr(CHR_RENDERED),
+                log.count_chr(CHR_READ),
+                log.count_chr(CHR_RENDERED | CHR_READ),
+                log.chr().len()
+            ));
+        }
+        Ok(text)
+    }
+
+    // Starts, saves or stops the profiler, then shows what takes the most cycles.
+    fn profile(&mut self, args: &str) -> Result<String, SimpleError> {
+        let (command, path) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        let path = path.trim();
+        match command {
+            "start" => {
+                self.nes.cpu_mut().set_profiler(Some(Profiler::new()));
+                return Ok("profiling".to_string());
+            }
+            "stop" => {
+                self.nes.cpu_mut().set_profiler(None);
+                return Ok("stopped profiling".to_string());
+            }
+            "" | "save" => {}
+            _ => return bail(format!("unknown profile command '{}'", command)),
+        }
+        let cpu = self.nes.cpu();
+        let profiler = match cpu.profiler() {
+            Some(profiler) => profiler,
+            None => return bail("not profiling".to_string()),
+        };
+        let label = |addr| cpu.label(addr).map(str::to_string);
+        if command == "save" {
+            if path.is_empty() {
+                return bail("missing file".to_string());
+            }
+            std::fs::write(path, profiler.folded_stacks(label)).map_err(SimpleError::from)?;
+            return Ok(format!("saved call stacks to {}", path));
+        }
+        Ok(profiler.report(label, PROFILE_REPORT_LEN))
+    }
+
+    fn add_breakpoint(&mut self, breakpoint: Breakpoint, condition: Option<Condition>) -> String {
+        let breakpoints = self.nes.cpu_mut().breakpoints_mut();
+        let id = breakpoints.add(breakpoint);
+        breakpoints.set_condition(id, condition);
+        self.describe(id)
+    }
+
+    // Describes the breakpoint |id|, e.g. "#1 write $2000-$2007 if A == 0 (log, 3 hits)".
+    fn describe(&self, id: BreakpointId) -> String {
+        let breakpoints = self.nes.cpu().breakpoints();
+        let range = |range: &std::ops::RangeInclusive<u16>| {
+            if range.start() == range.end() {
+                format!("${:04X}", range.start())
+            } else {
+                format!("${:04X}-${:04X}", range.start(), range.end())
+            }
+        };
+        let mut text = match breakpoints.get(id) {
+            Some(Breakpoint::Execute(addr)) => match self.nes.cpu().label(*addr) {
+                Some(label) => format!("#{} execute ${:04X} <{}>", id, addr, label),
+                None => format!("#{} execute ${:04X}", id, addr),
+            },
+            Some(Breakpoint::Read(addrs)) => format!("#{} read {}", id, range(addrs)),
+            Some(Breakpoint::Write(addrs, None)) => format!("#{} write {}", id, range(addrs)),
+            Some(Breakpoint::Write(addrs, Some(val))) => {
+                format!("#{} write ${:02X} to {}", id, val, range(addrs))
+            }
+            Some(Breakpoint::Opcode(code)) => format!("#{} opcode ${:02X}", id, code),
+            Some(Breakpoint::Brk) => format!("#{} BRK", id),
+            None => return format!("#{} deleted", id),
+        };
+        if let Some(condition) = breakpoints.condition(id) {
+            text.push_str(&format!(" if {}", condition));
+        }
+        let hits = breakpoints.hits(id).unwrap_or(0);
+        let action = match breakpoints.action(id) {
+            Some(Action::Log) => "log, ",
+            _ => "",
+        };
+        text.push_str(&format!(
+            " ({}{} hit{})",
+            action,
+            hits,
+            if hits == 1 { "" } else { "s" }
+        ));
+        text
+    }
+
+    fn registers(&self) -> String {
+        let cpu = self.nes.cpu();
+        let flags: String = [
+            (Status::N, 'N'),
+            (Status::V, 'V'),
+            (Status::empty(), '-'),
+            (Status::B, 'B'),
+            (Status::D, 'D'),
+            (Status::I, 'I'),
+            (Status::Z, 'Z'),
+            (Status::C, 'C'),
+        ]
+        .iter()
+        .map(|(flag, name)| match *name {
+            '-' => '-',
+            name if cpu.reg_status.contains(*flag) => name,
+            name => name.to_ascii_lowercase(),
+        })
+        .collect();
+        let mut text = format!(
+            "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} [{}] SP:{:02X} CYC:{}",
+            cpu.pc,
+            cpu.reg_a,
+            cpu.reg_x,
+            cpu.reg_y,
+            cpu.reg_status.bits() | STATUS_BIT5,
+            flags,
+            cpu.sp,
+            cpu.cycles
+        );
+        if let Some((scanline, dot)) = cpu.ppu_position() {
+            text.push_str(&format!(" PPU:{},{}", scanline, dot));
+        }
+        text
+    }
+
+    fn set_register(&mut self, register: &str, value: i64) -> Result<(), SimpleError> {
+        let cpu = self.nes.cpu_mut();
+        let flag = match register.to_ascii_lowercase().as_str() {
+            "a" => return set_byte(&mut cpu.reg_a, value),
+            "x" => return set_byte(&mut cpu.reg_x, value),
+            "y" => return set_byte(&mut cpu.reg_y, value),
+            "sp" => return set_byte(&mut cpu.sp, value),
+            "p" => {
+                let mut p = 0;
+                set_byte(&mut p, value)?;
+                cpu.reg_status = Status::from_bits_truncate(p);
+                return Ok(());
+            }
+            "pc" => {
+                cpu.pc = to_address(value)?;
+                return Ok(());
+            }
+            "c" => Status::C,
+            "z" => Status::Z,
+            "i" => Status::I,
+            "d" => Status::D,
+            "v" => Status::V,
+            "n" => Status::N,
+            _ => return bail(format!("unknown register '{}'", register)),
+        };
+        cpu.reg_status.set(flag, value != 0);
+        Ok(())
+    }
+
+    // Dumps |len| bytes from |addr|, 16 per line, with their ASCII text.
+    fn dump(&self, addr: u16, len: usize) -> String {
+        let cpu = self.nes.cpu();
+        let bytes: Vec<u8> = (0..len)
+            .map(|i| cpu.peek_mem(addr.wrapping_add(i as u16)))
+            .collect();
+        bytes
+            .chunks(MEM_DUMP_WIDTH as usize)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+                let text: String = chunk
+                    .iter()
+                    .map(|byte| match *byte {
+                        0x20..=0x7e => *byte as char,
+                        _ => '.',
+                    })
+                    .collect();
+                format!(
+                    "{:04X}  {:<47}  {}",
+                    addr.wrapping_add(i as u16 * MEM_DUMP_WIDTH),
+                    hex.join(" "),
+                    text
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn number(&self, text: &str) -> Result<i64, SimpleError> {
+        if text.trim().is_empty() {
+            return bail("missing number".to_string());
+        }
+        Ok(self.condition(text)?.eval(self.nes.cpu()))
+    }
+
+    // Parses |text| as a condition, with the labels of the loaded symbols.
+    fn condition(&self, text: &str) -> Result<Condition, SimpleError> {
+        let cpu = self.nes.cpu();
+        Condition::parse_with(text, |name| cpu.label_address(name))
+    }
+
+    // Splits "addr if condition" into the address and the condition.
+    fn split_condition<'a>(
+        &self,
+        args: &'a str,
+    ) -> Result<(&'a str, Option<Condition>), SimpleError> {
+        match args.find(" if ") {
+            Some(pos) => Ok((&args[..pos], Some(self.condition(&args[pos + 4..])?))),
+            None => Ok((args, None)),
+        }
+    }
+
+    // Splits "addr [count]", where the address may be an expression with spaces. The count is
+    // the last word, unless the whole text is an address.
+    fn split_count<'a>(&self, args: &'a str) -> (&'a str, &'a str) {
+        if args.is_empty() || self.number(args).is_ok() {
+            return (args, "");
+        }
+        args.rsplit_once(char::is_whitespace).unwrap_or((args, ""))
+    }
+
+    fn optional_number(&self, text: &str, default: usize) -> Result<usize, SimpleError> {
+        if text.trim().is_empty() {
+            return Ok(default);
+        }
+        match self.number(text)? {
+            number if number >= 0 => Ok(number as usize),
+            number => bail(format!("invalid count {}", number)),
+        }
+    }
+
+    fn address(&self, text: &str) -> Result<u16, SimpleError> {
+        to_address(self.number(text)?)
+    }
+
+    fn breakpoint_id(&self, text: &str) -> Result<BreakpointId, SimpleError> {
+        let id = text
+            .trim()
+            .trim_start_matches('#')
+            .parse()
+            .map_err(|_| SimpleError::new(format!("invalid breakpoint '{}'", text)))?;
+        match self.nes.cpu().breakpoints().get(id) {
+            Some(_) => Ok(id),
+            None => bail(format!("no breakpoint #{}", id)),
+        }
+    }
+}
+
+fn bail<T>(message: String) -> Result<T, SimpleError> {
+    Err(SimpleError::new(message))
+}
+
+fn to_address(value: i64) -> Result<u16, SimpleError> {
+    if (0..=0xffff).contains(&value) {
+        Ok(value as u16)
+    } else {
+        bail(format!("invalid address {}", value))
+    }
+}
+
+fn set_byte(reg: &mut u8, value: i64) -> Result<(), SimpleError> {
+    if (-0x80..=0xff).contains(&value) {
+        *reg = value as u8;
+        Ok(())
+    } else {
+        bail(format!("{} does not fit in a byte", value))
+    }
+}
+
+// Whether the instruction at the program counter of |cpu| is RTS or RTI.
+fn is_return(cpu: &CPU) -> bool {
+    let code = cpu.peek_mem(cpu.pc);
+    matches!(
+        disasm::decode(cpu.pc, |_| code).mnemonic,
+        Some("RTS") | Some("RTI")
+    )
+}
+
+// Disassembles up to |count| instructions before |addr|. Code is decoded from further back
+// until it falls in step with |addr|; instructions take 3 bytes at most.
+fn disassemble_before(cpu: &CPU, addr: u16, count: usize) -> Vec<Instruction> {
+    for back in (1..=count as u16 * 3).rev() {
+        let mut instructions = Vec::new();
+        let mut pc = addr.wrapping_sub(back);
+        while pc != addr && addr.wrapping_sub(pc) <= back {
+            let instruction = disasm::decode(pc, |a| cpu.peek_mem(a));
+            pc = pc.wrapping_add(instruction.len());
+            instructions.push(instruction);
+        }
+        if pc == addr {
+            let skip = instructions.len().saturating_sub(count);
+            return instructions.split_off(skip);
+        }
+    }
+    Vec::new()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler;
+    use crate::cartridge::test::nrom_image;
+    use crate::cartridge::Cartridge;
+
+    fn debugger() -> Debugger {
+        let program = assembler::assemble(
+            "
+                LDX #0
+            loop:
+                JSR add
+                STA $10
+                JMP loop
+            add:
+                INX
+                TXA
+                JSR double
+                RTS
+            double:
+                ASL A
+                RTS
+            ",
+        )
+        .unwrap();
+        Debugger::new(Nes::new(
+            Cartridge::parse(&nrom_image(&program.bytes)).unwrap(),
+        ))
+    }
+
+    fn pc(debugger: &Debugger) -> u16 {
+        debugger.nes().cpu().pc
+    }
+
+    #[test]
+    fn test_step() {
+        let mut debugger = debugger();
+
+        let text = debugger.execute("step").unwrap();
+        assert_eq!(text.starts_with("8002  20 0A 80  JSR $800A"), true);
+        assert_eq!(
+            debugger.execute("s 2").unwrap().starts_with("800B  8A"),
+            true
+        );
+        // An empty line repeats the last command that runs the console.
+        debugger.execute("regs").unwrap();
+        debugger.execute("").unwrap();
+        assert_eq!(pc(&debugger), 0x8010);
+        assert_eq!(
+            debugger.execute("finish").unwrap().starts_with("800F  60"),
+            true
+        );
+        debugger.execute("o").unwrap();
+        assert_eq!(pc(&debugger), 0x8005);
+        assert_eq!(debugger.nes().cpu().reg_a, 2);
+
+        debugger.execute("set pc $8002").unwrap();
+        debugger.execute("next").unwrap();
+        assert_eq!(pc(&debugger), 0x8005);
+        assert_eq!(debugger.nes().cpu().reg_a, 4);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = debugger();
+
+        assert_eq!(
+            debugger.execute("break $800A if X == 2").unwrap(),
+            "#0 execute $800A if X == 2 (0 hits)"
+        );
+        assert_eq!(
+            debugger.execute("watch $10").unwrap(),
+            "#1 write $0010 (0 hits)"
+        );
+        assert_eq!(
+            debugger.execute("action 1 log").unwrap(),
+            "#1 write $0010 (log, 0 hits)"
+        );
+        let text = debugger.execute("continue").unwrap();
+        assert_eq!(
+            text.lines().collect::<Vec<&str>>()[..3],
+            [
+                "#1 Write $0010 = $02 CYC:39",
+                "#1 Write $0010 = $04 CYC:75",
+                "breakpoint #0"
+            ]
+        );
+        assert_eq!(pc(&debugger), 0x800a);
+
+        debugger.execute("delete 0").unwrap();
+        debugger.execute("action 1 break").unwrap();
+        assert_eq!(
+            debugger.execute("condition 1 A == 6").unwrap(),
+            "#1 write $0010 if A == 6 (2 hits)"
+        );
+        let text = debugger.execute("c").unwrap();
+        assert_eq!(text.starts_with("watchpoint #1: Write $0010 = $06\n"), true);
+        assert_eq!(
+            debugger.execute("bl").unwrap(),
+            "#1 write $0010 if A == 6 (3 hits)"
+        );
+
+        assert_eq!(
+            debugger.execute("rwatch $10-$1f").unwrap(),
+            "#2 read $0010-$001F (0 hits)"
+        );
+        assert_eq!(
+            debugger.execute("watch $2007-$2000").unwrap_err().as_str(),
+            "range $2007-$2000 is reversed"
+        );
+        assert_eq!(
+            debugger.execute("delete 3").unwrap_err().as_str(),
+            "no breakpoint #3"
+        );
+    }
+
+    #[test]
+    fn test_frame_limit() {
+        let mut debugger = debugger();
+        debugger.set_frame_limit(2);
+
+        let text = debugger.execute("continue").unwrap();
+        assert_eq!(text.starts_with("no breakpoint fired in 2 frames\n"), true);
+        assert_eq!(debugger.nes().ppu().frame_count(), 2);
+        // The frame command is not limited.
+        assert_eq!(
+            debugger.execute("frame 3").unwrap().starts_with("no"),
+            false
+        );
+        assert_eq!(debugger.nes().ppu().frame_count(), 5);
+    }
+
+    #[test]
+    fn test_inspect() {
+        let mut debugger = debugger();
+        debugger.execute("set a $41").unwrap();
+        debugger.execute("set c 1").unwrap();
+
+        assert_eq!(
+            debugger.execute("regs").unwrap(),
+            "PC:8000 A:41 X:00 Y:00 P:25 [nv-bdIzC] SP:FD CYC:7 PPU:0,0"
+        );
+        assert_eq!(
+            debugger.execute("mem $FFFA 6").unwrap(),
+            format!("FFFA  {:<47}  ......", "00 FF 00 80 00 FF")
+        );
+        debugger.execute("step 3").unwrap();
+        assert_eq!(
+            debugger.execute("disasm").unwrap(),
+            "  8002  JSR $800A\n  8005  STA $10\n  8007  JMP $8002\n  800A  INX\n> 800B  TXA\n\
+             \x20 800C  JSR $8010\n  800F  RTS\n  8010  ASL A\n  8011  RTS\n  8012  NOP\n\
+             \x20 8013  NOP\n  8014  NOP"
+        );
+        assert_eq!(
+            debugger.execute("u $8010 2").unwrap(),
+            "  8010  ASL A\n  8011  RTS"
+        );
+        assert_eq!(
+            debugger.execute("u $8000 + 16 2").unwrap(),
+            "  8010  ASL A\n  8011  RTS"
+        );
+        assert_eq!(
+            debugger
+                .execute("mem [$FFFC] + 3")
+                .unwrap()
+                .starts_with("0003  "),
+            true
+        );
+        assert_eq!(
+            debugger.execute("set q 1").unwrap_err().as_str(),
+            "unknown register 'q'"
+        );
+        assert_eq!(
+            debugger.execute("jump").unwrap_err().as_str(),
+            "unknown command 'jump', try help"
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut debugger = debugger();
+        let symbols = debugger.nes_mut().cpu_mut().symbols_mut();
+        symbols.add("loop", 0x8002, Some(0));
+        symbols.add("add", 0x800a, Some(0));
+        symbols.add("total", 0x0010, None);
+
+        assert_eq!(
+            debugger.execute("break add if X == 2").unwrap(),
+            "#0 execute $800A <add> if X == 2 (0 hits)"
+        );
+        assert_eq!(
+            debugger.execute("continue").unwrap(),
+            "breakpoint #0\nadd:\n800A  E8        INX                             \
+             A:04 X:02 Y:00 P:24 SP:FB PPU:  0,261 CYC:87"
+        );
+        assert_eq!(
+            debugger.execute("u loop 3").unwrap(),
+            "loop:\n  8002  JSR add\n  8005  STA total\n  8007  JMP loop"
+        );
+        assert_eq!(
+            debugger.execute("mem total+1 1").unwrap(),
+            format!("0011  {:<47}  .", "00")
+        );
+        assert_eq!(
+            debugger.execute("symbols").unwrap_err().as_str(),
+            "missing symbol file"
+        );
+    }
+
+    #[test]
+    fn test_code_data_log() {
+        let mut debugger = debugger();
+        let path = std::env::temp_dir().join(format!("debugger_test_{}.cdl", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        assert_eq!(
+            debugger.execute("cdl").unwrap(),
+            "not logging code and data"
+        );
+        debugger.execute("cdl start").unwrap();
+        debugger.execute("step 3").unwrap();
+        assert_eq!(
+            debugger.execute("cdl").unwrap(),
+            "PRG ROM: 6 code, 0 data, 6 of 32768 bytes used\n\
+             CHR ROM: 0 rendered, 0 read, 0 of 8192 bytes used"
+        );
+        debugger.execute(&format!("cdl save {}", path)).unwrap();
+        debugger.execute("cdl stop").unwrap();
+        debugger.execute("step").unwrap();
+        let text = debugger.execute(&format!("cdl start {}", path));
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            text.unwrap().lines().next(),
+            Some("PRG ROM: 6 code, 0 data, 6 of 32768 bytes used")
+        );
+        assert_eq!(
+            debugger.execute("cdl save").unwrap_err().as_str(),
+            "missing CDL file"
+        );
+        assert_eq!(
+            debugger.execute("cdl pause").unwrap_err().as_str(),
+            "unknown cdl command 'pause'"
+        );
+    }
+
+    #[test]
+    fn test_profile() {
+        let mut debugger = debugger();
+        debugger
+            .nes_mut()
+            .cpu_mut()
+            .symbols_mut()
+            .add("add", 0x800a, None);
+
+        assert_eq!(
+            debugger.execute("profile").unwrap_err().as_str(),
+            "not profiling"
+        );
+        debugger.execute("profile start").unwrap();
+        debugger.execute("step 100").unwrap();
+        let report = debugger.execute("profile").unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[1].ends_with("1  $8000"), true);
+        assert_eq!(lines[2].ends_with("  add"), true);
+        // Headers, 3 routines, a blank line and the 10 instructions of the program.
+        assert_eq!(lines.len(), 2 + 3 + 1 + 10);
+
+        let path =
+            std::env::temp_dir().join(format!("debugger_test_{}.folded", std::process::id()));
+        let path = path.to_str().unwrap();
+        debugger.execute(&format!("profile save {}", path)).unwrap();
+        let folded = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            folded
+                .lines()
+                .map(|line| line.rsplit_once(' ').unwrap().0)
+                .collect::<Vec<_>>(),
+            vec!["$8000", "$8000;add", "$8000;add;$8010"]
+        );
+        assert_eq!(
+            debugger.execute("profile save").unwrap_err().as_str(),
+            "missing file"
+        );
+        debugger.execute("profile stop").unwrap();
+        assert_eq!(
+            debugger.execute("profile save x").unwrap_err().as_str(),
+            "not profiling"
+        );
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut debugger = debugger();
+        debugger
+            .nes_mut()
+            .cpu_mut()
+            .symbols_mut()
+            .add("add", 0x800a, None);
+
+        assert_eq!(debugger.execute("bt").unwrap(), "#0  $8000");
+        debugger.execute("break $8010").unwrap();
+        debugger.execute("continue").unwrap();
+        assert_eq!(
+            debugger.execute("backtrace").unwrap(),
+            "#0  $8010 in $8010\n#1  $800C in add\n#2  $8002"
+        );
+        debugger.execute("finish").unwrap();
+        assert_eq!(
+            debugger.execute("bt").unwrap(),
+            "#0  $800F in add\n#1  $8002"
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        let mut debugger = debugger();
+        let mut output = Vec::new();
+
+        debugger
+            .run("s\nbogus\nq\ns\n".as_bytes(), &mut output)
+            .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1].starts_with("(nes) 8002  20 0A 80"), true);
+        assert_eq!(lines[2], "(nes) error: unknown command 'bogus', try help");
+        assert_eq!(lines[3], "(nes) ");
+        assert_eq!(debugger.is_done(), true);
+    }
+}
@@ -0,0 +1,259 @@
+/**
+ * Disassembler of 6502 machine code.
+ *
+ * Instructions are decoded with the opcode table of the CPU and printed in the usual assembler
+ * syntax, e.g. "LDA ($20),Y" or "BNE $C012" where the target of the branch is resolved. The
+ * unofficial opcodes the CPU implements are decoded too, e.g. "LAX $20". Other bytes are printed
+ * as ".byte $xx".
+ *
+ * Code is read from a buffer or from the memory of a live CPU. Reading live memory goes through
+ * peeks, so that disassembling never has side effects on memory-mapped registers.
+ */
+use crate::cpu::{self, AddressingMode, CPU};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub addr: u16,
+    // The opcode followed by the operand.
+    pub bytes: Vec<u8>,
+    // None if the CPU does not implement the opcode.
+    pub mnemonic: Option<&'static str>,
+    pub mode: AddressingMode,
+    // Whether the opcode is an official one.
+    pub official: bool,
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // The operand as a number: a byte, or a little endian word.
+    pub fn operand(&self) -> u16 {
+        match self.bytes.len() {
+            2 => self.bytes[1] as u16,
+            3 => u16::from_le_bytes([self.bytes[1], self.bytes[2]]),
+            _ => 0,
+        }
+    }
+
+    // Address the instruction jumps or branches to, if it is known without executing it.
+    pub fn target(&self) -> Option<u16> {
+        match (self.mnemonic, self.mode) {
+            (Some(_), AddressingMode::Relative) => {
+                let next = self.addr.wrapping_add(self.len());
+                Some(next.wrapping_add(self.bytes[1] as i8 as u16))
+            }
+            (Some("JMP"), AddressingMode::Absolute) | (Some("JSR"), AddressingMode::Absolute) => {
+                Some(self.operand())
+            }
+            _ => None,
+        }
+    }
+
+    // The operand as printed, e.g. "($20),Y". Empty if there is none.
+    pub fn operand_text(&self) -> String {
+        self.operand_text_with(|_| None)
+    }
+
+    // The operand as printed, with the addresses that |label| names replaced by their label,
+    // e.g. "(pointer),Y".
+    pub fn operand_text_with<F: Fn(u16) -> Option<String>>(&self, label: F) -> String {
+        let operand = self.operand();
+        let zero_page = || label(operand).unwrap_or_else(|| format!("${:02X}", operand));
+        let absolute = |addr| label(addr).unwrap_or_else(|| format!("${:04X}", addr));
+        match self.mode {
+            _ if self.mnemonic.is_none() => format!("${:02X}", self.bytes[0]),
+            AddressingMode::Immediate => format!("#${:02X}", operand),
+            AddressingMode::ZeroPage => zero_page(),
+            AddressingMode::ZeroPageX => format!("{},X", zero_page()),
+            AddressingMode::ZeroPageY => format!("{},Y", zero_page()),
+            AddressingMode::Absolute => absolute(operand),
+            AddressingMode::AbsoluteX => format!("{},X", absolute(operand)),
+            AddressingMode::AbsoluteY => format!("{},Y", absolute(operand)),
+            AddressingMode::Indirect => format!("({})", absolute(operand)),
+            AddressingMode::IndirectX => format!("({},X)", zero_page()),
+            AddressingMode::IndirectY => format!("({}),Y", zero_page()),
+            AddressingMode::Accumulator => "A".to_string(),
+            AddressingMode::Relative => absolute(self.target().unwrap()),
+            AddressingMode::NoneAddressing => String::new(),
+        }
+    }
+
+    // The instruction as printed, with the addresses that |label| names replaced by their label.
+    pub fn to_string_with<F: Fn(u16) -> Option<String>>(&self, label: F) -> String {
+        let operand = self.operand_text_with(label);
+        let mnemonic = self.mnemonic.unwrap_or(".byte");
+        if operand.is_empty() {
+            mnemonic.to_string()
+        } else {
+            format!("{} {}", mnemonic, operand)
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_with(|_| None))
+    }
+}
+
+// Decodes the instruction at |addr|, reading memory with |read|.
+pub fn decode<F: Fn(u16) -> u8>(addr: u16, read: F) -> Instruction {
+    let code = read(addr);
+    match cpu::opcode(code) {
+        Some(opcode) => Instruction {
+            addr,
+            bytes: (0..opcode.bytes as u16)
+                .map(|i| read(addr.wrapping_add(i)))
+                .collect(),
+            mnemonic: Some(opcode.name),
+            mode: opcode.addressing_mode,
+            official: opcode.official,
+        },
+        None => unknown(addr, code),
+    }
+}
+
+// Disassembles |buf|, which is loaded at |origin|. An instruction cut by the end of the buffer is
+// left as bytes.
+pub fn disassemble(buf: &[u8], origin: u16) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < buf.len() {
+        let addr = origin.wrapping_add(offset as u16);
+        let instruction = match cpu::opcode(buf[offset]) {
+            Some(opcode) if offset + opcode.bytes as usize <= buf.len() => {
+                decode(addr, |a| buf[a.wrapping_sub(origin) as usize])
+            }
+            _ => unknown(addr, buf[offset]),
+        };
+        offset += instruction.bytes.len();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+// Disassembles |count| instructions from |addr| in the memory of |cpu|, without side effects.
+pub fn disassemble_cpu(cpu: &CPU, addr: u16, count: usize) -> Vec<Instruction> {
+    let mut instructions = Vec::with_capacity(count);
+    let mut addr = addr;
+    for _ in 0..count {
+        let instruction = decode(addr, |a| cpu.peek_mem(a));
+        addr = addr.wrapping_add(instruction.len());
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+fn unknown(addr: u16, code: u8) -> Instruction {
+    Instruction {
+        addr,
+        bytes: vec![code],
+        mnemonic: None,
+        mode: AddressingMode::NoneAddressing,
+        official: false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn text(bytes: &[u8]) -> String {
+        disassemble(bytes, 0xc000)[0].to_string()
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        assert_eq!(text(&[0xa9, 0x05]), "LDA #$05");
+        assert_eq!(text(&[0xa5, 0x20]), "LDA $20");
+        assert_eq!(text(&[0xb5, 0x20]), "LDA $20,X");
+        assert_eq!(text(&[0xb6, 0x20]), "LDX $20,Y");
+        assert_eq!(text(&[0xad, 0x34, 0x12]), "LDA $1234");
+        assert_eq!(text(&[0xbd, 0x34, 0x12]), "LDA $1234,X");
+        assert_eq!(text(&[0xb9, 0x34, 0x12]), "LDA $1234,Y");
+        assert_eq!(text(&[0x6c, 0x34, 0x12]), "JMP ($1234)");
+        assert_eq!(text(&[0xa1, 0x20]), "LDA ($20,X)");
+        assert_eq!(text(&[0xb1, 0x20]), "LDA ($20),Y");
+        assert_eq!(text(&[0x0a]), "ASL A");
+        assert_eq!(text(&[0xea]), "NOP");
+    }
+
+    #[test]
+    fn test_unofficial_opcodes() {
+        let instruction = &disassemble(&[0xa7, 0x20], 0xc000)[0];
+
+        assert_eq!(instruction.to_string(), "LAX $20");
+        assert_eq!(instruction.official, false);
+        assert_eq!(text(&[0x1c, 0x34, 0x12]), "NOP $1234,X");
+        assert_eq!(disassemble(&[0xea], 0xc000)[0].official, true);
+    }
+
+    #[test]
+    fn test_branch_target() {
+        let instructions = disassemble(&[0xd0, 0x10, 0xd0, 0xfc], 0xc000);
+
+        assert_eq!(instructions[0].to_string(), "BNE $C012");
+        assert_eq!(instructions[1].to_string(), "BNE $C000");
+        assert_eq!(instructions[1].target(), Some(0xc000));
+        assert_eq!(
+            decode(0xfffe, |a| if a == 0xfffe { 0xd0 } else { 0x01 }).target(),
+            Some(0x0001)
+        );
+        assert_eq!(text(&[0x20, 0x00, 0x80]), "JSR $8000");
+        assert_eq!(
+            disassemble(&[0x20, 0x00, 0x80], 0)[0].target(),
+            Some(0x8000)
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let label = |addr| match addr {
+            0x20 => Some("pointer".to_string()),
+            0xc000 => Some("loop".to_string()),
+            _ => None,
+        };
+        let instructions = disassemble(&[0xb1, 0x20, 0xd0, 0xfc, 0xa9, 0x20, 0x0a], 0xc000);
+
+        let texts: Vec<String> = instructions
+            .iter()
+            .map(|i| i.to_string_with(label))
+            .collect();
+        assert_eq!(
+            texts,
+            vec!["LDA (pointer),Y", "BNE loop", "LDA #$20", "ASL A"]
+        );
+        assert_eq!(text(&[0x6c, 0x00, 0xc0]), "JMP ($C000)");
+    }
+
+    #[test]
+    fn test_unknown_and_truncated() {
+        let instructions = disassemble(&[0x02, 0xea, 0xad, 0x34], 0x8000);
+
+        let texts: Vec<String> = instructions.iter().map(|i| i.to_string()).collect();
+        assert_eq!(texts, vec![".byte $02", "NOP", ".byte $AD", ".byte $34"]);
+        assert_eq!(instructions[2].addr, 0x8002);
+    }
+
+    #[test]
+    fn test_disassemble_cpu() {
+        let mut cpu = CPU::new();
+        // LDA #$01
+        // STA $0200
+        cpu.load(&[0xa9, 0x01, 0x8d, 0x00, 0x02]).unwrap();
+
+        let instructions = disassemble_cpu(&cpu, 0x8000, 2);
+
+        assert_eq!(instructions[0].to_string(), "LDA #$01");
+        assert_eq!(instructions[1].to_string(), "STA $0200");
+        assert_eq!(instructions[1].addr, 0x8002);
+        assert_eq!(instructions[1].bytes, vec![0x8d, 0x00, 0x02]);
+    }
+}
@@ -0,0 +1,144 @@
+/**
+ * Four Score 4-player adapter.
+ *
+ * The adapter plugs in both ports. Each port serializes two standard controllers followed by a
+ * signature telling the game that an adapter is present: 8 bits of controller 1 (or 2), 8 bits
+ * of controller 3 (or 4), then 8 bits of signature. The adapter is modelled as one device per
+ * port since the two halves share nothing but the strobe.
+ *
+ * See https://wiki.nesdev.com/w/index.php/Four_Score
+ */
+use crate::input::{InputContext, InputDevice};
+use crate::joypad::Joypad;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use simple_error::SimpleError;
+
+// Signatures, in the order they are read, i.e. least significant bit first.
+const SIGNATURE_PORT_1: u8 = 0b0000_1000;
+const SIGNATURE_PORT_2: u8 = 0b0000_0100;
+
+const NUM_BITS: u8 = 24;
+
+pub struct FourScorePort {
+    // Controller 1 and 3 on port 1, controller 2 and 4 on port 2.
+    joypads: [Joypad; 2],
+    signature: u8,
+    strobe: bool,
+    // Index of the next bit to report.
+    bit_index: u8,
+}
+
+impl FourScorePort {
+    // The half of the adapter plugged in port 1, serving controllers 1 and 3.
+    pub fn port1() -> Self {
+        FourScorePort::new(SIGNATURE_PORT_1)
+    }
+
+    // The half of the adapter plugged in port 2, serving controllers 2 and 4.
+    pub fn port2() -> Self {
+        FourScorePort::new(SIGNATURE_PORT_2)
+    }
+
+    fn new(signature: u8) -> Self {
+        FourScorePort {
+            joypads: [Joypad::new(), Joypad::new()],
+            signature,
+            strobe: false,
+            bit_index: 0,
+        }
+    }
+
+    // |index| 0 is the first controller of this port, 1 is the second one.
+    pub fn joypad_mut(&mut self, index: usize) -> &mut Joypad {
+        &mut self.joypads[index]
+    }
+
+    fn current_bit(&self) -> u8 {
+        let index = if self.strobe { 0 } else { self.bit_index };
+        let byte = match index / 8 {
+            0 => self.joypads[0].buttons().bits(),
+            1 => self.joypads[1].buttons().bits(),
+            2 => self.signature,
+            // Later reads return 0.
+            _ => 0,
+        };
+        (byte >> (index % 8)) & 0b0000_0001
+    }
+}
+
+impl InputDevice for FourScorePort {
+    fn write(&mut self, val: u8) {
+        self.strobe = val & 0b0000_0001 != 0;
+        if self.strobe {
+            self.bit_index = 0;
+        }
+    }
+
+    fn read(&mut self, _ctx: &InputContext) -> u8 {
+        let val = self.current_bit();
+        if !self.strobe && self.bit_index < NUM_BITS {
+            self.bit_index += 1;
+        }
+        val
+    }
+
+    fn peek(&self, _ctx: &InputContext) -> u8 {
+        self.current_bit()
+    }
+}
+
+// The signature depends on the port the adapter is plugged in, so it is not part of the state.
+impl Savestate for FourScorePort {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.joypads[0].save_state(w);
+        self.joypads[1].save_state(w);
+        w.write(&self.strobe);
+        w.write(&self.bit_index);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.joypads[0].load_state(r)?;
+        self.joypads[1].load_state(r)?;
+        self.strobe = r.read()?;
+        self.bit_index = r.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::joypad::JoypadButton;
+
+    fn read_all(port: &mut FourScorePort) -> Vec<u8> {
+        port.write(1);
+        port.write(0);
+        (0..NUM_BITS + 1)
+            .map(|_| port.read(&InputContext::none()))
+            .collect()
+    }
+
+    #[test]
+    fn test_port1() {
+        let mut port = FourScorePort::port1();
+        port.joypad_mut(0).set_button_pressed(JoypadButton::A, true);
+        port.joypad_mut(1)
+            .set_button_pressed(JoypadButton::RIGHT, true);
+
+        let bits = read_all(&mut port);
+
+        assert_eq!(&bits[0..8], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&bits[8..16], &[0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(&bits[16..24], &[0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(bits[24], 0);
+    }
+
+    #[test]
+    fn test_port2_signature() {
+        let mut port = FourScorePort::port2();
+
+        let bits = read_all(&mut port);
+
+        assert_eq!(&bits[16..24], &[0, 0, 1, 0, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,38 @@
+/**
+ * A rendered picture of 256x240 RGB pixels.
+ */
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+pub struct Frame {
+    // RGB, row by row.
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Frame {
+            data: vec![0; WIDTH * HEIGHT * 3],
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+        let base = (y * WIDTH + x) * 3;
+        if base + 2 < self.data.len() {
+            self.data[base] = rgb.0;
+            self.data[base + 1] = rgb.1;
+            self.data[base + 2] = rgb.2;
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * WIDTH + x) * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
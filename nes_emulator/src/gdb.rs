@@ -0,0 +1,652 @@
+/**
+ * Stub of the GDB remote serial protocol, so that debugger front ends can attach to the CPU over
+ * TCP, e.g. with "target remote localhost:2159".
+ *
+ * The stub reads and writes registers and memory, sets breakpoints and watchpoints (Z0 to Z4),
+ * steps and continues; Ctrl-C interrupts a running target. Memory is read without side effects.
+ * Writes go through the bus like the ones of the CPU, except that PRG ROM cannot be written: the
+ * cartridge would take them as mapper register writes and switch banks. There is a single thread,
+ * and packets are acknowledged.
+ *
+ * Registers, as sent by the g packet and numbered for p and P: A, X, Y, P and SP of one byte each,
+ * then PC of two bytes in little endian. The client can read them from the target description,
+ * target.xml.
+ *
+ * See https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+ */
+use crate::breakpoint::{self, Breakpoint, BreakpointId, StopReason, Target};
+use crate::cpu::{Status, STATUS_BIT5};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+const PACKET_SIZE: usize = 0x1000;
+// Instructions between two checks for an interrupt from the client.
+const POLL_INTERVAL: usize = 10_000;
+const INTERRUPT: u8 = 0x03;
+
+// Signals reported when the target stops.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+const REGISTER_PC: usize = 5;
+
+// Target description, served by qXfer:features:read.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nes_emulator.6502">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// The packet stream of a client.
+struct Connection {
+    stream: TcpStream,
+    // Bytes received but not handled yet.
+    pending: Vec<u8>,
+}
+
+impl Connection {
+    // Returns the next byte, or None when the client disconnected.
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.pending.is_empty() {
+            let mut buf = [0; 256];
+            let len = self.stream.read(&mut buf)?;
+            self.pending.extend_from_slice(&buf[..len]);
+            if len == 0 {
+                return Ok(None);
+            }
+        }
+        Ok(Some(self.pending.remove(0)))
+    }
+
+    // Returns the data of the next packet, or None when the client disconnected. Interrupts
+    // received while the target is stopped are answered with the stop reason.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(INTERRUPT) => return Ok(Some("?".to_string())),
+                // Acknowledgements and noise between packets.
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(byte) => *digit = byte,
+                }
+            }
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                == Some(self::checksum(&data));
+            if !valid {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+            self.stream.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+
+    // Returns whether the client sent an interrupt or disconnected, without waiting.
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 256];
+        self.stream.set_nonblocking(true)?;
+        let result = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => return Ok(true),
+            Ok(len) => self.pending.extend_from_slice(&buf[..len]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        match self.pending.iter().position(|byte| *byte == INTERRUPT) {
+            Some(pos) => {
+                self.pending.remove(pos);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+pub struct GdbStub<T: Target> {
+    target: T,
+    // The breakpoints set by the client, by Z packet type, address and length.
+    breakpoints: HashMap<(u8, u16, u16), Vec<BreakpointId>>,
+    // Why the target last stopped, as a stop reply.
+    last_stop: String,
+}
+
+impl<T: Target> GdbStub<T> {
+    pub fn new(target: T) -> Self {
+        GdbStub {
+            target,
+            breakpoints: HashMap::new(),
+            last_stop: format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    pub fn target(&self) -> &T {
+        &self.target
+    }
+
+    pub fn target_mut(&mut self) -> &mut T {
+        &mut self.target
+    }
+
+    // Waits for a client on |addr| and serves it.
+    pub fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        self.serve(stream)
+    }
+
+    // Serves the client on |stream| until it detaches, kills the target or disconnects. The
+    // breakpoints of the client are removed then.
+    pub fn serve(&mut self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let mut connection = Connection {
+            stream,
+            pending: Vec::new(),
+        };
+        let result = self.serve_packets(&mut connection);
+        for ids in self.breakpoints.drain().map(|(_, ids)| ids) {
+            for id in ids {
+                self.target.cpu_mut().breakpoints_mut().remove(id);
+            }
+        }
+        result
+    }
+
+    fn serve_packets(&mut self, connection: &mut Connection) -> io::Result<()> {
+        while let Some(packet) = connection.read_packet()? {
+            let reply = match packet.as_bytes().first() {
+                Some(b'c') => self.resume(connection, &packet[1..], false)?,
+                Some(b's') => self.resume(connection, &packet[1..], true)?,
+                Some(b'D') => {
+                    connection.write_packet("OK")?;
+                    return Ok(());
+                }
+                Some(b'k') => return Ok(()),
+                _ => self.handle(&packet).unwrap_or_default(),
+            };
+            connection.write_packet(&reply)?;
+        }
+        Ok(())
+    }
+
+    // Answers |packet|, or returns None if it is not supported.
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let mut chars = packet.chars();
+        let command = chars.next()?;
+        let args = chars.as_str();
+        match command {
+            '?' => Some(self.last_stop.clone()),
+            'g' => Some(
+                (0..=REGISTER_PC)
+                    .map(|reg| self.register(reg))
+                    .collect::<Vec<String>>()
+                    .concat(),
+            ),
+            'G' => {
+                let bytes = from_hex(args)?;
+                if bytes.len() != REGISTER_PC + 2 {
+                    return Some("E01".to_string());
+                }
+                for reg in 0..=REGISTER_PC {
+                    self.set_register(reg, &bytes[reg..]);
+                }
+                Some("OK".to_string())
+            }
+            'p' => {
+                let reg = usize::from_str_radix(args, 16).ok()?;
+                Some(match reg {
+                    0..=REGISTER_PC => self.register(reg),
+                    _ => "E01".to_string(),
+                })
+            }
+            'P' => {
+                let (reg, val) = args.split_once('=')?;
+                let reg = usize::from_str_radix(reg, 16).ok()?;
+                let bytes = from_hex(val)?;
+                let len = if reg == REGISTER_PC { 2 } else { 1 };
+                if reg > REGISTER_PC || bytes.len() != len {
+                    return Some("E01".to_string());
+                }
+                self.set_register(reg, &bytes);
+                Some("OK".to_string())
+            }
+            'm' => {
+                let (addr, len) = parse_range(args)?;
+                let cpu = self.target.cpu();
+                let bytes: Vec<u8> = (0..len.min(PACKET_SIZE as u16 / 2))
+                    .map(|i| cpu.peek_mem(addr.wrapping_add(i)))
+                    .collect();
+                Some(to_hex(&bytes))
+            }
+            'M' => {
+                let (range, data) = args.split_once(':')?;
+                let (addr, len) = parse_range(range)?;
+                let bytes = from_hex(data)?;
+                if bytes.len() != len as usize {
+                    return Some("E01".to_string());
+                }
+                let cpu = self.target.cpu_mut();
+                if (0..len).any(|i| cpu.prg_rom_offset(addr.wrapping_add(i)).is_some()) {
+                    return Some("E01".to_string());
+                }
+                for (i, byte) in bytes.iter().enumerate() {
+                    cpu.poke_mem(addr.wrapping_add(i as u16), *byte);
+                }
+                Some("OK".to_string())
+            }
+            'Z' | 'z' => {
+                let mut fields = args.split(',');
+                let kind = fields.next()?.parse().ok()?;
+                let addr = u16::from_str_radix(fields.next()?, 16).ok()?;
+                let len = u16::from_str_radix(fields.next()?, 16).ok()?.max(1);
+                if command == 'Z' {
+                    self.add_breakpoint(kind, addr, len)
+                } else {
+                    self.remove_breakpoint(kind, addr, len)
+                }
+            }
+            'H' | 'T' => Some("OK".to_string()),
+            'q' => match args.split(':').next()? {
+                "Supported" => Some(format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)),
+                "Xfer" => read_features(args),
+                "Attached" => Some("1".to_string()),
+                "C" => Some("QC1".to_string()),
+                "fThreadInfo" => Some("m1".to_string()),
+                "sThreadInfo" => Some("l".to_string()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn register(&self, reg: usize) -> String {
+        let cpu = self.target.cpu();
+        match reg {
+            0 => to_hex(&[cpu.reg_a]),
+            1 => to_hex(&[cpu.reg_x]),
+            2 => to_hex(&[cpu.reg_y]),
+            3 => to_hex(&[cpu.reg_status.bits() | STATUS_BIT5]),
+            4 => to_hex(&[cpu.sp]),
+            _ => to_hex(&cpu.pc.to_le_bytes()),
+        }
+    }
+
+    // Sets the register |reg| from the start of |bytes|.
+    fn set_register(&mut self, reg: usize, bytes: &[u8]) {
+        let cpu = self.target.cpu_mut();
+        match reg {
+            0 => cpu.reg_a = bytes[0],
+            1 => cpu.reg_x = bytes[0],
+            2 => cpu.reg_y = bytes[0],
+            3 => cpu.reg_status = Status::from_bits_truncate(bytes[0]) - Status::B,
+            4 => cpu.sp = bytes[0],
+            _ => cpu.pc = u16::from_le_bytes([bytes[0], bytes[1]]),
+        }
+    }
+
+    // Adds the breakpoint of Z packet type |kind|: 0 and 1 on execution, 2 on write, 3 on read
+    // and 4 on access, of |len| bytes from |addr|.
+    fn add_breakpoint(&mut self, kind: u8, addr: u16, len: u16) -> Option<String> {
+        let range = addr..=addr.saturating_add(len - 1);
+        let breakpoints: Vec<Breakpoint> = match kind {
+            0 | 1 => vec![Breakpoint::Execute(addr)],
+            2 => vec![Breakpoint::Write(range, None)],
+            3 => vec![Breakpoint::Read(range)],
+            4 => vec![
+                Breakpoint::Read(range.clone()),
+                Breakpoint::Write(range, None),
+            ],
+            _ => return None,
+        };
+        let ids = breakpoints
+            .into_iter()
+            .map(|breakpoint| self.target.cpu_mut().breakpoints_mut().add(breakpoint))
+            .collect();
+        if let Some(old) = self.breakpoints.insert((kind, addr, len), ids) {
+            for id in old {
+                self.target.cpu_mut().breakpoints_mut().remove(id);
+            }
+        }
+        Some("OK".to_string())
+    }
+
+    fn remove_breakpoint(&mut self, kind: u8, addr: u16, len: u16) -> Option<String> {
+        if kind > 4 {
+            return None;
+        }
+        for id in self
+            .breakpoints
+            .remove(&(kind, addr, len))
+            .unwrap_or_default()
+        {
+            self.target.cpu_mut().breakpoints_mut().remove(id);
+        }
+        Some("OK".to_string())
+    }
+
+    // Runs from |addr|, or from the program counter if empty, until a breakpoint fires or the
+    // client interrupts, or for one instruction if |step|. Returns the stop reply.
+    fn resume(
+        &mut self,
+        connection: &mut Connection,
+        addr: &str,
+        step: bool,
+    ) -> io::Result<String> {
+        if let Ok(addr) = u16::from_str_radix(addr, 16) {
+            self.target.cpu_mut().pc = addr;
+        }
+        let mut count = 0;
+        let mut interrupt = Ok(false);
+        let reason = breakpoint::run_until(&mut self.target, true, |_| {
+            count += 1;
+            if step {
+                return true;
+            }
+            if count % POLL_INTERVAL == 0 {
+                interrupt = connection.poll_interrupt();
+            }
+            !matches!(interrupt, Ok(false))
+        });
+        let reply = match reason {
+            Some(reason) => self.stop_reply(reason),
+            None if interrupt? => format!("S{:02x}", SIGINT),
+            None => format!("S{:02x}", SIGTRAP),
+        };
+        self.last_stop = reply.clone();
+        Ok(reply)
+    }
+
+    fn stop_reply(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::IllegalOpcode(_) => format!("S{:02x}", SIGILL),
+            StopReason::Watchpoint { id, access } => {
+                let kind = self
+                    .breakpoints
+                    .iter()
+                    .find(|(_, ids)| ids.contains(&id))
+                    .map_or(2, |((kind, _, _), _)| *kind);
+                let name = match kind {
+                    3 => "rwatch",
+                    4 => "awatch",
+                    _ => "watch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, name, access.addr)
+            }
+            _ => format!("S{:02x}", SIGTRAP),
+        }
+    }
+}
+
+// Parses "addr,len" in hex.
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((
+        u16::from_str_radix(addr, 16).ok()?,
+        u16::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Answers "qXfer:features:read:target.xml:offset,length" in |args| with the part of the target
+// description it asks for, prefixed with 'l' if it is the last part, else 'm'.
+fn read_features(args: &str) -> Option<String> {
+    let args = args.strip_prefix("Xfer:features:read:")?;
+    let (annex, range) = args.split_once(':')?;
+    if annex != "target.xml" {
+        return Some("E00".to_string());
+    }
+    let (offset, len) = range.split_once(',')?;
+    let offset = usize::from_str_radix(offset, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    let start = offset.min(TARGET_XML.len());
+    let end = start.saturating_add(len).min(TARGET_XML.len());
+    let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+    Some(format!("{}{}", more, &TARGET_XML[start..end]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nrom_image;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::CPU;
+    use crate::nes::Nes;
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use std::thread;
+
+    // A client that sends packets and returns the replies.
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) -> String {
+            write!(self.writer, "${}#{:02x}", data, checksum(data.as_bytes())).unwrap();
+            self.expect_ack();
+            self.reply()
+        }
+
+        fn expect_ack(&mut self) {
+            let mut ack = [0];
+            self.reader.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+        }
+
+        fn reply(&mut self) -> String {
+            let mut packet = Vec::new();
+            self.reader.read_until(b'#', &mut packet).unwrap();
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum).unwrap();
+            self.writer.write_all(b"+").unwrap();
+            let data = &packet[1..packet.len() - 1];
+            assert_eq!(packet[0], b'$');
+            assert_eq!(
+                std::str::from_utf8(&checksum).unwrap(),
+                format!("{:02x}", self::checksum(data))
+            );
+            String::from_utf8(data.to_vec()).unwrap()
+        }
+    }
+
+    // Serves |target| to |session| running on a client over a loopback socket. Returns the
+    // target and what the session returns.
+    fn serve<T: Target, R: Send + 'static>(
+        target: T,
+        session: impl FnOnce(&mut Client) -> R + Send + 'static,
+    ) -> (T, R) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            let mut client = Client {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+            };
+            session(&mut client)
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let mut stub = GdbStub::new(target);
+        stub.serve(stream).unwrap();
+        (stub.target, client.join().unwrap())
+    }
+
+    fn cpu(program: &[u8]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load(program).unwrap();
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_session() {
+        // LDA #$42
+        // STA $10
+        // loop:
+        // INX
+        // JMP loop
+        let cpu = cpu(&[0xa9, 0x42, 0x85, 0x10, 0xe8, 0x4c, 0x04, 0x80]);
+
+        let (cpu, replies) = serve(cpu, |client| {
+            [
+                "qSupported:multiprocess+",
+                "qXfer:features:read:target.xml:0,15",
+                "qXfer:features:read:target.xml:1000,10",
+                "qXfer:features:read:memory-map.xml:0,10",
+                "?",
+                "g",
+                "m8000,3",
+                "Z0,8004,1",
+                "c",
+                "p5",
+                "z0,8004,1",
+                "P5=0080",
+                "Z2,10,1",
+                "c",
+                "s",
+                "g",
+                "G0102030405ff80",
+                "M0200,2:abcd",
+                "m0200,2",
+                "vMustReplyEmpty",
+                "D",
+            ]
+            .iter()
+            .map(|packet| client.send(packet))
+            .collect::<Vec<String>>()
+        });
+
+        assert_eq!(
+            replies,
+            [
+                "PacketSize=1000;qXfer:features:read+",
+                "m<?xml version=\"1.0\"?>",
+                "l",
+                "E00",
+                "S05",
+                "00000020fd0080",
+                "a94285",
+                "OK",
+                "S05",
+                "0480",
+                "OK",
+                "OK",
+                "OK",
+                "T05watch:0010;",
+                "S05",
+                "42010020fd0580",
+                "OK",
+                "OK",
+                "abcd",
+                "",
+                "OK",
+            ]
+        );
+        assert_eq!(cpu.pc, 0x80ff);
+        assert_eq!(cpu.reg_status.bits(), 0x04);
+        // The breakpoints of the client are gone.
+        assert_eq!(cpu.breakpoints().is_empty(), true);
+    }
+
+    #[test]
+    fn test_write_prg_rom() {
+        // STA $8000 would switch banks on most mappers.
+        let nes = Nes::new(Cartridge::parse(&nrom_image(&[0xea])).unwrap());
+
+        let (nes, replies) = serve(nes, |client| {
+            [
+                "M8000,1:00",
+                "M7fff,2:0102",
+                "M0000,1:42",
+                "m8000,1",
+                "m0000,1",
+            ]
+            .iter()
+            .map(|packet| client.send(packet))
+            .collect::<Vec<String>>()
+        });
+
+        assert_eq!(replies, ["E01", "E01", "OK", "ea", "42"]);
+        assert_eq!(nes.cpu().peek_mem(0x7fff), 0x00);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        // JMP $8000
+        let cpu = cpu(&[0x4c, 0x00, 0x80]);
+
+        let (_, replies) = serve(cpu, |client| {
+            write!(client.writer, "$c#63").unwrap();
+            client.expect_ack();
+            client.writer.write_all(&[INTERRUPT]).unwrap();
+            let stop = client.reply();
+            (stop, client.send("p5"))
+        });
+
+        assert_eq!(replies, ("S02".to_string(), "0080".to_string()));
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let cpu = cpu(&[0xea]);
+
+        let (_, reply) = serve(cpu, |client| {
+            write!(client.writer, "$g#00").unwrap();
+            let mut nack = [0];
+            client.reader.read_exact(&mut nack).unwrap();
+            (nack[0], client.send("p0"))
+        });
+
+        assert_eq!(reply, (b'-', "00".to_string()));
+    }
+}
@@ -0,0 +1,179 @@
+/**
+ * Controller ports and the devices plugged in them.
+ *
+ * Both ports share the strobe written to bit 0 of 0x4016. Each port is read from its own
+ * register, 0x4016 for port 1 and 0x4017 for port 2. A device drives the low 5 bits of the data
+ * bus; the other bits are open bus.
+ *
+ * See https://wiki.nesdev.com/w/index.php/Input_devices
+ */
+use crate::frame::Frame;
+use crate::joypad::{Joypad, JoypadButton};
+use crate::savestate::{self, Savestate, StateReader, StateWriter};
+use simple_error::SimpleError;
+use std::any::Any;
+
+// Memory-mapped registers.
+pub const JOYPAD_REG_1: u16 = 0x4016;
+pub const JOYPAD_REG_2: u16 = 0x4017;
+
+// Bits driven by devices.
+const DEVICE_DATA_MASK: u8 = 0b0001_1111;
+
+// The upper bits keep the last value on the data bus, which is the high byte of the address.
+const OPEN_BUS: u8 = 0x40;
+
+// What a device may look at when it is read, e.g. a light gun looks at the picture on screen.
+pub struct InputContext<'a> {
+    // The frame being rendered. None if there is no picture, e.g. without a PPU.
+    pub frame: Option<&'a Frame>,
+    // Position of the beam.
+    pub scanline: usize,
+    pub dot: usize,
+}
+
+impl InputContext<'_> {
+    // A context without any picture.
+    pub fn none() -> InputContext<'static> {
+        InputContext {
+            frame: None,
+            scanline: 0,
+            dot: 0,
+        }
+    }
+}
+
+// Devices are part of save states, e.g. a controller in the middle of being read.
+pub trait InputDevice: Any + Savestate {
+    // Handles a CPU write to 0x4016.
+    fn write(&mut self, val: u8);
+
+    // Handles a CPU read of the port the device is plugged in. Returns the low 5 bits of the data
+    // bus.
+    fn read(&mut self, ctx: &InputContext) -> u8;
+
+    // Same as read() but without side effects.
+    fn peek(&self, ctx: &InputContext) -> u8;
+}
+
+pub struct ControllerPorts {
+    devices: [Box<dyn InputDevice>; 2],
+}
+
+impl ControllerPorts {
+    // Two standard controllers are plugged in by default.
+    pub fn new() -> Self {
+        ControllerPorts {
+            devices: [Box::new(Joypad::new()), Box::new(Joypad::new())],
+        }
+    }
+
+    // Plugs |device| in |port| (0 or 1) and returns the device that was plugged in before.
+    pub fn plug(&mut self, port: usize, device: Box<dyn InputDevice>) -> Box<dyn InputDevice> {
+        std::mem::replace(&mut self.devices[port], device)
+    }
+
+    // Returns the device in |port| if it is a |T|.
+    pub fn device<T: InputDevice>(&self, port: usize) -> Option<&T> {
+        (&*self.devices[port] as &dyn Any).downcast_ref::<T>()
+    }
+
+    // Returns the device in |port| if it is a |T|.
+    pub fn device_mut<T: InputDevice>(&mut self, port: usize) -> Option<&mut T> {
+        (&mut *self.devices[port] as &mut dyn Any).downcast_mut::<T>()
+    }
+
+    // Presses or releases |button| of the standard controller in |port|. Does nothing if another
+    // device is plugged in.
+    pub fn set_button_pressed(&mut self, port: usize, button: JoypadButton, pressed: bool) {
+        if let Some(joypad) = self.device_mut::<Joypad>(port) {
+            joypad.set_button_pressed(button, pressed);
+        }
+    }
+
+    // Handles a CPU write to 0x4016. The strobe goes to both ports.
+    pub fn write(&mut self, val: u8) {
+        for device in self.devices.iter_mut() {
+            device.write(val);
+        }
+    }
+
+    // Handles a CPU read of 0x4016 or 0x4017.
+    pub fn read(&mut self, addr: u16, ctx: &InputContext) -> u8 {
+        let port = ControllerPorts::port_of(addr);
+        OPEN_BUS | (self.devices[port].read(ctx) & DEVICE_DATA_MASK)
+    }
+
+    // Same as read() but without side effects.
+    pub fn peek(&self, addr: u16, ctx: &InputContext) -> u8 {
+        let port = ControllerPorts::port_of(addr);
+        OPEN_BUS | (self.devices[port].peek(ctx) & DEVICE_DATA_MASK)
+    }
+
+    fn port_of(addr: u16) -> usize {
+        match addr {
+            JOYPAD_REG_1 => 0,
+            JOYPAD_REG_2 => 1,
+            _ => panic!("0x{:04x} is not a controller port", addr),
+        }
+    }
+}
+
+// Which devices are plugged in is not part of the state, but the state of each device is. Loading
+// fails if a device of another kind is plugged in.
+impl Savestate for ControllerPorts {
+    fn save_state(&self, w: &mut StateWriter) {
+        for device in self.devices.iter() {
+            savestate::write_section(w, &**device);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        for device in self.devices.iter_mut() {
+            savestate::read_section(r, &mut **device)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for ControllerPorts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::zapper::Zapper;
+
+    #[test]
+    fn test_ports() {
+        let mut ports = ControllerPorts::new();
+        let ctx = InputContext::none();
+        ports.set_button_pressed(0, JoypadButton::B, true);
+        ports.set_button_pressed(1, JoypadButton::A, true);
+
+        ports.write(1);
+        ports.write(0);
+
+        assert_eq!(ports.read(JOYPAD_REG_1, &ctx), 0x40);
+        assert_eq!(ports.read(JOYPAD_REG_1, &ctx), 0x41);
+        assert_eq!(ports.peek(JOYPAD_REG_2, &ctx), 0x41);
+        assert_eq!(ports.read(JOYPAD_REG_2, &ctx), 0x41);
+        assert_eq!(ports.read(JOYPAD_REG_2, &ctx), 0x40);
+    }
+
+    #[test]
+    fn test_plug() {
+        let mut ports = ControllerPorts::new();
+
+        let old = ports.plug(1, Box::new(Zapper::new()));
+
+        assert_eq!((&*old as &dyn Any).is::<Joypad>(), true);
+        assert_eq!(ports.device::<Zapper>(1).is_some(), true);
+        assert_eq!(ports.device::<Joypad>(1).is_none(), true);
+        // No light, trigger released.
+        assert_eq!(ports.read(JOYPAD_REG_2, &InputContext::none()), 0x48);
+    }
+}
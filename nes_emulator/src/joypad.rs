@@ -0,0 +1,170 @@
+/**
+ * Standard NES controller.
+ *
+ * The controller is an 8-bit shift register. Writing 1 to bit 0 of 0x4016 (strobe) makes it
+ * continuously reload the state of the buttons; writing 0 freezes it so that the CPU can read
+ * the buttons one by one, in the order A, B, Select, Start, Up, Down, Left, Right, from 0x4016
+ * (port 1) or 0x4017 (port 2). After the 8 buttons, an official controller reports 1.
+ *
+ * See https://wiki.nesdev.com/w/index.php/Standard_controller
+ */
+use crate::input::{InputContext, InputDevice};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use bitflags::bitflags;
+use simple_error::SimpleError;
+
+bitflags! {
+    pub struct JoypadButton : u8 {
+        const A = 0b0000_0001;
+        const B = 0b0000_0010;
+        const SELECT = 0b0000_0100;
+        const START = 0b0000_1000;
+        const UP = 0b0001_0000;
+        const DOWN = 0b0010_0000;
+        const LEFT = 0b0100_0000;
+        const RIGHT = 0b1000_0000;
+    }
+}
+
+pub struct Joypad {
+    strobe: bool,
+    // Index of the next button to report.
+    button_index: u8,
+    button_status: JoypadButton,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            strobe: false,
+            button_index: 0,
+            button_status: JoypadButton::empty(),
+        }
+    }
+
+    pub fn set_button_pressed(&mut self, button: JoypadButton, pressed: bool) {
+        self.button_status.set(button, pressed);
+    }
+
+    pub fn set_buttons(&mut self, buttons: JoypadButton) {
+        self.button_status = buttons;
+    }
+
+    pub fn buttons(&self) -> JoypadButton {
+        self.button_status
+    }
+
+    // The bit reported by the next read.
+    fn current_bit(&self) -> u8 {
+        // While strobing, the register keeps reloading, so A is reported.
+        let index = if self.strobe { 0 } else { self.button_index };
+        if index > 7 {
+            1
+        } else {
+            (self.button_status.bits() >> index) & 0b0000_0001
+        }
+    }
+}
+
+impl InputDevice for Joypad {
+    fn write(&mut self, val: u8) {
+        self.strobe = val & 0b0000_0001 != 0;
+        if self.strobe {
+            self.button_index = 0;
+        }
+    }
+
+    fn read(&mut self, _ctx: &InputContext) -> u8 {
+        let val = self.current_bit();
+        if !self.strobe && self.button_index < 8 {
+            self.button_index += 1;
+        }
+        val
+    }
+
+    fn peek(&self, _ctx: &InputContext) -> u8 {
+        self.current_bit()
+    }
+}
+
+impl Savestate for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.strobe);
+        w.write(&self.button_index);
+        w.write(&self.button_status.bits());
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.strobe = r.read()?;
+        self.button_index = r.read()?;
+        self.button_status = JoypadButton::from_bits_truncate(r.read()?);
+        Ok(())
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read(joypad: &mut Joypad) -> u8 {
+        joypad.read(&InputContext::none())
+    }
+
+    #[test]
+    fn test_read_buttons_in_order() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed(JoypadButton::A, true);
+        joypad.set_button_pressed(JoypadButton::START, true);
+        joypad.set_button_pressed(JoypadButton::RIGHT, true);
+
+        joypad.write(1);
+        joypad.write(0);
+
+        let bits: Vec<u8> = (0..8).map(|_| read(&mut joypad)).collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_read_after_eight_buttons() {
+        let mut joypad = Joypad::new();
+        joypad.write(1);
+        joypad.write(0);
+
+        for _ in 0..8 {
+            assert_eq!(read(&mut joypad), 0);
+        }
+        assert_eq!(read(&mut joypad), 1);
+        assert_eq!(read(&mut joypad), 1);
+    }
+
+    #[test]
+    fn test_strobe_high_reports_a() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed(JoypadButton::A, true);
+        joypad.write(1);
+
+        assert_eq!(read(&mut joypad), 1);
+        assert_eq!(read(&mut joypad), 1);
+    }
+
+    #[test]
+    fn test_strobe_restarts_sequence() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed(JoypadButton::A, true);
+        joypad.write(1);
+        joypad.write(0);
+        read(&mut joypad);
+        read(&mut joypad);
+
+        joypad.write(1);
+        joypad.write(0);
+
+        assert_eq!(read(&mut joypad), 1);
+    }
+}
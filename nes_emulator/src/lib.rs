@@ -1,6 +1,40 @@
+// Tests state flags as `assert_eq!(status.contains(flag), true)` for readability.
+#![cfg_attr(test, allow(clippy::bool_assert_comparison))]
+
 extern crate simple_error;
 #[macro_use]
 extern crate lazy_static;
 extern crate bitflags;
 
+pub mod apu;
+pub mod arkanoid;
+pub mod assembler;
+pub mod blargg;
+pub mod breakpoint;
+pub mod bus;
+pub mod callstack;
+pub mod cartridge;
+pub mod cdl;
+pub mod condition;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod four_score;
+pub mod frame;
+pub mod gdb;
+pub mod input;
+pub mod joypad;
+pub mod mapper;
+pub mod movie;
+pub mod nes;
+pub mod nsf;
+pub mod ppu;
+pub mod profiler;
+pub mod ram_pattern;
+pub mod region;
+pub mod rewind;
+pub mod savestate;
+pub mod symbols;
+pub mod trace;
+pub mod wav;
+pub mod zapper;
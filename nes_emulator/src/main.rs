@@ -1,3 +1,63 @@
+/**
+ * Debugger of a NES game, driven by commands read from stdin:
+ *
+ *   nes_emulator game.nes
+ *   printf 'break $8000\ncontinue\nregs\n' | nes_emulator game.nes
+ *
+ * Type help for the list of commands. Labels are loaded from the symbol files next to the ROM,
+ * e.g. game.dbg, game.mlb or game.nes.0.nl. With --gdb, the console waits for a GDB client on the
+ * local port instead:
+ *
+ *   nes_emulator --gdb 2159 game.nes
+ */
+use nes_emulator_lib::cartridge::Cartridge;
+use nes_emulator_lib::debugger::Debugger;
+use nes_emulator_lib::gdb::GdbStub;
+use nes_emulator_lib::nes::Nes;
+use std::env;
+use std::io;
+use std::process;
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().collect();
+    let (gdb_port, path) = match args.as_slice() {
+        [_, path] => (None, path),
+        [_, flag, port, path] if flag == "--gdb" => match port.parse::<u16>() {
+            Ok(port) => (Some(port), path),
+            Err(_) => usage(&args[0]),
+        },
+        _ => usage(&args[0]),
+    };
+    let cartridge = match Cartridge::from_file(path) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            eprintln!("cannot load {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    let mut nes = Nes::new(cartridge);
+    match nes.cpu_mut().symbols_mut().load_beside(path) {
+        Ok(0) => {}
+        Ok(count) => eprintln!("loaded {} labels", count),
+        Err(e) => eprintln!("cannot load symbols: {}", e),
+    }
+    let result = match gdb_port {
+        Some(port) => {
+            eprintln!("waiting for a GDB client on port {}", port);
+            GdbStub::new(nes).listen(("127.0.0.1", port))
+        }
+        None => {
+            let stdin = io::stdin();
+            Debugger::new(nes).run(stdin.lock(), io::stdout())
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!("usage: {} [--gdb <port>] <rom.nes>", program);
+    process::exit(2);
 }
@@ -0,0 +1,699 @@
+/**
+ * Mappers, i.e. the hardware on the cartridge that maps PRG and CHR memory into the address
+ * spaces of the CPU and the PPU.
+ *
+ * The CPU sees the cartridge in [0x4020, 0xffff]: PRG RAM in [0x6000, 0x7fff] and PRG ROM in
+ * [0x8000, 0xffff]. The PPU sees CHR ROM or RAM in [0x0000, 0x1fff]. Most mappers switch banks
+ * when the CPU writes to the ROM area.
+ *
+ * See https://wiki.nesdev.com/w/index.php/Mapper
+ */
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::{self, Savestate, StateReader, StateWriter};
+use simple_error::SimpleError;
+use std::any::Any;
+
+const PRG_RAM_ADDR_START: u16 = 0x6000;
+const PRG_ROM_ADDR_START: u16 = 0x8000;
+
+const PRG_BANK_SIZE_16K: usize = 0x4000;
+const PRG_BANK_SIZE_32K: usize = 0x8000;
+const CHR_BANK_SIZE_4K: usize = 0x1000;
+const CHR_BANK_SIZE_8K: usize = 0x2000;
+
+pub const MAPPER_NROM: u16 = 0;
+pub const MAPPER_MMC1: u16 = 1;
+pub const MAPPER_UXROM: u16 = 2;
+pub const MAPPER_CNROM: u16 = 3;
+
+// The state of a mapper holds its registers and the RAM on the cartridge, but not the ROM.
+pub trait Mapper: Any + Savestate {
+    // Handles a CPU read in [0x4020, 0xffff].
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        self.cpu_peek(addr)
+    }
+
+    // Same as cpu_read() but without side effects.
+    fn cpu_peek(&self, addr: u16) -> u8;
+
+    // Handles a CPU write in [0x4020, 0xffff].
+    fn cpu_write(&mut self, addr: u16, val: u8);
+
+    // Handles a CPU write in [0x4020, 0xffff] on the cycle right after another write, as
+    // read-modify-write instructions do.
+    fn cpu_write_consecutive(&mut self, addr: u16, val: u8) {
+        self.cpu_write(addr, val)
+    }
+
+    // Handles a PPU read in [0x0000, 0x1fff].
+    fn ppu_read(&self, addr: u16) -> u8;
+
+    // Handles a PPU write in [0x0000, 0x1fff].
+    fn ppu_write(&mut self, addr: u16, val: u8);
+
+    fn mirroring(&self) -> Mirroring;
+
+    // PRG RAM on the cartridge, empty if there is none.
+    fn prg_ram_mut(&mut self) -> &mut [u8];
+
+    // Maps a CPU address in [0x8000, 0xffff] to an index in PRG ROM, with the banks selected now.
+    fn prg_rom_index(&self, addr: u16) -> usize;
+
+    // Maps a PPU address in [0x0000, 0x1fff] to an index in CHR ROM, with the banks selected now.
+    // None if the cartridge has CHR RAM.
+    fn chr_rom_index(&self, addr: u16) -> Option<usize>;
+
+    // Whether the mapper asserts the IRQ line.
+    fn irq(&self) -> bool {
+        false
+    }
+}
+
+// Creates the mapper with iNES number |id| holding |rom|.
+pub fn new_mapper(id: u16, rom: Rom) -> Result<Box<dyn Mapper>, SimpleError> {
+    let memory = Memory::new(rom);
+    match id {
+        MAPPER_NROM => Ok(Box::new(Nrom { memory })),
+        MAPPER_MMC1 => Ok(Box::new(Mmc1::new(memory))),
+        MAPPER_UXROM => Ok(Box::new(Uxrom { memory, bank: 0 })),
+        MAPPER_CNROM => Ok(Box::new(Cnrom { memory, bank: 0 })),
+        _ => Err(SimpleError::new(format!("unsupported mapper {}", id))),
+    }
+}
+
+// Memory on the cartridge, shared by all mappers.
+struct Memory {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    // CHR ROM, or CHR RAM if the cartridge has no CHR ROM.
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl Memory {
+    fn new(rom: Rom) -> Self {
+        let chr_is_ram = rom.chr_rom.is_empty();
+        let chr = if chr_is_ram {
+            vec![0; rom.chr_ram_size.max(CHR_BANK_SIZE_8K)]
+        } else {
+            rom.chr_rom
+        };
+        Memory {
+            prg_rom: rom.prg_rom,
+            prg_ram: vec![0; rom.prg_ram_size],
+            chr,
+            chr_is_ram,
+            mirroring: rom.mirroring,
+        }
+    }
+
+    // Maps |offset| in the |bank|-th PRG ROM bank of |bank_size| bytes to an index in |prg_rom|.
+    // Banks wrap around.
+    fn prg_rom_index(&self, bank_size: usize, bank: usize, offset: u16) -> usize {
+        let num_banks = (self.prg_rom.len() / bank_size).max(1);
+        let addr = (bank % num_banks) * bank_size + (offset as usize & (bank_size - 1));
+        addr % self.prg_rom.len()
+    }
+
+    // Returns the index of the last PRG ROM bank of |bank_size| bytes.
+    fn last_prg_bank(&self, bank_size: usize) -> usize {
+        (self.prg_rom.len() / bank_size).max(1) - 1
+    }
+
+    fn read_prg_ram(&self, addr: u16) -> u8 {
+        if self.prg_ram.is_empty() {
+            return 0;
+        }
+        let len = self.prg_ram.len();
+        self.prg_ram[(addr - PRG_RAM_ADDR_START) as usize % len]
+    }
+
+    fn write_prg_ram(&mut self, addr: u16, val: u8) {
+        if self.prg_ram.is_empty() {
+            return;
+        }
+        let len = self.prg_ram.len();
+        self.prg_ram[(addr - PRG_RAM_ADDR_START) as usize % len] = val;
+    }
+
+    // Maps |offset| in the |bank|-th CHR bank of |bank_size| bytes to an index in |chr|.
+    fn chr_index(&self, bank_size: usize, bank: usize, offset: u16) -> usize {
+        let num_banks = (self.chr.len() / bank_size).max(1);
+        ((bank % num_banks) * bank_size + (offset as usize & (bank_size - 1))) % self.chr.len()
+    }
+
+    fn chr_rom_index(&self, bank_size: usize, bank: usize, offset: u16) -> Option<usize> {
+        if self.chr_is_ram {
+            None
+        } else {
+            Some(self.chr_index(bank_size, bank, offset))
+        }
+    }
+
+    fn read_chr(&self, bank_size: usize, bank: usize, offset: u16) -> u8 {
+        self.chr[self.chr_index(bank_size, bank, offset)]
+    }
+
+    // Writes are ignored for CHR ROM.
+    fn write_chr(&mut self, bank_size: usize, bank: usize, offset: u16, val: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_index(bank_size, bank, offset);
+            self.chr[index] = val;
+        }
+    }
+}
+
+impl Savestate for Memory {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.prg_ram);
+        if self.chr_is_ram {
+            w.write(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        savestate::read_buffer(r, &mut self.prg_ram)?;
+        if self.chr_is_ram {
+            savestate::read_buffer(r, &mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+// Mapper 0: 16KB or 32KB of PRG ROM and 8KB of CHR, without any bank switching.
+//
+// See https://wiki.nesdev.com/w/index.php/NROM
+struct Nrom {
+    memory: Memory,
+}
+
+impl Mapper for Nrom {
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        match addr {
+            PRG_RAM_ADDR_START..=0x7fff => self.memory.read_prg_ram(addr),
+            PRG_ROM_ADDR_START..=0xffff => self.memory.prg_rom[self.prg_rom_index(addr)],
+            _ => 0,
+        }
+    }
+
+    fn prg_rom_index(&self, addr: u16) -> usize {
+        self.memory
+            .prg_rom_index(PRG_BANK_SIZE_32K, 0, addr - PRG_ROM_ADDR_START)
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        if let PRG_RAM_ADDR_START..=0x7fff = addr {
+            self.memory.write_prg_ram(addr, val);
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.memory.read_chr(CHR_BANK_SIZE_8K, 0, addr)
+    }
+
+    fn chr_rom_index(&self, addr: u16) -> Option<usize> {
+        self.memory.chr_rom_index(CHR_BANK_SIZE_8K, 0, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        self.memory.write_chr(CHR_BANK_SIZE_8K, 0, addr, val);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.memory.mirroring
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.memory.prg_ram
+    }
+}
+
+impl Savestate for Nrom {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.memory.load_state(r)
+    }
+}
+
+// Mapper 1: MMC1. The CPU loads its registers one bit at a time through a serial port.
+//
+// See https://wiki.nesdev.com/w/index.php/MMC1
+struct Mmc1 {
+    memory: Memory,
+    shift_register: u8,
+    shift_count: u8,
+    // Mirroring, PRG bank mode and CHR bank mode.
+    control: u8,
+    chr_bank0: u8,
+    chr_bank1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    fn new(memory: Memory) -> Self {
+        Mmc1 {
+            memory,
+            shift_register: 0,
+            shift_count: 0,
+            // Power on in PRG bank mode 3, i.e. the last bank is fixed at 0xc000.
+            control: 0b0_1100,
+            chr_bank0: 0,
+            chr_bank1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x8000..=0x9fff => self.control = val,
+            0xa000..=0xbfff => self.chr_bank0 = val,
+            0xc000..=0xdfff => self.chr_bank1 = val,
+            _ => self.prg_bank = val,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0b1_0000 == 0
+    }
+
+    // Maps a CHR address to a 4KB bank.
+    fn chr_bank(&self, addr: u16) -> usize {
+        if self.control & 0b1_0000 == 0 {
+            // 8KB mode ignores the low bit.
+            ((self.chr_bank0 & 0b1_1110) | (addr >> 12) as u8) as usize
+        } else if addr < 0x1000 {
+            self.chr_bank0 as usize
+        } else {
+            self.chr_bank1 as usize
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        match addr {
+            PRG_RAM_ADDR_START..=0x7fff if self.prg_ram_enabled() => self.memory.read_prg_ram(addr),
+            PRG_ROM_ADDR_START..=0xffff => self.memory.prg_rom[self.prg_rom_index(addr)],
+            _ => 0,
+        }
+    }
+
+    fn prg_rom_index(&self, addr: u16) -> usize {
+        let bank = (self.prg_bank & 0b0_1111) as usize;
+        let offset = addr - PRG_ROM_ADDR_START;
+        match (self.control >> 2) & 0b11 {
+            // 32KB mode ignores the low bit.
+            0 | 1 => self
+                .memory
+                .prg_rom_index(PRG_BANK_SIZE_32K, bank >> 1, offset),
+            // First bank fixed at 0x8000.
+            2 if addr < 0xc000 => self.memory.prg_rom_index(PRG_BANK_SIZE_16K, 0, offset),
+            2 => self.memory.prg_rom_index(PRG_BANK_SIZE_16K, bank, offset),
+            // Last bank fixed at 0xc000.
+            _ if addr < 0xc000 => self.memory.prg_rom_index(PRG_BANK_SIZE_16K, bank, offset),
+            _ => {
+                let last = self.memory.last_prg_bank(PRG_BANK_SIZE_16K);
+                self.memory.prg_rom_index(PRG_BANK_SIZE_16K, last, offset)
+            }
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            PRG_RAM_ADDR_START..=0x7fff if self.prg_ram_enabled() => {
+                self.memory.write_prg_ram(addr, val)
+            }
+            PRG_ROM_ADDR_START..=0xffff => {
+                if val & 0b1000_0000 != 0 {
+                    // Reset the shift register and fix the last bank at 0xc000.
+                    self.shift_register = 0;
+                    self.shift_count = 0;
+                    self.control |= 0b0_1100;
+                    return;
+                }
+                self.shift_register |= (val & 0b0000_0001) << self.shift_count;
+                self.shift_count += 1;
+                if self.shift_count == 5 {
+                    self.write_register(addr, self.shift_register);
+                    self.shift_register = 0;
+                    self.shift_count = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The serial port ignores the second of consecutive writes, so that a read-modify-write
+    // instruction on it, e.g. the INC games use to reset it, only counts once.
+    fn cpu_write_consecutive(&mut self, addr: u16, val: u8) {
+        if addr < PRG_ROM_ADDR_START {
+            self.cpu_write(addr, val)
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.memory
+            .read_chr(CHR_BANK_SIZE_4K, self.chr_bank(addr), addr)
+    }
+
+    fn chr_rom_index(&self, addr: u16) -> Option<usize> {
+        self.memory
+            .chr_rom_index(CHR_BANK_SIZE_4K, self.chr_bank(addr), addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        let bank = self.chr_bank(addr);
+        self.memory.write_chr(CHR_BANK_SIZE_4K, bank, addr, val);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.memory.prg_ram
+    }
+}
+
+impl Savestate for Mmc1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.write(&self.shift_register);
+        w.write(&self.shift_count);
+        w.write(&self.control);
+        w.write(&self.chr_bank0);
+        w.write(&self.chr_bank1);
+        w.write(&self.prg_bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.memory.load_state(r)?;
+        self.shift_register = r.read()?;
+        self.shift_count = r.read()?;
+        self.control = r.read()?;
+        self.chr_bank0 = r.read()?;
+        self.chr_bank1 = r.read()?;
+        self.prg_bank = r.read()?;
+        Ok(())
+    }
+}
+
+// Mapper 2: UxROM. A switchable 16KB PRG bank at 0x8000 and the last bank fixed at 0xc000.
+//
+// See https://wiki.nesdev.com/w/index.php/UxROM
+struct Uxrom {
+    memory: Memory,
+    bank: u8,
+}
+
+impl Mapper for Uxrom {
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        match addr {
+            PRG_RAM_ADDR_START..=0x7fff => self.memory.read_prg_ram(addr),
+            PRG_ROM_ADDR_START..=0xffff => self.memory.prg_rom[self.prg_rom_index(addr)],
+            _ => 0,
+        }
+    }
+
+    fn prg_rom_index(&self, addr: u16) -> usize {
+        let bank = if addr < 0xc000 {
+            self.bank as usize
+        } else {
+            self.memory.last_prg_bank(PRG_BANK_SIZE_16K)
+        };
+        self.memory
+            .prg_rom_index(PRG_BANK_SIZE_16K, bank, addr - PRG_ROM_ADDR_START)
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            PRG_RAM_ADDR_START..=0x7fff => self.memory.write_prg_ram(addr, val),
+            PRG_ROM_ADDR_START..=0xffff => self.bank = val,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.memory.read_chr(CHR_BANK_SIZE_8K, 0, addr)
+    }
+
+    fn chr_rom_index(&self, addr: u16) -> Option<usize> {
+        self.memory.chr_rom_index(CHR_BANK_SIZE_8K, 0, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        self.memory.write_chr(CHR_BANK_SIZE_8K, 0, addr, val);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.memory.mirroring
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.memory.prg_ram
+    }
+}
+
+impl Savestate for Uxrom {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.write(&self.bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.memory.load_state(r)?;
+        self.bank = r.read()?;
+        Ok(())
+    }
+}
+
+// Mapper 3: CNROM. Fixed PRG ROM like NROM and a switchable 8KB CHR bank.
+//
+// See https://wiki.nesdev.com/w/index.php/CNROM
+struct Cnrom {
+    memory: Memory,
+    bank: u8,
+}
+
+impl Mapper for Cnrom {
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        match addr {
+            PRG_RAM_ADDR_START..=0x7fff => self.memory.read_prg_ram(addr),
+            PRG_ROM_ADDR_START..=0xffff => self.memory.prg_rom[self.prg_rom_index(addr)],
+            _ => 0,
+        }
+    }
+
+    fn prg_rom_index(&self, addr: u16) -> usize {
+        self.memory
+            .prg_rom_index(PRG_BANK_SIZE_32K, 0, addr - PRG_ROM_ADDR_START)
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            PRG_RAM_ADDR_START..=0x7fff => self.memory.write_prg_ram(addr, val),
+            PRG_ROM_ADDR_START..=0xffff => self.bank = val,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.memory
+            .read_chr(CHR_BANK_SIZE_8K, self.bank as usize, addr)
+    }
+
+    fn chr_rom_index(&self, addr: u16) -> Option<usize> {
+        self.memory
+            .chr_rom_index(CHR_BANK_SIZE_8K, self.bank as usize, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        self.memory
+            .write_chr(CHR_BANK_SIZE_8K, self.bank as usize, addr, val);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.memory.mirroring
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.memory.prg_ram
+    }
+}
+
+impl Savestate for Cnrom {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.write(&self.bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.memory.load_state(r)?;
+        self.bank = r.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A ROM whose PRG banks of 16KB and CHR banks of 4KB are filled with their index.
+    fn rom(num_prg_banks: usize, num_chr_banks: usize) -> Rom {
+        let mut prg_rom = Vec::new();
+        for bank in 0..num_prg_banks {
+            prg_rom.extend(vec![bank as u8; PRG_BANK_SIZE_16K]);
+        }
+        let mut chr_rom = Vec::new();
+        for bank in 0..num_chr_banks {
+            chr_rom.extend(vec![bank as u8; CHR_BANK_SIZE_4K]);
+        }
+        Rom {
+            prg_rom,
+            chr_rom,
+            prg_ram_size: 0x2000,
+            chr_ram_size: 0,
+            mirroring: Mirroring::Vertical,
+        }
+    }
+
+    // Loads |val| in an MMC1 register through the serial port.
+    fn write_mmc1(mapper: &mut dyn Mapper, addr: u16, val: u8) {
+        for i in 0..5 {
+            mapper.cpu_write(addr, (val >> i) & 1);
+        }
+    }
+
+    #[test]
+    fn test_nrom_mirrors_16k() {
+        let mut rom = rom(1, 2);
+        rom.prg_rom[0x0123] = 0x42;
+        let mapper = new_mapper(MAPPER_NROM, rom).unwrap();
+
+        assert_eq!(mapper.cpu_peek(0x8123), 0x42);
+        assert_eq!(mapper.cpu_peek(0xc123), 0x42);
+        assert_eq!(mapper.ppu_read(0x1000), 1);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut mapper = new_mapper(MAPPER_NROM, rom(1, 2)).unwrap();
+
+        mapper.cpu_write(0x6010, 0x42);
+
+        assert_eq!(mapper.cpu_peek(0x6010), 0x42);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut mapper = new_mapper(MAPPER_UXROM, rom(4, 0)).unwrap();
+
+        mapper.ppu_write(0x0010, 0x42);
+
+        assert_eq!(mapper.ppu_read(0x0010), 0x42);
+        assert_eq!(mapper.chr_rom_index(0x0010), None);
+    }
+
+    #[test]
+    fn test_chr_rom_is_read_only() {
+        let mut mapper = new_mapper(MAPPER_NROM, rom(1, 2)).unwrap();
+
+        mapper.ppu_write(0x0010, 0x42);
+
+        assert_eq!(mapper.ppu_read(0x0010), 0);
+    }
+
+    #[test]
+    fn test_uxrom() {
+        let mut mapper = new_mapper(MAPPER_UXROM, rom(4, 0)).unwrap();
+
+        mapper.cpu_write(0x8000, 2);
+
+        assert_eq!(mapper.cpu_peek(0x8000), 2);
+        assert_eq!(mapper.cpu_peek(0xc000), 3);
+        assert_eq!(mapper.prg_rom_index(0x8001), 0x8001);
+        assert_eq!(mapper.prg_rom_index(0xc001), 0xc001);
+    }
+
+    #[test]
+    fn test_cnrom() {
+        let mut mapper = new_mapper(MAPPER_CNROM, rom(2, 8)).unwrap();
+
+        mapper.cpu_write(0x8000, 3);
+
+        assert_eq!(mapper.ppu_read(0x0000), 6);
+        assert_eq!(mapper.ppu_read(0x1000), 7);
+        assert_eq!(mapper.chr_rom_index(0x1001), Some(0x7001));
+    }
+
+    #[test]
+    fn test_mmc1_prg_banks() {
+        let mut mapper = new_mapper(MAPPER_MMC1, rom(8, 2)).unwrap();
+        assert_eq!(mapper.cpu_peek(0xc000), 7);
+
+        write_mmc1(&mut *mapper, 0xe000, 5);
+        assert_eq!(mapper.cpu_peek(0x8000), 5);
+        assert_eq!(mapper.cpu_peek(0xc000), 7);
+
+        // 32KB mode.
+        write_mmc1(&mut *mapper, 0x8000, 0b0_0000);
+        assert_eq!(mapper.cpu_peek(0x8000), 4);
+        assert_eq!(mapper.cpu_peek(0xc000), 5);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenLower);
+    }
+
+    #[test]
+    fn test_mmc1_chr_banks() {
+        let mut mapper = new_mapper(MAPPER_MMC1, rom(2, 8)).unwrap();
+
+        // 4KB mode.
+        write_mmc1(&mut *mapper, 0x8000, 0b1_0010);
+        write_mmc1(&mut *mapper, 0xa000, 3);
+        write_mmc1(&mut *mapper, 0xc000, 6);
+
+        assert_eq!(mapper.ppu_read(0x0000), 3);
+        assert_eq!(mapper.ppu_read(0x1000), 6);
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_mmc1_reset() {
+        let mut mapper = new_mapper(MAPPER_MMC1, rom(8, 2)).unwrap();
+        mapper.cpu_write(0xe000, 1);
+        mapper.cpu_write(0xe000, 1);
+
+        mapper.cpu_write(0xe000, 0x80);
+        write_mmc1(&mut *mapper, 0xe000, 2);
+
+        assert_eq!(mapper.cpu_peek(0x8000), 2);
+    }
+
+    #[test]
+    fn test_mmc1_ignores_consecutive_writes() {
+        let mut mapper = new_mapper(MAPPER_MMC1, rom(8, 2)).unwrap();
+        mapper.cpu_write(0xe000, 1);
+
+        // INC $e000 on a 0xff byte resets with 0xff, then writes 0x00.
+        mapper.cpu_write(0xe000, 0xff);
+        mapper.cpu_write_consecutive(0xe000, 0x00);
+        write_mmc1(&mut *mapper, 0xe000, 2);
+        assert_eq!(mapper.cpu_peek(0x8000), 2);
+
+        mapper.cpu_write_consecutive(0x6000, 0x42);
+        assert_eq!(mapper.cpu_peek(0x6000), 0x42);
+    }
+
+    #[test]
+    fn test_unsupported_mapper() {
+        assert_eq!(new_mapper(4, rom(2, 2)).is_err(), true);
+    }
+}
@@ -0,0 +1,622 @@
+/**
+ * Input movies: the controller input of every frame, replayed to reproduce a run exactly.
+ *
+ * A movie starts from power on or from a save state. Each frame holds the buttons of both
+ * standard controllers and whether the reset button is pressed. While recording, the hash of the
+ * machine state is kept for every frame, so that playback can tell the first frame at which it
+ * diverges from the recording, i.e. desyncs.
+ *
+ * Movies are imported from and exported to the FM2 format of FCEUX, a text header of "key value"
+ * lines followed by one "|commands|port0|port1|port2|" line per frame. State hashes are not part
+ * of FM2, and a save state in an FM2 file is in our own format, which FCEUX cannot read.
+ *
+ * See https://fceux.com/web/help/fm2.html
+ */
+use crate::joypad::{Joypad, JoypadButton};
+use crate::nes::Nes;
+use crate::region::Region;
+use simple_error::SimpleError;
+use std::path::Path;
+
+const FM2_VERSION: u32 = 3;
+
+// Bits of the commands field.
+const COMMAND_RESET: u8 = 0b0000_0001;
+const COMMAND_POWER: u8 = 0b0000_0010;
+
+// FM2 input devices.
+const FM2_PORT_NONE: u8 = 0;
+const FM2_PORT_GAMEPAD: u8 = 1;
+
+// Buttons as FM2 prints them, from the most significant bit of JoypadButton.
+const FM2_BUTTONS: [(char, JoypadButton); 8] = [
+    ('R', JoypadButton::RIGHT),
+    ('L', JoypadButton::LEFT),
+    ('D', JoypadButton::DOWN),
+    ('U', JoypadButton::UP),
+    ('T', JoypadButton::START),
+    ('S', JoypadButton::SELECT),
+    ('B', JoypadButton::B),
+    ('A', JoypadButton::A),
+];
+
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MovieFrame {
+    pub reset: bool,
+    pub buttons: [JoypadButton; 2],
+}
+
+impl MovieFrame {
+    pub fn new(buttons1: JoypadButton, buttons2: JoypadButton) -> Self {
+        MovieFrame {
+            reset: false,
+            buttons: [buttons1, buttons2],
+        }
+    }
+}
+
+impl Default for MovieFrame {
+    fn default() -> Self {
+        MovieFrame::new(JoypadButton::empty(), JoypadButton::empty())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Movie {
+    pub region: Region,
+    // Save state the movie starts from. None if it starts from power on.
+    pub savestate: Option<Vec<u8>>,
+    pub frames: Vec<MovieFrame>,
+    // Hash of the machine state after each frame, see Nes::state_hash(). Empty for imported
+    // movies.
+    pub checksums: Vec<u64>,
+    pub rerecord_count: u32,
+    // Informational FM2 fields, kept as they are.
+    pub rom_filename: String,
+    pub rom_checksum: String,
+    pub guid: String,
+    pub comments: Vec<String>,
+}
+
+impl Movie {
+    // An empty movie starting from power on.
+    fn with_region(region: Region) -> Self {
+        Movie {
+            region,
+            savestate: None,
+            frames: Vec::new(),
+            checksums: Vec::new(),
+            rerecord_count: 0,
+            rom_filename: String::new(),
+            rom_checksum: String::new(),
+            guid: String::new(),
+            comments: Vec::new(),
+        }
+    }
+
+    // Starts a movie from power on. |nes| must have just been powered on.
+    pub fn from_power_on(nes: &Nes) -> Self {
+        Movie::with_region(nes.region())
+    }
+
+    // Starts a movie from the current state of |nes|.
+    pub fn from_savestate(nes: &Nes) -> Self {
+        Movie {
+            savestate: Some(nes.save()),
+            ..Movie::from_power_on(nes)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // Runs one frame of |nes| with |input| and appends it to the movie.
+    pub fn record_frame(&mut self, nes: &mut Nes, input: MovieFrame) -> Result<(), SimpleError> {
+        run_frame(nes, &input)?;
+        self.frames.push(input);
+        self.checksums.push(nes.state_hash());
+        Ok(())
+    }
+
+    // Drops the frames after the first |frame| ones to record them again. The host restores the
+    // machine to the state it had after |frame| frames, e.g. with a save state.
+    pub fn rerecord_from(&mut self, frame: usize) {
+        self.frames.truncate(frame);
+        self.checksums.truncate(frame);
+        self.rerecord_count += 1;
+    }
+
+    pub fn from_fm2_file<P: AsRef<Path>>(path: P) -> Result<Movie, SimpleError> {
+        let text = std::fs::read_to_string(path).map_err(SimpleError::from)?;
+        Movie::parse_fm2(&text)
+    }
+
+    pub fn save_fm2_file<P: AsRef<Path>>(&self, path: P) -> Result<(), SimpleError> {
+        std::fs::write(path, self.to_fm2()).map_err(SimpleError::from)
+    }
+
+    pub fn parse_fm2(text: &str) -> Result<Movie, SimpleError> {
+        let mut movie = Movie::with_region(Region::Ntsc);
+        let mut ports = [FM2_PORT_GAMEPAD, FM2_PORT_GAMEPAD];
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('|') {
+                movie
+                    .frames
+                    .push(parse_fm2_frame(line, &ports).map_err(|e| {
+                        SimpleError::new(format!("FM2 line {}: {}", i + 1, e.as_str()))
+                    })?);
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (key, value) = match line.find(' ') {
+                Some(pos) => (&line[..pos], &line[pos + 1..]),
+                None => (line, ""),
+            };
+            let number = || {
+                value.trim().parse::<u32>().map_err(|_| {
+                    SimpleError::new(format!("FM2 line {}: {} is not a number", i + 1, key))
+                })
+            };
+            match key {
+                "version" if number()? != FM2_VERSION => {
+                    return Err(SimpleError::new(format!(
+                        "unsupported FM2 version {}",
+                        value
+                    )));
+                }
+                "rerecordCount" => movie.rerecord_count = number()?,
+                "palFlag" if number()? != 0 => movie.region = Region::Pal,
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "romChecksum" => movie.rom_checksum = value.to_string(),
+                "guid" => movie.guid = value.to_string(),
+                "comment" => movie.comments.push(value.to_string()),
+                "port0" | "port1" => {
+                    let port = number()? as u8;
+                    if port != FM2_PORT_NONE && port != FM2_PORT_GAMEPAD {
+                        return Err(SimpleError::new(format!(
+                            "unsupported FM2 input device {} in {}",
+                            port, key
+                        )));
+                    }
+                    ports[(key == "port1") as usize] = port;
+                }
+                "fourscore" | "FDS" | "binary" if number()? != 0 => {
+                    return Err(SimpleError::new(format!("unsupported FM2 option {}", key)));
+                }
+                "savestate" => movie.savestate = Some(decode_blob(value)?),
+                _ => {}
+            }
+        }
+        Ok(movie)
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut text = String::new();
+        text.push_str(&format!("version {}\n", FM2_VERSION));
+        text.push_str("emuVersion 0\n");
+        text.push_str(&format!("rerecordCount {}\n", self.rerecord_count));
+        text.push_str(&format!("palFlag {}\n", (self.region == Region::Pal) as u8));
+        text.push_str(&format!("romFilename {}\n", self.rom_filename));
+        text.push_str(&format!("romChecksum {}\n", self.rom_checksum));
+        text.push_str(&format!("guid {}\n", self.guid));
+        text.push_str("fourscore 0\n");
+        text.push_str("microphone 0\n");
+        text.push_str(&format!("port0 {}\n", FM2_PORT_GAMEPAD));
+        text.push_str(&format!("port1 {}\n", FM2_PORT_GAMEPAD));
+        text.push_str(&format!("port2 {}\n", FM2_PORT_NONE));
+        text.push_str("FDS 0\n");
+        text.push_str("NewPPU 0\n");
+        for comment in self.comments.iter() {
+            text.push_str(&format!("comment {}\n", comment));
+        }
+        if let Some(savestate) = &self.savestate {
+            text.push_str(&format!("savestate base64:{}\n", encode_base64(savestate)));
+        }
+
+        for frame in self.frames.iter() {
+            text.push_str(&format!("|{}|", frame.reset as u8 * COMMAND_RESET));
+            for buttons in frame.buttons.iter() {
+                for (c, button) in FM2_BUTTONS.iter() {
+                    text.push(if buttons.contains(*button) { *c } else { '.' });
+                }
+                text.push('|');
+            }
+            text.push_str("|\n");
+        }
+        text
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PlaybackStatus {
+    Playing,
+    // The machine differs from the recording after |frame|. Playback goes on.
+    Desync { frame: usize },
+    Finished,
+}
+
+// Plays a movie back, one frame at a time.
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+    first_desync: Option<usize>,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> Self {
+        MoviePlayer {
+            movie,
+            frame: 0,
+            first_desync: None,
+        }
+    }
+
+    // Prepares |nes| to play the movie from its first frame. Without a save state, |nes| must
+    // have just been powered on.
+    pub fn start(&mut self, nes: &mut Nes) -> Result<(), SimpleError> {
+        if nes.region() != self.movie.region {
+            return Err(SimpleError::new(format!(
+                "movie was recorded on a {:?} console, not {:?}",
+                self.movie.region,
+                nes.region()
+            )));
+        }
+        if let Some(savestate) = &self.movie.savestate {
+            nes.load(savestate)?;
+        }
+        self.frame = 0;
+        self.first_desync = None;
+        Ok(())
+    }
+
+    // Runs the next frame of the movie.
+    pub fn step(&mut self, nes: &mut Nes) -> Result<PlaybackStatus, SimpleError> {
+        let input = match self.movie.frames.get(self.frame) {
+            Some(input) => *input,
+            None => return Ok(PlaybackStatus::Finished),
+        };
+        run_frame(nes, &input)?;
+        let frame = self.frame;
+        self.frame += 1;
+
+        match self.movie.checksums.get(frame) {
+            Some(expected) if *expected != nes.state_hash() => {
+                self.first_desync.get_or_insert(frame);
+                Ok(PlaybackStatus::Desync { frame })
+            }
+            _ => Ok(PlaybackStatus::Playing),
+        }
+    }
+
+    // Index of the next frame to play.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    // The first frame after which the machine differed from the recording.
+    pub fn first_desync(&self) -> Option<usize> {
+        self.first_desync
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    // Gives the movie back, e.g. to re-record from the current frame.
+    pub fn into_movie(self) -> Movie {
+        self.movie
+    }
+}
+
+// Runs one frame of |nes| with |input|.
+fn run_frame(nes: &mut Nes, input: &MovieFrame) -> Result<(), SimpleError> {
+    if input.reset {
+        nes.reset();
+    }
+    for (port, buttons) in input.buttons.iter().enumerate() {
+        match nes.input_mut().device_mut::<Joypad>(port) {
+            Some(joypad) => joypad.set_buttons(*buttons),
+            None if buttons.is_empty() => {}
+            None => {
+                return Err(SimpleError::new(format!(
+                    "movie presses buttons on port {}, which has no controller",
+                    port
+                )))
+            }
+        }
+    }
+    nes.run_frame()?;
+    Ok(())
+}
+
+fn parse_fm2_frame(line: &str, ports: &[u8; 2]) -> Result<MovieFrame, SimpleError> {
+    let fields: Vec<&str> = line.split('|').collect();
+    // The line starts and ends with '|', hence the empty first and last fields.
+    if fields.len() < 4 {
+        return Err(SimpleError::new("input line has too few fields"));
+    }
+    let commands = fields[1]
+        .trim()
+        .parse::<u8>()
+        .map_err(|_| SimpleError::new("invalid commands"))?;
+    if commands & COMMAND_POWER != 0 {
+        return Err(SimpleError::new("power cycling is not supported"));
+    }
+
+    let mut frame = MovieFrame {
+        reset: commands & COMMAND_RESET != 0,
+        ..Default::default()
+    };
+    for (port, field) in fields[2..4].iter().enumerate() {
+        if ports[port] == FM2_PORT_NONE {
+            continue;
+        }
+        let chars: Vec<char> = field.chars().collect();
+        if chars.len() != FM2_BUTTONS.len() {
+            return Err(SimpleError::new(format!("invalid input on port {}", port)));
+        }
+        for (c, (_, button)) in chars.iter().zip(FM2_BUTTONS.iter()) {
+            if *c != '.' && *c != ' ' {
+                frame.buttons[port].insert(*button);
+            }
+        }
+    }
+    Ok(frame)
+}
+
+// Decodes a binary FM2 field: "base64:" followed by base64, or hexadecimal with a "0x" prefix.
+fn decode_blob(value: &str) -> Result<Vec<u8>, SimpleError> {
+    let value = value.trim();
+    if let Some(base64) = value.strip_prefix("base64:") {
+        return decode_base64(base64);
+    }
+    let hex = value.strip_prefix("0x").unwrap_or(value);
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(SimpleError::new("invalid hexadecimal in FM2"));
+    }
+    Ok(hex
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| (hex_digit(pair[0]) << 4) | hex_digit(pair[1]))
+        .collect())
+}
+
+// Returns the value of the hexadecimal digit |c|.
+fn hex_digit(c: u8) -> u8 {
+    (c as char).to_digit(16).unwrap() as u8
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn decode_base64(text: &str) -> Result<Vec<u8>, SimpleError> {
+    let mut bytes = Vec::new();
+    let mut n = 0u32;
+    let mut bits = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let val = BASE64_ALPHABET
+            .iter()
+            .position(|a| *a == c)
+            .ok_or_else(|| SimpleError::new("invalid base64 in FM2"))?;
+        n = n << 6 | val as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((n >> bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nrom_image;
+    use crate::cartridge::Cartridge;
+
+    // Reads the controller of port 1 every frame and adds the buttons to $10.
+    //
+    // LDA #$80         <= 0x8000
+    // STA $2000
+    // JMP $8005        <= 0x8005
+    // LDA #$01         <= 0x8008, NMI handler.
+    // STA $4016
+    // LDA #$00
+    // STA $4016
+    // LDX #$08
+    // LDA $4016        <= 0x8014
+    // LSR
+    // LDA $10
+    // ADC #$00
+    // STA $10
+    // DEX
+    // BNE $8014
+    // RTI
+    fn nes() -> Nes {
+        let program = vec![
+            0xa9, 0x80, 0x8d, 0x00, 0x20, 0x4c, 0x05, 0x80, 0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9,
+            0x00, 0x8d, 0x16, 0x40, 0xa2, 0x08, 0xad, 0x16, 0x40, 0x4a, 0xa5, 0x10, 0x69, 0x00,
+            0x85, 0x10, 0xca, 0xd0, 0xf3, 0x40,
+        ];
+        let mut image = nrom_image(&program);
+        image[16 + 0x7ffa] = 0x08;
+        image[16 + 0x7ffb] = 0x80;
+        Nes::new(Cartridge::parse(&image).unwrap())
+    }
+
+    fn record(nes: &mut Nes, movie: &mut Movie) {
+        for i in 0..10 {
+            let buttons = if i % 3 == 0 {
+                JoypadButton::A | JoypadButton::RIGHT
+            } else {
+                JoypadButton::empty()
+            };
+            let input = MovieFrame::new(buttons, JoypadButton::empty());
+            movie.record_frame(nes, input).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_record_and_play() {
+        let mut nes1 = nes();
+        let mut movie = Movie::from_power_on(&nes1);
+        record(&mut nes1, &mut movie);
+        let mut nes2 = nes();
+        let mut player = MoviePlayer::new(movie);
+
+        player.start(&mut nes2).unwrap();
+        while player.step(&mut nes2).unwrap() != PlaybackStatus::Finished {}
+
+        assert_eq!(nes1.cpu().peek_mem(0x10) > 0, true);
+        assert_eq!(nes2.save(), nes1.save());
+        assert_eq!(player.first_desync(), None);
+    }
+
+    #[test]
+    fn test_play_from_savestate() {
+        let mut nes1 = nes();
+        nes1.run_frame().unwrap();
+        let mut movie = Movie::from_savestate(&nes1);
+        record(&mut nes1, &mut movie);
+        let mut nes2 = nes();
+        let mut player = MoviePlayer::new(movie);
+
+        player.start(&mut nes2).unwrap();
+        while player.step(&mut nes2).unwrap() != PlaybackStatus::Finished {}
+
+        assert_eq!(nes2.save(), nes1.save());
+    }
+
+    #[test]
+    fn test_desync() {
+        let mut nes1 = nes();
+        let mut movie = Movie::from_power_on(&nes1);
+        record(&mut nes1, &mut movie);
+        movie.frames[4].buttons[0] = JoypadButton::B;
+        let mut nes2 = nes();
+        let mut player = MoviePlayer::new(movie);
+
+        player.start(&mut nes2).unwrap();
+        for _ in 0..4 {
+            assert_eq!(player.step(&mut nes2), Ok(PlaybackStatus::Playing));
+        }
+
+        assert_eq!(
+            player.step(&mut nes2),
+            Ok(PlaybackStatus::Desync { frame: 4 })
+        );
+        assert_eq!(player.first_desync(), Some(4));
+    }
+
+    #[test]
+    fn test_rerecord() {
+        let mut nes = nes();
+        let mut movie = Movie::from_power_on(&nes);
+        movie.record_frame(&mut nes, MovieFrame::default()).unwrap();
+        let state = nes.save();
+        movie
+            .record_frame(
+                &mut nes,
+                MovieFrame::new(JoypadButton::A, JoypadButton::empty()),
+            )
+            .unwrap();
+
+        nes.load(&state).unwrap();
+        movie.rerecord_from(1);
+        movie.record_frame(&mut nes, MovieFrame::default()).unwrap();
+
+        assert_eq!(movie.len(), 2);
+        assert_eq!(movie.rerecord_count, 1);
+        assert_eq!(movie.frames[1], MovieFrame::default());
+    }
+
+    #[test]
+    fn test_fm2() {
+        let nes = nes();
+        let mut movie = Movie::from_savestate(&nes);
+        movie.comments.push("author someone".to_string());
+        movie.frames.push(MovieFrame::new(
+            JoypadButton::A | JoypadButton::UP,
+            JoypadButton::START,
+        ));
+        movie.frames.push(MovieFrame {
+            reset: true,
+            ..Default::default()
+        });
+
+        let text = movie.to_fm2();
+        let mut parsed = Movie::parse_fm2(&text).unwrap();
+
+        assert_eq!(
+            text.contains("\n|0|...U...A|....T...||\n|1|........|........||\n"),
+            true
+        );
+        parsed.checksums = movie.checksums.clone();
+        assert_eq!(parsed, movie);
+    }
+
+    #[test]
+    fn test_parse_fceux_fm2() {
+        let text = "version 3\nemuVersion 22020\nrerecordCount 12\npalFlag 0\n\
+                    romFilename game\nguid 452DE2C3-EF43-2FA9-77AC-0677FC51543B\nfourscore 0\n\
+                    port0 1\nport1 0\nport2 0\n|0|R..U...A|||\n|1|. . . . ||";
+
+        let movie = Movie::parse_fm2(text).unwrap();
+
+        assert_eq!(movie.rerecord_count, 12);
+        assert_eq!(movie.rom_filename, "game");
+        assert_eq!(movie.frames.len(), 2);
+        assert_eq!(
+            movie.frames[0].buttons[0],
+            JoypadButton::RIGHT | JoypadButton::UP | JoypadButton::A
+        );
+        assert_eq!(movie.frames[1].reset, true);
+        assert_eq!(movie.frames[1].buttons[0], JoypadButton::empty());
+        assert_eq!(Movie::parse_fm2("version 2\n").is_err(), true);
+        assert_eq!(Movie::parse_fm2("port0 2\n").is_err(), true);
+    }
+
+    #[test]
+    fn test_base64() {
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"].iter() {
+            assert_eq!(decode_base64(&encode_base64(bytes)), Ok(bytes.to_vec()));
+        }
+        assert_eq!(encode_base64(b"foob"), "Zm9vYg==");
+        assert_eq!(decode_blob("0x0aff"), Ok(vec![0x0a, 0xff]));
+        assert_eq!(decode_blob("0x+f+f").is_err(), true);
+    }
+
+    #[test]
+    fn test_invalid_savestate_blob() {
+        assert_eq!(Movie::parse_fm2("savestate a\u{20ac}\n").is_err(), true);
+        assert_eq!(Movie::parse_fm2("savestate 0x+f+f\n").is_err(), true);
+    }
+}
@@ -0,0 +1,741 @@
+/**
+ * The NES console: CPU, PPU, APU, cartridge and controllers, kept in sync by a master clock.
+ *
+ * Every component runs at a fixed division of the master clock, which depends on the region: on
+ * NTSC, the CPU (and the APU with it) every 12 ticks and the PPU every 4 ticks, i.e. 3 dots per
+ * CPU cycle. PAL consoles run the CPU every 16 ticks and the PPU every 5, i.e. 3.2 dots per CPU
+ * cycle.
+ *
+ * The CPU executes a whole instruction on its first cycle, then owes the cycles the instruction
+ * takes. Each following CPU cycle pays one of them off while the PPU and the APU catch up, so
+ * the other components never lag behind by more than one instruction. Interrupts are checked
+ * between instructions.
+ *
+ * Execution is deterministic: the same cartridge, power-on state and input always lead to the same
+ * state, which state_hash() summarizes cheaply. Nothing depends on the time or on the host, so
+ * anything that varies between consoles must be chosen explicitly when powering on.
+ *
+ * See https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+ */
+use crate::apu::{Apu, DEFAULT_SAMPLE_RATE};
+use crate::bus::Bus;
+use crate::cartridge::Cartridge;
+use crate::cdl::{PRG_DATA, PRG_PCM};
+use crate::cpu::{Status, CPU};
+use crate::frame::Frame;
+use crate::input::{ControllerPorts, InputContext, JOYPAD_REG_1, JOYPAD_REG_2};
+use crate::ppu::Ppu;
+use crate::ram_pattern::RamPattern;
+use crate::region::Region;
+use crate::savestate::{self, Savestate, StateReader, StateWriter};
+use simple_error::SimpleError;
+use std::path::Path;
+
+const RAM_SIZE: usize = 0x800;
+
+// Registers.
+const REG_APU_STATUS: u16 = 0x4015;
+const REG_OAM_DMA: u16 = 0x4014;
+
+// OAM DMA halts the CPU for 513 cycles, plus one if it starts on an odd cycle.
+const OAM_DMA_CYCLES: u64 = 513;
+
+// Everything the CPU sees on its address bus.
+pub struct NesBus {
+    ram: [u8; RAM_SIZE],
+    pub ppu: Ppu,
+    pub apu: Apu,
+    pub input: ControllerPorts,
+    pub cartridge: Cartridge,
+    // Whether an OAM DMA happened during the current instruction.
+    oam_dma: bool,
+    // Whether the last access was a write. Instructions start with a read, so it is not part of
+    // the state.
+    last_write: bool,
+}
+
+impl NesBus {
+    pub fn new(cartridge: Cartridge, region: Region) -> Self {
+        NesBus {
+            ram: [0; RAM_SIZE],
+            ppu: Ppu::with_region(region),
+            apu: Apu::with_region(region, DEFAULT_SAMPLE_RATE),
+            input: ControllerPorts::new(),
+            cartridge,
+            oam_dma: false,
+            last_write: false,
+        }
+    }
+
+    // Advances the APU by one CPU cycle, feeding the DMC with sample bytes.
+    fn tick_apu(&mut self) {
+        self.apu.tick();
+        if let Some(addr) = self.apu.dmc_pending_read() {
+            self.cartridge.log_prg(addr, PRG_DATA | PRG_PCM);
+            let val = self.peek(addr);
+            self.apu.dmc_fill_sample_buffer(val);
+        }
+    }
+
+    // Copies the |page| of CPU memory to OAM.
+    fn oam_dma(&mut self, page: u8) {
+        let start = (page as u16) << 8;
+        for i in 0..0x100 {
+            let val = self.read(start + i);
+            self.ppu.write_oam_data(val);
+        }
+        self.oam_dma = true;
+    }
+}
+
+impl Bus for NesBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.last_write = false;
+        match addr {
+            0x2000..=0x3fff => self.ppu.read_register(addr, &self.cartridge),
+            REG_APU_STATUS => self.apu.read_status(),
+            JOYPAD_REG_1 | JOYPAD_REG_2 => {
+                // A light gun looks at the picture where the beam is.
+                let ctx = InputContext {
+                    frame: Some(self.ppu.frame()),
+                    scanline: self.ppu.scanline(),
+                    dot: self.ppu.dot(),
+                };
+                self.input.read(addr, &ctx)
+            }
+            0x4020..=0xffff => self.cartridge.cpu_read(addr),
+            _ => self.peek(addr),
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1fff => self.ram[addr as usize % RAM_SIZE],
+            0x2000..=0x3fff => self.ppu.peek_register(addr, &self.cartridge),
+            REG_APU_STATUS => self.apu.peek_status(),
+            JOYPAD_REG_1 | JOYPAD_REG_2 => {
+                let ctx = InputContext {
+                    frame: Some(self.ppu.frame()),
+                    scanline: self.ppu.scanline(),
+                    dot: self.ppu.dot(),
+                };
+                self.input.peek(addr, &ctx)
+            }
+            0x4020..=0xffff => self.cartridge.cpu_peek(addr),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        let consecutive = std::mem::replace(&mut self.last_write, true);
+        match addr {
+            0x0000..=0x1fff => self.ram[addr as usize % RAM_SIZE] = val,
+            0x2000..=0x3fff => self.ppu.write_register(addr, val, &mut self.cartridge),
+            REG_OAM_DMA => self.oam_dma(val),
+            JOYPAD_REG_1 => self.input.write(val),
+            0x4000..=0x4013 | REG_APU_STATUS | JOYPAD_REG_2 => self.apu.write_register(addr, val),
+            0x4020..=0xffff if consecutive => self.cartridge.cpu_write_consecutive(addr, val),
+            0x4020..=0xffff => self.cartridge.cpu_write(addr, val),
+            _ => {}
+        }
+    }
+
+    fn ppu_position(&self) -> Option<(usize, usize)> {
+        Some((self.ppu.scanline(), self.ppu.dot()))
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.cartridge.prg_rom_offset(addr)
+    }
+
+    fn is_logging_code_data(&self) -> bool {
+        self.cartridge.is_logging_code_data()
+    }
+
+    fn log_code_data(&mut self, addr: u16, flags: u8) {
+        self.cartridge.log_prg(addr, flags)
+    }
+}
+
+impl Savestate for NesBus {
+    fn save_state(&self, w: &mut StateWriter) {
+        savestate::write_buffer(w, &self.ram);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+        self.input.save_state(w);
+        self.cartridge.save_state(w);
+        w.write(&self.oam_dma);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        savestate::read_buffer(r, &mut self.ram)?;
+        self.ppu.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.input.load_state(r)?;
+        self.cartridge.load_state(r)?;
+        self.oam_dma = r.read()?;
+        Ok(())
+    }
+}
+
+pub struct Nes {
+    cpu: CPU,
+    region: Region,
+    master_clock: u64,
+    // Master clock of the last PPU dot.
+    ppu_clock: u64,
+    // CPU cycles the current instruction still takes.
+    pending_cycles: u64,
+}
+
+impl Nes {
+    // Powers on a console with |cartridge| inserted. The region is the one the header of the
+    // cartridge asks for, NTSC by default.
+    pub fn new(cartridge: Cartridge) -> Self {
+        let region = cartridge.region.unwrap_or_default();
+        Nes::with_region(cartridge, region)
+    }
+
+    // Powers on a |region| console with |cartridge| inserted. RAM is cleared.
+    pub fn with_region(cartridge: Cartridge, region: Region) -> Self {
+        Nes::with_ram_pattern(cartridge, region, RamPattern::Zeros)
+    }
+
+    // Powers on a |region| console with |cartridge| inserted, with |pattern| in internal RAM,
+    // PRG RAM and OAM.
+    pub fn with_ram_pattern(cartridge: Cartridge, region: Region, pattern: RamPattern) -> Self {
+        let mut bus = NesBus::new(cartridge, region);
+        pattern.fill(&mut [&mut bus.ram, bus.cartridge.prg_ram_mut(), bus.ppu.oam_mut()]);
+        let mut cpu = CPU::with_bus(Box::new(bus));
+        cpu.reset();
+        cpu.reg_status.insert(Status::I);
+        Nes {
+            // The other components run during the reset sequence.
+            pending_cycles: cpu.cycles,
+            cpu,
+            region,
+            master_clock: 0,
+            ppu_clock: 0,
+        }
+    }
+
+    // Presses the reset button.
+    pub fn reset(&mut self) {
+        let cycles = self.cpu.cycles;
+        self.cpu.soft_reset();
+        self.pending_cycles += self.cpu.cycles - cycles;
+
+        let bus = self.bus_mut();
+        bus.apu.write_register(REG_APU_STATUS, 0);
+        bus.ppu.reset();
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn bus(&self) -> &NesBus {
+        self.cpu.bus::<NesBus>().unwrap()
+    }
+
+    pub fn bus_mut(&mut self) -> &mut NesBus {
+        self.cpu.bus_mut::<NesBus>().unwrap()
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.bus().ppu
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.bus().apu
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.bus().cartridge
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.bus_mut().cartridge
+    }
+
+    pub fn input_mut(&mut self) -> &mut ControllerPorts {
+        &mut self.bus_mut().input
+    }
+
+    // The picture, complete after run_frame().
+    pub fn frame(&self) -> &Frame {
+        self.ppu().frame()
+    }
+
+    // Takes the audio samples rendered so far.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.bus_mut().apu.take_samples()
+    }
+
+    // Saves the state of the whole console.
+    pub fn save(&self) -> Vec<u8> {
+        savestate::save(self)
+    }
+
+    // Restores a state made by save() on a console of the same region running the same game.
+    // On error, the console is left untouched.
+    pub fn load(&mut self, data: &[u8]) -> Result<(), SimpleError> {
+        savestate::load(self, data)
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), SimpleError> {
+        savestate::save_to_file(self, path)
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SimpleError> {
+        savestate::load_from_file(self, path)
+    }
+
+    // Hash of the CPU registers, RAM, VRAM, palette, OAM and the mapper state, e.g. to log once
+    // per frame and compare runs. The hash is stable across builds and platforms.
+    pub fn state_hash(&self) -> u64 {
+        let cpu = &self.cpu;
+        let bus = self.bus();
+        let mut hash = FNV_OFFSET_BASIS;
+        hash = fnv1a(
+            hash,
+            &[
+                cpu.reg_a,
+                cpu.reg_x,
+                cpu.reg_y,
+                cpu.reg_status.bits(),
+                cpu.sp,
+            ],
+        );
+        hash = fnv1a(hash, &cpu.pc.to_le_bytes());
+        hash = fnv1a(hash, &cpu.cycles.to_le_bytes());
+        hash = fnv1a(hash, &bus.ram);
+        hash = fnv1a(hash, bus.ppu.vram());
+        hash = fnv1a(hash, bus.ppu.palette());
+        hash = fnv1a(hash, bus.ppu.oam());
+        let mut mapper_state = StateWriter::new();
+        bus.cartridge.save_state(&mut mapper_state);
+        fnv1a(hash, &mapper_state.into_bytes())
+    }
+
+    // Number of master clock ticks since power on.
+    pub fn master_clock(&self) -> u64 {
+        self.master_clock
+    }
+
+    // Advances the console by one CPU cycle. Fails on an illegal opcode, before the cycle.
+    pub fn step_cycle(&mut self) -> Result<(), SimpleError> {
+        if self.pending_cycles == 0 {
+            self.start_instruction()?;
+        }
+        self.pending_cycles -= 1;
+        self.master_clock += self.region.cpu_clock_divider();
+        let ppu_clock_divider = self.region.ppu_clock_divider();
+
+        let bus = self.cpu.bus_mut::<NesBus>().unwrap();
+        bus.tick_apu();
+        while self.ppu_clock + ppu_clock_divider <= self.master_clock {
+            self.ppu_clock += ppu_clock_divider;
+            bus.ppu.tick(&bus.cartridge);
+        }
+        Ok(())
+    }
+
+    // Finishes the current instruction, then runs the next one. Entering an interrupt handler
+    // counts as an instruction.
+    pub fn step_instruction(&mut self) -> Result<(), SimpleError> {
+        while self.pending_cycles > 0 {
+            self.step_cycle()?;
+        }
+        self.step_cycle()?;
+        while self.pending_cycles > 0 {
+            self.step_cycle()?;
+        }
+        Ok(())
+    }
+
+    // Runs until the PPU starts the next frame. The picture of the frame is then complete.
+    pub fn run_frame(&mut self) -> Result<(), SimpleError> {
+        let frame_count = self.ppu().frame_count();
+        while self.ppu().frame_count() == frame_count {
+            self.step_cycle()?;
+        }
+        Ok(())
+    }
+
+    // Enters a pending interrupt handler or executes the next instruction, and records the
+    // cycles it takes.
+    fn start_instruction(&mut self) -> Result<(), SimpleError> {
+        let bus = self.bus_mut();
+        let nmi = bus.ppu.take_nmi();
+        let irq = bus.apu.irq() || bus.cartridge.irq();
+
+        let start_cycles = self.cpu.cycles;
+        if nmi {
+            self.cpu.nmi();
+        } else if !(irq && self.cpu.irq()) {
+            self.cpu.step()?;
+        }
+
+        let bus = self.bus_mut();
+        if std::mem::replace(&mut bus.oam_dma, false) {
+            let alignment = self.cpu.cycles % 2;
+            self.cpu.cycles += OAM_DMA_CYCLES + alignment;
+        }
+        self.pending_cycles = self.cpu.cycles - start_cycles;
+        Ok(())
+    }
+}
+
+// 64-bit FNV-1a. See http://www.isthe.com/chongo/tech/comp/fnv/
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+impl Savestate for Nes {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&(self.region as u8));
+        w.write(&self.master_clock);
+        w.write(&self.ppu_clock);
+        w.write(&self.pending_cycles);
+        self.cpu.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        let region = r.read::<u8>()?;
+        if region != self.region as u8 {
+            return Err(SimpleError::new(format!(
+                "save state is for another region than {:?}",
+                self.region
+            )));
+        }
+        self.master_clock = r.read()?;
+        self.ppu_clock = r.read()?;
+        self.pending_cycles = r.read()?;
+        self.cpu.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nrom_image;
+    use crate::joypad::JoypadButton;
+    use crate::ppu::DOTS_PER_SCANLINE;
+
+    // Offset of PRG ROM in an NROM image.
+    const PRG_ROM_OFFSET: usize = 16;
+
+    fn nes(image: &[u8]) -> Nes {
+        Nes::new(Cartridge::parse(image).unwrap())
+    }
+
+    #[test]
+    fn test_power_on() {
+        // JMP $8000
+        let nes = nes(&nrom_image(&[0x4c, 0x00, 0x80]));
+
+        assert_eq!(nes.cpu().pc, 0x8000);
+        assert_eq!(nes.cpu().reg_status.contains(Status::I), true);
+    }
+
+    #[test]
+    fn test_reset() {
+        // LDA #$01
+        // LDX #$02
+        // LDY #$03
+        // loop:
+        // JMP loop
+        let mut nes = nes(&nrom_image(&[
+            0xa9, 0x01, 0xa2, 0x02, 0xa0, 0x03, 0x4c, 0x06, 0x80,
+        ]));
+        for _ in 0..4 {
+            nes.step_instruction().unwrap();
+        }
+        nes.cpu_mut().reg_status.remove(Status::I);
+        let cycles = nes.cpu().cycles;
+
+        nes.reset();
+
+        let cpu = nes.cpu();
+        assert_eq!((cpu.reg_a, cpu.reg_x, cpu.reg_y), (0x01, 0x02, 0x03));
+        assert_eq!(cpu.sp, 0xfa);
+        assert_eq!(cpu.reg_status.contains(Status::I), true);
+        assert_eq!(cpu.pc, 0x8000);
+        assert_eq!(cpu.cycles, cycles + 7);
+    }
+
+    #[test]
+    fn test_step_cycle() {
+        // LDA #$01
+        let mut nes = nes(&nrom_image(&[0xa9, 0x01]));
+        // The reset sequence.
+        for _ in 0..7 {
+            nes.step_cycle().unwrap();
+        }
+        assert_eq!(nes.cpu().pc, 0x8000);
+        assert_eq!(nes.ppu().dot(), 21);
+
+        nes.step_cycle().unwrap();
+        assert_eq!(nes.cpu().pc, 0x8002);
+        assert_eq!(nes.cpu().reg_a, 0x01);
+        nes.step_cycle().unwrap();
+
+        assert_eq!(nes.cpu().pc, 0x8002);
+        assert_eq!(nes.master_clock(), 9 * 12);
+        assert_eq!(nes.ppu().dot(), 27);
+    }
+
+    #[test]
+    fn test_step_instruction() {
+        // LDA #$01
+        // STA $0200
+        let mut nes = nes(&nrom_image(&[0xa9, 0x01, 0x8d, 0x00, 0x02]));
+
+        nes.step_instruction().unwrap();
+        assert_eq!(nes.cpu().pc, 0x8002);
+        assert_eq!(nes.cpu().cycles, 9);
+        nes.step_instruction().unwrap();
+
+        assert_eq!(nes.cpu().pc, 0x8005);
+        assert_eq!(nes.cpu().peek_mem(0x0200), 0x01);
+        assert_eq!(nes.master_clock(), 13 * 12);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        // JMP $8000
+        let mut nes = nes(&nrom_image(&[0x4c, 0x00, 0x80]));
+
+        nes.run_frame().unwrap();
+        assert_eq!(nes.ppu().frame_count(), 1);
+        let frame_clock = (DOTS_PER_SCANLINE * Region::Ntsc.scanlines_per_frame()) as u64 * 4;
+        assert_eq!(nes.master_clock() >= frame_clock, true);
+        assert_eq!(nes.master_clock() < frame_clock + 12, true);
+        nes.run_frame().unwrap();
+
+        assert_eq!(nes.ppu().frame_count(), 2);
+    }
+
+    #[test]
+    fn test_illegal_opcode() {
+        // JSR $8003
+        // .byte $02
+        let mut nes = nes(&nrom_image(&[0x20, 0x03, 0x80, 0x02]));
+        nes.step_instruction().unwrap();
+        let cycles = nes.cpu().cycles;
+
+        assert_eq!(
+            nes.step_instruction().unwrap_err().as_str(),
+            "illegal opcode 0x02 at 0x8003\n#0  $8003 in $8003\n#1  $8000"
+        );
+        assert_eq!(nes.run_frame().is_err(), true);
+        assert_eq!(nes.cpu().pc, 0x8003);
+        assert_eq!(nes.cpu().cycles, cycles);
+    }
+
+    #[test]
+    fn test_nmi() {
+        // LDA #$80         <= 0x8000
+        // STA $2000
+        // JMP $8005        <= 0x8005
+        // INC $10          <= 0x8008, NMI handler.
+        // RTI
+        let program = vec![
+            0xa9, 0x80, 0x8d, 0x00, 0x20, 0x4c, 0x05, 0x80, 0xe6, 0x10, 0x40,
+        ];
+        let mut image = nrom_image(&program);
+        image[PRG_ROM_OFFSET + 0x7ffa] = 0x08;
+        image[PRG_ROM_OFFSET + 0x7ffb] = 0x80;
+        let mut nes = nes(&image);
+
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+
+        assert_eq!(nes.cpu().peek_mem(0x10), 2);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        // LDA #$42
+        // STA $0201
+        // LDA #$02
+        // STA $4014
+        let program = vec![0xa9, 0x42, 0x8d, 0x01, 0x02, 0xa9, 0x02, 0x8d, 0x14, 0x40];
+        let mut nes = nes(&nrom_image(&program));
+
+        for _ in 0..4 {
+            nes.step_instruction().unwrap();
+        }
+
+        assert_eq!(nes.ppu().oam()[1], 0x42);
+        // 7 for reset, 2 + 4 + 2 + 4 for the program, then 513 plus 1 for alignment.
+        assert_eq!(nes.cpu().cycles, 7 + 12 + 514);
+    }
+
+    #[test]
+    fn test_controller() {
+        // LDA #$01
+        // STA $4016
+        // LDA #$00
+        // STA $4016
+        // LDA $4016
+        // STA $10
+        let program = vec![
+            0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40, 0xad, 0x16, 0x40, 0x85,
+            0x10,
+        ];
+        let mut nes = nes(&nrom_image(&program));
+        nes.input_mut().set_button_pressed(0, JoypadButton::A, true);
+
+        for _ in 0..6 {
+            nes.step_instruction().unwrap();
+        }
+
+        assert_eq!(nes.cpu().peek_mem(0x10), 0x41);
+    }
+
+    #[test]
+    fn test_ram_mirroring() {
+        // LDA #$42
+        // STA $0805
+        let mut nes = nes(&nrom_image(&[0xa9, 0x42, 0x8d, 0x05, 0x08]));
+
+        nes.step_instruction().unwrap();
+        nes.step_instruction().unwrap();
+
+        assert_eq!(nes.cpu().peek_mem(0x0005), 0x42);
+        assert_eq!(nes.cpu().peek_mem(0x1805), 0x42);
+    }
+
+    #[test]
+    fn test_region_from_header() {
+        let mut image = nrom_image(&[0x4c, 0x00, 0x80]);
+        // NES 2.0, PAL.
+        image[7] |= 0b0000_1000;
+        image[12] = 1;
+
+        assert_eq!(nes(&image).region(), Region::Pal);
+        assert_eq!(nes(&nrom_image(&[])).region(), Region::Ntsc);
+    }
+
+    #[test]
+    fn test_pal_frame() {
+        // JMP $8000
+        let image = nrom_image(&[0x4c, 0x00, 0x80]);
+        let mut nes = Nes::with_region(Cartridge::parse(&image).unwrap(), Region::Pal);
+
+        nes.run_frame().unwrap();
+
+        let frame_clock = (DOTS_PER_SCANLINE * 312) as u64 * 5;
+        assert_eq!(nes.master_clock() >= frame_clock, true);
+        assert_eq!(nes.master_clock() < frame_clock + 16, true);
+    }
+
+    #[test]
+    fn test_save_load() {
+        // LDA #$80         <= 0x8000
+        // STA $2000
+        // INC $11          <= 0x8005
+        // JMP $8005
+        // INC $10          <= 0x800a, NMI handler.
+        // RTI
+        let program = vec![
+            0xa9, 0x80, 0x8d, 0x00, 0x20, 0xe6, 0x11, 0x4c, 0x05, 0x80, 0xe6, 0x10, 0x40,
+        ];
+        let mut image = nrom_image(&program);
+        image[PRG_ROM_OFFSET + 0x7ffa] = 0x0a;
+        image[PRG_ROM_OFFSET + 0x7ffb] = 0x80;
+        let mut nes = nes(&image);
+        nes.run_frame().unwrap();
+        for _ in 0..100 {
+            nes.step_cycle().unwrap();
+        }
+
+        let state = nes.save();
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+        let expected = nes.save();
+        nes.load(&state).unwrap();
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+
+        assert_eq!(nes.cpu().peek_mem(0x10), 3);
+        assert_eq!(nes.save(), expected);
+    }
+
+    #[test]
+    fn test_load_other_game() {
+        let mut nes1 = nes(&nrom_image(&[0x4c, 0x00, 0x80]));
+        let nes2 = nes(&nrom_image(&[0x4c, 0x00, 0x81]));
+        nes1.step_instruction().unwrap();
+        let cycles = nes1.cpu().cycles;
+
+        let err = nes1.load(&nes2.save()).unwrap_err();
+
+        assert_eq!(err.as_str().contains("another game"), true);
+        assert_eq!(nes1.cpu().cycles, cycles);
+    }
+
+    #[test]
+    fn test_load_other_region() {
+        let image = nrom_image(&[0x4c, 0x00, 0x80]);
+        let mut nes = nes(&image);
+        let pal = Nes::with_region(Cartridge::parse(&image).unwrap(), Region::Pal);
+
+        assert_eq!(nes.load(&pal.save()).is_err(), true);
+    }
+
+    #[test]
+    fn test_state_hash() {
+        // INC $10
+        // JMP $8000
+        let image = nrom_image(&[0xe6, 0x10, 0x4c, 0x00, 0x80]);
+        let mut nes1 = nes(&image);
+        let mut nes2 = nes(&image);
+
+        for _ in 0..3 {
+            nes1.run_frame().unwrap();
+            nes2.run_frame().unwrap();
+            assert_eq!(nes1.state_hash(), nes2.state_hash());
+        }
+        let hash = nes1.state_hash();
+        nes1.step_instruction().unwrap();
+
+        assert_ne!(nes1.state_hash(), hash);
+        assert_eq!(fnv1a(FNV_OFFSET_BASIS, b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_ram_pattern() {
+        // LDA $0004
+        // STA $6000
+        let image = nrom_image(&[0xad, 0x04, 0x00, 0x8d, 0x00, 0x60]);
+        let mut nes = Nes::with_ram_pattern(
+            Cartridge::parse(&image).unwrap(),
+            Region::Ntsc,
+            RamPattern::Alternating,
+        );
+        assert_eq!(nes.cpu().peek_mem(0x0003), 0x00);
+        assert_eq!(nes.cpu().peek_mem(0x6004), 0xff);
+        assert_eq!(nes.ppu().oam()[4], 0xff);
+
+        nes.step_instruction().unwrap();
+        nes.step_instruction().unwrap();
+
+        assert_eq!(nes.cpu().peek_mem(0x6000), 0xff);
+    }
+}
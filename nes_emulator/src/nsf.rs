@@ -0,0 +1,835 @@
+/**
+ * Player of NSF and NSFe music files.
+ *
+ * An NSF file holds the music engine of a game together with its data. The player sets up the
+ * 6502 the way the file asks for, calls the INIT routine once per track, then calls the PLAY
+ * routine at the declared rate while the APU renders audio.
+ *
+ * See https://wiki.nesdev.com/w/index.php/NSF and https://wiki.nesdev.com/w/index.php/NSFe
+ */
+use crate::apu::{Apu, CPU_CLOCK_RATE_NTSC, DEFAULT_SAMPLE_RATE};
+use crate::bus::Bus;
+use crate::cpu::CPU;
+use crate::region::Region;
+use crate::savestate::{self, Savestate, StateReader, StateWriter};
+use bitflags::bitflags;
+use simple_error::SimpleError;
+use std::fs;
+use std::path::Path;
+
+const NSF_MAGIC: &[u8] = b"NESM\x1a";
+const NSFE_MAGIC: &[u8] = b"NSFE";
+const NSF_HEADER_SIZE: usize = 0x80;
+
+// Bank switching registers. 0x5ff6 and 0x5ff7 only exist for FDS.
+const REG_FDS_BANK_START: u16 = 0x5ff6;
+const REG_BANK_START: u16 = 0x5ff8;
+const REG_BANK_END: u16 = 0x5fff;
+
+const BANK_SIZE: usize = 0x1000;
+
+// The player makes INIT and PLAY return to this address, which is never mapped to code.
+const RETURN_ADDR: u16 = 0x4100;
+
+// How long a routine may run before the player gives up on it, in CPU cycles.
+const ROUTINE_CYCLE_LIMIT: u64 = CPU_CLOCK_RATE_NTSC as u64;
+
+bitflags! {
+    // Expansion sound chips used by the music.
+    pub struct ExpansionChips : u8 {
+        const VRC6 = 0b0000_0001;
+        const VRC7 = 0b0000_0010;
+        const FDS = 0b0000_0100;
+        const MMC5 = 0b0000_1000;
+        const N163 = 0b0001_0000;
+        const S5B = 0b0010_0000;
+    }
+}
+
+pub struct Nsf {
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub total_songs: u8,
+    // 0-based.
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    // Initial banks of [0x8000, 0xffff] in 4KB pages. None if the music does not bank switch.
+    pub bankswitch_init: Option<[u8; 8]>,
+    // Period of PLAY in microseconds.
+    pub ntsc_play_speed: u16,
+    pub pal_play_speed: u16,
+    pub pal: bool,
+    pub dual_region: bool,
+    pub expansion_chips: ExpansionChips,
+    // Only available in NSFe.
+    pub track_labels: Vec<String>,
+    // Length of each track in milliseconds. Only available in NSFe.
+    pub track_times: Vec<Option<u32>>,
+    pub data: Vec<u8>,
+}
+
+impl Nsf {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Nsf, SimpleError> {
+        let bytes = fs::read(path).map_err(|e| SimpleError::with("cannot read NSF file", e))?;
+        Nsf::parse(&bytes)
+    }
+
+    // Parses either an NSF or an NSFe file.
+    pub fn parse(bytes: &[u8]) -> Result<Nsf, SimpleError> {
+        if bytes.starts_with(NSF_MAGIC) {
+            Nsf::parse_nsf(bytes)
+        } else if bytes.starts_with(NSFE_MAGIC) {
+            Nsf::parse_nsfe(bytes)
+        } else {
+            Err(SimpleError::new("not an NSF or NSFe file"))
+        }
+    }
+
+    fn parse_nsf(bytes: &[u8]) -> Result<Nsf, SimpleError> {
+        if bytes.len() < NSF_HEADER_SIZE {
+            return Err(SimpleError::new(format!(
+                "NSF header is truncated: {} bytes",
+                bytes.len()
+            )));
+        }
+
+        let version = bytes[0x05];
+        let banks = read_bank_init(&bytes[0x70..0x78]);
+        let mut data = &bytes[NSF_HEADER_SIZE..];
+        // NSF2 may append metadata after the program data.
+        let data_len =
+            bytes[0x7d] as usize | (bytes[0x7e] as usize) << 8 | (bytes[0x7f] as usize) << 16;
+        if version >= 2 && data_len != 0 && data_len < data.len() {
+            data = &data[..data_len];
+        }
+
+        let nsf = Nsf {
+            title: read_string(&bytes[0x0e..0x2e]),
+            artist: read_string(&bytes[0x2e..0x4e]),
+            copyright: read_string(&bytes[0x4e..0x6e]),
+            total_songs: bytes[0x06],
+            starting_song: bytes[0x07].saturating_sub(1),
+            load_addr: read_u16(&bytes[0x08..]),
+            init_addr: read_u16(&bytes[0x0a..]),
+            play_addr: read_u16(&bytes[0x0c..]),
+            bankswitch_init: banks,
+            ntsc_play_speed: read_u16(&bytes[0x6e..]),
+            pal_play_speed: read_u16(&bytes[0x78..]),
+            pal: bytes[0x7a] & 0b0000_0001 != 0,
+            dual_region: bytes[0x7a] & 0b0000_0010 != 0,
+            expansion_chips: ExpansionChips::from_bits_truncate(bytes[0x7b]),
+            track_labels: Vec::new(),
+            track_times: Vec::new(),
+            data: data.to_vec(),
+        };
+        nsf.validate()?;
+        Ok(nsf)
+    }
+
+    fn parse_nsfe(bytes: &[u8]) -> Result<Nsf, SimpleError> {
+        let mut nsf = Nsf {
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            total_songs: 1,
+            starting_song: 0,
+            load_addr: 0,
+            init_addr: 0,
+            play_addr: 0,
+            bankswitch_init: None,
+            ntsc_play_speed: 16639,
+            pal_play_speed: 19997,
+            pal: false,
+            dual_region: false,
+            expansion_chips: ExpansionChips::empty(),
+            track_labels: Vec::new(),
+            track_times: Vec::new(),
+            data: Vec::new(),
+        };
+        let mut has_info = false;
+        let mut has_data = false;
+
+        let mut pos = NSFE_MAGIC.len();
+        while pos < bytes.len() {
+            if pos + 8 > bytes.len() {
+                return Err(SimpleError::new(format!(
+                    "NSFe chunk header at offset {} is truncated",
+                    pos
+                )));
+            }
+            let len =
+                u32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+                    as usize;
+            let id = &bytes[pos + 4..pos + 8];
+            let start = pos + 8;
+            if start + len > bytes.len() {
+                return Err(SimpleError::new(format!(
+                    "NSFe chunk {} is truncated",
+                    String::from_utf8_lossy(id)
+                )));
+            }
+            let chunk = &bytes[start..start + len];
+            pos = start + len;
+
+            match id {
+                b"INFO" => {
+                    if chunk.len() < 8 {
+                        return Err(SimpleError::new("NSFe INFO chunk is too short"));
+                    }
+                    nsf.load_addr = read_u16(&chunk[0..]);
+                    nsf.init_addr = read_u16(&chunk[2..]);
+                    nsf.play_addr = read_u16(&chunk[4..]);
+                    nsf.pal = chunk[6] & 0b0000_0001 != 0;
+                    nsf.dual_region = chunk[6] & 0b0000_0010 != 0;
+                    nsf.expansion_chips = ExpansionChips::from_bits_truncate(chunk[7]);
+                    if chunk.len() > 8 {
+                        nsf.total_songs = chunk[8];
+                    }
+                    if chunk.len() > 9 {
+                        nsf.starting_song = chunk[9];
+                    }
+                    has_info = true;
+                }
+                b"DATA" => {
+                    nsf.data = chunk.to_vec();
+                    has_data = true;
+                }
+                b"BANK" => {
+                    let mut banks = [0; 8];
+                    for (i, bank) in chunk.iter().take(8).enumerate() {
+                        banks[i] = *bank;
+                    }
+                    nsf.bankswitch_init = Some(banks);
+                }
+                b"RATE" => {
+                    if chunk.len() >= 2 {
+                        nsf.ntsc_play_speed = read_u16(&chunk[0..]);
+                    }
+                    if chunk.len() >= 4 {
+                        nsf.pal_play_speed = read_u16(&chunk[2..]);
+                    }
+                }
+                b"auth" => {
+                    let mut strings = chunk.split(|b| *b == 0).map(read_string);
+                    nsf.title = strings.next().unwrap_or_default();
+                    nsf.artist = strings.next().unwrap_or_default();
+                    nsf.copyright = strings.next().unwrap_or_default();
+                }
+                b"tlbl" => {
+                    nsf.track_labels = chunk
+                        .split(|b| *b == 0)
+                        .map(read_string)
+                        .take(nsf.total_songs as usize)
+                        .collect();
+                }
+                b"time" => {
+                    nsf.track_times = chunk
+                        .chunks_exact(4)
+                        .map(|t| {
+                            let ms = i32::from_le_bytes([t[0], t[1], t[2], t[3]]);
+                            if ms < 0 {
+                                None
+                            } else {
+                                Some(ms as u32)
+                            }
+                        })
+                        .collect();
+                }
+                b"NEND" => break,
+                _ => {
+                    // Chunks starting with an upper case letter must be understood.
+                    if id[0].is_ascii_uppercase() {
+                        return Err(SimpleError::new(format!(
+                            "unsupported mandatory NSFe chunk {}",
+                            String::from_utf8_lossy(id)
+                        )));
+                    }
+                }
+            }
+        }
+
+        if !has_info || !has_data {
+            return Err(SimpleError::new("NSFe file misses its INFO or DATA chunk"));
+        }
+        nsf.validate()?;
+        Ok(nsf)
+    }
+
+    fn validate(&self) -> Result<(), SimpleError> {
+        if self.total_songs == 0 {
+            return Err(SimpleError::new("NSF has no song"));
+        }
+        let min_load_addr = if self.expansion_chips.contains(ExpansionChips::FDS) {
+            0x6000
+        } else {
+            0x8000
+        };
+        if self.load_addr < min_load_addr {
+            return Err(SimpleError::new(format!(
+                "NSF load address 0x{:04x} is below 0x{:04x}",
+                self.load_addr, min_load_addr
+            )));
+        }
+        Ok(())
+    }
+
+    // The region the music is played for: PAL if it only supports PAL, NTSC otherwise.
+    pub fn region(&self) -> Region {
+        if self.pal && !self.dual_region {
+            Region::Pal
+        } else {
+            Region::Ntsc
+        }
+    }
+
+    // The PLAY period in CPU cycles.
+    fn play_period_cycles(&self) -> f64 {
+        let region = self.region();
+        let speed = match region {
+            Region::Pal if self.pal_play_speed == 0 => 19997,
+            Region::Pal => self.pal_play_speed,
+            _ if self.ntsc_play_speed == 0 => 16639,
+            _ => self.ntsc_play_speed,
+        };
+        speed as f64 * region.cpu_clock_rate() / 1_000_000.0
+    }
+}
+
+// Reads a little endian u16.
+fn read_u16(bytes: &[u8]) -> u16 {
+    bytes[0] as u16 | (bytes[1] as u16) << 8
+}
+
+// Reads a null-terminated string.
+fn read_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn read_bank_init(bytes: &[u8]) -> Option<[u8; 8]> {
+    if bytes.iter().all(|b| *b == 0) {
+        return None;
+    }
+    let mut banks = [0; 8];
+    banks.copy_from_slice(bytes);
+    Some(banks)
+}
+
+// The bus seen by the music code: 2KB of RAM, the APU, bank switching registers, 8KB of RAM at
+// 0x6000 and the program banks at 0x8000.
+pub struct NsfBus {
+    ram: [u8; 0x800],
+    prg_ram: [u8; 0x2000],
+    // The program, padded so that it starts on a bank boundary.
+    rom: Vec<u8>,
+    banks: [u8; 8],
+    // FDS music runs from RAM in [0x6000, 0xffff] and bank switches by copying.
+    fds_ram: Option<Vec<u8>>,
+    pub apu: Apu,
+}
+
+impl NsfBus {
+    pub fn new(nsf: &Nsf, apu: Apu) -> Self {
+        let (padding, banks) = match nsf.bankswitch_init {
+            Some(banks) => ((nsf.load_addr & 0x0fff) as usize, banks),
+            None => (
+                (nsf.load_addr as usize).saturating_sub(0x8000),
+                [0, 1, 2, 3, 4, 5, 6, 7],
+            ),
+        };
+
+        let mut rom = vec![0; padding];
+        rom.extend_from_slice(&nsf.data);
+        let num_banks = rom.len().div_ceil(BANK_SIZE).max(1);
+        rom.resize(num_banks * BANK_SIZE, 0);
+
+        let mut bus = NsfBus {
+            ram: [0; 0x800],
+            prg_ram: [0; 0x2000],
+            rom,
+            banks,
+            fds_ram: None,
+            apu,
+        };
+
+        if nsf.expansion_chips.contains(ExpansionChips::FDS) {
+            let mut fds_ram = vec![0; 0xa000];
+            if nsf.bankswitch_init.is_none() {
+                let start = nsf.load_addr as usize - 0x6000;
+                let len = nsf.data.len().min(fds_ram.len() - start);
+                fds_ram[start..start + len].copy_from_slice(&nsf.data[..len]);
+                bus.fds_ram = Some(fds_ram);
+            } else {
+                bus.fds_ram = Some(fds_ram);
+                // [0x6000, 0x7fff] mirrors the banks of [0xe000, 0xffff] initially.
+                bus.write(REG_FDS_BANK_START, banks[6]);
+                bus.write(REG_FDS_BANK_START + 1, banks[7]);
+                for (i, bank) in banks.iter().enumerate() {
+                    bus.write(REG_BANK_START + i as u16, *bank);
+                }
+            }
+        }
+
+        bus
+    }
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        let num_banks = self.rom.len() / BANK_SIZE;
+        let bank = self.banks[((addr - 0x8000) as usize) / BANK_SIZE] as usize % num_banks;
+        self.rom[bank * BANK_SIZE + (addr as usize & (BANK_SIZE - 1))]
+    }
+
+    // Copies the 4KB |bank| of the program into FDS RAM at |addr|.
+    fn copy_bank_to_fds_ram(&mut self, addr: u16, bank: u8) {
+        let num_banks = self.rom.len() / BANK_SIZE;
+        let src = (bank as usize % num_banks) * BANK_SIZE;
+        let dst = (addr - 0x6000) as usize;
+        if let Some(fds_ram) = self.fds_ram.as_mut() {
+            fds_ram[dst..dst + BANK_SIZE].copy_from_slice(&self.rom[src..src + BANK_SIZE]);
+        }
+    }
+
+    // Advances the APU by |cycles| CPU cycles, feeding the DMC with sample bytes.
+    pub fn tick(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.apu.tick();
+            if let Some(addr) = self.apu.dmc_pending_read() {
+                let val = self.peek(addr);
+                self.apu.dmc_fill_sample_buffer(val);
+            }
+        }
+    }
+}
+
+impl Bus for NsfBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        if addr == 0x4015 {
+            return self.apu.read_status();
+        }
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1fff => self.ram[(addr & 0x07ff) as usize],
+            0x4015 => self.apu.peek_status(),
+            0x6000..=0xffff => match &self.fds_ram {
+                Some(fds_ram) => fds_ram[(addr - 0x6000) as usize],
+                None if addr < 0x8000 => self.prg_ram[(addr - 0x6000) as usize],
+                None => self.read_rom(addr),
+            },
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram[(addr & 0x07ff) as usize] = val,
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(addr, val),
+            REG_FDS_BANK_START..=REG_BANK_END => {
+                if self.fds_ram.is_some() {
+                    let slot = addr - REG_FDS_BANK_START;
+                    self.copy_bank_to_fds_ram(0x6000 + slot * BANK_SIZE as u16, val);
+                    if addr >= REG_BANK_START {
+                        self.banks[(addr - REG_BANK_START) as usize] = val;
+                    }
+                } else if addr >= REG_BANK_START {
+                    self.banks[(addr - REG_BANK_START) as usize] = val;
+                }
+            }
+            0x6000..=0xffff => match self.fds_ram.as_mut() {
+                Some(fds_ram) => fds_ram[(addr - 0x6000) as usize] = val,
+                None if addr < 0x8000 => self.prg_ram[(addr - 0x6000) as usize] = val,
+                None => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+// The program itself is not saved: it comes from the NSF file.
+impl Savestate for NsfBus {
+    fn save_state(&self, w: &mut StateWriter) {
+        savestate::write_buffer(w, &self.ram);
+        savestate::write_buffer(w, &self.prg_ram);
+        savestate::write_buffer(w, &self.banks);
+        w.write(&self.fds_ram);
+        self.apu.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        savestate::read_buffer(r, &mut self.ram)?;
+        savestate::read_buffer(r, &mut self.prg_ram)?;
+        savestate::read_buffer(r, &mut self.banks)?;
+        let fds_ram: Option<Vec<u8>> = r.read()?;
+        if fds_ram.as_ref().map(|ram| ram.len()) != self.fds_ram.as_ref().map(|ram| ram.len()) {
+            return Err(SimpleError::new(
+                "save state does not match the FDS RAM of the NSF",
+            ));
+        }
+        self.fds_ram = fds_ram;
+        self.apu.load_state(r)
+    }
+}
+
+pub struct NsfPlayer {
+    nsf: Nsf,
+    cpu: CPU,
+    sample_rate: u32,
+    // CPU cycles left before the next call of PLAY.
+    cycles_until_play: f64,
+    // Samples rendered but not yet returned.
+    pending_samples: Vec<f32>,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf) -> Self {
+        NsfPlayer::with_sample_rate(nsf, DEFAULT_SAMPLE_RATE)
+    }
+
+    pub fn with_sample_rate(nsf: Nsf, sample_rate: u32) -> Self {
+        let apu = Apu::with_region(nsf.region(), sample_rate);
+        let cpu = CPU::with_bus(Box::new(NsfBus::new(&nsf, apu)));
+        NsfPlayer {
+            nsf,
+            cpu,
+            sample_rate,
+            cycles_until_play: 0.0,
+            pending_samples: Vec::new(),
+        }
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Resets the machine and runs INIT for |song| (0-based).
+    pub fn start_song(&mut self, song: u8) -> Result<(), SimpleError> {
+        if song >= self.nsf.total_songs {
+            return Err(SimpleError::new(format!(
+                "song {} is out of range, the NSF has {} songs",
+                song, self.nsf.total_songs
+            )));
+        }
+
+        let apu = Apu::with_region(self.nsf.region(), self.sample_rate);
+        self.cpu = CPU::with_bus(Box::new(NsfBus::new(&self.nsf, apu)));
+        self.pending_samples.clear();
+
+        // Silence the APU the way the NSF specification asks for.
+        for addr in 0x4000..=0x4013 {
+            self.bus_mut().write(addr, 0);
+        }
+        self.bus_mut().write(0x4015, 0x00);
+        self.bus_mut().write(0x4015, 0x0f);
+        self.bus_mut().write(0x4017, 0x40);
+
+        self.cpu.reg_a = song;
+        // X selects NTSC (0) or PAL (1).
+        self.cpu.reg_x = (self.nsf.region() == Region::Pal) as u8;
+        self.call_routine(self.nsf.init_addr)?;
+
+        self.cycles_until_play = 0.0;
+        Ok(())
+    }
+
+    // Renders |num_samples| samples of the current song, calling PLAY at the declared rate.
+    pub fn render(&mut self, num_samples: usize) -> Result<Vec<f32>, SimpleError> {
+        let period = self.nsf.play_period_cycles();
+
+        while self.pending_samples.len() < num_samples {
+            if self.cycles_until_play <= 0.0 {
+                let cycles = self.call_routine(self.nsf.play_addr)?;
+                self.cycles_until_play += period - cycles as f64;
+            } else {
+                // The CPU idles until the next PLAY.
+                let cycles = self.cycles_until_play.ceil() as u64;
+                self.bus_mut().tick(cycles);
+                self.cycles_until_play -= cycles as f64;
+            }
+            let samples = self.bus_mut().apu.take_samples();
+            self.pending_samples.extend(samples);
+        }
+
+        let rest = self.pending_samples.split_off(num_samples);
+        Ok(std::mem::replace(&mut self.pending_samples, rest))
+    }
+
+    // Renders |seconds| of |song| from its beginning.
+    pub fn render_song(&mut self, song: u8, seconds: f64) -> Result<Vec<f32>, SimpleError> {
+        self.start_song(song)?;
+        self.render((seconds * self.sample_rate as f64) as usize)
+    }
+
+    fn bus_mut(&mut self) -> &mut NsfBus {
+        self.cpu.bus_mut::<NsfBus>().unwrap()
+    }
+
+    // Calls the routine at |addr| as if by JSR and runs it until it returns. Returns the number of
+    // CPU cycles it took.
+    fn call_routine(&mut self, addr: u16) -> Result<u64, SimpleError> {
+        self.cpu.push16(RETURN_ADDR.wrapping_sub(1));
+        self.cpu.pc = addr;
+
+        let start = self.cpu.cycles;
+        while self.cpu.pc != RETURN_ADDR {
+            if self.cpu.cycles - start > ROUTINE_CYCLE_LIMIT {
+                return Err(SimpleError::new(format!(
+                    "routine at 0x{:04x} did not return within {} cycles",
+                    addr, ROUTINE_CYCLE_LIMIT
+                )));
+            }
+            let before = self.cpu.cycles;
+            self.cpu.step()?;
+            let elapsed = self.cpu.cycles - before;
+            self.bus_mut().tick(elapsed);
+        }
+        Ok(self.cpu.cycles - start)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Builds an NSF file loaded at 0x8000 whose INIT stores A into 0x00 and whose PLAY increments
+    // 0x01 and plays a square wave.
+    fn build_nsf() -> Vec<u8> {
+        let mut bytes = vec![0; NSF_HEADER_SIZE];
+        bytes[0..5].copy_from_slice(NSF_MAGIC);
+        bytes[0x05] = 1;
+        bytes[0x06] = 3;
+        bytes[0x07] = 2;
+        bytes[0x08..0x0a].copy_from_slice(&0x8000u16.to_le_bytes());
+        bytes[0x0a..0x0c].copy_from_slice(&0x8000u16.to_le_bytes());
+        bytes[0x0c..0x0e].copy_from_slice(&0x8003u16.to_le_bytes());
+        bytes[0x0e..0x13].copy_from_slice(b"Title");
+        bytes[0x2e..0x34].copy_from_slice(b"Artist");
+        bytes[0x6e..0x70].copy_from_slice(&16639u16.to_le_bytes());
+
+        // INIT ($8000):
+        // STA $00
+        // RTS
+        // PLAY ($8003):
+        // INC $01
+        // LDA #$bf
+        // STA $4000
+        // LDA #$fd
+        // STA $4002
+        // LDA #$08
+        // STA $4003
+        // RTS
+        bytes.extend_from_slice(&[
+            0x85, 0x00, 0x60, 0xe6, 0x01, 0xa9, 0xbf, 0x8d, 0x00, 0x40, 0xa9, 0xfd, 0x8d, 0x02,
+            0x40, 0xa9, 0x08, 0x8d, 0x03, 0x40, 0x60,
+        ]);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_nsf() {
+        let nsf = Nsf::parse(&build_nsf()).unwrap();
+
+        assert_eq!(nsf.title, "Title");
+        assert_eq!(nsf.artist, "Artist");
+        assert_eq!(nsf.total_songs, 3);
+        assert_eq!(nsf.starting_song, 1);
+        assert_eq!(nsf.load_addr, 0x8000);
+        assert_eq!(nsf.init_addr, 0x8000);
+        assert_eq!(nsf.play_addr, 0x8003);
+        assert_eq!(nsf.bankswitch_init, None);
+        assert_eq!(nsf.expansion_chips, ExpansionChips::empty());
+    }
+
+    #[test]
+    fn test_parse_nsf_expansion_chips() {
+        let mut bytes = build_nsf();
+        bytes[0x7b] = 0b0000_0101;
+
+        let nsf = Nsf::parse(&bytes).unwrap();
+
+        assert_eq!(
+            nsf.expansion_chips,
+            ExpansionChips::VRC6 | ExpansionChips::FDS
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_magic() {
+        assert_eq!(
+            Nsf::parse(b"NESM").err(),
+            Some(SimpleError::new("not an NSF or NSFe file"))
+        );
+    }
+
+    #[test]
+    fn test_parse_nsf_low_load_addr() {
+        let mut bytes = build_nsf();
+        bytes[0x08..0x0a].copy_from_slice(&0x6000u16.to_le_bytes());
+
+        assert_eq!(
+            Nsf::parse(&bytes).err(),
+            Some(SimpleError::new("NSF load address 0x6000 is below 0x8000"))
+        );
+    }
+
+    fn push_chunk(bytes: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(id);
+        bytes.extend_from_slice(data);
+    }
+
+    #[test]
+    fn test_parse_nsfe() {
+        let mut bytes = NSFE_MAGIC.to_vec();
+        push_chunk(
+            &mut bytes,
+            b"INFO",
+            &[0x00, 0x80, 0x00, 0x80, 0x03, 0x80, 0x00, 0x00, 0x02, 0x01],
+        );
+        push_chunk(&mut bytes, b"DATA", &[0x60, 0x00, 0x00, 0x60]);
+        push_chunk(&mut bytes, b"auth", b"Game\0Composer\0Copyright\0Ripper\0");
+        push_chunk(&mut bytes, b"tlbl", b"Intro\0Boss\0");
+        push_chunk(
+            &mut bytes,
+            b"time",
+            &[0x10, 0x27, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff],
+        );
+        push_chunk(&mut bytes, b"xtra", &[0x01]);
+        push_chunk(&mut bytes, b"NEND", &[]);
+
+        let nsf = Nsf::parse(&bytes).unwrap();
+
+        assert_eq!(nsf.title, "Game");
+        assert_eq!(nsf.artist, "Composer");
+        assert_eq!(nsf.copyright, "Copyright");
+        assert_eq!(nsf.total_songs, 2);
+        assert_eq!(nsf.starting_song, 1);
+        assert_eq!(nsf.play_addr, 0x8003);
+        assert_eq!(nsf.data, vec![0x60, 0x00, 0x00, 0x60]);
+        assert_eq!(nsf.track_labels, vec!["Intro", "Boss"]);
+        assert_eq!(nsf.track_times, vec![Some(10000), None]);
+    }
+
+    #[test]
+    fn test_parse_nsfe_unknown_mandatory_chunk() {
+        let mut bytes = NSFE_MAGIC.to_vec();
+        push_chunk(&mut bytes, b"XTRA", &[0x01]);
+
+        assert_eq!(
+            Nsf::parse(&bytes).err(),
+            Some(SimpleError::new("unsupported mandatory NSFe chunk XTRA"))
+        );
+    }
+
+    #[test]
+    fn test_bus_bankswitch() {
+        let mut bytes = build_nsf();
+        bytes.truncate(NSF_HEADER_SIZE);
+        // Load in the middle of a bank and start with bank 1 at 0x8000.
+        bytes[0x08..0x0a].copy_from_slice(&0x8100u16.to_le_bytes());
+        bytes[0x70] = 1;
+        let mut data = vec![0xaa; BANK_SIZE - 0x100];
+        data.extend(vec![0xbb; BANK_SIZE]);
+        bytes.extend(data);
+
+        let nsf = Nsf::parse(&bytes).unwrap();
+        let mut bus = NsfBus::new(&nsf, Apu::new());
+
+        assert_eq!(bus.read(0x8000), 0xbb);
+        bus.write(0x5ff8, 0);
+        assert_eq!(bus.read(0x8000), 0x00);
+        assert_eq!(bus.read(0x8100), 0xaa);
+        // Bank 2 does not exist, so it wraps around to bank 0.
+        bus.write(0x5ff9, 2);
+        assert_eq!(bus.read(0x9100), 0xaa);
+    }
+
+    #[test]
+    fn test_bus_fds_ram() {
+        let mut bytes = build_nsf();
+        bytes[0x7b] = ExpansionChips::FDS.bits();
+        let nsf = Nsf::parse(&bytes).unwrap();
+        let mut bus = NsfBus::new(&nsf, Apu::new());
+
+        assert_eq!(bus.read(0x8000), 0x85);
+        bus.write(0x8000, 0x12);
+        assert_eq!(bus.read(0x8000), 0x12);
+    }
+
+    #[test]
+    fn test_start_song_runs_init() {
+        let mut player = NsfPlayer::new(Nsf::parse(&build_nsf()).unwrap());
+
+        assert_eq!(player.start_song(2), Ok(()));
+
+        assert_eq!(player.bus_mut().read(0x0000), 2);
+    }
+
+    #[test]
+    fn test_start_song_out_of_range() {
+        let mut player = NsfPlayer::new(Nsf::parse(&build_nsf()).unwrap());
+
+        assert_eq!(
+            player.start_song(3),
+            Err(SimpleError::new(
+                "song 3 is out of range, the NSF has 3 songs"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_render_calls_play_at_rate() {
+        let mut player = NsfPlayer::new(Nsf::parse(&build_nsf()).unwrap());
+
+        let samples = player.render_song(0, 0.5).unwrap();
+
+        assert_eq!(samples.len(), DEFAULT_SAMPLE_RATE as usize / 2);
+        // About 60 calls per second.
+        assert_eq!(player.bus_mut().read(0x0001), 31);
+        assert!(samples.iter().any(|s| s.abs() > 0.01));
+    }
+
+    #[test]
+    fn test_pal() {
+        let mut bytes = build_nsf();
+        bytes[0x78..0x7a].copy_from_slice(&19997u16.to_le_bytes());
+        bytes[0x7a] = 0b0000_0001;
+        let mut player = NsfPlayer::new(Nsf::parse(&bytes).unwrap());
+        assert_eq!(player.nsf().region(), Region::Pal);
+
+        let samples = player.render_song(0, 0.5).unwrap();
+
+        assert_eq!(samples.len(), DEFAULT_SAMPLE_RATE as usize / 2);
+        assert_eq!(player.cpu.reg_x, 1);
+        // About 50 calls per second.
+        assert_eq!(player.bus_mut().read(0x0001), 26);
+
+        // Music that supports both is played for NTSC.
+        bytes[0x7a] = 0b0000_0011;
+        assert_eq!(Nsf::parse(&bytes).unwrap().region(), Region::Ntsc);
+    }
+
+    #[test]
+    fn test_routine_never_returns() {
+        let mut bytes = build_nsf();
+        // INIT: JMP $8000
+        bytes[NSF_HEADER_SIZE..NSF_HEADER_SIZE + 3].copy_from_slice(&[0x4c, 0x00, 0x80]);
+        let mut player = NsfPlayer::new(Nsf::parse(&bytes).unwrap());
+
+        assert_eq!(
+            player.start_song(0),
+            Err(SimpleError::new(format!(
+                "routine at 0x8000 did not return within {} cycles",
+                ROUTINE_CYCLE_LIMIT
+            )))
+        );
+    }
+}
@@ -0,0 +1,991 @@
+/**
+ * Picture processing unit (2C02).
+ *
+ * The PPU draws 262 scanlines of 341 dots per frame (312 scanlines on PAL and Dendy). Scanlines
+ * [0, 239] are visible, then the PPU idles until it enters vertical blank, where it may raise an
+ * NMI. The last scanline, the pre-render scanline, prepares the first scanline of the next frame.
+ *
+ * Pixels are rendered one dot at a time from the scrolling registers, so that games can change
+ * the scroll or the palette between scanlines. Fetches within a scanline are not emulated: a
+ * scanline always shows the tiles addressed when it started.
+ *
+ * See https://wiki.nesdev.com/w/index.php/PPU
+ */
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::cdl::{CHR_READ, CHR_RENDERED};
+use crate::frame::{Frame, WIDTH};
+use crate::region::Region;
+use crate::savestate::{self, Savestate, StateReader, StateWriter};
+use bitflags::bitflags;
+use simple_error::SimpleError;
+
+// Registers, mirrored every 8 bytes in [0x2000, 0x3fff].
+const REG_CTRL: u16 = 0;
+const REG_MASK: u16 = 1;
+const REG_STATUS: u16 = 2;
+const REG_OAM_ADDR: u16 = 3;
+const REG_OAM_DATA: u16 = 4;
+const REG_SCROLL: u16 = 5;
+const REG_ADDR: u16 = 6;
+const REG_DATA: u16 = 7;
+
+// Timing.
+pub const DOTS_PER_SCANLINE: usize = 341;
+pub const VISIBLE_SCANLINES: usize = 240;
+
+// Memory layout of the PPU address space.
+const NAMETABLE_ADDR_START: u16 = 0x2000;
+const NAMETABLE_SIZE: usize = 0x400;
+const ATTRIBUTE_TABLE_OFFSET: u16 = 0x3c0;
+const PALETTE_ADDR_START: u16 = 0x3f00;
+const PPU_ADDR_MASK: u16 = 0x3fff;
+
+const OAM_SIZE: usize = 256;
+const MAX_SPRITES_PER_SCANLINE: usize = 8;
+
+// Layout of the scrolling registers v and t: yyy NN YYYYY XXXXX, i.e. fine Y, nametable,
+// coarse Y and coarse X.
+const SCROLL_COARSE_X: u16 = 0x001f;
+const SCROLL_COARSE_Y: u16 = 0x03e0;
+const SCROLL_NAMETABLE_X: u16 = 0x0400;
+const SCROLL_NAMETABLE_Y: u16 = 0x0800;
+const SCROLL_FINE_Y: u16 = 0x7000;
+const SCROLL_HORIZONTAL: u16 = SCROLL_COARSE_X | SCROLL_NAMETABLE_X;
+const SCROLL_VERTICAL: u16 = SCROLL_COARSE_Y | SCROLL_NAMETABLE_Y | SCROLL_FINE_Y;
+
+// Colors of the 2C02, indexed by the values of the palette RAM.
+#[rustfmt::skip]
+const SYSTEM_PALETTE: [(u8, u8, u8); 64] = [
+    (0x80, 0x80, 0x80), (0x00, 0x3d, 0xa6), (0x00, 0x12, 0xb0), (0x44, 0x00, 0x96),
+    (0xa1, 0x00, 0x5e), (0xc7, 0x00, 0x28), (0xba, 0x06, 0x00), (0x8c, 0x17, 0x00),
+    (0x5c, 0x2f, 0x00), (0x10, 0x45, 0x00), (0x05, 0x4a, 0x00), (0x00, 0x47, 0x2e),
+    (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05), (0x05, 0x05, 0x05),
+    (0xc7, 0xc7, 0xc7), (0x00, 0x77, 0xff), (0x21, 0x55, 0xff), (0x82, 0x37, 0xfa),
+    (0xeb, 0x2f, 0xb5), (0xff, 0x29, 0x50), (0xff, 0x22, 0x00), (0xd6, 0x32, 0x00),
+    (0xc4, 0x62, 0x00), (0x35, 0x80, 0x00), (0x05, 0x8f, 0x00), (0x00, 0x8a, 0x55),
+    (0x00, 0x99, 0xcc), (0x21, 0x21, 0x21), (0x09, 0x09, 0x09), (0x09, 0x09, 0x09),
+    (0xff, 0xff, 0xff), (0x0f, 0xd7, 0xff), (0x69, 0xa2, 0xff), (0xd4, 0x80, 0xff),
+    (0xff, 0x45, 0xf3), (0xff, 0x61, 0x8b), (0xff, 0x88, 0x33), (0xff, 0x9c, 0x12),
+    (0xfa, 0xbc, 0x20), (0x9f, 0xe3, 0x0e), (0x2b, 0xf0, 0x35), (0x0c, 0xf0, 0xa4),
+    (0x05, 0xfb, 0xff), (0x5e, 0x5e, 0x5e), (0x0d, 0x0d, 0x0d), (0x0d, 0x0d, 0x0d),
+    (0xff, 0xff, 0xff), (0xa6, 0xfc, 0xff), (0xb3, 0xec, 0xff), (0xda, 0xab, 0xeb),
+    (0xff, 0xa8, 0xf9), (0xff, 0xab, 0xb3), (0xff, 0xd2, 0xb0), (0xff, 0xef, 0xa6),
+    (0xff, 0xf7, 0x9c), (0xd7, 0xe8, 0x95), (0xa6, 0xed, 0xaf), (0xa2, 0xf2, 0xda),
+    (0x99, 0xff, 0xfc), (0xdd, 0xdd, 0xdd), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11),
+];
+
+bitflags! {
+    pub struct PpuCtrl : u8 {
+        const NAMETABLE_X = 0b0000_0001;
+        const NAMETABLE_Y = 0b0000_0010;
+        const VRAM_INCREMENT = 0b0000_0100;
+        const SPRITE_PATTERN_ADDR = 0b0000_1000;
+        const BACKGROUND_PATTERN_ADDR = 0b0001_0000;
+        const SPRITE_SIZE = 0b0010_0000;
+        const MASTER_SLAVE = 0b0100_0000;
+        const GENERATE_NMI = 0b1000_0000;
+    }
+}
+
+bitflags! {
+    pub struct PpuMask : u8 {
+        const GREYSCALE = 0b0000_0001;
+        const SHOW_BACKGROUND_LEFT = 0b0000_0010;
+        const SHOW_SPRITES_LEFT = 0b0000_0100;
+        const SHOW_BACKGROUND = 0b0000_1000;
+        const SHOW_SPRITES = 0b0001_0000;
+        const EMPHASIZE_RED = 0b0010_0000;
+        const EMPHASIZE_GREEN = 0b0100_0000;
+        const EMPHASIZE_BLUE = 0b1000_0000;
+    }
+}
+
+bitflags! {
+    pub struct PpuStatus : u8 {
+        const SPRITE_OVERFLOW = 0b0010_0000;
+        const SPRITE_ZERO_HIT = 0b0100_0000;
+        const VBLANK = 0b1000_0000;
+    }
+}
+
+// Sprite attributes in byte 2 of an OAM entry.
+const SPRITE_PALETTE: u8 = 0b0000_0011;
+const SPRITE_BEHIND_BACKGROUND: u8 = 0b0010_0000;
+const SPRITE_FLIP_HORIZONTAL: u8 = 0b0100_0000;
+const SPRITE_FLIP_VERTICAL: u8 = 0b1000_0000;
+
+// A sprite found on the scanline being rendered, with its row of pattern already fetched.
+#[derive(Clone, Copy)]
+struct LineSprite {
+    x: u8,
+    pattern_lo: u8,
+    pattern_hi: u8,
+    attributes: u8,
+    is_sprite_zero: bool,
+}
+
+pub struct Ppu {
+    region: Region,
+    ctrl: PpuCtrl,
+    mask: PpuMask,
+    status: PpuStatus,
+    oam_addr: u8,
+    oam: [u8; OAM_SIZE],
+    // 2KB of VRAM, plus 2KB for four-screen mirroring.
+    vram: [u8; NAMETABLE_SIZE * 4],
+    palette: [u8; 32],
+
+    // Scrolling registers: current VRAM address, temporary VRAM address, fine X scroll and the
+    // write toggle shared by 0x2005 and 0x2006.
+    //
+    // See https://wiki.nesdev.com/w/index.php/PPU_scrolling
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    w: bool,
+
+    // Reads of 0x2007 outside of the palette return the content of this buffer.
+    read_buffer: u8,
+    // The last value written to or read from a register.
+    open_bus: u8,
+
+    scanline: usize,
+    dot: usize,
+    frame_count: u64,
+    odd_frame: bool,
+    nmi_pending: bool,
+
+    line_sprites: Vec<LineSprite>,
+    frame: Frame,
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Ppu::with_region(Region::Ntsc)
+    }
+
+    pub fn with_region(region: Region) -> Self {
+        Ppu {
+            region,
+            ctrl: PpuCtrl::empty(),
+            mask: PpuMask::empty(),
+            status: PpuStatus::empty(),
+            oam_addr: 0,
+            oam: [0; OAM_SIZE],
+            vram: [0; NAMETABLE_SIZE * 4],
+            palette: [0; 32],
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
+            read_buffer: 0,
+            open_bus: 0,
+            scanline: 0,
+            dot: 0,
+            frame_count: 0,
+            odd_frame: false,
+            nmi_pending: false,
+            line_sprites: Vec::with_capacity(MAX_SPRITES_PER_SCANLINE),
+            frame: Frame::new(),
+        }
+    }
+
+    // The reset button clears the control registers and the write toggle, but neither the memory
+    // nor the position of the beam.
+    pub fn reset(&mut self) {
+        self.ctrl = PpuCtrl::empty();
+        self.mask = PpuMask::empty();
+        self.w = false;
+        self.read_buffer = 0;
+    }
+
+    pub fn scanline(&self) -> usize {
+        self.scanline
+    }
+
+    pub fn dot(&self) -> usize {
+        self.dot
+    }
+
+    // Number of frames completed.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // The picture. Scanlines above the beam belong to the frame being rendered, the others to
+    // the previous frame.
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn oam(&self) -> &[u8; OAM_SIZE] {
+        &self.oam
+    }
+
+    pub fn oam_mut(&mut self) -> &mut [u8; OAM_SIZE] {
+        &mut self.oam
+    }
+
+    // Nametables, including the 2KB of four-screen mirroring.
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn palette(&self) -> &[u8; 32] {
+        &self.palette
+    }
+
+    // Returns whether an NMI was raised since the last call.
+    pub fn take_nmi(&mut self) -> bool {
+        std::mem::replace(&mut self.nmi_pending, false)
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask
+            .intersects(PpuMask::SHOW_BACKGROUND | PpuMask::SHOW_SPRITES)
+    }
+
+    // Handles a CPU read in [0x2000, 0x3fff].
+    pub fn read_register(&mut self, addr: u16, cartridge: &Cartridge) -> u8 {
+        let val = self.peek_register(addr, cartridge);
+        match addr & 0b111 {
+            REG_STATUS => {
+                self.status.remove(PpuStatus::VBLANK);
+                self.w = false;
+            }
+            REG_DATA => {
+                let vram_addr = self.v & PPU_ADDR_MASK;
+                // Palette reads are not buffered, but fill the buffer with the nametable
+                // "underneath" the palette.
+                let buffered_addr = if vram_addr >= PALETTE_ADDR_START {
+                    vram_addr - 0x1000
+                } else {
+                    vram_addr
+                };
+                if buffered_addr < NAMETABLE_ADDR_START {
+                    cartridge.log_chr(buffered_addr, CHR_READ);
+                }
+                self.read_buffer = self.read_vram(buffered_addr, cartridge);
+                self.increment_vram_addr();
+            }
+            REG_OAM_DATA => {}
+            // Reading a write-only register does not refresh the open bus.
+            _ => return val,
+        }
+        self.open_bus = val;
+        val
+    }
+
+    // Same as read_register() but without side effects.
+    pub fn peek_register(&self, addr: u16, cartridge: &Cartridge) -> u8 {
+        match addr & 0b111 {
+            REG_STATUS => self.status.bits() | (self.open_bus & 0b0001_1111),
+            REG_OAM_DATA => self.oam[self.oam_addr as usize],
+            REG_DATA => {
+                let vram_addr = self.v & PPU_ADDR_MASK;
+                if vram_addr >= PALETTE_ADDR_START {
+                    (self.open_bus & 0b1100_0000) | self.read_vram(vram_addr, cartridge)
+                } else {
+                    self.read_buffer
+                }
+            }
+            // Write-only registers.
+            _ => self.open_bus,
+        }
+    }
+
+    // Handles a CPU write in [0x2000, 0x3fff].
+    pub fn write_register(&mut self, addr: u16, val: u8, cartridge: &mut Cartridge) {
+        self.open_bus = val;
+        match addr & 0b111 {
+            REG_CTRL => {
+                let was_nmi_enabled = self.ctrl.contains(PpuCtrl::GENERATE_NMI);
+                self.ctrl = PpuCtrl::from_bits_truncate(val);
+                self.t = (self.t & !(SCROLL_NAMETABLE_X | SCROLL_NAMETABLE_Y))
+                    | (((val & 0b11) as u16) << 10);
+                // Enabling NMI during vertical blank raises it immediately.
+                if !was_nmi_enabled
+                    && self.ctrl.contains(PpuCtrl::GENERATE_NMI)
+                    && self.status.contains(PpuStatus::VBLANK)
+                {
+                    self.nmi_pending = true;
+                }
+            }
+            REG_MASK => self.mask = PpuMask::from_bits_truncate(val),
+            REG_OAM_ADDR => self.oam_addr = val,
+            REG_OAM_DATA => self.write_oam_data(val),
+            REG_SCROLL => {
+                if !self.w {
+                    self.t = (self.t & !SCROLL_COARSE_X) | (val >> 3) as u16;
+                    self.fine_x = val & 0b111;
+                } else {
+                    self.t = (self.t & !(SCROLL_COARSE_Y | SCROLL_FINE_Y))
+                        | (((val >> 3) as u16) << 5)
+                        | (((val & 0b111) as u16) << 12);
+                }
+                self.w = !self.w;
+            }
+            REG_ADDR => {
+                if !self.w {
+                    self.t = (self.t & 0x00ff) | (((val & 0b0011_1111) as u16) << 8);
+                } else {
+                    self.t = (self.t & 0xff00) | val as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            }
+            REG_DATA => {
+                self.write_vram(self.v & PPU_ADDR_MASK, val, cartridge);
+                self.increment_vram_addr();
+            }
+            _ => {}
+        }
+    }
+
+    // Writes |val| to OAM at OAMADDR, then increments OAMADDR. Also used by OAM DMA.
+    pub fn write_oam_data(&mut self, val: u8) {
+        self.oam[self.oam_addr as usize] = val;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    // Advances the PPU by one dot.
+    pub fn tick(&mut self, cartridge: &Cartridge) {
+        let rendering_enabled = self.rendering_enabled();
+        let pre_render_scanline = self.region.pre_render_scanline();
+
+        if self.scanline < VISIBLE_SCANLINES && (1..=WIDTH).contains(&self.dot) {
+            self.render_pixel(self.dot - 1, cartridge);
+        }
+
+        if rendering_enabled
+            && (self.scanline < VISIBLE_SCANLINES || self.scanline == pre_render_scanline)
+        {
+            match self.dot {
+                256 => self.increment_y(),
+                257 => {
+                    self.v = (self.v & !SCROLL_HORIZONTAL) | (self.t & SCROLL_HORIZONTAL);
+                    self.evaluate_sprites(cartridge);
+                }
+                280..=304 if self.scanline == pre_render_scanline => {
+                    self.v = (self.v & !SCROLL_VERTICAL) | (self.t & SCROLL_VERTICAL);
+                }
+                _ => {}
+            }
+        }
+
+        if self.dot == 1 {
+            if self.scanline == self.region.vblank_scanline() {
+                self.status.insert(PpuStatus::VBLANK);
+                if self.ctrl.contains(PpuCtrl::GENERATE_NMI) {
+                    self.nmi_pending = true;
+                }
+            } else if self.scanline == pre_render_scanline {
+                self.status.remove(
+                    PpuStatus::VBLANK | PpuStatus::SPRITE_ZERO_HIT | PpuStatus::SPRITE_OVERFLOW,
+                );
+            }
+        }
+
+        // The pre-render scanline is one dot shorter on odd frames when rendering on NTSC.
+        let last_dot = if self.scanline == pre_render_scanline
+            && self.odd_frame
+            && rendering_enabled
+            && self.region.skips_odd_frame_dot()
+        {
+            DOTS_PER_SCANLINE - 2
+        } else {
+            DOTS_PER_SCANLINE - 1
+        };
+        self.dot += 1;
+        if self.dot > last_dot {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == self.region.scanlines_per_frame() {
+                self.scanline = 0;
+                self.frame_count += 1;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+
+    fn render_pixel(&mut self, x: usize, cartridge: &Cartridge) {
+        let left_edge = x < 8;
+
+        let mut background = 0;
+        if self.mask.contains(PpuMask::SHOW_BACKGROUND)
+            && (!left_edge || self.mask.contains(PpuMask::SHOW_BACKGROUND_LEFT))
+        {
+            background = self.background_pixel(x, cartridge);
+        }
+
+        let mut sprite = None;
+        if self.mask.contains(PpuMask::SHOW_SPRITES)
+            && (!left_edge || self.mask.contains(PpuMask::SHOW_SPRITES_LEFT))
+        {
+            sprite = self.sprite_pixel(x);
+        }
+
+        let palette_addr = match sprite {
+            Some((pixel, attributes, is_sprite_zero)) => {
+                if is_sprite_zero && background & 0b11 != 0 && x != WIDTH - 1 {
+                    self.status.insert(PpuStatus::SPRITE_ZERO_HIT);
+                }
+                if background & 0b11 == 0 || attributes & SPRITE_BEHIND_BACKGROUND == 0 {
+                    0x10 | ((attributes & SPRITE_PALETTE) << 2) | pixel
+                } else {
+                    background
+                }
+            }
+            None => background,
+        };
+
+        let mut color = self.palette[palette_index(palette_addr as u16)];
+        if self.mask.contains(PpuMask::GREYSCALE) {
+            color &= 0x30;
+        }
+        self.frame
+            .set_pixel(x, self.scanline, SYSTEM_PALETTE[(color & 0x3f) as usize]);
+    }
+
+    // Returns the palette address of the background at |x| on the current scanline, or 0 if
+    // the background is transparent.
+    fn background_pixel(&self, x: usize, cartridge: &Cartridge) -> u8 {
+        let scrolled_x = x + self.fine_x as usize;
+        let mut coarse_x = (self.v & SCROLL_COARSE_X) as usize + scrolled_x / 8;
+        let mut nametable = self.v & (SCROLL_NAMETABLE_X | SCROLL_NAMETABLE_Y);
+        if coarse_x >= 32 {
+            coarse_x -= 32;
+            nametable ^= SCROLL_NAMETABLE_X;
+        }
+        let coarse_y = (self.v & SCROLL_COARSE_Y) >> 5;
+        let fine_y = (self.v & SCROLL_FINE_Y) >> 12;
+
+        let nametable_addr = NAMETABLE_ADDR_START | nametable;
+        let tile = self.read_vram(
+            nametable_addr | (coarse_y << 5) | coarse_x as u16,
+            cartridge,
+        );
+        let attribute = self.read_vram(
+            nametable_addr
+                | ATTRIBUTE_TABLE_OFFSET
+                | ((coarse_y >> 2) << 3)
+                | (coarse_x >> 2) as u16,
+            cartridge,
+        );
+        let attribute_shift = ((coarse_y & 0b10) << 1) | (coarse_x as u16 & 0b10);
+        let palette = (attribute >> attribute_shift) & 0b11;
+
+        let pattern_table = if self.ctrl.contains(PpuCtrl::BACKGROUND_PATTERN_ADDR) {
+            0x1000
+        } else {
+            0
+        };
+        let pattern_addr = pattern_table | ((tile as u16) << 4) | fine_y;
+        let pixel = pattern_pixel(
+            fetch_pattern(pattern_addr, cartridge),
+            fetch_pattern(pattern_addr + 8, cartridge),
+            7 - (scrolled_x % 8) as u8,
+        );
+        if pixel == 0 {
+            0
+        } else {
+            (palette << 2) | pixel
+        }
+    }
+
+    // Returns the pixel, the attributes and whether it is sprite 0 for the frontmost opaque
+    // sprite at |x| on the current scanline.
+    fn sprite_pixel(&self, x: usize) -> Option<(u8, u8, bool)> {
+        for sprite in self.line_sprites.iter() {
+            let column = x.wrapping_sub(sprite.x as usize);
+            if column >= 8 {
+                continue;
+            }
+            let bit = if sprite.attributes & SPRITE_FLIP_HORIZONTAL != 0 {
+                column as u8
+            } else {
+                7 - column as u8
+            };
+            let pixel = pattern_pixel(sprite.pattern_lo, sprite.pattern_hi, bit);
+            if pixel != 0 {
+                return Some((pixel, sprite.attributes, sprite.is_sprite_zero));
+            }
+        }
+        None
+    }
+
+    // Finds the sprites on the next scanline and fetches their patterns.
+    fn evaluate_sprites(&mut self, cartridge: &Cartridge) {
+        self.line_sprites.clear();
+        // Sprites are never drawn on the first scanline.
+        if self.scanline >= VISIBLE_SCANLINES {
+            return;
+        }
+
+        let height = if self.ctrl.contains(PpuCtrl::SPRITE_SIZE) {
+            16
+        } else {
+            8
+        };
+        for i in 0..OAM_SIZE / 4 {
+            let entry = &self.oam[i * 4..i * 4 + 4];
+            // Sprites are drawn one scanline below their Y coordinate.
+            let row = self.scanline.wrapping_sub(entry[0] as usize);
+            if row >= height {
+                continue;
+            }
+            if self.line_sprites.len() == MAX_SPRITES_PER_SCANLINE {
+                self.status.insert(PpuStatus::SPRITE_OVERFLOW);
+                break;
+            }
+
+            let attributes = entry[2];
+            let row = if attributes & SPRITE_FLIP_VERTICAL != 0 {
+                height - 1 - row
+            } else {
+                row
+            } as u16;
+            let tile = entry[1] as u16;
+            let pattern_addr = if height == 16 {
+                // Bit 0 of the tile selects the pattern table.
+                ((tile & 1) << 12) | ((tile & 0xfe) << 4) | ((row & 0b1000) << 1) | (row & 0b111)
+            } else {
+                let pattern_table = if self.ctrl.contains(PpuCtrl::SPRITE_PATTERN_ADDR) {
+                    0x1000
+                } else {
+                    0
+                };
+                pattern_table | (tile << 4) | row
+            };
+            self.line_sprites.push(LineSprite {
+                x: entry[3],
+                pattern_lo: fetch_pattern(pattern_addr, cartridge),
+                pattern_hi: fetch_pattern(pattern_addr + 8, cartridge),
+                attributes,
+                is_sprite_zero: i == 0,
+            });
+        }
+    }
+
+    // Moves v to the next row of pixels, wrapping to the next nametable down.
+    fn increment_y(&mut self) {
+        if self.v & SCROLL_FINE_Y != SCROLL_FINE_Y {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !SCROLL_FINE_Y;
+        let mut coarse_y = (self.v & SCROLL_COARSE_Y) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= SCROLL_NAMETABLE_Y;
+        } else if coarse_y == 31 {
+            // Rows 30 and 31 hold attributes, and wrap without switching nametable.
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !SCROLL_COARSE_Y) | (coarse_y << 5);
+    }
+
+    fn increment_vram_addr(&mut self) {
+        let increment = if self.ctrl.contains(PpuCtrl::VRAM_INCREMENT) {
+            32
+        } else {
+            1
+        };
+        self.v = self.v.wrapping_add(increment) & 0x7fff;
+    }
+
+    fn read_vram(&self, addr: u16, cartridge: &Cartridge) -> u8 {
+        let addr = addr & PPU_ADDR_MASK;
+        if addr < NAMETABLE_ADDR_START {
+            cartridge.ppu_read(addr)
+        } else if addr < PALETTE_ADDR_START {
+            self.vram[nametable_index(addr, cartridge.mirroring())]
+        } else {
+            self.palette[palette_index(addr)]
+        }
+    }
+
+    fn write_vram(&mut self, addr: u16, val: u8, cartridge: &mut Cartridge) {
+        let addr = addr & PPU_ADDR_MASK;
+        if addr < NAMETABLE_ADDR_START {
+            cartridge.ppu_write(addr, val);
+        } else if addr < PALETTE_ADDR_START {
+            self.vram[nametable_index(addr, cartridge.mirroring())] = val;
+        } else {
+            self.palette[palette_index(addr)] = val & 0x3f;
+        }
+    }
+}
+
+// The region is part of the configuration, not of the state.
+impl Savestate for Ppu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.ctrl.bits());
+        w.write(&self.mask.bits());
+        w.write(&self.status.bits());
+        w.write(&self.oam_addr);
+        savestate::write_buffer(w, &self.oam);
+        savestate::write_buffer(w, &self.vram);
+        savestate::write_buffer(w, &self.palette);
+        w.write(&self.v);
+        w.write(&self.t);
+        w.write(&self.fine_x);
+        w.write(&self.w);
+        w.write(&self.read_buffer);
+        w.write(&self.open_bus);
+        w.write(&self.scanline);
+        w.write(&self.dot);
+        w.write(&self.frame_count);
+        w.write(&self.odd_frame);
+        w.write(&self.nmi_pending);
+        w.write(&self.line_sprites.len());
+        for sprite in self.line_sprites.iter() {
+            w.write(&sprite.x);
+            w.write(&sprite.pattern_lo);
+            w.write(&sprite.pattern_hi);
+            w.write(&sprite.attributes);
+            w.write(&sprite.is_sprite_zero);
+        }
+        w.write(&self.frame.data);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.ctrl = PpuCtrl::from_bits_truncate(r.read()?);
+        self.mask = PpuMask::from_bits_truncate(r.read()?);
+        self.status = PpuStatus::from_bits_truncate(r.read()?);
+        self.oam_addr = r.read()?;
+        savestate::read_buffer(r, &mut self.oam)?;
+        savestate::read_buffer(r, &mut self.vram)?;
+        savestate::read_buffer(r, &mut self.palette)?;
+        self.v = r.read()?;
+        self.t = r.read()?;
+        self.fine_x = r.read()?;
+        self.w = r.read()?;
+        self.read_buffer = r.read()?;
+        self.open_bus = r.read()?;
+        self.scanline = r.read()?;
+        self.dot = r.read()?;
+        if self.scanline >= self.region.scanlines_per_frame() || self.dot >= DOTS_PER_SCANLINE {
+            return Err(SimpleError::new(format!(
+                "save state has the PPU at scanline {}, dot {}, out of the frame",
+                self.scanline, self.dot
+            )));
+        }
+        self.frame_count = r.read()?;
+        self.odd_frame = r.read()?;
+        self.nmi_pending = r.read()?;
+        let num_sprites = r.read::<usize>()?;
+        if num_sprites > MAX_SPRITES_PER_SCANLINE {
+            return Err(SimpleError::new(format!(
+                "save state has {} sprites on a line",
+                num_sprites
+            )));
+        }
+        self.line_sprites.clear();
+        for _ in 0..num_sprites {
+            self.line_sprites.push(LineSprite {
+                x: r.read()?,
+                pattern_lo: r.read()?,
+                pattern_hi: r.read()?,
+                attributes: r.read()?,
+                is_sprite_zero: r.read()?,
+            });
+        }
+        savestate::read_buffer(r, &mut self.frame.data)
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Maps a nametable address in [0x2000, 0x3eff] to an index in VRAM.
+fn nametable_index(addr: u16, mirroring: Mirroring) -> usize {
+    let index = (addr - NAMETABLE_ADDR_START) as usize % (NAMETABLE_SIZE * 4);
+    let table = index / NAMETABLE_SIZE;
+    let physical_table = match mirroring {
+        Mirroring::Horizontal => table / 2,
+        Mirroring::Vertical => table % 2,
+        Mirroring::SingleScreenLower => 0,
+        Mirroring::SingleScreenUpper => 1,
+        Mirroring::FourScreen => table,
+    };
+    physical_table * NAMETABLE_SIZE + index % NAMETABLE_SIZE
+}
+
+// Maps a palette address to an index in palette RAM. The backdrop entries of the sprite palettes
+// mirror the ones of the background palettes.
+fn palette_index(addr: u16) -> usize {
+    let index = (addr & 0x1f) as usize;
+    if index >= 0x10 && index.is_multiple_of(4) {
+        index - 0x10
+    } else {
+        index
+    }
+}
+
+// Reads a row of pattern at |addr| to draw it.
+fn fetch_pattern(addr: u16, cartridge: &Cartridge) -> u8 {
+    cartridge.log_chr(addr, CHR_RENDERED);
+    cartridge.ppu_read(addr)
+}
+
+// Returns the 2-bit pixel at |bit| of a row of pattern.
+fn pattern_pixel(lo: u8, hi: u8, bit: u8) -> u8 {
+    (((hi >> bit) & 1) << 1) | ((lo >> bit) & 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::ines_image;
+
+    // A cartridge with CHR RAM and horizontal mirroring.
+    fn cartridge() -> Cartridge {
+        let mut image = ines_image(0, &[0; 0x4000], &[]);
+        image[6] = 0;
+        Cartridge::parse(&image).unwrap()
+    }
+
+    fn write_vram(ppu: &mut Ppu, cartridge: &mut Cartridge, addr: u16, data: &[u8]) {
+        ppu.write_register(0x2006, (addr >> 8) as u8, cartridge);
+        ppu.write_register(0x2006, addr as u8, cartridge);
+        for val in data {
+            ppu.write_register(0x2007, *val, cartridge);
+        }
+    }
+
+    fn tick_to(ppu: &mut Ppu, cartridge: &Cartridge, scanline: usize, dot: usize) {
+        while ppu.scanline() != scanline || ppu.dot() != dot {
+            ppu.tick(cartridge);
+        }
+    }
+
+    #[test]
+    fn test_vblank_and_nmi() {
+        let mut cartridge = cartridge();
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, 0x80, &mut cartridge);
+
+        tick_to(&mut ppu, &cartridge, Region::Ntsc.vblank_scanline(), 1);
+        assert_eq!(ppu.take_nmi(), false);
+        ppu.tick(&cartridge);
+
+        assert_eq!(ppu.take_nmi(), true);
+        assert_eq!(ppu.take_nmi(), false);
+        assert_eq!(ppu.read_register(0x2002, &cartridge) & 0x80, 0x80);
+        assert_eq!(ppu.read_register(0x2002, &cartridge) & 0x80, 0);
+
+        tick_to(&mut ppu, &cartridge, 0, 0);
+        assert_eq!(ppu.frame_count(), 1);
+    }
+
+    #[test]
+    fn test_enable_nmi_in_vblank() {
+        let mut cartridge = cartridge();
+        let mut ppu = Ppu::new();
+        tick_to(&mut ppu, &cartridge, Region::Ntsc.vblank_scanline(), 2);
+
+        ppu.write_register(0x2000, 0x80, &mut cartridge);
+
+        assert_eq!(ppu.take_nmi(), true);
+    }
+
+    #[test]
+    fn test_read_data_buffered() {
+        let mut cartridge = cartridge();
+        let mut ppu = Ppu::new();
+        write_vram(&mut ppu, &mut cartridge, 0x2400, &[0x11, 0x22]);
+
+        write_vram(&mut ppu, &mut cartridge, 0x2400, &[]);
+
+        assert_eq!(ppu.read_register(0x2007, &cartridge), 0);
+        assert_eq!(ppu.read_register(0x2007, &cartridge), 0x11);
+        assert_eq!(ppu.peek_register(0x2007, &cartridge), 0x22);
+        assert_eq!(ppu.read_register(0x2007, &cartridge), 0x22);
+    }
+
+    #[test]
+    fn test_vram_increment_32() {
+        let mut cartridge = cartridge();
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, 0x04, &mut cartridge);
+
+        write_vram(&mut ppu, &mut cartridge, 0x2000, &[0x11, 0x22]);
+
+        assert_eq!(ppu.read_vram(0x2020, &cartridge), 0x22);
+    }
+
+    #[test]
+    fn test_horizontal_mirroring() {
+        let mut cartridge = cartridge();
+        let mut ppu = Ppu::new();
+
+        write_vram(&mut ppu, &mut cartridge, 0x2005, &[0x42]);
+
+        assert_eq!(ppu.read_vram(0x2405, &cartridge), 0x42);
+        assert_eq!(ppu.read_vram(0x2805, &cartridge), 0);
+        assert_eq!(ppu.read_vram(0x3005, &cartridge), 0x42);
+    }
+
+    #[test]
+    fn test_palette_mirroring() {
+        let mut cartridge = cartridge();
+        let mut ppu = Ppu::new();
+
+        write_vram(&mut ppu, &mut cartridge, 0x3f10, &[0x21]);
+        write_vram(&mut ppu, &mut cartridge, 0x3f00, &[]);
+
+        // Palette reads are not buffered.
+        assert_eq!(ppu.read_register(0x2007, &cartridge), 0x21);
+    }
+
+    #[test]
+    fn test_scroll_registers() {
+        let mut cartridge = cartridge();
+        let mut ppu = Ppu::new();
+
+        ppu.write_register(0x2000, 0x03, &mut cartridge);
+        ppu.write_register(0x2005, 0b0111_1101, &mut cartridge);
+        ppu.write_register(0x2005, 0b0101_1110, &mut cartridge);
+
+        assert_eq!(ppu.t, 0b0110_1101_0110_1111);
+        assert_eq!(ppu.fine_x, 0b101);
+
+        // Reading the status resets the write toggle.
+        ppu.write_register(0x2006, 0x3f, &mut cartridge);
+        ppu.read_register(0x2002, &cartridge);
+        ppu.write_register(0x2006, 0x21, &mut cartridge);
+        ppu.write_register(0x2006, 0x08, &mut cartridge);
+        assert_eq!(ppu.v, 0x2108);
+    }
+
+    #[test]
+    fn test_render_background() {
+        let mut cartridge = cartridge();
+        let mut ppu = Ppu::new();
+        // Tile 1 has its leftmost column set to color 3.
+        write_vram(&mut ppu, &mut cartridge, 0x0010, &[0x80; 16]);
+        // Tile 1 at the top left corner with palette 1.
+        write_vram(&mut ppu, &mut cartridge, 0x2000, &[0x01]);
+        write_vram(&mut ppu, &mut cartridge, 0x23c0, &[0x01]);
+        write_vram(
+            &mut ppu,
+            &mut cartridge,
+            0x3f00,
+            &[0x0f, 0, 0, 0, 0, 0, 0, 0x30],
+        );
+        ppu.write_register(0x2006, 0, &mut cartridge);
+        ppu.write_register(0x2006, 0, &mut cartridge);
+        ppu.write_register(0x2001, 0x0a, &mut cartridge);
+
+        tick_to(&mut ppu, &cartridge, 1, 0);
+
+        assert_eq!(ppu.frame().pixel(0, 0), SYSTEM_PALETTE[0x30]);
+        assert_eq!(ppu.frame().pixel(1, 0), SYSTEM_PALETTE[0x0f]);
+        assert_eq!(ppu.frame().pixel(8, 0), SYSTEM_PALETTE[0x0f]);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        let mut cartridge = cartridge();
+        let mut ppu = Ppu::new();
+        // Tile 1 is opaque.
+        write_vram(&mut ppu, &mut cartridge, 0x0010, &[0xff; 16]);
+        write_vram(&mut ppu, &mut cartridge, 0x2000, &[0x01; 64]);
+        // Sprite 0 at (16, 10).
+        ppu.write_register(0x2003, 0, &mut cartridge);
+        for val in [9, 1, 0, 16].iter() {
+            ppu.write_register(0x2004, *val, &mut cartridge);
+        }
+        ppu.write_register(0x2006, 0, &mut cartridge);
+        ppu.write_register(0x2006, 0, &mut cartridge);
+        ppu.write_register(0x2001, 0x18, &mut cartridge);
+
+        tick_to(&mut ppu, &cartridge, 10, 17);
+        assert_eq!(ppu.peek_register(0x2002, &cartridge) & 0x40, 0);
+        ppu.tick(&cartridge);
+
+        assert_eq!(ppu.peek_register(0x2002, &cartridge) & 0x40, 0x40);
+
+        // Cleared on the pre-render scanline.
+        tick_to(&mut ppu, &cartridge, Region::Ntsc.pre_render_scanline(), 2);
+        assert_eq!(ppu.peek_register(0x2002, &cartridge) & 0x40, 0);
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let mut cartridge = cartridge();
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2003, 0, &mut cartridge);
+        for _ in 0..9 {
+            for val in [20, 0, 0, 0].iter() {
+                ppu.write_register(0x2004, *val, &mut cartridge);
+            }
+        }
+        ppu.write_register(0x2001, 0x10, &mut cartridge);
+
+        tick_to(&mut ppu, &cartridge, 20, 258);
+
+        assert_eq!(ppu.peek_register(0x2002, &cartridge) & 0x20, 0x20);
+    }
+
+    #[test]
+    fn test_odd_frame_skips_dot() {
+        let mut cartridge = cartridge();
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2001, 0x08, &mut cartridge);
+        let frame_dots = DOTS_PER_SCANLINE * Region::Ntsc.scanlines_per_frame();
+
+        for _ in 0..frame_dots {
+            ppu.tick(&cartridge);
+        }
+        assert_eq!((ppu.scanline(), ppu.dot()), (0, 0));
+        for _ in 0..frame_dots - 1 {
+            ppu.tick(&cartridge);
+        }
+
+        assert_eq!((ppu.scanline(), ppu.dot()), (0, 0));
+        assert_eq!(ppu.frame_count(), 2);
+    }
+
+    #[test]
+    fn test_pal_frame() {
+        let mut cartridge = cartridge();
+        let mut ppu = Ppu::with_region(Region::Pal);
+        ppu.write_register(0x2000, 0x80, &mut cartridge);
+        ppu.write_register(0x2001, 0x08, &mut cartridge);
+
+        tick_to(&mut ppu, &cartridge, 241, 2);
+        assert_eq!(ppu.take_nmi(), true);
+        tick_to(&mut ppu, &cartridge, 310, 2);
+        assert_eq!(ppu.peek_register(0x2002, &cartridge) & 0x80, 0x80);
+        tick_to(&mut ppu, &cartridge, 311, 2);
+        assert_eq!(ppu.peek_register(0x2002, &cartridge) & 0x80, 0);
+
+        // No dot is skipped on odd frames.
+        let frame_dots = DOTS_PER_SCANLINE * 312;
+        tick_to(&mut ppu, &cartridge, 0, 0);
+        for _ in 0..frame_dots - 1 {
+            ppu.tick(&cartridge);
+        }
+        assert_eq!((ppu.scanline(), ppu.dot()), (311, 340));
+    }
+
+    #[test]
+    fn test_dendy_vblank() {
+        let mut cartridge = cartridge();
+        let mut ppu = Ppu::with_region(Region::Dendy);
+        ppu.write_register(0x2000, 0x80, &mut cartridge);
+
+        tick_to(&mut ppu, &cartridge, 241, 2);
+        assert_eq!(ppu.take_nmi(), false);
+        tick_to(&mut ppu, &cartridge, 291, 2);
+
+        assert_eq!(ppu.take_nmi(), true);
+    }
+}
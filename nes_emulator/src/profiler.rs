@@ -0,0 +1,352 @@
+/**
+ * Profiler of the cycles a program spends per instruction address and per subroutine.
+ *
+ * The CPU reports each instruction and each interrupt it enters. The cycles elapsed until the next
+ * one, including those of an OAM DMA, go to the address of the instruction and to the routines on
+ * the call stack: the routine at the top gets them as self cycles, and every routine on the stack
+ * as total cycles.
+ *
+ * The cycles of the instruction in progress are counted once the next one starts.
+ *
+ * Routines are named by their entry address: the target of JSR, or the handler of an interrupt or
+ * BRK. The first instruction profiled starts the root routine, and the routines it calls are those
+ * on the call stack the CPU keeps, see callstack.rs.
+ *
+ * Results are a report sorted by cycles, and the call stacks folded in the format of flamegraph.pl,
+ * i.e. "main;update;draw 1234" per line. See https://github.com/brendangregg/FlameGraph
+ */
+use crate::callstack::Frame;
+use crate::cpu::CPU;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PcStats {
+    pub pc: u16,
+    // Number of times the instruction executed.
+    pub count: u64,
+    pub cycles: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RoutineStats {
+    pub entry: u16,
+    pub calls: u64,
+    // Cycles spent in the routine itself, and with the routines it calls.
+    pub self_cycles: u64,
+    pub total_cycles: u64,
+}
+
+#[derive(Default)]
+pub struct Profiler {
+    pcs: HashMap<u16, PcStats>,
+    routines: HashMap<u16, RoutineStats>,
+    // Cycles per call stack, as entries of routines from the root.
+    stacks: HashMap<Vec<u16>, u64>,
+    // The first address profiled, and the call stack of the CPU at the last instruction.
+    root: Option<u16>,
+    frames: Vec<Frame>,
+    // The address the cycles since |last_cycles| go to, and whether an instruction executed
+    // there rather than an interrupt.
+    last: Option<(u16, bool)>,
+    last_cycles: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    // Cycles profiled so far.
+    pub fn total_cycles(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    // Instructions, by cycles spent, most first.
+    pub fn pcs(&self) -> Vec<PcStats> {
+        let mut pcs: Vec<PcStats> = self.pcs.values().copied().collect();
+        pcs.sort_by_key(|stats| (std::cmp::Reverse(stats.cycles), stats.pc));
+        pcs
+    }
+
+    // Routines, by total cycles spent, most first.
+    pub fn routines(&self) -> Vec<RoutineStats> {
+        let mut routines: Vec<RoutineStats> = self.routines.values().copied().collect();
+        routines.sort_by_key(|stats| (std::cmp::Reverse(stats.total_cycles), stats.entry));
+        routines
+    }
+
+    // The routines on the call stack now, from the root.
+    pub fn call_stack(&self) -> Vec<u16> {
+        self.root
+            .iter()
+            .copied()
+            .chain(self.frames.iter().map(|frame| frame.entry))
+            .collect()
+    }
+
+    // Formats the |limit| routines and instructions that take the most cycles. Addresses are
+    // named by |label| when it knows them.
+    pub fn report<F: Fn(u16) -> Option<String>>(&self, label: F, limit: usize) -> String {
+        let total = self.total_cycles().max(1) as f64;
+        let percent = |cycles: u64| 100.0 * cycles as f64 / total;
+        let name = |addr| label(addr).unwrap_or_else(|| format!("${:04X}", addr));
+        let mut lines = vec![format!(
+            "{:>12} {:>6} {:>12} {:>6} {:>8}  routine",
+            "cycles", "%", "self", "%", "calls"
+        )];
+        for stats in self.routines().iter().take(limit) {
+            lines.push(format!(
+                "{:>12} {:>5.1}% {:>12} {:>5.1}% {:>8}  {}",
+                stats.total_cycles,
+                percent(stats.total_cycles),
+                stats.self_cycles,
+                percent(stats.self_cycles),
+                stats.calls,
+                name(stats.entry)
+            ));
+        }
+        lines.push(String::new());
+        lines.push(format!(
+            "{:>12} {:>6} {:>12}  address",
+            "cycles", "%", "count"
+        ));
+        for stats in self.pcs().iter().take(limit) {
+            let text = match label(stats.pc) {
+                Some(label) => format!("${:04X} <{}>", stats.pc, label),
+                None => format!("${:04X}", stats.pc),
+            };
+            lines.push(format!(
+                "{:>12} {:>5.1}% {:>12}  {}",
+                stats.cycles,
+                percent(stats.cycles),
+                stats.count,
+                text
+            ));
+        }
+        lines.join("\n")
+    }
+
+    // Formats the cycles per call stack for flamegraph.pl, one stack per line. Routines are named
+    // by |label| when it knows them.
+    pub fn folded_stacks<F: Fn(u16) -> Option<String>>(&self, label: F) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .filter(|(_, cycles)| **cycles > 0)
+            .map(|(stack, cycles)| {
+                let names: Vec<String> = stack
+                    .iter()
+                    .map(|entry| label(*entry).unwrap_or_else(|| format!("${:04X}", entry)))
+                    .collect();
+                format!("{} {}", names.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    // Records that |cpu| is about to execute the instruction at its program counter.
+    pub(crate) fn instruction(&mut self, cpu: &CPU) {
+        self.record(cpu, true);
+    }
+
+    // Records that |cpu| entered an interrupt handler, before the cycles it takes.
+    pub(crate) fn interrupt(&mut self, cpu: &CPU) {
+        self.record(cpu, false);
+    }
+
+    fn record(&mut self, cpu: &CPU, executed: bool) {
+        self.settle(cpu.cycles);
+        if self.root.is_none() {
+            self.root = Some(cpu.pc);
+            self.count_call(cpu.pc);
+        }
+        // Frames the stack did not have before are new calls.
+        let frames = cpu.call_stack().frames();
+        let kept = self
+            .frames
+            .iter()
+            .zip(frames)
+            .take_while(|(old, new)| old == new)
+            .count();
+        for frame in &frames[kept..] {
+            self.count_call(frame.entry);
+        }
+        self.frames = frames.to_vec();
+        self.last = Some((cpu.pc, executed));
+    }
+
+    // Gives the cycles since the last instruction to it and to the call stack.
+    fn settle(&mut self, cycles: u64) {
+        let elapsed = cycles.saturating_sub(self.last_cycles);
+        self.last_cycles = cycles;
+        if let Some((pc, executed)) = self.last {
+            let stats = self.pcs.entry(pc).or_insert(PcStats {
+                pc,
+                ..Default::default()
+            });
+            stats.count += executed as u64;
+            stats.cycles += elapsed;
+            let entries = self.call_stack();
+            for (i, entry) in entries.iter().enumerate() {
+                let stats = self.routines.get_mut(entry).unwrap();
+                // Recursive routines count once.
+                if !entries[..i].contains(entry) {
+                    stats.total_cycles += elapsed;
+                }
+                if i == entries.len() - 1 {
+                    stats.self_cycles += elapsed;
+                }
+            }
+            *self.stacks.entry(entries).or_insert(0) += elapsed;
+        }
+    }
+
+    fn count_call(&mut self, entry: u16) {
+        self.routines
+            .entry(entry)
+            .or_insert(RoutineStats {
+                entry,
+                ..Default::default()
+            })
+            .calls += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler;
+    use crate::cartridge::test::nrom_image;
+    use crate::cartridge::Cartridge;
+    use crate::nes::Nes;
+
+    fn profile(source: &str, instructions: usize) -> (Nes, Profiler) {
+        let program = assembler::assemble(source).unwrap();
+        let mut nes = Nes::new(Cartridge::parse(&nrom_image(&program.bytes)).unwrap());
+        nes.cpu_mut().set_profiler(Some(Profiler::new()));
+        for _ in 0..instructions {
+            nes.step_instruction().unwrap();
+        }
+        let profiler = nes.cpu_mut().set_profiler(None).unwrap();
+        (nes, profiler)
+    }
+
+    #[test]
+    fn test_routines() {
+        let (nes, profiler) = profile(
+            "
+                LDX #0
+            loop:
+                JSR add
+                JMP loop
+            add:
+                INX
+                JSR double
+                RTS
+            double:
+                NOP
+                RTS
+            ",
+            // LDX, then JSR add, INX, JSR double, NOP, RTS, RTS and JMP loop twice, and the
+            // next JSR add, which is in progress.
+            1 + 7 * 2 + 1,
+        );
+
+        let routines = profiler.routines();
+        let entries: Vec<u16> = routines.iter().map(|stats| stats.entry).collect();
+        assert_eq!(entries, vec![0x8000, 0x8008, 0x800d]);
+        // Profiling starts after the reset, and the last JSR is in progress.
+        assert_eq!(profiler.total_cycles(), nes.cpu().cycles - 7 - 6);
+        assert_eq!(routines[0].total_cycles, profiler.total_cycles());
+        // LDX 2, then JSR 6 and JMP 3 twice.
+        assert_eq!(routines[0].self_cycles, 2 + 9 * 2);
+        // INX 2, JSR 6 and RTS 6.
+        assert_eq!(routines[1].self_cycles, 14 * 2);
+        assert_eq!(routines[1].calls, 2);
+        // NOP 2 and RTS 6.
+        assert_eq!(routines[2].total_cycles, 8 * 2);
+        assert_eq!(routines[1].total_cycles, (14 + 8) * 2);
+        assert_eq!(
+            profiler.pcs()[0],
+            PcStats {
+                pc: 0x8002,
+                count: 2,
+                cycles: 12
+            }
+        );
+        assert_eq!(profiler.call_stack(), vec![0x8000]);
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        let (_, profiler) = profile(
+            "
+            loop:
+                JSR update
+                JMP loop
+            update:
+                RTS
+            ",
+            4,
+        );
+
+        let label = |addr| match addr {
+            0x8000 => Some("main".to_string()),
+            _ => None,
+        };
+        assert_eq!(profiler.folded_stacks(label), "main 9\nmain;$8006 6\n");
+        let report = profiler.report(label, 1);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(
+            lines[1],
+            "          15 100.0%            9  60.0%        1  main"
+        );
+        assert_eq!(lines[4], "           6  40.0%            1  $8000 <main>");
+    }
+
+    #[test]
+    fn test_interrupts_and_unbalanced_returns() {
+        let (_, profiler) = profile(
+            "
+                JSR drop
+            drop:
+                PLA
+                PLA
+                JSR jump
+            jump:
+                LDA #$80
+                PHA
+                LDA #$10
+                PHA
+                RTS
+            ",
+            10,
+        );
+
+        // The call of jump took the place of the return address that drop pulled, and the RTS
+        // to $8011 was a jump.
+        assert_eq!(profiler.call_stack(), vec![0x8000, 0x8008]);
+
+        // JMP $8000
+        let mut nes = Nes::new(Cartridge::parse(&nrom_image(&[0x4c, 0x00, 0x80])).unwrap());
+        nes.cpu_mut().set_profiler(Some(Profiler::new()));
+        nes.step_instruction().unwrap();
+        nes.cpu_mut().nmi();
+        assert_eq!(
+            nes.cpu().profiler().unwrap().call_stack(),
+            vec![0x8000, 0xff00]
+        );
+        // RTI
+        nes.step_instruction().unwrap();
+        nes.step_instruction().unwrap();
+        let profiler = nes.cpu().profiler().unwrap();
+        assert_eq!(profiler.call_stack(), vec![0x8000]);
+        let routines = profiler.routines();
+        let handler = routines.iter().find(|stats| stats.entry == 0xff00).unwrap();
+        // Entering the handler takes 7 cycles, and RTI 6.
+        assert_eq!((handler.entry, handler.total_cycles), (0xff00, 13));
+        assert_eq!(handler.calls, 1);
+    }
+}
@@ -0,0 +1,106 @@
+/**
+ * Content of RAM at power on.
+ *
+ * RAM of real consoles powers on with a content that varies between consoles and between power
+ * cycles, roughly half set bits in patterns that depend on the chips. Some games read RAM before
+ * writing it, e.g. to seed their random number generator, and behave differently depending on
+ * it. Emulators pick a pattern instead; random content comes from an explicit seed so that runs
+ * stay reproducible.
+ *
+ * See https://wiki.nesdev.com/w/index.php/CPU_power_up_state
+ */
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RamPattern {
+    #[default]
+    Zeros,
+    // All $FF.
+    Ones,
+    // Blocks of 4 bytes of $00 then 4 bytes of $FF, as FCEUX does.
+    Alternating,
+    // Pseudo-random bytes generated from the seed.
+    Random(u64),
+}
+
+impl RamPattern {
+    // Fills |memories| in order. Random content goes on from one memory to the next, so that
+    // memories filled at once differ.
+    pub fn fill(&self, memories: &mut [&mut [u8]]) {
+        let mut rng = SplitMix64 {
+            state: match self {
+                RamPattern::Random(seed) => *seed,
+                _ => 0,
+            },
+        };
+        for memory in memories.iter_mut() {
+            for (i, byte) in memory.iter_mut().enumerate() {
+                *byte = match self {
+                    RamPattern::Zeros => 0x00,
+                    RamPattern::Ones => 0xff,
+                    RamPattern::Alternating if i & 0b100 == 0 => 0x00,
+                    RamPattern::Alternating => 0xff,
+                    RamPattern::Random(_) => rng.next() as u8,
+                };
+            }
+        }
+    }
+}
+
+// SplitMix64, which gives good random numbers from any seed, including 0.
+//
+// See https://prng.di.unimi.it/splitmix64.c
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filled(pattern: RamPattern) -> Vec<u8> {
+        let mut memory = vec![0x42; 16];
+        pattern.fill(&mut [&mut memory]);
+        memory
+    }
+
+    #[test]
+    fn test_patterns() {
+        assert_eq!(filled(RamPattern::Zeros), vec![0x00; 16]);
+        assert_eq!(filled(RamPattern::Ones), vec![0xff; 16]);
+        assert_eq!(
+            filled(RamPattern::Alternating)[..12],
+            [0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_random() {
+        let random = filled(RamPattern::Random(1));
+
+        assert_eq!(filled(RamPattern::Random(1)), random);
+        assert_ne!(filled(RamPattern::Random(2)), random);
+        assert_eq!(random.iter().any(|byte| *byte != random[0]), true);
+
+        let mut a = vec![0; 8];
+        let mut b = vec![0; 8];
+        RamPattern::Random(1).fill(&mut [&mut a, &mut b]);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_splitmix64() {
+        let mut rng = SplitMix64 { state: 0 };
+
+        assert_eq!(rng.next(), 0xe220_a839_7b1d_cdaf);
+    }
+}
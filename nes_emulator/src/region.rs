@@ -0,0 +1,125 @@
+/**
+ * Timing of the different consoles.
+ *
+ * NTSC consoles (2A03 CPU, 2C02 PPU) run at 60 frames per second. PAL consoles (2A07 CPU, 2C07
+ * PPU) and the Dendy, a Famicom clone sold in Russia, run at 50 frames per second with 312
+ * scanlines per frame. The Dendy has the PAL clock but divides it like an NTSC console for the
+ * PPU/CPU ratio and keeps the VBlank of NTSC, starting it 50 scanlines later.
+ *
+ * See https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+ */
+use crate::ppu::DOTS_PER_SCANLINE;
+
+// Master clock rates in Hz.
+const MASTER_CLOCK_RATE_NTSC: f64 = 236_250_000.0 / 11.0;
+const MASTER_CLOCK_RATE_PAL: f64 = 26_601_712.5;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    pub fn master_clock_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => MASTER_CLOCK_RATE_NTSC,
+            Region::Pal | Region::Dendy => MASTER_CLOCK_RATE_PAL,
+        }
+    }
+
+    // Master clock ticks per CPU cycle.
+    pub fn cpu_clock_divider(&self) -> u64 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    // Master clock ticks per PPU dot.
+    pub fn ppu_clock_divider(&self) -> u64 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    pub fn cpu_clock_rate(&self) -> f64 {
+        self.master_clock_rate() / self.cpu_clock_divider() as f64
+    }
+
+    pub fn scanlines_per_frame(&self) -> usize {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    // The scanline on which VBlank starts. It lasts until the pre-render scanline, the last one.
+    pub fn vblank_scanline(&self) -> usize {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    pub fn pre_render_scanline(&self) -> usize {
+        self.scanlines_per_frame() - 1
+    }
+
+    // Whether the pre-render scanline is one dot shorter on odd frames when rendering.
+    pub fn skips_odd_frame_dot(&self) -> bool {
+        *self == Region::Ntsc
+    }
+
+    // Frames per second, assuming rendering is enabled.
+    pub fn frame_rate(&self) -> f64 {
+        let mut dots_per_frame = (DOTS_PER_SCANLINE * self.scanlines_per_frame()) as f64;
+        if self.skips_odd_frame_dot() {
+            dots_per_frame -= 0.5;
+        }
+        self.master_clock_rate() / (dots_per_frame * self.ppu_clock_divider() as f64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert_eq!(
+            (actual - expected).abs() < 0.001,
+            true,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_cpu_clock_rate() {
+        assert_near(Region::Ntsc.cpu_clock_rate() / 1e6, 1.789773);
+        assert_near(Region::Pal.cpu_clock_rate() / 1e6, 1.662607);
+        assert_near(Region::Dendy.cpu_clock_rate() / 1e6, 1.773448);
+    }
+
+    #[test]
+    fn test_frame_rate() {
+        assert_near(Region::Ntsc.frame_rate(), 60.0988);
+        assert_near(Region::Pal.frame_rate(), 50.0070);
+        assert_near(Region::Dendy.frame_rate(), 50.0070);
+    }
+
+    #[test]
+    fn test_vblank_length() {
+        let vblank_scanlines =
+            |region: Region| region.pre_render_scanline() - region.vblank_scanline();
+
+        assert_eq!(vblank_scanlines(Region::Ntsc), 20);
+        assert_eq!(vblank_scanlines(Region::Pal), 70);
+        assert_eq!(vblank_scanlines(Region::Dendy), 20);
+    }
+}
@@ -0,0 +1,299 @@
+/**
+ * Rewind buffer, to step the machine backwards frame by frame.
+ *
+ * The host saves a state once per frame. Only the newest state is kept whole; every older one is
+ * kept as the difference with the state of the next frame, XORed and run-length encoded. Most of
+ * the machine does not change from one frame to the next, so a difference mostly holds runs of
+ * zeros and takes a few hundred bytes where a whole state takes hundreds of kilobytes.
+ *
+ * Stepping back decodes the newest difference against the newest state. The oldest differences
+ * are dropped when the buffer holds too many frames or too many bytes.
+ */
+use crate::savestate::{self, Savestate};
+use simple_error::SimpleError;
+use std::collections::VecDeque;
+
+pub struct Rewind {
+    max_frames: usize,
+    max_bytes: usize,
+    // State of the last frame pushed or rewound to.
+    current: Option<Vec<u8>>,
+    // Differences between each frame and the next one, the newest at the back.
+    deltas: VecDeque<Vec<u8>>,
+    delta_bytes: usize,
+}
+
+impl Rewind {
+    // Creates a buffer able to step back up to |max_frames| frames, with at most |max_bytes| of
+    // differences.
+    pub fn new(max_frames: usize, max_bytes: usize) -> Self {
+        Rewind {
+            max_frames,
+            max_bytes,
+            current: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    // Saves the state of |component| as the newest frame.
+    pub fn push<S: Savestate + ?Sized>(&mut self, component: &S) {
+        let state = savestate::save(component);
+        if let Some(current) = self.current.take() {
+            let delta = encode_delta(&state, &current);
+            self.delta_bytes += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.current = Some(state);
+
+        while self.deltas.len() > self.max_frames || self.delta_bytes > self.max_bytes {
+            match self.deltas.pop_front() {
+                Some(delta) => self.delta_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Restores |component| to the frame before the newest one, which becomes the newest.
+    // Returns false if there is no frame to step back to.
+    pub fn step_back<S: Savestate + ?Sized>(
+        &mut self,
+        component: &mut S,
+    ) -> Result<bool, SimpleError> {
+        let (current, delta) = match (&self.current, self.deltas.back()) {
+            (Some(current), Some(delta)) => (current, delta),
+            _ => return Ok(false),
+        };
+        let state = decode_delta(current, delta)?;
+        savestate::load(component, &state)?;
+
+        let delta = self.deltas.pop_back().unwrap();
+        self.delta_bytes -= delta.len();
+        self.current = Some(state);
+        Ok(true)
+    }
+
+    // Number of frames that can be stepped back.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    // Bytes held by the buffer.
+    pub fn memory_usage(&self) -> usize {
+        self.delta_bytes + self.current.as_ref().map_or(0, |state| state.len())
+    }
+
+    pub fn clear(&mut self) {
+        self.current = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+    }
+}
+
+// Encodes |old| as a difference with |new|.
+//
+// The difference starts with the length of |old|, followed by the XOR of both states, the shorter
+// one padded with zeros, as a sequence of (number of zeros, number of literal bytes, literal
+// bytes). Numbers are LEB128 varints.
+fn encode_delta(new: &[u8], old: &[u8]) -> Vec<u8> {
+    let len = new.len().max(old.len());
+    let xor = |i: usize| new.get(i).copied().unwrap_or(0) ^ old.get(i).copied().unwrap_or(0);
+
+    let mut delta = Vec::new();
+    write_varint(&mut delta, old.len());
+    let mut i = 0;
+    while i < len {
+        let zeros_start = i;
+        while i < len && xor(i) == 0 {
+            i += 1;
+        }
+        let literal_start = i;
+        // Short runs of zeros are cheaper as literals.
+        while i < len && (xor(i) != 0 || (i + 2 < len && (xor(i + 1) != 0 || xor(i + 2) != 0))) {
+            i += 1;
+        }
+        write_varint(&mut delta, literal_start - zeros_start);
+        write_varint(&mut delta, i - literal_start);
+        delta.extend((literal_start..i).map(xor));
+    }
+    delta
+}
+
+// Decodes a difference made by encode_delta() against |new|, returning the old state.
+fn decode_delta(new: &[u8], delta: &[u8]) -> Result<Vec<u8>, SimpleError> {
+    let corrupted = || SimpleError::new("rewind buffer is corrupted");
+    let mut pos = 0;
+    let old_len = read_varint(delta, &mut pos).ok_or_else(corrupted)?;
+
+    let mut old = new.to_vec();
+    old.resize(new.len().max(old_len), 0);
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos).ok_or_else(corrupted)?;
+        let literal_len = read_varint(delta, &mut pos).ok_or_else(corrupted)?;
+        if pos + literal_len > delta.len() || i + literal_len > old.len() {
+            return Err(corrupted());
+        }
+        for byte in &delta[pos..pos + literal_len] {
+            old[i] ^= byte;
+            i += 1;
+        }
+        pos += literal_len;
+    }
+    old.truncate(old_len);
+    Ok(old)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        buf.push((val as u8) | 0x80);
+        val >>= 7;
+    }
+    buf.push(val as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut val = 0usize;
+    let mut shift = 0;
+    loop {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+        if shift >= usize::BITS {
+            return None;
+        }
+        val |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(val);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nrom_image;
+    use crate::cartridge::Cartridge;
+    use crate::nes::Nes;
+
+    #[test]
+    fn test_delta() {
+        let old = vec![1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 0, 9, 9];
+        let mut new = old.clone();
+        new[2] = 0x33;
+        new[12] = 0;
+
+        let delta = encode_delta(&new, &old);
+
+        assert_eq!(decode_delta(&new, &delta), Ok(old.clone()));
+        // Length, then 2 zeros and the literal 0x30, then 9 zeros and the literal 0x09.
+        assert_eq!(delta, vec![14, 2, 1, 0x30, 9, 1, 0x09, 1, 0]);
+        assert_eq!(decode_delta(&old, &encode_delta(&old, &new)), Ok(new));
+    }
+
+    #[test]
+    fn test_delta_of_different_lengths() {
+        let old = vec![1, 2, 3];
+        let new = vec![1, 2, 3, 4, 5];
+
+        assert_eq!(
+            decode_delta(&new, &encode_delta(&new, &old)),
+            Ok(old.clone())
+        );
+        assert_eq!(decode_delta(&old, &encode_delta(&old, &new)), Ok(new));
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300);
+        let mut pos = 0;
+
+        assert_eq!(buf, vec![0xac, 0x02]);
+        assert_eq!(read_varint(&buf, &mut pos), Some(300));
+        assert_eq!(read_varint(&buf, &mut pos), None);
+    }
+
+    fn nes() -> Nes {
+        // INC $10
+        // JMP $8000
+        let image = nrom_image(&[0xe6, 0x10, 0x4c, 0x00, 0x80]);
+        Nes::new(Cartridge::parse(&image).unwrap())
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut nes = nes();
+        let mut rewind = Rewind::new(100, usize::MAX);
+        let mut states = Vec::new();
+        for _ in 0..5 {
+            nes.run_frame().unwrap();
+            rewind.push(&nes);
+            states.push(nes.save());
+        }
+
+        for expected in states.iter().rev().skip(1) {
+            assert_eq!(rewind.step_back(&mut nes), Ok(true));
+            assert_eq!(&nes.save(), expected);
+        }
+
+        assert_eq!(rewind.step_back(&mut nes), Ok(false));
+        assert_eq!(&nes.save(), &states[0]);
+    }
+
+    #[test]
+    fn test_push_after_step_back() {
+        let mut nes = nes();
+        let mut rewind = Rewind::new(100, usize::MAX);
+        nes.run_frame().unwrap();
+        rewind.push(&nes);
+        let state = nes.save();
+        nes.run_frame().unwrap();
+        rewind.push(&nes);
+        rewind.step_back(&mut nes).unwrap();
+
+        nes.run_frame().unwrap();
+        rewind.push(&nes);
+
+        assert_eq!(rewind.len(), 1);
+        assert_eq!(rewind.step_back(&mut nes), Ok(true));
+        assert_eq!(nes.save(), state);
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut nes = nes();
+        let mut rewind = Rewind::new(3, usize::MAX);
+        for _ in 0..10 {
+            nes.run_frame().unwrap();
+            rewind.push(&nes);
+        }
+
+        assert_eq!(rewind.len(), 3);
+
+        let mut rewind = Rewind::new(100, 0);
+        nes.run_frame().unwrap();
+        rewind.push(&nes);
+        nes.run_frame().unwrap();
+        rewind.push(&nes);
+
+        assert_eq!(rewind.is_empty(), true);
+    }
+
+    #[test]
+    fn test_deltas_are_small() {
+        let mut nes = nes();
+        let mut rewind = Rewind::new(100, usize::MAX);
+        for _ in 0..60 {
+            nes.run_frame().unwrap();
+            rewind.push(&nes);
+        }
+
+        let state_size = nes.save().len();
+        assert_eq!(rewind.memory_usage() < 2 * state_size, true);
+    }
+}
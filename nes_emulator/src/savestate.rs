@@ -0,0 +1,383 @@
+/**
+ * Save states, i.e. snapshots of the whole machine that can be restored later.
+ *
+ * A save state is a binary blob: a magic number and a format version, followed by the state of
+ * each component in little endian, in a fixed order. Components implement Savestate to write
+ * their state and read it back in the same order. Bumping SAVESTATE_VERSION is required whenever
+ * that order or a layout changes, since states of another version cannot be read.
+ */
+use simple_error::SimpleError;
+use std::path::Path;
+
+const SAVESTATE_MAGIC: &[u8] = b"NESSTATE";
+
+// Version of the format. States of any other version are rejected.
+pub const SAVESTATE_VERSION: u32 = 2;
+
+pub trait Savestate {
+    fn save_state(&self, w: &mut StateWriter);
+
+    // Restores the state written by save_state(). On error, the component may be left partially
+    // restored.
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError>;
+}
+
+// Values that can be written to a save state.
+pub trait StateValue: Sized {
+    fn write_to(&self, w: &mut StateWriter);
+    fn read_from(r: &mut StateReader) -> Result<Self, SimpleError>;
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn write<T: StateValue>(&mut self, val: &T) {
+        val.write_to(self);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, pos: 0 }
+    }
+
+    pub fn read<T: StateValue>(&mut self) -> Result<T, SimpleError> {
+        T::read_from(self)
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], SimpleError> {
+        if self.data.len() - self.pos < len {
+            return Err(SimpleError::new("save state is truncated"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    // Reads exactly |buf.len()| bytes into |buf|.
+    pub fn read_into(&mut self, buf: &mut [u8]) -> Result<(), SimpleError> {
+        buf.copy_from_slice(self.read_bytes(buf.len())?);
+        Ok(())
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+macro_rules! impl_state_value_for_int {
+    ($($t:ty),*) => {
+        $(
+            impl StateValue for $t {
+                fn write_to(&self, w: &mut StateWriter) {
+                    w.write_bytes(&self.to_le_bytes());
+                }
+
+                fn read_from(r: &mut StateReader) -> Result<Self, SimpleError> {
+                    let mut bytes = [0; std::mem::size_of::<$t>()];
+                    r.read_into(&mut bytes)?;
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_state_value_for_int!(u8, u16, u32, u64, i8, f32, f64);
+
+impl StateValue for bool {
+    fn write_to(&self, w: &mut StateWriter) {
+        w.write(&(*self as u8));
+    }
+
+    fn read_from(r: &mut StateReader) -> Result<Self, SimpleError> {
+        match r.read::<u8>()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            val => Err(SimpleError::new(format!(
+                "invalid boolean {} in save state",
+                val
+            ))),
+        }
+    }
+}
+
+// Sizes and indices are saved as 64-bit integers so that states do not depend on the platform.
+impl StateValue for usize {
+    fn write_to(&self, w: &mut StateWriter) {
+        w.write(&(*self as u64));
+    }
+
+    fn read_from(r: &mut StateReader) -> Result<Self, SimpleError> {
+        Ok(r.read::<u64>()? as usize)
+    }
+}
+
+impl<T: StateValue> StateValue for Option<T> {
+    fn write_to(&self, w: &mut StateWriter) {
+        w.write(&self.is_some());
+        if let Some(val) = self {
+            w.write(val);
+        }
+    }
+
+    fn read_from(r: &mut StateReader) -> Result<Self, SimpleError> {
+        if r.read::<bool>()? {
+            Ok(Some(r.read()?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<A: StateValue, B: StateValue> StateValue for (A, B) {
+    fn write_to(&self, w: &mut StateWriter) {
+        w.write(&self.0);
+        w.write(&self.1);
+    }
+
+    fn read_from(r: &mut StateReader) -> Result<Self, SimpleError> {
+        Ok((r.read()?, r.read()?))
+    }
+}
+
+// Byte buffers are saved with their length, so that a buffer of another size is detected.
+impl StateValue for Vec<u8> {
+    fn write_to(&self, w: &mut StateWriter) {
+        w.write(&self.len());
+        w.write_bytes(self);
+    }
+
+    fn read_from(r: &mut StateReader) -> Result<Self, SimpleError> {
+        let len = r.read::<usize>()?;
+        Ok(r.read_bytes(len)?.to_vec())
+    }
+}
+
+// Reads a byte buffer saved by StateWriter::write(&Vec<u8>) into |buf|, which must have the same
+// size.
+pub fn read_buffer(r: &mut StateReader, buf: &mut [u8]) -> Result<(), SimpleError> {
+    let len = r.read::<usize>()?;
+    if len != buf.len() {
+        return Err(SimpleError::new(format!(
+            "save state has a buffer of {} bytes where {} are expected",
+            len,
+            buf.len()
+        )));
+    }
+    r.read_into(buf)
+}
+
+// Writes a byte buffer the way StateWriter::write(&Vec<u8>) does.
+pub fn write_buffer(w: &mut StateWriter, buf: &[u8]) {
+    w.write(&buf.len());
+    w.write_bytes(buf);
+}
+
+// Writes the state of |component| with its length, for components that may be swapped for another
+// kind between saving and loading, e.g. a device plugged in a controller port.
+pub fn write_section<S: Savestate + ?Sized>(w: &mut StateWriter, component: &S) {
+    let mut section = StateWriter::new();
+    component.save_state(&mut section);
+    w.write(&section.into_bytes());
+}
+
+// Reads a state written by write_section(). Fails if |component| does not read the exact state.
+pub fn read_section<S: Savestate + ?Sized>(
+    r: &mut StateReader,
+    component: &mut S,
+) -> Result<(), SimpleError> {
+    let len = r.read::<usize>()?;
+    let mut section = StateReader::new(r.read_bytes(len)?);
+    component.load_state(&mut section)?;
+    if !section.is_at_end() {
+        return Err(SimpleError::new(
+            "save state does not match the component it is loaded into",
+        ));
+    }
+    Ok(())
+}
+
+// Saves the state of |component| to a blob.
+pub fn save<S: Savestate + ?Sized>(component: &S) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    w.write_bytes(SAVESTATE_MAGIC);
+    w.write(&SAVESTATE_VERSION);
+    component.save_state(&mut w);
+    w.into_bytes()
+}
+
+// Restores the state of |component| from a blob made by save(). On error, |component| is left
+// untouched.
+pub fn load<S: Savestate + ?Sized>(component: &mut S, data: &[u8]) -> Result<(), SimpleError> {
+    let mut r = StateReader::new(data);
+    if data.len() < SAVESTATE_MAGIC.len() || r.read_bytes(SAVESTATE_MAGIC.len())? != SAVESTATE_MAGIC
+    {
+        return Err(SimpleError::new("not a save state"));
+    }
+    let version = r.read::<u32>()?;
+    if version != SAVESTATE_VERSION {
+        return Err(SimpleError::new(format!(
+            "incompatible save state version {}, expected {}",
+            version, SAVESTATE_VERSION
+        )));
+    }
+
+    let backup = save(component);
+    let result = component.load_state(&mut r).and_then(|_| {
+        if r.is_at_end() {
+            Ok(())
+        } else {
+            Err(SimpleError::new("save state has trailing data"))
+        }
+    });
+    if result.is_err() {
+        let mut r = StateReader::new(&backup[SAVESTATE_MAGIC.len() + 4..]);
+        component
+            .load_state(&mut r)
+            .expect("failed to restore the state before loading");
+    }
+    result
+}
+
+pub fn save_to_file<S: Savestate + ?Sized, P: AsRef<Path>>(
+    component: &S,
+    path: P,
+) -> Result<(), SimpleError> {
+    std::fs::write(path, save(component)).map_err(SimpleError::from)
+}
+
+pub fn load_from_file<S: Savestate + ?Sized, P: AsRef<Path>>(
+    component: &mut S,
+    path: P,
+) -> Result<(), SimpleError> {
+    let data = std::fs::read(path).map_err(SimpleError::from)?;
+    load(component, &data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Component {
+        a: u8,
+        b: u64,
+        c: Option<u16>,
+        d: Vec<u8>,
+    }
+
+    impl Savestate for Component {
+        fn save_state(&self, w: &mut StateWriter) {
+            w.write(&self.a);
+            w.write(&self.b);
+            w.write(&self.c);
+            w.write(&self.d);
+        }
+
+        fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+            self.a = r.read()?;
+            self.b = r.read()?;
+            self.c = r.read()?;
+            self.d = r.read()?;
+            Ok(())
+        }
+    }
+
+    fn component() -> Component {
+        Component {
+            a: 0x42,
+            b: 0x1234_5678_9abc,
+            c: Some(0xbeef),
+            d: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn test_save_load() {
+        let data = save(&component());
+        let mut loaded = Component::default();
+
+        assert_eq!(load(&mut loaded, &data), Ok(()));
+
+        assert_eq!(loaded, component());
+    }
+
+    #[test]
+    fn test_incompatible_version() {
+        let mut data = save(&component());
+        data[SAVESTATE_MAGIC.len()] = 0xff;
+        let mut loaded = Component::default();
+
+        let err = load(&mut loaded, &data).unwrap_err();
+
+        assert_eq!(err.as_str().contains("version 255"), true);
+    }
+
+    #[test]
+    fn test_not_a_save_state() {
+        let mut loaded = Component::default();
+
+        assert_eq!(load(&mut loaded, b"NES\x1a").is_err(), true);
+    }
+
+    #[test]
+    fn test_failed_load_leaves_component_untouched() {
+        let mut data = save(&component());
+        data.truncate(data.len() - 1);
+        let mut loaded = Component {
+            a: 7,
+            ..Default::default()
+        };
+
+        assert_eq!(load(&mut loaded, &data).is_err(), true);
+
+        assert_eq!(loaded.a, 7);
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let mut data = save(&component());
+        data.push(0);
+        let mut loaded = Component::default();
+
+        assert_eq!(load(&mut loaded, &data).is_err(), true);
+    }
+
+    #[test]
+    fn test_file() {
+        let path = std::env::temp_dir().join("nes_emulator_test_savestate.state");
+        let mut loaded = Component::default();
+
+        save_to_file(&component(), &path).unwrap();
+        load_from_file(&mut loaded, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, component());
+    }
+}
@@ -0,0 +1,433 @@
+/**
+ * Labels of a game, loaded from the debug files of assemblers and emulators:
+ *   - ca65/ld65 .dbg files, written by "ld65 --dbgfile";
+ *   - FCEUX .nl files, one per 16KB PRG ROM bank ("game.nes.0.nl", "game.nes.1.nl", ...) and one
+ *     for RAM ("game.nes.ram.nl");
+ *   - Mesen .mlb files.
+ *
+ * A label is keyed by CPU address and PRG ROM bank, since different banks of a game are mapped at
+ * the same addresses. Banks are 16KB like in FCEUX, and a label in PRG ROM matches its bank
+ * wherever the mapper puts it. Labels outside of PRG ROM, e.g. in RAM, have no bank.
+ *
+ * See https://cc65.github.io/doc/debugging.html, https://fceux.com/web/help/NLFilesFormat.html and
+ * https://www.mesen.ca/docs/debugging/debuggerintegration.html
+ */
+use simple_error::SimpleError;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+pub const PRG_BANK_SIZE: usize = 0x4000;
+
+const INES_HEADER_SIZE: usize = 16;
+const PRG_ROM_ADDR_START: u16 = 0x8000;
+const PRG_RAM_ADDR_START: u16 = 0x6000;
+const INTERNAL_RAM_SIZE: u16 = 0x800;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u16,
+    // The 16KB PRG ROM bank of the label, None outside of PRG ROM or if the file does not tell.
+    pub bank: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+    // Indices in |symbols|, by address in the bank and bank for labels in PRG ROM, or by CPU
+    // address. The first label at an address wins.
+    by_location: HashMap<(u16, Option<usize>), usize>,
+    by_addr: HashMap<u16, usize>,
+    by_name: HashMap<String, usize>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    pub fn add(&mut self, name: &str, addr: u16, bank: Option<usize>) {
+        let index = self.symbols.len();
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            addr,
+            bank,
+        });
+        self.by_location
+            .entry(location(addr, bank))
+            .or_insert(index);
+        self.by_addr.entry(addr).or_insert(index);
+        self.by_name.entry(name.to_string()).or_insert(index);
+    }
+
+    // Returns the label at |addr|, which is in PRG ROM |bank| if known.
+    pub fn label(&self, addr: u16, bank: Option<usize>) -> Option<&str> {
+        let index = match bank {
+            Some(_) => self
+                .by_location
+                .get(&location(addr, bank))
+                .or_else(|| self.by_location.get(&(addr, None))),
+            None => self
+                .by_location
+                .get(&(addr, None))
+                .or_else(|| self.by_addr.get(&addr)),
+        };
+        index.map(|index| self.symbols[*index].name.as_str())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.by_name.get(name).map(|index| &self.symbols[*index])
+    }
+
+    // Loads the symbol file at |path|, whose format is told by its extension. Returns the number
+    // of labels loaded.
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, SimpleError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(SimpleError::from)?;
+        let file_name = path.file_name().map_or(String::new(), |name| {
+            name.to_string_lossy().to_ascii_lowercase()
+        });
+        let len = self.len();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("dbg") => self.load_dbg(&text)?,
+            Some("mlb") => self.load_mlb(&text)?,
+            Some("nl") => {
+                // The bank is the last part of the name before the extension, in hex.
+                let bank = file_name
+                    .trim_end_matches(".nl")
+                    .rsplit('.')
+                    .next()
+                    .and_then(|bank| usize::from_str_radix(bank, 16).ok());
+                self.load_nl(&text, bank)?
+            }
+            _ => {
+                return Err(SimpleError::new(format!(
+                    "unknown symbol file format: {}",
+                    path.display()
+                )))
+            }
+        }
+        Ok(self.len() - len)
+    }
+
+    // Loads the symbol files next to the ROM at |rom_path|: "game.dbg", "game.mlb" and
+    // "game.nes.*.nl". Returns the number of labels loaded.
+    pub fn load_beside<P: AsRef<Path>>(&mut self, rom_path: P) -> Result<usize, SimpleError> {
+        let rom_path = rom_path.as_ref();
+        let mut paths = vec![
+            rom_path.with_extension("dbg"),
+            rom_path.with_extension("mlb"),
+        ];
+        let dir = match rom_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = format!(
+            "{}.",
+            rom_path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        if let Ok(entries) = fs::read_dir(dir) {
+            let mut nl_paths: Vec<_> = entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    name.starts_with(&prefix) && name.ends_with(".nl")
+                })
+                .collect();
+            nl_paths.sort();
+            paths.extend(nl_paths);
+        }
+        let mut count = 0;
+        for path in paths.iter().filter(|path| path.is_file()) {
+            count += self.load_file(path)?;
+        }
+        Ok(count)
+    }
+
+    // Loads the labels of a ca65/ld65 .dbg file. Banks are found from where segments are in the
+    // output file, which must be an iNES image.
+    pub fn load_dbg(&mut self, text: &str) -> Result<(), SimpleError> {
+        // Start address and offset in the output file of each segment.
+        let mut segments = HashMap::new();
+        let mut labels = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let (kind, fields) = match line.split_once('\t') {
+                Some((kind, fields)) => (kind, dbg_fields(fields)),
+                None => continue,
+            };
+            let number = |key| {
+                fields
+                    .get(key)
+                    .map(|val: &&str| parse_dbg_number(val))
+                    .transpose()
+                    .map_err(|_| {
+                        SimpleError::new(format!("invalid {} on line {} of .dbg file", key, i + 1))
+                    })
+            };
+            match kind {
+                "seg" => {
+                    segments.insert(number("id")?, (number("start")?, number("ooffs")?));
+                }
+                "sym" if fields.get("type") == Some(&"lab") => {
+                    let name = fields.get("name").unwrap_or(&"").trim_matches('"');
+                    if let Some(val) = number("val")? {
+                        labels.push((name.to_string(), val, number("seg")?));
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (name, val, segment) in labels {
+            let bank = match segments.get(&segment) {
+                // A label below the start of its segment is not in it, and gets no bank.
+                Some((Some(start), Some(offset))) if *offset >= INES_HEADER_SIZE => val
+                    .checked_sub(*start)
+                    .map(|delta| (offset - INES_HEADER_SIZE + delta) / PRG_BANK_SIZE),
+                _ => None,
+            };
+            let addr = val as u16;
+            self.add(&name, addr, bank.filter(|_| addr >= PRG_ROM_ADDR_START));
+        }
+        Ok(())
+    }
+
+    // Loads the labels of an FCEUX .nl file of PRG ROM |bank|, or of RAM if None.
+    pub fn load_nl(&mut self, text: &str, bank: Option<usize>) -> Result<(), SimpleError> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if !line.starts_with('$') {
+                continue;
+            }
+            let mut fields = line[1..].split('#');
+            // Arrays are "$addr/size".
+            let addr = fields.next().unwrap_or("").split('/').next().unwrap_or("");
+            let addr = u16::from_str_radix(addr, 16).map_err(|_| {
+                SimpleError::new(format!("invalid address on line {} of .nl file", i + 1))
+            })?;
+            match fields.next() {
+                Some(name) if !name.is_empty() => {
+                    self.add(name, addr, bank.filter(|_| addr >= PRG_ROM_ADDR_START))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Loads the labels of a Mesen .mlb file. Labels in PRG ROM are given by offset; they are put
+    // in the window at $8000.
+    pub fn load_mlb(&mut self, text: &str) -> Result<(), SimpleError> {
+        for (i, line) in text.lines().enumerate() {
+            let fields: Vec<&str> = line.trim().splitn(4, ':').collect();
+            if fields.len() < 3 || fields[2].is_empty() {
+                continue;
+            }
+            // Ranges are "start-end".
+            let start = fields[1].split('-').next().unwrap_or("");
+            let offset = usize::from_str_radix(start, 16).map_err(|_| {
+                SimpleError::new(format!("invalid address on line {} of .mlb file", i + 1))
+            })?;
+            let name = fields[2];
+            match fields[0] {
+                "P" | "NesPrgRom" => {
+                    let addr = PRG_ROM_ADDR_START + (offset % PRG_BANK_SIZE) as u16;
+                    self.add(name, addr, Some(offset / PRG_BANK_SIZE));
+                }
+                "R" | "NesInternalRam" => {
+                    self.add(name, offset as u16 % INTERNAL_RAM_SIZE, None);
+                }
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => {
+                    self.add(name, PRG_RAM_ADDR_START.wrapping_add(offset as u16), None);
+                }
+                "G" | "NesMemory" => self.add(name, offset as u16, None),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// Where a label is: its address in its bank if it is in PRG ROM, since banks move.
+fn location(addr: u16, bank: Option<usize>) -> (u16, Option<usize>) {
+    match bank {
+        Some(_) => (addr % PRG_BANK_SIZE as u16, bank),
+        None => (addr, None),
+    }
+}
+
+// Splits the "key=value,key=value" fields of a .dbg line. Values may be quoted.
+fn dbg_fields(text: &str) -> HashMap<&str, &str> {
+    let mut fields = HashMap::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ',')))
+    {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                if let Some((key, val)) = text[start..i].split_once('=') {
+                    fields.insert(key, val);
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    fields
+}
+
+fn parse_dbg_number(text: &str) -> Result<usize, std::num::ParseIntError> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_label() {
+        let mut symbols = Symbols::new();
+        symbols.add("counter", 0x0010, None);
+        symbols.add("bank0_init", 0x8000, Some(0));
+        symbols.add("bank1_init", 0x8000, Some(1));
+        symbols.add("nmi", 0xc000, Some(3));
+        symbols.add("alias", 0x0010, None);
+
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols.label(0x0010, None), Some("counter"));
+        assert_eq!(symbols.label(0x8000, Some(1)), Some("bank1_init"));
+        assert_eq!(symbols.label(0x8000, Some(2)), None);
+        // Without banks, the first label at the address.
+        assert_eq!(symbols.label(0x8000, None), Some("bank0_init"));
+        // Bank 3 mapped at $8000 instead of $C000.
+        assert_eq!(symbols.label(0x8000, Some(3)), Some("nmi"));
+        assert_eq!(
+            symbols.get("nmi"),
+            Some(&Symbol {
+                name: "nmi".to_string(),
+                addr: 0xc000,
+                bank: Some(3)
+            })
+        );
+        assert_eq!(symbols.get("missing"), None);
+    }
+
+    #[test]
+    fn test_load_dbg() {
+        let mut symbols = Symbols::new();
+        let text = [
+            "version\tmajor=2,minor=0",
+            "seg\tid=0,name=\"HEADER\",start=0x000000,size=0x0010,addrsize=absolute,type=ro,\
+             oname=\"game.nes\",ooffs=0",
+            "seg\tid=1,name=\"CODE\",start=0x00C000,size=0x4000,addrsize=absolute,type=ro,\
+             oname=\"game.nes\",ooffs=16400",
+            "seg\tid=2,name=\"ZEROPAGE\",start=0x000000,size=0x0010,addrsize=zeropage,type=rw",
+            "sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=1,ref=2,val=0xC010,seg=1,\
+             type=lab",
+            "sym\tid=1,name=\"frame\",addrsize=zeropage,scope=0,def=3,val=0x2,seg=2,type=lab",
+            "sym\tid=2,name=\"PPUCTRL\",addrsize=absolute,scope=0,def=4,val=0x2000,type=equ",
+            // Below the start of its segment.
+            "sym\tid=3,name=\"stray\",addrsize=absolute,scope=0,def=5,val=0x8000,seg=1,type=lab",
+        ]
+        .join("\n");
+        symbols.load_dbg(&text).unwrap();
+
+        assert_eq!(
+            symbols.iter().cloned().collect::<Vec<Symbol>>(),
+            vec![
+                Symbol {
+                    name: "reset".to_string(),
+                    addr: 0xc010,
+                    bank: Some(1)
+                },
+                Symbol {
+                    name: "frame".to_string(),
+                    addr: 0x0002,
+                    bank: None
+                },
+                Symbol {
+                    name: "stray".to_string(),
+                    addr: 0x8000,
+                    bank: None
+                },
+            ]
+        );
+        assert_eq!(
+            Symbols::new().load_dbg("sym\tname=\"x\",val=0xZZ,type=lab"),
+            Err(SimpleError::new("invalid val on line 1 of .dbg file"))
+        );
+    }
+
+    #[test]
+    fn test_load_nl() {
+        let mut symbols = Symbols::new();
+        symbols
+            .load_nl("$C000#Reset#Power on\n$C010##Only a comment\n", Some(1))
+            .unwrap();
+        symbols.load_nl("$0300/10#Buffer#\n", None).unwrap();
+
+        assert_eq!(symbols.label(0xc000, Some(1)), Some("Reset"));
+        assert_eq!(symbols.label(0xc010, Some(1)), None);
+        assert_eq!(symbols.label(0x0300, None), Some("Buffer"));
+        assert_eq!(
+            symbols.load_nl("$XYZ#Bad#", None),
+            Err(SimpleError::new("invalid address on line 1 of .nl file"))
+        );
+    }
+
+    #[test]
+    fn test_load_mlb() {
+        let mut symbols = Symbols::new();
+        symbols
+            .load_mlb(
+                "P:4010:Reset:Power on\nR:0010-0011:pointer\nW:0100:save_data\n\
+                 G:2000:PPUCTRL\nP:0020::Only a comment\n",
+            )
+            .unwrap();
+
+        assert_eq!(symbols.label(0xc010, Some(1)), Some("Reset"));
+        assert_eq!(symbols.get("Reset").map(|symbol| symbol.addr), Some(0x8010));
+        assert_eq!(symbols.label(0x0010, None), Some("pointer"));
+        assert_eq!(symbols.label(0x6100, None), Some("save_data"));
+        assert_eq!(symbols.label(0x2000, None), Some("PPUCTRL"));
+        assert_eq!(symbols.len(), 4);
+    }
+
+    #[test]
+    fn test_load_beside() {
+        let dir = std::env::temp_dir().join(format!("symbols_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("game.nes.0.nl"), "$8000#Start#\n").unwrap();
+        fs::write(dir.join("game.nes.ram.nl"), "$0000#temp#\n").unwrap();
+        fs::write(dir.join("game.mlb"), "P:0004:Loop\n").unwrap();
+        fs::write(dir.join("other.nes.0.nl"), "$8000#Other#\n").unwrap();
+
+        let mut symbols = Symbols::new();
+        let count = symbols.load_beside(dir.join("game.nes"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(count, Ok(3));
+        assert_eq!(symbols.label(0x8000, Some(0)), Some("Start"));
+        assert_eq!(symbols.label(0x0000, None), Some("temp"));
+        assert_eq!(symbols.label(0x8004, Some(0)), Some("Loop"));
+    }
+}
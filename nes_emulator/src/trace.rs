@@ -0,0 +1,281 @@
+/**
+ * Trace of executed instructions, in the format of nestest.log.
+ *
+ * Each line shows the state of the CPU before an instruction:
+ *
+ *   C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+ *
+ * i.e. the program counter, the bytes of the instruction, the disassembly annotated with the
+ * effective address and the value found there, the registers, the PPU scanline and dot, and the
+ * number of CPU cycles elapsed. The PPU position is left out when the bus has no PPU. Operands
+ * are shown by label when the CPU has symbols. Unofficial opcodes are marked with '*', e.g.
+ * "*NOP $A9 = 00".
+ *
+ * Annotations are computed with peeks, so that tracing never changes what the program sees.
+ *
+ * See https://www.qmtpro.com/~nes/misc/nestest.log
+ */
+use crate::cpu::{AddressingMode, CPU, STATUS_BIT5};
+use crate::disasm::{self, Instruction};
+
+// Formats the instruction at the program counter of |cpu|, with the PPU at |ppu_position|
+// (scanline, dot).
+pub fn trace_line(cpu: &CPU, ppu_position: Option<(usize, usize)>) -> String {
+    let instruction = disasm::decode(cpu.pc, |addr| cpu.peek_mem(addr));
+    let bytes: Vec<String> = instruction
+        .bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    let mark = if instruction.mnemonic.is_some() && !instruction.official {
+        '*'
+    } else {
+        ' '
+    };
+    let mut line = format!(
+        "{:04X}  {:<8} {}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+        cpu.pc,
+        bytes.join(" "),
+        mark,
+        annotated(cpu, &instruction),
+        cpu.reg_a,
+        cpu.reg_x,
+        cpu.reg_y,
+        cpu.reg_status.bits() | STATUS_BIT5,
+        cpu.sp,
+    );
+    if let Some((scanline, dot)) = ppu_position {
+        line.push_str(&format!(" PPU:{:>3},{:>3}", scanline, dot));
+    }
+    line.push_str(&format!(" CYC:{}", cpu.cycles));
+    line
+}
+
+// The disassembly of |instruction|, followed by the addresses it goes through and the value it
+// operates on, e.g. "LDA ($80,X) @ 80 = 0200 = 5A".
+fn annotated(cpu: &CPU, instruction: &Instruction) -> String {
+    let text = instruction.to_string_with(|addr| cpu.label(addr).map(str::to_string));
+    if instruction.mnemonic.is_none() {
+        return text;
+    }
+    let operand = instruction.operand();
+    let peek = |addr: u16| cpu.peek_mem(addr);
+    let peek_zero_page16 =
+        |ptr: u8| u16::from_le_bytes([peek(ptr as u16), peek(ptr.wrapping_add(1) as u16)]);
+    match instruction.mode {
+        AddressingMode::ZeroPage => format!("{} = {:02X}", text, peek(operand)),
+        AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+            let index = if instruction.mode == AddressingMode::ZeroPageX {
+                cpu.reg_x
+            } else {
+                cpu.reg_y
+            };
+            let addr = (operand as u8).wrapping_add(index) as u16;
+            format!("{} @ {:02X} = {:02X}", text, addr, peek(addr))
+        }
+        // Jumps do not operate on memory.
+        AddressingMode::Absolute if instruction.target().is_some() => text,
+        AddressingMode::Absolute => format!("{} = {:02X}", text, peek(operand)),
+        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+            let index = if instruction.mode == AddressingMode::AbsoluteX {
+                cpu.reg_x
+            } else {
+                cpu.reg_y
+            };
+            let addr = operand.wrapping_add(index as u16);
+            format!("{} @ {:04X} = {:02X}", text, addr, peek(addr))
+        }
+        AddressingMode::Indirect => {
+            // Same bug as the CPU: the pointer does not carry into its high byte.
+            let hi_addr = (operand & 0xff00) | (operand.wrapping_add(1) & 0x00ff);
+            let addr = u16::from_le_bytes([peek(operand), peek(hi_addr)]);
+            format!("{} = {:04X}", text, addr)
+        }
+        AddressingMode::IndirectX => {
+            let ptr = (operand as u8).wrapping_add(cpu.reg_x);
+            let addr = peek_zero_page16(ptr);
+            format!("{} @ {:02X} = {:04X} = {:02X}", text, ptr, addr, peek(addr))
+        }
+        AddressingMode::IndirectY => {
+            let base = peek_zero_page16(operand as u8);
+            let addr = base.wrapping_add(cpu.reg_y as u16);
+            format!(
+                "{} = {:04X} @ {:04X} = {:02X}",
+                text,
+                base,
+                addr,
+                peek(addr)
+            )
+        }
+        _ => text,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nrom_image;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::{Mem, Status};
+    use crate::nes::Nes;
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    // A writer whose content stays readable after it is given to the CPU.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn lines(&self) -> Vec<String> {
+            let text = String::from_utf8(self.0.borrow().clone()).unwrap();
+            text.lines().map(|line| line.to_string()).collect()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn cpu(program: &[u8]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load(program).unwrap();
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_trace_line() {
+        let mut cpu = cpu(&[0x4c, 0xf5, 0xc5]);
+        cpu.pc = 0x8000;
+        cpu.reg_status = Status::I;
+
+        assert_eq!(
+            trace_line(&cpu, Some((0, 21))),
+            "8000  4C F5 C5  JMP $C5F5                       \
+             A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7"
+        );
+        assert_eq!(
+            trace_line(&cpu, None),
+            "8000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7"
+        );
+
+        let cpu = self::cpu(&[0x04, 0xa9]);
+        assert_eq!(
+            trace_line(&cpu, None),
+            "8000  04 A9    *NOP $A9 = 00                    A:00 X:00 Y:00 P:20 SP:FD CYC:7"
+        );
+    }
+
+    fn annotation(program: &[u8], setup: impl Fn(&mut CPU)) -> String {
+        let mut cpu = cpu(program);
+        setup(&mut cpu);
+        let instruction = disasm::decode(cpu.pc, |addr| cpu.peek_mem(addr));
+        annotated(&cpu, &instruction)
+    }
+
+    #[test]
+    fn test_annotations() {
+        let memory = |cpu: &mut CPU| {
+            cpu.reg_x = 0x02;
+            cpu.reg_y = 0x03;
+            let mem = cpu.bus_mut::<Mem>().unwrap();
+            for (addr, val) in [
+                (0x0010, 0x42),
+                (0x0012, 0x43),
+                (0x0013, 0x44),
+                (0x0020, 0x00),
+                (0x0021, 0x03),
+                (0x0300, 0x89),
+                (0x0302, 0x8a),
+                (0x0303, 0x8b),
+                (0x02ff, 0x00),
+                (0x0200, 0x07),
+            ] {
+                mem.write(addr, val);
+            }
+        };
+
+        assert_eq!(annotation(&[0xa9, 0x10], memory), "LDA #$10");
+        assert_eq!(annotation(&[0xa5, 0x10], memory), "LDA $10 = 42");
+        assert_eq!(annotation(&[0xb5, 0x10], memory), "LDA $10,X @ 12 = 43");
+        assert_eq!(annotation(&[0xb6, 0x10], memory), "LDX $10,Y @ 13 = 44");
+        assert_eq!(annotation(&[0xad, 0x00, 0x03], memory), "LDA $0300 = 89");
+        assert_eq!(
+            annotation(&[0xbd, 0x00, 0x03], memory),
+            "LDA $0300,X @ 0302 = 8A"
+        );
+        assert_eq!(
+            annotation(&[0x6c, 0xff, 0x02], memory),
+            "JMP ($02FF) = 0700"
+        );
+        assert_eq!(
+            annotation(&[0xa1, 0x1e], memory),
+            "LDA ($1E,X) @ 20 = 0300 = 89"
+        );
+        assert_eq!(
+            annotation(&[0xb1, 0x20], memory),
+            "LDA ($20),Y = 0300 @ 0303 = 8B"
+        );
+        assert_eq!(annotation(&[0x20, 0x00, 0x90], memory), "JSR $9000");
+        assert_eq!(annotation(&[0x0a], memory), "ASL A");
+        assert_eq!(annotation(&[0x02], memory), ".byte $02");
+    }
+
+    #[test]
+    fn test_labels() {
+        let labels = |cpu: &mut CPU| {
+            cpu.symbols_mut().add("counter", 0x0010, None);
+            cpu.symbols_mut().add("main", 0x8000, None);
+        };
+
+        assert_eq!(annotation(&[0xa5, 0x10], labels), "LDA counter = 00");
+        assert_eq!(annotation(&[0x4c, 0x00, 0x80], labels), "JMP main");
+    }
+
+    #[test]
+    fn test_tracer() {
+        // LDX #$01
+        // INX
+        // INX
+        let mut cpu = cpu(&[0xa2, 0x01, 0xe8, 0xe8]);
+        let buffer = SharedBuffer::default();
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.is_tracing(), false);
+        cpu.set_tracer(Some(Box::new(buffer.clone())));
+        cpu.step().unwrap();
+        assert_eq!(cpu.set_tracer(None).is_some(), true);
+        cpu.step().unwrap();
+
+        assert_eq!(
+            buffer.lines(),
+            vec!["8002  E8        INX                             A:00 X:01 Y:00 P:20 SP:FD CYC:9"]
+        );
+    }
+
+    #[test]
+    fn test_tracer_shows_ppu_position() {
+        // INX
+        // JMP $8000
+        let image = nrom_image(&[0xe8, 0x4c, 0x00, 0x80]);
+        let mut nes = Nes::new(Cartridge::parse(&image).unwrap());
+        let buffer = SharedBuffer::default();
+        nes.cpu_mut().set_tracer(Some(Box::new(buffer.clone())));
+
+        nes.step_instruction().unwrap();
+        nes.step_instruction().unwrap();
+
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].ends_with("PPU:  0, 21 CYC:7"), true);
+        assert_eq!(lines[1].ends_with("PPU:  0, 27 CYC:9"), true);
+    }
+}
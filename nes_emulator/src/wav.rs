@@ -0,0 +1,54 @@
+/**
+ * Minimal writer of 16-bit PCM mono WAV files.
+ */
+use std::io::{self, Write};
+
+const BITS_PER_SAMPLE: u16 = 16;
+const NUM_CHANNELS: u16 = 1;
+
+// Writes |samples| in [-1.0, 1.0] to |w| as a WAV file. Samples out of range are clipped.
+pub fn write_wav<W: Write>(w: &mut W, samples: &[f32], sample_rate: u32) -> io::Result<()> {
+    let block_align = NUM_CHANNELS * BITS_PER_SAMPLE / 8;
+    let data_len = samples.len() as u32 * block_align as u32;
+
+    w.write_all(b"RIFF")?;
+    w.write_all(&(36 + data_len).to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&16u32.to_le_bytes())?;
+    // PCM.
+    w.write_all(&1u16.to_le_bytes())?;
+    w.write_all(&NUM_CHANNELS.to_le_bytes())?;
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    w.write_all(b"data")?;
+    w.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        let val = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        w.write_all(&val.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_wav() {
+        let mut buf: Vec<u8> = Vec::new();
+
+        assert_eq!(write_wav(&mut buf, &[0.0, 1.0, -2.0], 44100).is_ok(), true);
+
+        assert_eq!(buf.len(), 44 + 6);
+        assert_eq!(&buf[0..4], b"RIFF");
+        assert_eq!(&buf[8..12], b"WAVE");
+        assert_eq!(&buf[40..44], &6u32.to_le_bytes());
+        assert_eq!(&buf[44..], &[0x00, 0x00, 0xff, 0x7f, 0x01, 0x80]);
+    }
+}
@@ -0,0 +1,202 @@
+/**
+ * Zapper light gun.
+ *
+ * The Zapper has a photodiode that sees the CRT beam light up the spot it is aimed at, and a
+ * trigger. It reports D3 = 0 when it sees light and D4 = 1 while the trigger is pulled.
+ *
+ * Light is sensed against the frame being rendered: the aimed pixels must be bright and the beam
+ * must have passed them recently, since the phosphor of a CRT fades within a few scanlines.
+ *
+ * See https://wiki.nesdev.com/w/index.php/Zapper
+ */
+use crate::frame::{HEIGHT, WIDTH};
+use crate::input::{InputContext, InputDevice};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use simple_error::SimpleError;
+
+const LIGHT_NOT_DETECTED: u8 = 0b0000_1000;
+const TRIGGER_PULLED: u8 = 0b0001_0000;
+
+// Minimum luminance of a pixel seen as light.
+const LIGHT_THRESHOLD: u32 = 0xc0;
+
+// How many scanlines a pixel stays lit after the beam drew it.
+const LIGHT_PERSISTENCE_SCANLINES: usize = 26;
+
+// The photodiode sees a small area around the aimed pixel.
+const SENSE_RADIUS: usize = 1;
+
+pub struct Zapper {
+    // Aimed pixel. None if the gun aims off screen.
+    aim: Option<(usize, usize)>,
+    trigger: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper {
+            aim: None,
+            trigger: false,
+        }
+    }
+
+    // Aims at pixel (|x|, |y|). Coordinates off screen aim away from the TV.
+    pub fn aim(&mut self, x: usize, y: usize) {
+        self.aim = if x < WIDTH && y < HEIGHT {
+            Some((x, y))
+        } else {
+            None
+        };
+    }
+
+    pub fn aim_off_screen(&mut self) {
+        self.aim = None;
+    }
+
+    pub fn set_trigger(&mut self, pulled: bool) {
+        self.trigger = pulled;
+    }
+
+    fn detects_light(&self, ctx: &InputContext) -> bool {
+        let (frame, (x, y)) = match (ctx.frame, self.aim) {
+            (Some(frame), Some(aim)) => (frame, aim),
+            _ => return false,
+        };
+
+        // The beam has not reached the aimed pixel yet in this frame.
+        if ctx.scanline < y || (ctx.scanline == y && ctx.dot <= x) {
+            return false;
+        }
+        if ctx.scanline - y > LIGHT_PERSISTENCE_SCANLINES {
+            return false;
+        }
+
+        let x_range = x.saturating_sub(SENSE_RADIUS)..(x + SENSE_RADIUS + 1).min(WIDTH);
+        let y_range = y.saturating_sub(SENSE_RADIUS)..(y + SENSE_RADIUS + 1).min(HEIGHT);
+        y_range.into_iter().any(|py| {
+            x_range.clone().any(|px| {
+                let (r, g, b) = frame.pixel(px, py);
+                luminance(r, g, b) >= LIGHT_THRESHOLD
+            })
+        })
+    }
+}
+
+// Luminance with the weights of ITU-R BT.601, in [0, 255].
+fn luminance(r: u8, g: u8, b: u8) -> u32 {
+    (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000
+}
+
+impl InputDevice for Zapper {
+    fn write(&mut self, _val: u8) {}
+
+    fn read(&mut self, ctx: &InputContext) -> u8 {
+        self.peek(ctx)
+    }
+
+    fn peek(&self, ctx: &InputContext) -> u8 {
+        let mut val = 0;
+        if !self.detects_light(ctx) {
+            val |= LIGHT_NOT_DETECTED;
+        }
+        if self.trigger {
+            val |= TRIGGER_PULLED;
+        }
+        val
+    }
+}
+
+impl Savestate for Zapper {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.aim);
+        w.write(&self.trigger);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SimpleError> {
+        self.aim = r.read()?;
+        self.trigger = r.read()?;
+        Ok(())
+    }
+}
+
+impl Default for Zapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame::Frame;
+
+    fn context(frame: &Frame, scanline: usize, dot: usize) -> InputContext<'_> {
+        InputContext {
+            frame: Some(frame),
+            scanline,
+            dot,
+        }
+    }
+
+    #[test]
+    fn test_trigger() {
+        let mut zapper = Zapper::new();
+
+        zapper.set_trigger(true);
+
+        assert_eq!(zapper.read(&InputContext::none()), 0b0001_1000);
+    }
+
+    #[test]
+    fn test_detects_white() {
+        let mut frame = Frame::new();
+        frame.set_pixel(100, 50, (0xff, 0xff, 0xff));
+        let mut zapper = Zapper::new();
+        zapper.aim(101, 50);
+
+        assert_eq!(zapper.read(&context(&frame, 60, 0)), 0);
+    }
+
+    #[test]
+    fn test_ignores_dark_pixels() {
+        let mut frame = Frame::new();
+        frame.set_pixel(100, 50, (0x20, 0x20, 0xff));
+        let mut zapper = Zapper::new();
+        zapper.aim(100, 50);
+
+        assert_eq!(zapper.read(&context(&frame, 60, 0)), LIGHT_NOT_DETECTED);
+    }
+
+    #[test]
+    fn test_beam_not_yet_there() {
+        let mut frame = Frame::new();
+        frame.set_pixel(100, 50, (0xff, 0xff, 0xff));
+        let mut zapper = Zapper::new();
+        zapper.aim(100, 50);
+
+        assert_eq!(zapper.read(&context(&frame, 50, 90)), LIGHT_NOT_DETECTED);
+        assert_eq!(zapper.read(&context(&frame, 50, 110)), 0);
+    }
+
+    #[test]
+    fn test_light_fades() {
+        let mut frame = Frame::new();
+        frame.set_pixel(100, 50, (0xff, 0xff, 0xff));
+        let mut zapper = Zapper::new();
+        zapper.aim(100, 50);
+
+        assert_eq!(zapper.read(&context(&frame, 100, 0)), LIGHT_NOT_DETECTED);
+    }
+
+    #[test]
+    fn test_aim_off_screen() {
+        let mut frame = Frame::new();
+        frame.set_pixel(100, 50, (0xff, 0xff, 0xff));
+        let mut zapper = Zapper::new();
+        zapper.aim(100, 50);
+
+        zapper.aim(300, 50);
+
+        assert_eq!(zapper.read(&context(&frame, 60, 0)), LIGHT_NOT_DETECTED);
+    }
+}
@@ -0,0 +1,65 @@
+/**
+ * Runs every test ROM found under tests/roms/blargg/, e.g. the single ROMs of instr_test-v5, and
+ * checks that each reports success through the $6000 status protocol. ROMs that report through the
+ * screen or beeps only are not supported.
+ *
+ * See https://github.com/christopherpow/nes-test-roms
+ */
+use nes_emulator_lib::blargg;
+use nes_emulator_lib::cartridge::Cartridge;
+use nes_emulator_lib::nes::Nes;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+mod common;
+
+// The longest tests run for about 30 seconds.
+const MAX_FRAMES: usize = 60 * 60;
+
+// Returns the .nes files under |dir|, sorted.
+fn roms(dir: &Path) -> Vec<PathBuf> {
+    let mut roms = Vec::new();
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                roms.extend(self::roms(&path));
+            } else if path.extension().is_some_and(|ext| ext == "nes") {
+                roms.push(path);
+            }
+        }
+    }
+    roms.sort();
+    roms
+}
+
+fn run_rom(path: &Path) -> Result<(), String> {
+    let cartridge = Cartridge::from_file(path).map_err(|e| e.to_string())?;
+    let mut nes = Nes::new(cartridge);
+    let result = blargg::run(&mut nes, MAX_FRAMES).map_err(|e| e.to_string())?;
+    if result.passed() {
+        Ok(())
+    } else {
+        Err(format!(
+            "failed with code {}: {}",
+            result.code, result.message
+        ))
+    }
+}
+
+#[test]
+#[ignore = "needs the ROMs in tests/roms/blargg"]
+fn test_blargg_roms() {
+    let dir = common::rom_dir("blargg");
+    let roms = roms(&dir);
+    assert!(!roms.is_empty(), "no .nes file under tests/roms/blargg");
+    let failures: Vec<String> = roms
+        .iter()
+        .filter_map(|path| {
+            run_rom(path)
+                .err()
+                .map(|e| format!("{}: {}", path.strip_prefix(&dir).unwrap().display(), e))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
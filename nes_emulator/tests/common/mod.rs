@@ -0,0 +1,36 @@
+#![allow(dead_code)]
+/**
+ * Helpers of the tests that run test ROMs and data files of other projects.
+ *
+ * These files are not distributed with the sources. They go in tests/roms/, and the tests that
+ * need them are ignored by default: run them with `cargo test -- --ignored`. Such a test fails,
+ * rather than passes, when its files are missing.
+ */
+use std::fs;
+use std::path::PathBuf;
+
+// Returns the path of |name| in tests/roms/.
+pub fn rom_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("roms")
+        .join(name)
+}
+
+// Reads the file |name| in tests/roms/. Fails the test if it cannot.
+pub fn read_rom(name: &str) -> Vec<u8> {
+    fs::read(rom_path(name)).unwrap_or_else(|e| panic!("cannot read tests/roms/{}: {}", name, e))
+}
+
+// Reads the text file |name| in tests/roms/. Fails the test if it cannot.
+pub fn read_rom_text(name: &str) -> String {
+    fs::read_to_string(rom_path(name))
+        .unwrap_or_else(|e| panic!("cannot read tests/roms/{}: {}", name, e))
+}
+
+// Returns the path of the directory |name| in tests/roms/. Fails the test if it is missing.
+pub fn rom_dir(name: &str) -> PathBuf {
+    let dir = rom_path(name);
+    assert!(dir.is_dir(), "tests/roms/{} is missing", name);
+    dir
+}
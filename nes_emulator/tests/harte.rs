@@ -0,0 +1,266 @@
+/**
+ * Tom Harte's SingleStepTests of the NES 6502.
+ *
+ * Each opcode has a JSON file of 10,000 cases. A case gives the registers and the content of RAM
+ * before and after one instruction, and the bus activity of every cycle in between as
+ * [address, value, "read" or "write"]. The test runs each case on a flat memory behind a
+ * recording bus and checks the registers, RAM, the number of cycles and the access of each
+ * cycle, dummy reads and writes included.
+ *
+ * The opcodes the CPU implements are checked, i.e. the official ones and the stable unofficial
+ * ones that nestest uses. B and bit 5 of P are not flags and are not compared.
+ *
+ * See https://github.com/SingleStepTests/65x02/tree/main/nes6502
+ */
+use nes_emulator_lib::bus::{AccessKind, BusAccess, RecordingBus};
+use nes_emulator_lib::cpu::{Mem, Status, CPU};
+use nes_emulator_lib::disasm;
+use serde_json::Value;
+use std::fs;
+
+mod common;
+
+// Bits of P that are not flags.
+const NOT_FLAGS: u8 = 0b0011_0000;
+
+fn field(state: &Value, name: &str) -> Result<u64, String> {
+    state[name]
+        .as_u64()
+        .ok_or_else(|| format!("missing field {}", name))
+}
+
+// Returns the [address, value, ...] entries of |array|.
+fn entries(array: &Value) -> Result<Vec<(u16, u8, &Value)>, String> {
+    array
+        .as_array()
+        .ok_or("missing array")?
+        .iter()
+        .map(|entry| match (entry[0].as_u64(), entry[1].as_u64()) {
+            (Some(addr), Some(val)) => Ok((addr as u16, val as u8, &entry[2])),
+            _ => Err(format!("invalid entry {}", entry)),
+        })
+        .collect()
+}
+
+fn set_state(cpu: &mut CPU, state: &Value) -> Result<(), String> {
+    cpu.pc = field(state, "pc")? as u16;
+    cpu.sp = field(state, "s")? as u8;
+    cpu.reg_a = field(state, "a")? as u8;
+    cpu.reg_x = field(state, "x")? as u8;
+    cpu.reg_y = field(state, "y")? as u8;
+    cpu.reg_status = Status::from_bits_truncate(field(state, "p")? as u8) - Status::B;
+    let mem = cpu.bus_mut::<RecordingBus<Mem>>().unwrap().inner_mut();
+    for (addr, val, _) in entries(&state["ram"])? {
+        mem.write(addr, val);
+    }
+    Ok(())
+}
+
+fn check_state(cpu: &CPU, state: &Value) -> Result<(), String> {
+    let registers = [
+        ("pc", cpu.pc as u64),
+        ("s", cpu.sp as u64),
+        ("a", cpu.reg_a as u64),
+        ("x", cpu.reg_x as u64),
+        ("y", cpu.reg_y as u64),
+    ];
+    for (name, actual) in registers.iter() {
+        let expected = field(state, name)?;
+        if *actual != expected {
+            return Err(format!(
+                "{} is {:#x}, expected {:#x}",
+                name, actual, expected
+            ));
+        }
+    }
+    let p = field(state, "p")? as u8 | NOT_FLAGS;
+    if cpu.reg_status.bits() | NOT_FLAGS != p {
+        return Err(format!(
+            "p is {:#04x}, expected {:#04x}",
+            cpu.reg_status.bits() | NOT_FLAGS,
+            p
+        ));
+    }
+    for (addr, val, _) in entries(&state["ram"])? {
+        if cpu.peek_mem(addr) != val {
+            return Err(format!(
+                "${:04X} is {:#04x}, expected {:#04x}",
+                addr,
+                cpu.peek_mem(addr),
+                val
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn describe(access: Option<&BusAccess>) -> String {
+    match access {
+        Some(access) => format!(
+            "{:?} of {:#04x} at ${:04X}",
+            access.kind, access.val, access.addr
+        ),
+        None => "nothing".to_string(),
+    }
+}
+
+// Checks that |accesses| are the bus activity of |cycles|, one access per cycle.
+fn check_accesses(accesses: &[BusAccess], cycles: &[BusAccess]) -> Result<(), String> {
+    for cycle in 0..accesses.len().max(cycles.len()) {
+        let (actual, expected) = (accesses.get(cycle), cycles.get(cycle));
+        if actual != expected {
+            return Err(format!(
+                "cycle {}: {}, expected {}",
+                cycle + 1,
+                describe(actual),
+                describe(expected)
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Runs the case |test|.
+fn run_case(test: &Value) -> Result<(), String> {
+    let mut cpu = CPU::with_bus(Box::new(RecordingBus::new(Mem::new())));
+    set_state(&mut cpu, &test["initial"])?;
+    let cycles = entries(&test["cycles"])?
+        .into_iter()
+        .map(|(addr, val, kind)| match kind.as_str() {
+            Some("read") => Ok(BusAccess {
+                addr,
+                val,
+                kind: AccessKind::Read,
+            }),
+            Some("write") => Ok(BusAccess {
+                addr,
+                val,
+                kind: AccessKind::Write,
+            }),
+            _ => Err(format!("invalid bus activity {}", kind)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    cpu.step().map_err(|e| e.to_string())?;
+
+    check_state(&cpu, &test["final"])?;
+    if cpu.cycles != cycles.len() as u64 {
+        return Err(format!(
+            "took {} cycles, expected {}",
+            cpu.cycles,
+            cycles.len()
+        ));
+    }
+    let accesses = cpu.bus::<RecordingBus<Mem>>().unwrap().accesses();
+    check_accesses(accesses, &cycles)
+}
+
+// Runs all the cases of |tests| and reports the first failing one.
+fn run_cases(tests: &Value) -> Result<usize, String> {
+    let tests = tests.as_array().ok_or("not an array of cases")?;
+    let mut failures = 0;
+    let mut first_failure = None;
+    for test in tests {
+        if let Err(e) = run_case(test) {
+            failures += 1;
+            first_failure.get_or_insert_with(|| format!("{}: {}", test["name"], e));
+        }
+    }
+    match first_failure {
+        None => Ok(tests.len()),
+        Some(failure) => Err(format!(
+            "{} of {} cases failed, first {}",
+            failures,
+            tests.len(),
+            failure
+        )),
+    }
+}
+
+#[test]
+#[ignore = "needs tests/roms/nes6502/v1"]
+fn test_single_step_tests() {
+    let dir = common::rom_dir("nes6502/v1");
+    let mut failures = Vec::new();
+    for code in 0..=0xffu8 {
+        if disasm::decode(0, |_| code).mnemonic.is_none() {
+            continue;
+        }
+        let path = dir.join(format!("{:02x}.json", code));
+        let json = match fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(e) => {
+                failures.push(format!(
+                    "{:02x}: cannot read {}: {}",
+                    code,
+                    path.display(),
+                    e
+                ));
+                continue;
+            }
+        };
+        let result = serde_json::from_str(&json)
+            .map_err(|e| e.to_string())
+            .and_then(|tests| run_cases(&tests));
+        if let Err(e) = result {
+            failures.push(format!("{:02x}: {}", code, e));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn test_run_case() {
+    // JSR $FE34 with its operand on the stack: the high byte of the target is read after the
+    // return address is pushed over it.
+    let jsr = r#"[{
+        "name": "20 34 01",
+        "initial": {"pc": 508, "s": 255, "a": 0, "x": 0, "y": 0, "p": 36,
+            "ram": [[508, 32], [509, 52], [510, 1], [511, 0]]},
+        "final": {"pc": 65076, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+            "ram": [[508, 32], [509, 52], [510, 254], [511, 1]]},
+        "cycles": [[508, 32, "read"], [509, 52, "read"], [511, 0, "read"],
+            [511, 1, "write"], [510, 254, "write"], [510, 254, "read"]]
+    }]"#;
+
+    assert_eq!(run_cases(&serde_json::from_str(jsr).unwrap()), Ok(1));
+
+    // LDA $12,X
+    let lda = r#"[{
+        "name": "b5 12",
+        "initial": {"pc": 32768, "s": 253, "a": 0, "x": 1, "y": 0, "p": 36,
+            "ram": [[32768, 181], [32769, 18], [19, 128]]},
+        "final": {"pc": 32770, "s": 253, "a": 127, "x": 1, "y": 0, "p": 164,
+            "ram": [[19, 128]]},
+        "cycles": [[32768, 181, "read"], [32769, 18, "read"], [18, 0, "read"],
+            [19, 128, "read"]]
+    }]"#;
+
+    assert_eq!(
+        run_cases(&serde_json::from_str(lda).unwrap()),
+        Err("1 of 1 cases failed, first \"b5 12\": a is 0x80, expected 0x7f".to_string())
+    );
+}
+
+#[test]
+fn test_check_accesses() {
+    let access = |addr, val, kind| BusAccess { addr, val, kind };
+    let cycles = [
+        access(0x8000, 0xe6, AccessKind::Read),
+        access(0x8001, 0x10, AccessKind::Read),
+        access(0x0010, 0x00, AccessKind::Read),
+        access(0x0010, 0x00, AccessKind::Write),
+        access(0x0010, 0x01, AccessKind::Write),
+    ];
+
+    assert_eq!(check_accesses(&cycles, &cycles), Ok(()));
+    // Without the dummy write.
+    assert_eq!(
+        check_accesses(&[cycles[0], cycles[1], cycles[2], cycles[4]], &cycles),
+        Err("cycle 4: Write of 0x01 at $0010, expected Write of 0x00 at $0010".to_string())
+    );
+    assert_eq!(
+        check_accesses(&cycles[..4], &cycles),
+        Err("cycle 5: nothing, expected Write of 0x01 at $0010".to_string())
+    );
+}
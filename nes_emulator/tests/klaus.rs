@@ -0,0 +1,140 @@
+/**
+ * Klaus Dormann's 6502 functional and decimal tests.
+ *
+ * Both are 64KB images run by the CPU on a flat memory, without any NES hardware. The tests report
+ * their result by trapping, i.e. looping on a branch or a jump to itself. The functional test
+ * traps at a known address when all tests pass, and anywhere else when a test fails, with the
+ * number of the failing test at $0200. The decimal test traps when done, with its error flag at
+ * $000B.
+ *
+ * The decimal test needs the decimal mode of a 6502, which the 2A03 of the NES does not have.
+ * The addresses below are the ones of the default configuration.
+ *
+ * See https://github.com/Klaus2m5/6502_65C02_functional_tests
+ */
+use nes_emulator_lib::assembler;
+use nes_emulator_lib::cpu::{Mem, CPU};
+
+mod common;
+
+const FUNCTIONAL_START: u16 = 0x0400;
+const FUNCTIONAL_SUCCESS: u16 = 0x3469;
+const FUNCTIONAL_TEST_CASE: u16 = 0x0200;
+
+const DECIMAL_START: u16 = 0x0200;
+const DECIMAL_ERROR: u16 = 0x000b;
+
+// The functional test runs about 30 million instructions.
+const MAX_INSTRUCTIONS: u64 = 100_000_000;
+
+// Creates a CPU with |image| as its whole memory, starting at |start|.
+fn cpu_with_image(image: &[u8], start: u16) -> CPU {
+    let mut cpu = CPU::new();
+    cpu.bus_mut::<Mem>().unwrap().write_range(0, image).unwrap();
+    cpu.set_decimal_mode(true);
+    cpu.pc = start;
+    cpu
+}
+
+// Runs |cpu| until an instruction jumps to itself or the next one is an illegal opcode, and
+// returns the address where it stopped.
+fn run_until_trap(cpu: &mut CPU) -> Result<u16, String> {
+    for _ in 0..MAX_INSTRUCTIONS {
+        let pc = cpu.pc;
+        if cpu.step().is_err() || cpu.pc == pc {
+            return Ok(pc);
+        }
+    }
+    Err(format!(
+        "no trap after {} instructions, at ${:04X}",
+        MAX_INSTRUCTIONS, cpu.pc
+    ))
+}
+
+// Runs the functional test in |image| and reports the failing test, if any.
+fn run_functional(image: &[u8], success: u16) -> Result<(), String> {
+    let mut cpu = cpu_with_image(image, FUNCTIONAL_START);
+    let trap = run_until_trap(&mut cpu)?;
+    if trap == success {
+        Ok(())
+    } else {
+        Err(format!(
+            "test ${:02X} failed: trapped at ${:04X}",
+            cpu.peek_mem(FUNCTIONAL_TEST_CASE),
+            trap
+        ))
+    }
+}
+
+fn run_decimal(image: &[u8]) -> Result<(), String> {
+    let mut cpu = cpu_with_image(image, DECIMAL_START);
+    let trap = run_until_trap(&mut cpu)?;
+    match cpu.peek_mem(DECIMAL_ERROR) {
+        0 => Ok(()),
+        _ => Err(format!("decimal test failed: stopped at ${:04X}", trap)),
+    }
+}
+
+#[test]
+#[ignore = "needs tests/roms/6502_functional_test.bin"]
+fn test_functional() {
+    let image = common::read_rom("6502_functional_test.bin");
+    assert_eq!(run_functional(&image, FUNCTIONAL_SUCCESS), Ok(()));
+}
+
+#[test]
+#[ignore = "needs tests/roms/6502_decimal_test.bin"]
+fn test_decimal() {
+    let image = common::read_rom("6502_decimal_test.bin");
+    assert_eq!(run_decimal(&image), Ok(()));
+}
+
+// Assembles |source| into a 64KB image.
+fn image(source: &str) -> Vec<u8> {
+    let assembly = assembler::assemble(source).unwrap();
+    let mut image = vec![0; 0x10000];
+    let origin = assembly.origin as usize;
+    image[origin..origin + assembly.bytes.len()].copy_from_slice(&assembly.bytes);
+    image
+}
+
+#[test]
+fn test_runner() {
+    let functional = "
+        .org $0400
+        test_case = $0200
+        LDA #1
+        STA test_case
+        CMP #1
+        BNE fail
+        LDA #2
+        STA test_case
+        CMP #3
+        BNE fail
+    success:
+        JMP success
+    fail:
+        BNE fail
+    ";
+    let functional = image(functional);
+
+    assert_eq!(
+        run_functional(&functional, 0x0412),
+        Err("test $02 failed: trapped at $0415".to_string())
+    );
+    assert_eq!(run_functional(&functional, 0x0415), Ok(()));
+
+    let decimal = "
+        .org $0200
+        SED
+        CLC
+        LDA #$19
+        ADC #$01
+        CMP #$20
+        BEQ done
+        INC $0B
+    done:
+        .byte $db
+    ";
+    assert_eq!(run_decimal(&image(decimal)), Ok(()));
+}
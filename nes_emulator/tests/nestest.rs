@@ -0,0 +1,97 @@
+/**
+ * Conformance test of the CPU against nestest.
+ *
+ * nestest.nes exercises every instruction, including flags, the stack and page crossing. Started
+ * at $C000 ("automation mode"), it runs without a PPU or controllers, and nestest.log records the
+ * state of a reference emulator before each instruction. The test runs the ROM and compares our
+ * trace with the log line by line, reporting the first line that differs with the lines before
+ * it. The whole log is compared, including the unofficial opcodes it ends with.
+ *
+ * See https://www.qmtpro.com/~nes/misc/nestest.txt
+ */
+use nes_emulator_lib::bus::Bus;
+use nes_emulator_lib::cartridge::Cartridge;
+use nes_emulator_lib::cpu::Status;
+use nes_emulator_lib::nes::Nes;
+use nes_emulator_lib::trace;
+
+mod common;
+
+// Automation mode starts here instead of at the reset vector.
+const AUTOMATION_START: u16 = 0xc000;
+
+// Number of lines shown before the first difference.
+const CONTEXT_LINES: usize = 5;
+
+// Compares the trace produced by |next_line| with |expected|. |next_line| is called once per
+// line, so that the program stops at the first difference.
+// Returns the number of lines compared, or a report of the first difference.
+fn compare_trace<F: FnMut() -> String>(expected: &str, mut next_line: F) -> Result<usize, String> {
+    let expected: Vec<&str> = expected.lines().collect();
+    for (i, expected_line) in expected.iter().enumerate() {
+        let line = next_line();
+        if line != *expected_line {
+            let mut report = format!("trace differs at line {}:\n", i + 1);
+            for context in &expected[i.saturating_sub(CONTEXT_LINES)..i] {
+                report.push_str(&format!("           {}\n", context));
+            }
+            report.push_str(&format!("expected:  {}\n", expected_line));
+            report.push_str(&format!("actual:    {}\n", line));
+            return Err(report);
+        }
+    }
+    Ok(expected.len())
+}
+
+#[test]
+#[ignore = "needs tests/roms/nestest.nes and nestest.log"]
+fn test_nestest() {
+    let rom = common::read_rom("nestest.nes");
+    let log = common::read_rom_text("nestest.log");
+    let mut nes = Nes::new(Cartridge::parse(&rom).unwrap());
+    nes.cpu_mut().pc = AUTOMATION_START;
+    nes.cpu_mut().reg_status = Status::I;
+
+    let result = compare_trace(&log, || {
+        let line = trace::trace_line(nes.cpu(), nes.bus().ppu_position());
+        nes.step_instruction().unwrap();
+        line
+    });
+
+    match result {
+        Ok(lines) => assert!(lines > 0, "nestest.log is empty"),
+        Err(report) => panic!("{}", report),
+    }
+}
+
+#[test]
+fn test_compare_trace() {
+    let expected = "line 1\nline 2\nline 3\nC000  04 A9    *NOP $A9 = 00\nline 5\n";
+    let actual = |lines: &'static [&'static str]| {
+        let mut lines = lines.iter();
+        move || lines.next().unwrap().to_string()
+    };
+
+    assert_eq!(
+        compare_trace(
+            expected,
+            actual(&[
+                "line 1",
+                "line 2",
+                "line 3",
+                "C000  04 A9    *NOP $A9 = 00",
+                "line 5"
+            ])
+        ),
+        Ok(5)
+    );
+    assert_eq!(
+        compare_trace(expected, actual(&["line 1", "line 2", "other"])),
+        Err("trace differs at line 3:\n           \
+             line 1\n           \
+             line 2\n\
+             expected:  line 3\n\
+             actual:    other\n"
+            .to_string())
+    );
+}